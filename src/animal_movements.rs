@@ -0,0 +1,414 @@
+//! Between-herd transfers of animals.
+//!
+//! Every farm sends out a number of shipments per day according to its
+//! [ContactRate] (see [ShipmentDistribution]), each to a farm chosen from its
+//! [AdjacentFarms], in proportion to the [AdjacencyWeights] if present.
+//!
+//! A shipment is a batch of [MovementBatchSize] animals, that is sampled
+//! hypergeometrically from the susceptible, infected and recovered animals of
+//! the origin, and then physically moved to the destination. Thus the
+//! [HerdSize] changes on both sides, and infection enters the destination
+//! with the infected animals in the batch.
+//!
+//! Shipments to or from restricted farms may be stopped, see
+//! [crate::regulator_movement_restrictions].
+//!
+//! All movements are put out as [MovementEvents], whether or not they carry
+//! any infected animals. Chain [update_animal_movements] into
+//! [record_animal_movements] and then [infection_events_from_movements] to
+//! get [InfectionEvents] for e.g. the [crate::transmission_tree].
+//!
+//! The recorder is set up by [setup_animal_movements_recording].
+//!
+//! Note: This replaces [crate::between_herd_spread_model::update_between_herd_spread_model],
+//! and thus the two shouldn't run in the same scenario.
+//!
+
+use std::fs::File;
+
+use csv::Writer;
+use rand_distr::Hypergeometric;
+
+use crate::{
+    between_herd_spread_model::{
+        ContactRate, InfectionEvents, InfectionEventsBatchId, InfectionRoute, ShipmentDistribution,
+    },
+    csv_outputs::CsvOutputs,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    populations::{AdjacencyWeights, AdjacentFarms, Cattle, FarmId, HerdSize},
+    prelude::*,
+    regulator_movement_restrictions::MovementRestrictions,
+    scenario_rng::{AnimalMovementsStream, ProcessRng},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+/// Number of animals in a shipment. Smaller herds send out all their animals.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
+pub struct MovementBatchSize(pub usize);
+
+impl Default for MovementBatchSize {
+    fn default() -> Self {
+        Self(10)
+    }
+}
+
+/// A batch of animals moved from `origin` to `destination`.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimalMovement {
+    pub origin: FarmId,
+    pub destination: FarmId,
+    pub susceptible: usize,
+    pub infected: usize,
+    pub recovered: usize,
+}
+
+impl AnimalMovement {
+    pub fn batch_size(&self) -> usize {
+        self.susceptible + self.infected + self.recovered
+    }
+}
+
+/// All movements of a single tick.
+#[derive(Debug, Clone)]
+pub struct MovementEvents {
+    pub scenario_tick: Time,
+    pub movements: Vec<AnimalMovement>,
+}
+
+/// Components of the farms that send and receive animals.
+pub(crate) type MovingFarm = (
+    &'static mut HerdSize,
+    &'static mut Susceptible,
+    &'static mut Infected,
+    &'static mut Recovered,
+);
+
+/// Draws `batch_size` animals from the compartments without replacement,
+/// and returns the moved `(susceptible, infected, recovered)`.
+pub fn sample_batch<R: Rng + ?Sized>(
+    batch_size: usize,
+    susceptible: usize,
+    infected: usize,
+    recovered: usize,
+    rng: &mut R,
+) -> (usize, usize, usize) {
+    let total = susceptible + infected + recovered;
+    let batch_size = batch_size.min(total);
+    if batch_size == 0 {
+        return (0, 0, 0);
+    }
+    let moved_susceptible = Hypergeometric::new(total as u64, susceptible as u64, batch_size as u64)
+        .unwrap()
+        .sample(rng) as usize;
+    let remaining_batch = batch_size - moved_susceptible;
+    let moved_infected = if remaining_batch == 0 {
+        0
+    } else {
+        Hypergeometric::new(
+            (infected + recovered) as u64,
+            infected as u64,
+            remaining_batch as u64,
+        )
+        .unwrap()
+        .sample(rng) as usize
+    };
+    (
+        moved_susceptible,
+        moved_infected,
+        remaining_batch - moved_infected,
+    )
+}
+
+/// Moves a batch of `batch_size` animals, see [sample_batch], from `origin`
+/// to `destination`. Returns `None` if the origin has no animals left.
+pub(crate) fn transfer_batch<R: Rng + ?Sized>(
+    farms: &mut Query<'_, MovingFarm, With<Cattle>>,
+    (origin, origin_entity): (FarmId, Entity),
+    (destination, destination_entity): (FarmId, Entity),
+    batch_size: usize,
+    rng: &mut R,
+) -> Option<AnimalMovement> {
+    let (susceptible, infected, recovered) = {
+        let (mut herd_size, mut sus, mut inf, mut rec) = farms
+            .get_mut(origin_entity)
+            .expect("failed to find origin farm of a movement");
+        let batch = sample_batch(batch_size, sus.0, inf.0, rec.0, rng);
+        sus.0 -= batch.0;
+        inf.0 -= batch.1;
+        rec.0 -= batch.2;
+        herd_size.remove_animals(batch.0 + batch.1 + batch.2);
+        batch
+    };
+    if susceptible + infected + recovered == 0 {
+        // the origin has been emptied by earlier shipments
+        return None;
+    }
+
+    let (mut herd_size, mut sus, mut inf, mut rec) = farms
+        .get_mut(destination_entity)
+        .expect("failed to find destination farm of a movement");
+    sus.0 += susceptible;
+    inf.0 += infected;
+    rec.0 += recovered;
+    herd_size.add_animals(susceptible + infected + recovered);
+
+    Some(AnimalMovement {
+        origin,
+        destination,
+        susceptible,
+        infected,
+        recovered,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_animal_movements(
+    origins: Query<
+        (
+            Entity,
+            &FarmId,
+            &AdjacentFarms,
+            Option<&AdjacencyWeights>,
+            &ContactRate,
+        ),
+        With<Cattle>,
+    >,
+    mut farms: Query<MovingFarm, With<Cattle>>,
+    mut rng: ResMut<ProcessRng<AnimalMovementsStream>>,
+    batch_size: Option<Res<MovementBatchSize>>,
+    shipment_distribution: Option<Res<ShipmentDistribution>>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+    movement_restrictions: MovementRestrictions,
+) -> Option<MovementEvents> {
+    let batch_size = batch_size.map_or_else(MovementBatchSize::default, |x| *x);
+    let shipment_distribution = shipment_distribution.map_or_else(Default::default, |x| *x);
+
+    let shipments = origins
+        .iter()
+        .map(
+            |(entity, farm_id, adjacent_farms, adjacency_weights, contact_rate)| {
+                let shipments = shipment_distribution.sample_shipments(*contact_rate, &mut *rng);
+                (
+                    entity,
+                    *farm_id,
+                    adjacent_farms,
+                    adjacency_weights,
+                    shipments,
+                )
+            },
+        )
+        .filter(|(.., shipments)| *shipments > 0)
+        .collect_vec();
+
+    let mut movements = Vec::new();
+    for (origin_entity, origin, adjacent_farms, adjacency_weights, shipments) in shipments {
+        for _ in 0..shipments {
+            let destination = match adjacent_farms.choose_neighbour(adjacency_weights, &mut *rng) {
+                Some(destination) if *destination != origin => *destination,
+                _ => continue,
+            };
+            let destination_entity = *farm_map
+                .0
+                .get(&destination)
+                .expect("failed to find destination farm of a movement");
+            if !movement_restrictions.permits(origin_entity, destination_entity, &mut *rng) {
+                continue;
+            }
+
+            if let Some(movement) = transfer_batch(
+                &mut farms,
+                (origin, origin_entity),
+                (destination, destination_entity),
+                batch_size.0,
+                &mut *rng,
+            ) {
+                movements.push(movement);
+            }
+        }
+    }
+
+    if movements.is_empty() {
+        None
+    } else {
+        Some(MovementEvents {
+            scenario_tick: scenario_time.current_time(),
+            movements,
+        })
+    }
+}
+
+/// Movements that carried infected animals, as a batch of between-herd
+/// infection events.
+pub fn infection_events_from_movements(
+    In(events): In<Option<MovementEvents>>,
+    mut current_batch_id: ResMut<InfectionEventsBatchId>,
+) -> Option<InfectionEvents> {
+    let MovementEvents {
+        scenario_tick,
+        movements,
+    } = events?;
+    let events_values = movements
+        .into_iter()
+        .filter(|movement| movement.infected > 0)
+        .map(|movement| (movement.origin, movement.destination, movement.infected))
+        .collect_vec();
+    if events_values.is_empty() {
+        return None;
+    }
+
+    current_batch_id.0 += 1;
+    Some(InfectionEvents {
+        scenario_tick,
+        batch_id: current_batch_id.0,
+        route: InfectionRoute::Trade,
+        events_values,
+    })
+}
+
+#[derive(derive_more::From)]
+pub struct AnimalMovementsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for AnimalMovementsRecorder {
+    const FILE_NAME: &'static str = "animal_movements.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_animal_movements].
+pub fn setup_animal_movements_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "animal_movements.csv",
+        &[
+            "scenario_tick",
+            "origin_farm_id",
+            "destination_farm_id",
+            "susceptible",
+            "infected",
+            "recovered",
+        ],
+    );
+    commands.insert_resource(AnimalMovementsRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_animal_movements_recording]
+pub fn record_animal_movements(
+    In(events): In<Option<MovementEvents>>,
+    mut csv_file: ResMut<AnimalMovementsRecorder>,
+) -> Option<MovementEvents> {
+    if let Some(MovementEvents {
+        scenario_tick,
+        movements,
+    }) = &events
+    {
+        for movement in movements {
+            csv_file
+                .0
+                .serialize((
+                    scenario_tick,
+                    movement.origin.0,
+                    movement.destination.0,
+                    movement.susceptible,
+                    movement.infected,
+                    movement.recovered,
+                ))
+                .unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parameters::Rate,
+        scenario_rng::{insert_random_streams, MasterSeed},
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_sample_batch() {
+        let mut rng = StdRng::seed_from_u64(20210706);
+        for _ in 0..100 {
+            let (s, i, r) = sample_batch(10, 20, 5, 3, &mut rng);
+            assert_eq!(s + i + r, 10);
+            assert!(s <= 20 && i <= 5 && r <= 3);
+        }
+        assert_eq!(sample_batch(10, 2, 1, 0, &mut rng), (2, 1, 0));
+        assert_eq!(sample_batch(10, 0, 0, 0, &mut rng), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_movements_conserve_animals() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210706));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId::default());
+
+        let farm_id_to_entity_map: HashMap<FarmId, Entity> = (1..=3)
+            .map(|farm_id| {
+                let adjacent_farms: AdjacentFarms =
+                    AdjacentFarms::new_single_population(vec![FarmId::new_single_population(
+                        farm_id % 3 + 1,
+                    )]);
+                let farm_id: FarmId = FarmId::new_single_population(farm_id);
+                let entity = world
+                    .spawn()
+                    .insert_bundle((
+                        Cattle,
+                        farm_id,
+                        adjacent_farms,
+                        ContactRate::new(Rate::new(2.).unwrap()),
+                        HerdSize::new_single_population(100),
+                        Susceptible(90),
+                        Infected(10),
+                        Recovered(0),
+                    ))
+                    .id();
+                (farm_id, entity)
+            })
+            .collect();
+        world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+
+        #[derive(Debug, Default)]
+        struct TotalMovements(usize);
+        world.insert_resource(TotalMovements::default());
+        fn count_movements(
+            In(events): In<Option<MovementEvents>>,
+            mut total: ResMut<TotalMovements>,
+        ) -> Option<MovementEvents> {
+            total.0 += events.as_ref().map_or(0, |x| x.movements.len());
+            events
+        }
+
+        let mut stage = SystemStage::single(
+            update_animal_movements
+                .system()
+                .chain(count_movements.system())
+                .chain(infection_events_from_movements.system())
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        for _ in 0..10 {
+            stage.run(&mut world);
+        }
+
+        assert!(world.get_resource::<TotalMovements>().unwrap().0 > 0);
+        let farms = world
+            .query::<(&HerdSize, &Susceptible, &Infected, &Recovered)>()
+            .iter(&world)
+            .map(|(herd_size, s, i, r)| {
+                assert_eq!(herd_size.0, s.0 + i.0 + r.0);
+                herd_size.0
+            })
+            .collect_vec();
+        assert_eq!(farms.iter().sum::<usize>(), 300);
+    }
+}
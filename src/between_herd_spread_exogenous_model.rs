@@ -1,70 +1,391 @@
 //!
 //!
 //!
-//! This should provide an interface for exogenous infection pressure onto farms.
+//! This should provide an interface for exogenous infection pressure onto farms,
+//! e.g. from a wildlife or an environmental reservoir.
 //!
+//! The hazard is part of the within-herd update
+//! [crate::sir_spread_model::update_disease_compartments], such that the
+//! exogenous infections are subject to recovery in the same timestep as the
+//! rest. Each susceptible animal is infected from the reservoir with the farm's
+//! [ExogenousInfectionRate], scaled by the [ExogenousSeasonality] if present.
+//!
+//! The rate is either the same for every farm, or looked up in an
+//! [ExogenousRaster] by the [FarmCoordinates] of the farm, see
+//! [setup_exogenous_infection_rate].
+//!
+//! The introductions are put out as [ExogenousInfectionEvents] by
+//! [collect_exogenous_infection_events], and are recorded separately from the
+//! between-herd events, see [record_exogenous_infection_events].
+//!
+
+use anyhow::ensure;
+use csv::Writer;
+use std::fs::File;
 
 use crate::prelude::*;
 use crate::{
+    csv_outputs::CsvOutputs,
     parameters::Rate,
     // cattle_population::CattleFarm,
-    populations::Cattle,
-    sir_spread_model::{Infected, Susceptible},
+    populations::{Cattle, FarmCoordinates, FarmId},
+    scenario_time::scenario_timer::{ScenarioTime, Time, DAYS_IN_A_YEAR},
 };
 
+/// Daily rate of infection of a susceptible animal from the reservoir.
 #[readonly::make]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display)]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
 pub struct ExogenousInfectionRate(pub Rate);
 
+/// Number of animals on the farm that were infected from the reservoir in
+/// the last update.
+#[derive(Debug, Clone, Copy, Default, derive_more::Into, derive_more::From)]
+pub struct ExogenousIntroductions(pub usize);
+
+/// Grid of [ExogenousInfectionRate]s, e.g. the density of a wildlife host.
+///
+/// The rates are stored row by row, starting from the cell at (`x_min`, `y_min`).
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedExogenousRaster")
+)]
+#[derive(Debug, Clone)]
+#[readonly::make]
+pub struct ExogenousRaster {
+    pub x_min: f64,
+    pub y_min: f64,
+    /// Width and height of a cell (in km).
+    pub cell_size: f64,
+    pub columns: usize,
+    pub rates: Vec<Rate>,
+}
+
+impl ExogenousRaster {
+    /// The cells must be of positive size, the rates must fill whole rows of
+    /// `columns` cells, and each rate must be finite and non-negative.
+    pub fn new(
+        x_min: f64,
+        y_min: f64,
+        cell_size: f64,
+        columns: usize,
+        rates: Vec<f64>,
+    ) -> Result<Self> {
+        ensure!(
+            x_min.is_finite() && y_min.is_finite(),
+            "origin of the exogenous raster must be finite, got ({}, {})",
+            x_min,
+            y_min
+        );
+        ensure!(
+            cell_size.is_finite() && cell_size > 0.,
+            "cell size of the exogenous raster must be positive, got {}",
+            cell_size
+        );
+        ensure!(
+            columns > 0 && rates.len() % columns == 0,
+            "exogenous raster of {} rates cannot be split into rows of {} columns",
+            rates.len(),
+            columns
+        );
+        let rates = rates
+            .into_iter()
+            .map(|rate| {
+                ensure!(
+                    rate.is_finite(),
+                    "exogenous infection rates must be finite, got {}",
+                    rate
+                );
+                Rate::new(rate)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            x_min,
+            y_min,
+            cell_size,
+            columns,
+            rates,
+        })
+    }
+
+    /// Rate of the cell that contains `coordinates`, if it is within the grid.
+    pub fn rate_at(&self, coordinates: &FarmCoordinates) -> Option<ExogenousInfectionRate> {
+        let column = ((coordinates.x - self.x_min) / self.cell_size).floor();
+        let row = ((coordinates.y - self.y_min) / self.cell_size).floor();
+        if column < 0. || row < 0. || column as usize >= self.columns {
+            return None;
+        }
+        self.rates
+            .get(row as usize * self.columns + column as usize)
+            .copied()
+            .map(ExogenousInfectionRate::new)
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+struct UncheckedExogenousRaster {
+    x_min: f64,
+    y_min: f64,
+    cell_size: f64,
+    columns: usize,
+    rates: Vec<f64>,
+}
+
+#[cfg(feature = "serialize")]
+impl std::convert::TryFrom<UncheckedExogenousRaster> for ExogenousRaster {
+    type Error = anyhow::Error;
+
+    fn try_from(raster: UncheckedExogenousRaster) -> Result<Self> {
+        Self::new(
+            raster.x_min,
+            raster.y_min,
+            raster.cell_size,
+            raster.columns,
+            raster.rates,
+        )
+    }
+}
+
+/// Seasonal variation of the reservoir, that scales the exogenous
+/// infection rates by `1 + amplitude * cos(2π (day - peak_day) / 364)`.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedExogenousSeasonality")
+)]
+#[derive(Debug, Clone, Copy)]
+#[readonly::make]
+pub struct ExogenousSeasonality {
+    /// Between 0 and 1, where 0 means no seasonality.
+    pub amplitude: f64,
+    /// Day in the year where the pressure peaks, see
+    /// [ScenarioTime::day_in_the_year].
+    pub peak_day: Time,
+}
+
+impl ExogenousSeasonality {
+    /// The amplitude must be within [0, 1], as the rates would otherwise
+    /// turn negative in the off-season.
+    pub fn new(amplitude: f64, peak_day: Time) -> Result<Self> {
+        ensure!(
+            (0. ..=1.).contains(&amplitude),
+            "amplitude of the seasonality must be between 0 and 1, got {}",
+            amplitude
+        );
+        Ok(Self {
+            amplitude,
+            peak_day,
+        })
+    }
+
+    pub fn multiplier(&self, day_in_the_year: Time) -> f64 {
+        let phase = (day_in_the_year as f64 - self.peak_day as f64) / DAYS_IN_A_YEAR as f64;
+        1. + self.amplitude * (2. * std::f64::consts::PI * phase).cos()
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+struct UncheckedExogenousSeasonality {
+    amplitude: f64,
+    peak_day: Time,
+}
+
+#[cfg(feature = "serialize")]
+impl std::convert::TryFrom<UncheckedExogenousSeasonality> for ExogenousSeasonality {
+    type Error = anyhow::Error;
+
+    fn try_from(seasonality: UncheckedExogenousSeasonality) -> Result<Self> {
+        Self::new(seasonality.amplitude, seasonality.peak_day)
+    }
+}
+
+/// Adds the [ExogenousInfectionRate] to every farm, either from the
+/// [ExogenousRaster], or the initial rate given as a resource. Farms outside
+/// of the raster get the initial rate, if present.
 pub fn setup_exogenous_infection_rate(
     mut commands: Commands,
-    initial_exogenous_infection_rate: Option<ExogenousInfectionRate>,
-    query: Query<Entity, With<Cattle>>,
+    initial_exogenous_infection_rate: Option<Res<ExogenousInfectionRate>>,
+    raster: Option<Res<ExogenousRaster>>,
+    query: Query<(Entity, Option<&FarmCoordinates>), With<Cattle>>,
 ) {
-    let initial_exogenous_infection_rate =
-        initial_exogenous_infection_rate.expect("initial value as a resource is not present.");
+    assert!(
+        initial_exogenous_infection_rate.is_some() || raster.is_some(),
+        "neither an initial `ExogenousInfectionRate` nor an `ExogenousRaster` is present."
+    );
 
-    query.for_each(|x| {
-        commands.entity(x).insert(initial_exogenous_infection_rate);
+    query.for_each(|(entity, coordinates)| {
+        let rate = raster
+            .as_ref()
+            .zip(coordinates)
+            .and_then(|(raster, coordinates)| raster.rate_at(coordinates))
+            .or_else(|| initial_exogenous_infection_rate.as_deref().copied());
+        if let Some(rate) = rate {
+            commands
+                .entity(entity)
+                .insert_bundle((rate, ExogenousIntroductions::default()));
+        }
     });
 }
 
-/// This is here to mimic the same process-structure everywhere.
-/// But really this is belongs within the disease model, as it should
-/// be a disease model that specifically handles exogenous infections within
-/// its update-scheme.
-pub fn update_exogenous_infection_rate() {
-    unimplemented!("This doesn't do anything.")
+/// Introductions from the reservoir in a single tick.
+#[derive(Debug, Clone)]
+pub struct ExogenousInfectionEvents {
+    pub scenario_tick: Time,
+    /// Farms and their number of animals infected from the reservoir.
+    pub introductions: Vec<(FarmId, usize)>,
 }
 
-///
-///
-/// This should be part of the disease model; Because right now, there are
-/// fewer individuals in the infected compartment when recovery is being
-/// considered for this timestep.
-pub fn update_exogenous_infection_rate_outside_of_disease_model(
-    mut query: Query<(&mut Infected, &mut Susceptible, &ExogenousInfectionRate)>,
-) {
-    query.for_each_mut(|(mut inf, mut sus, rate)| {
-        let delta_inf = sus.0 as f64 * rate.0 .0;
-        let delta_inf = delta_inf.round() as usize;
-
-        // is it possible to infect more animals on the farm?
-        if sus.0 > delta_inf {
-            sus.0 -= delta_inf;
-
-            //TODO: consider adding a step here where you consider newInf -> Recovered
-            // as those would have been ignored due to this step not being part of
-            // the spread model.
-            // let delta_rec = todo!("recovery rate is not readily available to be accessed from another system")
-            // note: this is way rates should live on the entities that they
-            // are affecting, as to maintain coherence when other models into
-            // the simulation as well.
-            //
-            // rec.0 += delta_rec;
-            // inf.0 += (delta_inf - delta_rec);
-            inf.0 += delta_inf;
+/// Add this after [crate::sir_spread_model::update_disease_compartments].
+pub fn collect_exogenous_infection_events(
+    query: Query<(&FarmId, &ExogenousIntroductions), Changed<ExogenousIntroductions>>,
+    scenario_time: Res<ScenarioTime>,
+) -> Option<ExogenousInfectionEvents> {
+    let introductions = query
+        .iter()
+        .filter(|(_, introductions)| introductions.0 > 0)
+        .map(|(farm_id, introductions)| (*farm_id, introductions.0))
+        .sorted_by_key(|(farm_id, _)| *farm_id)
+        .collect_vec();
+    if introductions.is_empty() {
+        None
+    } else {
+        Some(ExogenousInfectionEvents {
+            scenario_tick: scenario_time.current_time(),
+            introductions,
+        })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct ExogenousInfectionEventsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for ExogenousInfectionEventsRecorder {
+    const FILE_NAME: &'static str = "exogenous_infection_events.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_exogenous_infection_events].
+pub fn setup_exogenous_infection_events_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "exogenous_infection_events.csv",
+        &["scenario_tick", "farm_id", "new_infections"],
+    );
+    commands.insert_resource(ExogenousInfectionEventsRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_exogenous_infection_events_recording]
+pub fn record_exogenous_infection_events(
+    In(events): In<Option<ExogenousInfectionEvents>>,
+    mut csv_file: ResMut<ExogenousInfectionEventsRecorder>,
+) -> Option<ExogenousInfectionEvents> {
+    if let Some(ExogenousInfectionEvents {
+        scenario_tick,
+        introductions,
+    }) = &events
+    {
+        for (farm_id, new_infections) in introductions {
+            csv_file
+                .0
+                .serialize((scenario_tick, farm_id.0, new_infections))
+                .unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        populations::HerdSize,
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::{
+            update_disease_compartments, DiseaseParameters, Infected, Recovered, Susceptible,
+        },
+    };
+
+    #[test]
+    fn test_raster_and_seasonality() {
+        let raster = ExogenousRaster::new(0., 0., 10., 2, vec![0.1, 0.2, 0.3, 0.4]).unwrap();
+        let rate = |x, y| raster.rate_at(&FarmCoordinates::new(x, y)).map(|x| (x.0).0);
+        assert_eq!(rate(5., 5.), Some(0.1));
+        assert_eq!(rate(15., 5.), Some(0.2));
+        assert_eq!(rate(15., 15.), Some(0.4));
+        assert_eq!(rate(25., 5.), None);
+        assert_eq!(rate(5., 25.), None);
+        assert_eq!(rate(-5., 5.), None);
+
+        assert!(ExogenousRaster::new(0., 0., 10., 2, vec![0.1, -0.2]).is_err());
+        assert!(ExogenousRaster::new(0., 0., 10., 2, vec![0.1, f64::NAN]).is_err());
+        assert!(ExogenousRaster::new(0., 0., 0., 2, vec![0.1, 0.2]).is_err());
+        assert!(ExogenousRaster::new(0., 0., 10., 2, vec![0.1, 0.2, 0.3]).is_err());
+        assert!(ExogenousRaster::new(0., 0., 10., 0, vec![]).is_err());
+        assert!(serde_json::from_str::<ExogenousRaster>(
+            r#"{"x_min": 0.0, "y_min": 0.0, "cell_size": 10.0, "columns": 2, "rates": [0.1, -0.2]}"#
+        )
+        .is_err());
+
+        let seasonality = ExogenousSeasonality::new(0.5, 100).unwrap();
+        assert!((seasonality.multiplier(100) - 1.5).abs() < 1e-12);
+        assert!((seasonality.multiplier(100 + 182) - 0.5).abs() < 1e-12);
+        assert!(ExogenousSeasonality::new(1.5, 100).is_err());
+        assert!(ExogenousSeasonality::new(f64::NAN, 100).is_err());
+        assert!(serde_json::from_str::<ExogenousSeasonality>(
+            r#"{"amplitude": -0.1, "peak_day": 0}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_exogenous_introductions() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210714));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(ExogenousInfectionRate::new(Rate::new(0.01).unwrap()));
+        for farm_id in 1..=2 {
+            world.spawn().insert_bundle((
+                Cattle,
+                FarmId::<()>::new_single_population(farm_id),
+                HerdSize::new_single_population(1_000),
+                Susceptible(1_000),
+                Infected(0),
+                Recovered(0),
+                DiseaseParameters::new(0., 0.),
+            ));
         }
-    })
+        let mut setup = SystemStage::single(setup_exogenous_infection_rate.system());
+        setup.run(&mut world);
+
+        fn store_events(In(events): In<Option<ExogenousInfectionEvents>>, mut commands: Commands) {
+            if let Some(events) = events {
+                commands.insert_resource(events);
+            }
+        }
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(update_disease_compartments.system().label("disease"))
+            .add_system(
+                collect_exogenous_infection_events
+                    .system()
+                    .chain(store_events.system())
+                    .after("disease"),
+            );
+        stage.run(&mut world);
+
+        let infected: usize = world.query::<&Infected>().iter(&world).map(|x| x.0).sum();
+        // 0.01 * 2_000 animals
+        assert!((10..=30).contains(&infected), "{}", infected);
+        let events = world
+            .get_resource::<ExogenousInfectionEvents>()
+            .expect("no introductions from the reservoir");
+        let introduced: usize = events.introductions.iter().map(|(_, x)| x).sum();
+        assert_eq!(introduced, infected);
+    }
 }
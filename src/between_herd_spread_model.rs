@@ -7,26 +7,114 @@
 //! The startup system [setup_between_herd_spread_model] is necessary for
 //! this to make sense.
 
-use std::convert::TryFrom;
-
+use anyhow::{ensure, Result};
 use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use itertools::Itertools;
 use rand::prelude::*;
+use rand_distr::{Gamma, Poisson};
 
 use crate::{
     farm_id_to_entity_map::FarmIdEntityMap,
-    parameters::{Probability, Rate},
-    populations::{AdjacentFarms, Cattle, FarmId, HerdSize},
+    parameters::Rate,
+    populations::{AdjacencyWeights, AdjacentFarms, Cattle, FarmId, HerdSize},
+    regulator_contact_tracing::ContactHistory,
+    regulator_movement_restrictions::MovementRestrictions,
+    scenario_rng::{BetweenHerdSpreadStream, ProcessRng},
     scenario_time::scenario_timer::ScenarioTime,
     sir_spread_model::{Infected, Susceptible},
 };
 
+/// Mean number of outgoing shipments of animals per day.
+///
+/// This may exceed 1, as high-trade farms make several shipments a day.
 #[readonly::make]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
 pub struct ContactRate(pub Rate);
 
+/// Distribution of the number of shipments per day, that have the farm's
+/// [ContactRate] as the mean.
+///
+/// Add as a resource to change the default [ShipmentDistribution::Poisson].
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "UncheckedShipmentDistribution")
+)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShipmentDistribution {
+    Poisson,
+    /// Overdispersed shipments, with variance `mean + mean^2 / dispersion`.
+    ///
+    /// Sampled as a Gamma-Poisson mixture. Construct it with
+    /// [ShipmentDistribution::negative_binomial].
+    NegativeBinomial {
+        dispersion: f64,
+    },
+}
+
+impl Default for ShipmentDistribution {
+    fn default() -> Self {
+        Self::Poisson
+    }
+}
+
+impl ShipmentDistribution {
+    /// The dispersion must be positive and finite.
+    pub fn negative_binomial(dispersion: f64) -> Result<Self> {
+        ensure!(
+            dispersion.is_finite() && dispersion > 0.,
+            "dispersion of shipments must be positive, got {}",
+            dispersion
+        );
+        Ok(Self::NegativeBinomial { dispersion })
+    }
+
+    /// Number of shipments of a farm with `contact_rate` in a single day.
+    pub fn sample_shipments<R: Rng + ?Sized>(
+        &self,
+        contact_rate: ContactRate,
+        rng: &mut R,
+    ) -> usize {
+        let mean = (contact_rate.0).0;
+        if mean <= 0. {
+            return 0;
+        }
+        let mean = match *self {
+            Self::Poisson => mean,
+            Self::NegativeBinomial { dispersion } => Gamma::new(dispersion, mean / dispersion)
+                .expect("dispersion of shipments must be positive")
+                .sample(rng),
+        };
+        if mean <= 0. {
+            return 0;
+        }
+        Poisson::new(mean).unwrap().sample(rng) as usize
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+enum UncheckedShipmentDistribution {
+    Poisson,
+    NegativeBinomial { dispersion: f64 },
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<UncheckedShipmentDistribution> for ShipmentDistribution {
+    type Error = anyhow::Error;
+
+    fn try_from(unchecked: UncheckedShipmentDistribution) -> Result<Self> {
+        match unchecked {
+            UncheckedShipmentDistribution::Poisson => Ok(Self::Poisson),
+            UncheckedShipmentDistribution::NegativeBinomial { dispersion } => {
+                Self::negative_binomial(dispersion)
+            }
+        }
+    }
+}
+
 /// Here we add the contact rate to each farm, as to be able to change it
 /// on a pr. farm basis later on.
 ///
@@ -45,17 +133,25 @@ pub fn setup_between_herd_spread_model(
     query.for_each(|(entity, _)| {
         commands.entity(entity).insert(initial_contact_rate);
     });
+
+    commands.insert_resource(InfectionEventsBatchId::default());
 }
 
-//TODO: Store the last infection events batches in the system param as a local
-// for now until there appears a system that needs to use it somehow, and then
-// make that available as a resource?
+/// No. of the last batch of between-herd events that was put out by the
+/// (spread) model.
+///
+/// This used to be a [Local] of the spread model, but it is part of the state
+/// that has to be checkpointed, see [crate::checkpoint].
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, Default, derive_more::Into, derive_more::From)]
+pub struct InfectionEventsBatchId(pub usize);
 
 /// Components necessary to determine the infection pressure of actively
 /// infected farms.
 type InfectedFarms = (
     &'static Infected,
     &'static AdjacentFarms,
+    Option<&'static AdjacencyWeights>,
     &'static ContactRate,
     &'static HerdSize,
     &'static FarmId,
@@ -68,7 +164,7 @@ type AffectedFarm = (&'static mut Susceptible, &'static mut Infected);
 pub struct BetweenHerdSpreadModel<'a> {
     /// No. of the last batch of between-herd events that was put out by this
     /// (spread) model.
-    current_batch_id: Local<'a, usize>,
+    current_batch_id: ResMut<'a, InfectionEventsBatchId>,
 
     /// Get infected farms and spread through contacts to other farms.
     query: QuerySet<(Query<'a, InfectedFarms>, Query<'a, AffectedFarm>)>,
@@ -76,110 +172,113 @@ pub struct BetweenHerdSpreadModel<'a> {
 
 pub fn update_between_herd_spread_model(
     mut model: BetweenHerdSpreadModel,
-    mut rng: ResMut<StdRng>,
+    mut rng: ResMut<ProcessRng<BetweenHerdSpreadStream>>,
+    shipment_distribution: Option<Res<ShipmentDistribution>>,
     scenario_tick: Res<ScenarioTime>,
     farm_map: Res<FarmIdEntityMap>,
+    movement_restrictions: MovementRestrictions,
+    mut contact_history: Option<ResMut<ContactHistory>>,
 ) -> Option<InfectionEvents> {
-    // determine from farms
-    // let active_infected_farms = query.iter_mut().filter(|info| info.0.0 > 0);
-
-    //FIXME: do something with this
-    //
-    // let new_infection_events:Vec<(FarmId, FarmId)> = active_infected_farms
-    // let infectious_farms: Vec<(Infected, AdjacentFarms, HerdSize, FarmId)> = model.query
-    // let infectious_farms = model.query
+    let shipment_distribution = shipment_distribution.map_or_else(Default::default, |x| *x);
+    // the shipments of farms without infected animals are only of interest
+    // to contact tracing
+    let is_tracing = contact_history.is_some();
+
+    // first, how many batches of animals is an infectious farm sending out?
     let infectious_farms = model
         .query
         .q0()
         .iter()
-        // first, is an infectious farm going to send out any batches of animals?
-        //FIXME: ensure this works for all rates, not only for <= 1.
-        // determine if there are animal movements
-        .filter(|info| {
-            // .filter(|(farm,)| {
-            let infected: Infected = *info.0;
-            let infected: Infected = infected;
-            infected.0 > 0
-        })
-        .filter(|(_, _, contact_rate, _, _)| {
-            // .filter(|(farm, )| {
-            rng.gen_bool(Probability::try_from(contact_rate.0).unwrap().0)
-        })
+        .filter(|(infected, .., herd_size, _)| infected.0 > 0 || (is_tracing && herd_size.0 > 0))
         .map(
-            |info: (&Infected, &AdjacentFarms, &ContactRate, &HerdSize, &FarmId)| {
-                (*info.0, info.1.clone(), *info.3, *info.4)
+            |(infected, adjacent_farms, adjacency_weights, contact_rate, herd_size, farm_id)| {
+                let shipments = shipment_distribution.sample_shipments(*contact_rate, &mut *rng);
+                (
+                    *infected,
+                    adjacent_farms.clone(),
+                    adjacency_weights.cloned(),
+                    *herd_size,
+                    *farm_id,
+                    shipments,
+                )
             },
         )
-        // .map(|(farm,)| farm.clone())
+        .filter(|(.., shipments)| *shipments > 0)
         .collect_vec();
 
-    let new_infection_events: Vec<(FarmId, FarmId, usize)> = infectious_farms
-        .into_iter()
-        // determine destination farm (from, target)
-        .filter_map(|(infected, adjacent_farms, herd_size, from_farm_id)| {
-            // .filter_map(|farm| {
-            let herd_size: HerdSize = herd_size;
-            let adjacent_farms: AdjacentFarms = adjacent_farms;
-            let target_farm_id = adjacent_farms.0.choose(&mut *rng).unwrap();
+    let mut new_infection_events: Vec<(FarmId, FarmId, usize)> = Vec::new();
+    for (infected, adjacent_farms, adjacency_weights, herd_size, from_farm_id, shipments) in
+        infectious_farms
+    {
+        // now will this result in an infection?
+        let infection_pressure = infected.0 as f64 / herd_size.0 as f64;
+        debug_assert!(
+            herd_size.0 >= infected.0,
+            "cannot have more infected animals than animals in the farm."
+        );
+
+        for _ in 0..shipments {
+            // each shipment determines its destination farm independently
+            let target_farm_id =
+                match adjacent_farms.choose_neighbour(adjacency_weights.as_ref(), &mut *rng) {
+                    Some(target_farm_id) => target_farm_id,
+                    // isolated farm, e.g. from a generated contact network
+                    None => break,
+                };
+
+            //TODO: facilitate this through a common trait for between-herd
+            let target_farm_entity_id = farm_map.0.get(target_farm_id).unwrap();
+            if !movement_restrictions.permits(
+                farm_map.0[&from_farm_id],
+                *target_farm_entity_id,
+                &mut *rng,
+            ) {
+                // the shipment was stopped by the regulators
+                continue;
+            }
+            if let Some(contact_history) = contact_history.as_mut() {
+                contact_history.add(scenario_tick.current_time(), from_farm_id, *target_farm_id);
+            }
 
             //FIXME: can an infected farm infect another infected farm?
 
-            // now will this result in an infection?
-            let infection_pressure = infected.0 as f64 / herd_size.0 as f64;
-            debug_assert!(
-                herd_size.0 >= infected.0,
-                "cannot have more infected animals than animals in the farm."
-            );
+            if infected.0 == 0 || !rng.gen_bool(infection_pressure) {
+                // no infectious animals in this shipment
+                continue;
+            }
 
-            // info!(
-            //     "infection pressure: {} / {} = {:.3}",
-            //     infected.0, herd_size.0, infection_pressure
-            // );
-
-            if rng.gen_bool(infection_pressure) {
-                // add infection to target
-                //TODO: facilitate this through a common trait for between-herd
-                let target_farm_entity_id = farm_map.0.get(target_farm_id).unwrap();
-                // info!("target_farm id and entity.id: {:?}, {:?}", target_farm_id, target_farm_entity_id);
-
-                //FIXME: this made the disease compartments no longer be read-only
-                // incorporate that into a disease model interface
-                let successful_infection = model
-                    .query
-                    .q1_mut()
-                    // select target farm's disease components
-                    .get_mut(*target_farm_entity_id)
-                    .map(|(mut sus, mut inf)| {
-                        if sus.0 >= 1 {
-                            sus.0 -= 1;
-                            inf.0 += 1;
-                            true
-                        } else {
-                            false
-                        }
-                    })
-                    .expect("failed to find target farm to infect");
-                if successful_infection {
-                    // `origin ~> target, #new infections`
-                    Some((from_farm_id, *target_farm_id, 1))
-                    // Some((farm.farm_id, *target_farm_id, 1))
-                } else {
-                    // there wasn't any susceptible animals to infect
-                    None
-                }
-            } else {
-                // no contact between the two determined
-                None
+            // add infection to target
+            //FIXME: this made the disease compartments no longer be read-only
+            // incorporate that into a disease model interface
+            let successful_infection = model
+                .query
+                .q1_mut()
+                // select target farm's disease components
+                .get_mut(*target_farm_entity_id)
+                .map(|(mut sus, mut inf)| {
+                    if sus.0 >= 1 {
+                        sus.0 -= 1;
+                        inf.0 += 1;
+                        true
+                    } else {
+                        false
+                    }
+                })
+                .expect("failed to find target farm to infect");
+            if successful_infection {
+                // `origin ~> target, #new infections`
+                new_infection_events.push((from_farm_id, *target_farm_id, 1));
             }
-        })
-        .collect_vec();
+            // otherwise there wasn't any susceptible animals to infect
+        }
+    }
 
     // TODO: record how much this impacts the disease spread.
     let total_new_infection_events = new_infection_events.len();
 
     if total_new_infection_events > 0 {
         // since there were new infections, update the model
-        *model.current_batch_id += 1;
+        model.current_batch_id.0 += 1;
 
         // debug
         trace!(
@@ -190,7 +289,8 @@ pub fn update_between_herd_spread_model(
         //export that new infections events occurred
         Some(InfectionEvents {
             scenario_tick: scenario_tick.current_time(),
-            batch_id: *model.current_batch_id,
+            batch_id: model.current_batch_id.0,
+            route: InfectionRoute::Trade,
             events_values: new_infection_events,
         })
     } else {
@@ -201,6 +301,44 @@ pub fn update_between_herd_spread_model(
 //TODO: Maybe this should reside in its own module, as it is generally unrelated
 // to the actual exectution of the between-herd spread module.
 
+/// The way the infection got from one farm to another.
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InfectionRoute {
+    /// Trade contacts, e.g. shipments of animals.
+    Trade,
+    /// Airborne and neighbourhood spread, see [crate::local_spread_model].
+    LocalSpread,
+    /// Trade through a market, see [crate::markets].
+    Market,
+    /// Visits by vets, milk tankers, etc., see [crate::indirect_contacts].
+    IndirectContact,
+}
+
+impl Default for InfectionRoute {
+    fn default() -> Self {
+        Self::Trade
+    }
+}
+
+impl InfectionRoute {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InfectionRoute::Trade => "trade",
+            InfectionRoute::LocalSpread => "local_spread",
+            InfectionRoute::Market => "market",
+            InfectionRoute::IndirectContact => "indirect_contact",
+        }
+    }
+}
+
+impl std::fmt::Display for InfectionRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 // #[readonly::make]
 #[derive(Debug, Clone)]
 pub struct InfectionEvents {
@@ -209,6 +347,8 @@ pub struct InfectionEvents {
     /// Infection events are put out in batches
     /// and they can be grouped according to them.
     pub batch_id: usize,
+    /// All events of a batch are from the same process.
+    pub route: InfectionRoute,
     /// An event consists an origin farm `from` and a target farm `to` and
     /// then the number of infectious animals that were introduced to the fold.
     pub events_values: Vec<(FarmId, FarmId, usize)>,
@@ -222,10 +362,12 @@ pub fn print_between_herd_infection_events(
         let InfectionEvents {
             scenario_tick,
             batch_id,
+            route,
             events_values,
         } = infection_events;
         info!("Between-herd spread events");
         info!("Batch id: {}", batch_id);
+        info!("Route: {}", route);
         info!("Time: {}", scenario_tick);
         // info!("{:#?}", events);
         for (origin, target, _) in events_values {
@@ -237,3 +379,108 @@ pub fn print_between_herd_infection_events(
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipments_above_one_per_day() {
+        let mut rng = StdRng::seed_from_u64(20210705);
+        let contact_rate = ContactRate::new(Rate::new(3.5).unwrap());
+        let days = 10_000;
+
+        for distribution in [
+            ShipmentDistribution::Poisson,
+            ShipmentDistribution::negative_binomial(0.5).unwrap(),
+        ] {
+            let total_shipments: usize = (0..days)
+                .map(|_| distribution.sample_shipments(contact_rate, &mut rng))
+                .sum();
+            let mean_shipments = total_shipments as f64 / days as f64;
+            assert!(
+                (mean_shipments - 3.5).abs() < 0.25,
+                "{:?}: {}",
+                distribution,
+                mean_shipments
+            );
+        }
+
+        let no_contacts = ContactRate::new(Rate::new(0.).unwrap());
+        assert_eq!(
+            ShipmentDistribution::Poisson.sample_shipments(no_contacts, &mut rng),
+            0
+        );
+    }
+
+    #[test]
+    fn test_invalid_dispersion_is_rejected() {
+        for dispersion in [0., -1., f64::NAN, f64::INFINITY] {
+            assert!(ShipmentDistribution::negative_binomial(dispersion).is_err());
+        }
+
+        let parsed: ShipmentDistribution =
+            serde_json::from_str(r#"{"NegativeBinomial":{"dispersion":2.0}}"#).unwrap();
+        assert_eq!(parsed, ShipmentDistribution::negative_binomial(2.).unwrap());
+        assert!(serde_json::from_str::<ShipmentDistribution>(
+            r#"{"NegativeBinomial":{"dispersion":-2.0}}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<ShipmentDistribution>(r#""Poisson""#).is_ok());
+    }
+
+    #[test]
+    fn test_uninfected_shipments_are_traced() {
+        let mut world = World::new();
+        crate::scenario_rng::insert_random_streams(
+            &mut world,
+            crate::scenario_rng::MasterSeed(20210722),
+        );
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId::default());
+        world.insert_resource(ContactHistory::default());
+        let mut farm_map = std::collections::HashMap::new();
+        for (farm_id, contact) in [(1, 2), (2, 1)] {
+            let farm_id: FarmId = FarmId::new_single_population(farm_id);
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    Cattle,
+                    farm_id,
+                    HerdSize::new_single_population(100),
+                    AdjacentFarms::<()>::new_single_population(vec![
+                        FarmId::new_single_population(contact),
+                    ]),
+                    ContactRate::new(Rate::new(10.).unwrap()),
+                    Susceptible(100),
+                    Infected(0),
+                ))
+                .id();
+            farm_map.insert(farm_id, entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map));
+
+        SystemStage::single(
+            update_between_herd_spread_model
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        )
+        .run(&mut world);
+
+        let farm = FarmId::new_single_population;
+        let (backward, forward) = world
+            .get_resource::<ContactHistory>()
+            .unwrap()
+            .contacts_of(farm(1), 0);
+        assert_eq!(backward, [farm(2)].iter().copied().collect());
+        assert_eq!(forward, [farm(2)].iter().copied().collect());
+        assert_eq!(
+            world
+                .query::<&Infected>()
+                .iter(&world)
+                .map(|x| x.0)
+                .sum::<usize>(),
+            0
+        );
+    }
+}
@@ -2,46 +2,37 @@ use csv::Writer;
 use std::fs::File;
 
 use crate::between_herd_spread_model::InfectionEvents;
+use crate::csv_outputs::CsvOutputs;
 use crate::prelude::*;
 
 #[derive(derive_more::From)]
 pub struct BetweenHerdInfectionEventsRecorder(Writer<File>);
 
-/// This is coupled with system [record_between_herd_infection_events].
-pub fn setup_between_herd_infection_events_recording(mut commands: Commands) {
-    //TODO: determine an appropriate buffer capacity
-    let buffer_capacity_in_bytes = 100_000_000; // 100 mb.
-                                                // create the path (not necessarily the file)
-                                                //TODO: put this somewhere else
-    let path_to_csv_file: std::path::PathBuf = "outputs/between_herd_infection_events.csv".into();
-    let mut path_to_directory = path_to_csv_file.clone();
-    path_to_directory.pop();
-    std::fs::create_dir_all(path_to_directory).unwrap();
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for BetweenHerdInfectionEventsRecorder {
+    const FILE_NAME: &'static str = "between_herd_infection_events.csv";
 
-    let wtr = std::fs::OpenOptions::new()
-        // .append(false)
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path_to_csv_file)
-        .unwrap();
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
 
-    let mut csv_writer = csv::WriterBuilder::new()
-        .has_headers(false)
-        .buffer_capacity(buffer_capacity_in_bytes)
-        .flexible(false)
-        .delimiter(b';') // change to `false`
-        .from_writer(wtr);
-    csv_writer
-        .write_record(&[
+/// This is coupled with system [record_between_herd_infection_events].
+pub fn setup_between_herd_infection_events_recording(
+    mut commands: Commands,
+    csv_outputs: CsvOutputs,
+) {
+    let csv_writer = csv_outputs.create(
+        "between_herd_infection_events.csv",
+        &[
             "scenario_tick",
             "batch_id",
+            "route",
             "origin_farm_id",
             "target_farm_id",
             "new_infections",
-        ])
-        .unwrap();
-
+        ],
+    );
     commands.insert_resource(BetweenHerdInfectionEventsRecorder::from(csv_writer));
 }
 
@@ -55,11 +46,15 @@ pub fn record_between_herd_infection_events(
         let InfectionEvents {
             scenario_tick,
             batch_id,
+            route,
             events_values,
         } = events;
 
         events_values.into_iter().for_each(|x| {
-            csv_file.0.serialize((scenario_tick, batch_id, x)).unwrap();
+            csv_file
+                .0
+                .serialize((scenario_tick, batch_id, route.as_str(), x))
+                .unwrap();
         })
     } else {
         // no infection events
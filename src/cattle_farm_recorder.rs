@@ -3,6 +3,7 @@ use csv::Writer;
 use std::fs::File;
 
 use crate::{
+    csv_outputs::CsvOutputs,
     // cattle_population::{CattleFarm, FarmId},
     populations::{Cattle, FarmId},
     scenario_time::scenario_timer::ScenarioTime,
@@ -12,41 +13,27 @@ use crate::{
 #[derive(derive_more::From)]
 pub struct CattleFarmsCSVRecorder(Writer<File>);
 
-/// This is coupled with system [record_cattle_farm_components].
-pub fn setup_cattle_farm_recorder(mut commands: Commands) {
-    //TODO: determine an appropriate buffer capacity
-    let buffer_capacity_in_bytes = 100_000_000; // 100 mb.
-                                                // create the path (not necessarily the file)
-                                                //TODO: put this somewhere else
-    let path_to_csv_file: std::path::PathBuf = "outputs/cattle_farm_outputs.csv".into();
-    let mut path_to_directory = path_to_csv_file.clone();
-    path_to_directory.pop();
-    std::fs::create_dir_all(path_to_directory).unwrap();
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for CattleFarmsCSVRecorder {
+    const FILE_NAME: &'static str = "cattle_farm_outputs.csv";
 
-    let wtr = std::fs::OpenOptions::new()
-        // .append(false)
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path_to_csv_file)
-        .unwrap();
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
 
-    let mut csv_writer = csv::WriterBuilder::new()
-        .has_headers(false)
-        .buffer_capacity(buffer_capacity_in_bytes)
-        .flexible(false) // change to `false`
-        .delimiter(b';')
-        .from_writer(wtr);
-    csv_writer
-        .write_record(&[
+/// This is coupled with system [record_cattle_farm_components].
+pub fn setup_cattle_farm_recorder(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "cattle_farm_outputs.csv",
+        &[
             "scenario_time",
             "farm_id",
             "susceptible",
             "infected",
             "recovered",
-        ])
-        .unwrap();
-
+        ],
+    );
     commands.insert_resource(CattleFarmsCSVRecorder::from(csv_writer));
 }
 
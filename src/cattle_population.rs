@@ -1,9 +1,14 @@
 //!
 //!
 //!
-use crate::populations::{AdjacentFarms, Cattle, FarmId, HerdSize};
+use crate::populations::{
+    AdjacencyWeights, AdjacentFarms, Cattle, FarmCoordinates, FarmId, FarmType, HerdSize,
+};
 use crate::prelude::*;
 
+#[cfg(feature = "serialize")]
+use anyhow::{ensure, Context};
+
 #[cfg(feature = "serialize")]
 pub fn deserialize_generated_farm_id<'de, D: serde::Deserializer<'de>>(
     deserializer: D,
@@ -48,6 +53,38 @@ pub struct CattleFarmBundle {
 
 #[cfg(feature = "serialize")]
 pub fn load_ring_population() -> impl Iterator<Item = CattleFarmBundle> + Clone {
+    load_population("assets/population_info.json", "assets/ring_adjacency.json")
+        .expect("failed to load the ring population")
+        .into_iter()
+        .map(|cattle_farm| cattle_farm.bundle)
+}
+
+/// A farm from the population files, along with its optional components.
+#[derive(Debug, Clone)]
+pub struct LoadedCattleFarm {
+    pub bundle: CattleFarmBundle,
+    pub adjacency_weights: Option<AdjacencyWeights>,
+    pub coordinates: Option<FarmCoordinates>,
+    pub farm_type: Option<FarmType>,
+}
+
+/// Loads the farms from `population_info` and their adjacent farms from
+/// `adjacency`.
+///
+/// The adjacency records may have a `weights` array alongside `adjacent`,
+/// in which case the farm gets [AdjacencyWeights]. Likewise the population
+/// records may have `x` and `y` coordinates (in km), see [FarmCoordinates],
+/// and a `farm_type`, see [FarmType].
+///
+/// Fails if the files cannot be read, or if the records of the two files do
+/// not match up.
+#[cfg(feature = "serialize")]
+pub fn load_population(
+    population_info: impl AsRef<std::path::Path>,
+    adjacency: impl AsRef<std::path::Path>,
+) -> Result<Vec<LoadedCattleFarm>> {
+    let population_info = population_info.as_ref();
+    let adjacency = adjacency.as_ref();
     #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     #[derive(Debug, Clone)]
     struct PopulationRecord {
@@ -55,13 +92,20 @@ pub fn load_ring_population() -> impl Iterator<Item = CattleFarmBundle> + Clone
         farm_id: FarmId,
         #[serde(deserialize_with = "deserialize_generated_herd_size")]
         herd_size: HerdSize,
+        #[serde(default)]
+        x: Option<f64>,
+        #[serde(default)]
+        y: Option<f64>,
+        #[serde(default)]
+        farm_type: Option<usize>,
     }
-    let population_info_file = std::fs::File::open("assets/population_info.json").unwrap();
+    let population_info_file = std::fs::File::open(population_info)
+        .with_context(|| format!("failed to open {}", population_info.display()))?;
     let population_info_reader =
         std::io::BufReader::with_capacity(100_000_000, population_info_file);
 
-    let pop_record: Vec<PopulationRecord> =
-        serde_json::from_reader(population_info_reader).unwrap();
+    let pop_record: Vec<PopulationRecord> = serde_json::from_reader(population_info_reader)
+        .with_context(|| format!("invalid population info {}", population_info.display()))?;
 
     // dbg!(pop_record.iter().take(10).collect_vec());
 
@@ -73,36 +117,68 @@ pub fn load_ring_population() -> impl Iterator<Item = CattleFarmBundle> + Clone
         #[serde(rename(deserialize = "adjacent"))]
         #[serde(deserialize_with = "deserialize_generated_adjacent_farms")]
         adjacent_farms: AdjacentFarms,
+        #[serde(default)]
+        weights: Option<AdjacencyWeights>,
     }
 
-    let adjacency = std::fs::File::open("assets/ring_adjacency.json").unwrap();
-    let adjacency_file_buffer = std::io::BufReader::with_capacity(100_000_000, adjacency);
+    let adjacency_file = std::fs::File::open(adjacency)
+        .with_context(|| format!("failed to open {}", adjacency.display()))?;
+    let adjacency_file_buffer = std::io::BufReader::with_capacity(100_000_000, adjacency_file);
 
-    let adjacency: Vec<AdjacencyRecord> = serde_json::from_reader(adjacency_file_buffer).unwrap();
+    let adjacency: Vec<AdjacencyRecord> = serde_json::from_reader(adjacency_file_buffer)
+        .with_context(|| format!("invalid adjacency {}", adjacency.display()))?;
 
     // dbg!(adjacency.iter().take(10).collect_vec());
 
-    pop_record.into_iter().zip_eq(adjacency).map(|(info, adj)| {
-        let PopulationRecord {
-            farm_id: pop_farm_id,
-            herd_size,
-        } = info;
-        let AdjacencyRecord {
-            farm_id,
-            adjacent_farms,
-        } = adj;
-        assert_eq!(
-            pop_farm_id, farm_id,
-            "farm id from adjacency and from population info should match"
-        );
-
-        CattleFarmBundle {
-            cattle_farm: Cattle,
-            farm_id,
-            herd_size,
-            adjacent_farms,
-        }
-    })
+    ensure!(
+        pop_record.len() == adjacency.len(),
+        "population info has {} farms, but the adjacency has {}",
+        pop_record.len(),
+        adjacency.len()
+    );
+    pop_record
+        .into_iter()
+        .zip(adjacency)
+        .map(|(info, adj)| {
+            let PopulationRecord {
+                farm_id: pop_farm_id,
+                herd_size,
+                x,
+                y,
+                farm_type,
+            } = info;
+            let AdjacencyRecord {
+                farm_id,
+                adjacent_farms,
+                weights,
+            } = adj;
+            ensure!(
+                pop_farm_id == farm_id,
+                "farm id {} from the adjacency does not match farm id {} from the population info",
+                farm_id.0,
+                pop_farm_id.0
+            );
+            if let Some(weights) = weights.as_ref() {
+                ensure!(
+                    weights.weights().len() == adjacent_farms.0.len(),
+                    "farm {} must have a weight for every adjacent farm",
+                    farm_id.0
+                );
+            }
+
+            Ok(LoadedCattleFarm {
+                bundle: CattleFarmBundle {
+                    cattle_farm: Cattle,
+                    farm_id,
+                    herd_size,
+                    adjacent_farms,
+                },
+                adjacency_weights: weights,
+                coordinates: x.zip(y).map(|(x, y)| FarmCoordinates::new(x, y)),
+                farm_type: farm_type.map(FarmType),
+            })
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -116,4 +192,55 @@ mod tests {
 
         info!("{:#?}", iter_cattle_farm_bundle.take(10).collect_vec());
     }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_mismatched_population_fails() {
+        let directory = std::env::temp_dir().join("epi_bevy_test_mismatched_population");
+        std::fs::create_dir_all(&directory).unwrap();
+        let population_info = directory.join("population_info.json");
+        let adjacency = directory.join("adjacency.json");
+        std::fs::write(
+            &population_info,
+            r#"[{"farm_id": 1, "herd_size": 10}, {"farm_id": 2, "herd_size": 20}]"#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            &adjacency,
+            r#"[{"farm_id": 1, "adjacent": [2]}, {"farm_id": 2, "adjacent": [1]}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            load_population(&population_info, &adjacency).unwrap().len(),
+            2
+        );
+
+        std::fs::write(
+            &adjacency,
+            r#"[{"farm_id": 1, "adjacent": [2]}, {"farm_id": 3, "adjacent": [1]}]"#,
+        )
+        .unwrap();
+        assert!(load_population(&population_info, &adjacency).is_err());
+
+        std::fs::write(&adjacency, r#"[{"farm_id": 1, "adjacent": [2]}]"#).unwrap();
+        assert!(load_population(&population_info, &adjacency).is_err());
+
+        std::fs::write(
+            &adjacency,
+            r#"[{"farm_id": 1, "adjacent": [2], "weights": [1.0, 2.0]}, {"farm_id": 2, "adjacent": [1]}]"#,
+        )
+        .unwrap();
+        assert!(load_population(&population_info, &adjacency).is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_adjacency_weights_record() {
+        let weights: AdjacencyWeights = serde_json::from_str("[1.0, 3.0]").unwrap();
+        assert_eq!(weights.weights(), &[1.0, 3.0]);
+        assert!(serde_json::from_str::<AdjacencyWeights>("[0.0, 0.0]").is_err());
+    }
 }
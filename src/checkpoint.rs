@@ -0,0 +1,842 @@
+//! Snapshot of the full simulation state, that can be written to disk at a
+//! given tick, and later restored in order to resume the scenario from there.
+//!
+//! The snapshot consists of
+//!
+//! * All farm entities and their components, and the markets
+//! * [ScenarioTime] and the state of all the random streams, see
+//!   [crate::scenario_rng]
+//! * [InfectionEventsBatchId] of the between-herd spread model
+//! * The [TransmissionTree]
+//! * Positions of the csv-recorders, such that a resumed scenario appends to
+//!   the outputs from where the checkpoint was taken.
+//!
+//! The [FarmIdEntityMap] is rebuilt when the snapshot is restored, as entity
+//! ids are not preserved. So are the spatial indices (i.e. [SpatialIndex],
+//! [ZoningIndex] and [VaccinationIndex]), from the coordinates of the farms.
+//!
+//! A snapshot cannot be taken of farms (or markets) with components that are
+//! not part of the snapshot, as these would be lost when it is restored.
+//!
+//! Note: Parameters that are stored as resources (e.g. [crate::regulator_active_surveillance::DetectionRate])
+//! are not part of the snapshot, and these must be present in the [World]
+//! that the snapshot is restored into.
+//!
+
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use bevy::ecs::component::Component;
+use csv::Writer;
+
+use crate::{
+    animal_movements::AnimalMovementsRecorder,
+    between_herd_spread_exogenous_model::{
+        ExogenousInfectionEventsRecorder, ExogenousInfectionRate, ExogenousIntroductions,
+    },
+    between_herd_spread_model::{ContactRate, InfectionEventsBatchId},
+    between_herd_spread_model_record::BetweenHerdInfectionEventsRecorder,
+    cattle_farm_recorder::CattleFarmsCSVRecorder,
+    csv_outputs::{csv_writer, OutputDirectory},
+    diagnostic_tests::HerdTestsRecorder,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    local_spread_model::SpatialIndex,
+    markets::{MarketAttendance, MarketBundle, MarketId, MarketMixing},
+    populations::{
+        AdjacencyWeights, AdjacentFarms, Cattle, FarmCoordinates, FarmId, FarmType, HerdSize,
+        Market,
+    },
+    prelude::*,
+    regulator_actions::ScheduledActions,
+    regulator_active_surveillance::{DetectionsRecorder, UntreatedFarms},
+    regulator_biosecurity::Biosecurity,
+    regulator_capacity::ControlDemandRecorder,
+    regulator_contact_tracing::{ContactHistory, TracedFarms},
+    regulator_movement_restrictions::{MovementRestriction, NationalStandstill},
+    regulator_removals::{Culled, CulledAnimalsRecorder},
+    regulator_vaccination::{Vaccinated, VaccinationIndex, VaccinationQueue, VaccinationsRecorder},
+    regulator_zones::{ZoneMembership, ZoneMembershipRecorder, ZoningIndex},
+    regulatory_status::{RegulatoryStatus, StatusChangesRecorder},
+    reproduction_number::ReproductionNumberRecorder,
+    scenario_rng::{random_streams_state, restore_random_streams, MasterSeed},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{DiseaseParameters, Infected, Recovered, Susceptible},
+    transmission_tree::TransmissionTree,
+};
+
+pub use crate::csv_outputs::ResumeFromCheckpoint;
+
+/// Recorders that write to a csv-file, and whose position in that file is
+/// part of a [WorldSnapshot].
+pub trait CsvRecorder: Component + From<Writer<File>> {
+    /// Name of the csv-file in the [OutputDirectory] that this recorder
+    /// writes to.
+    const FILE_NAME: &'static str;
+
+    fn writer_mut(&mut self) -> &mut Writer<File>;
+}
+
+/// Position (in bytes) of a csv-recorder in its output file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecorderPosition {
+    pub path: PathBuf,
+    pub position: u64,
+}
+
+/// All components of a farm entity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FarmSnapshot {
+    pub farm_id: usize,
+    pub herd_size: usize,
+    pub adjacent_farms: Vec<usize>,
+    #[serde(default)]
+    pub adjacency_weights: Option<AdjacencyWeights>,
+    #[serde(default)]
+    pub coordinates: Option<FarmCoordinates>,
+    pub susceptible: usize,
+    pub infected: usize,
+    pub recovered: usize,
+    pub disease_parameters: Option<DiseaseParameters>,
+    pub contact_rate: Option<ContactRate>,
+    #[serde(default)]
+    pub market_attendance: Option<MarketAttendance>,
+    #[serde(default)]
+    pub farm_type: Option<FarmType>,
+    #[serde(default)]
+    pub biosecurity: Option<Biosecurity>,
+    #[serde(default)]
+    pub exogenous_infection_rate: Option<ExogenousInfectionRate>,
+    #[serde(default)]
+    pub regulatory_status: Option<RegulatoryStatus>,
+    #[serde(default)]
+    pub culled: Option<Culled>,
+    #[serde(default)]
+    pub movement_restriction: Option<MovementRestriction>,
+    #[serde(default)]
+    pub zone_membership: Option<ZoneMembership>,
+    #[serde(default)]
+    pub vaccinated: Option<Vaccinated>,
+}
+
+/// All components of a market entity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketSnapshot {
+    pub market_id: usize,
+    pub mixing: MarketMixing,
+}
+
+/// Cell sizes of the spatial indices that were present, as these are rebuilt
+/// from the coordinates of the farms.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpatialIndices {
+    pub local_spread: Option<f64>,
+    pub zoning: Option<f64>,
+    pub vaccination: Option<f64>,
+}
+
+/// The simulation state at a given tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub scenario_time: ScenarioTime,
+    pub master_seed: Option<MasterSeed>,
+    /// States of the process streams keyed by stream no.
+    pub random_streams: BTreeMap<u64, ScenarioRng>,
+    pub infection_events_batch_id: Option<InfectionEventsBatchId>,
+    pub transmission_tree: Option<TransmissionTree>,
+    /// Pending actions of the regulators.
+    #[serde(default)]
+    pub scheduled_actions: Option<ScheduledActions>,
+    #[serde(default)]
+    pub national_standstill: Option<NationalStandstill>,
+    /// Contacts that may still be traced, and the farms awaiting a test.
+    #[serde(default)]
+    pub contact_history: Option<ContactHistory>,
+    #[serde(default)]
+    pub traced_farms: Option<TracedFarms>,
+    #[serde(default)]
+    pub vaccination_queue: Option<VaccinationQueue>,
+    /// Detected farms awaiting treatment.
+    #[serde(default)]
+    pub untreated_farms: Option<UntreatedFarms>,
+    /// Farms in the order that they are iterated in, which is kept when they
+    /// are restored, as the processes draw for one farm after another.
+    pub farms: Vec<FarmSnapshot>,
+    /// Markets sorted by their market id.
+    #[serde(default)]
+    pub markets: Vec<MarketSnapshot>,
+    #[serde(default)]
+    pub spatial_indices: SpatialIndices,
+    pub recorders: Vec<RecorderPosition>,
+}
+
+impl WorldSnapshot {
+    /// Write the snapshot as json to `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let writer = std::io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Read a snapshot that was written by [WorldSnapshot::save].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = std::io::BufReader::new(
+            File::open(path)
+                .with_context(|| format!("failed to open checkpoint {}", path.display()))?,
+        );
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+/// Take a snapshot of the current simulation state.
+///
+/// Fails if a farm or a market has a component that is not part of the
+/// snapshot.
+///
+/// Note: This flushes the csv-recorders.
+pub fn take_snapshot(world: &mut World) -> Result<WorldSnapshot> {
+    ensure_known_components::<FarmId>(
+        world,
+        &[
+            TypeId::of::<Cattle>(),
+            TypeId::of::<FarmId>(),
+            TypeId::of::<HerdSize>(),
+            TypeId::of::<AdjacentFarms>(),
+            TypeId::of::<AdjacencyWeights>(),
+            TypeId::of::<FarmCoordinates>(),
+            TypeId::of::<Susceptible>(),
+            TypeId::of::<Infected>(),
+            TypeId::of::<Recovered>(),
+            TypeId::of::<DiseaseParameters>(),
+            TypeId::of::<ContactRate>(),
+            TypeId::of::<MarketAttendance>(),
+            TypeId::of::<FarmType>(),
+            TypeId::of::<Biosecurity>(),
+            TypeId::of::<ExogenousInfectionRate>(),
+            // only holds the introductions of the last update
+            TypeId::of::<ExogenousIntroductions>(),
+            TypeId::of::<RegulatoryStatus>(),
+            TypeId::of::<Culled>(),
+            TypeId::of::<MovementRestriction>(),
+            TypeId::of::<ZoneMembership>(),
+            TypeId::of::<Vaccinated>(),
+        ],
+    )?;
+    ensure_known_components::<MarketId>(
+        world,
+        &[
+            TypeId::of::<Market>(),
+            TypeId::of::<MarketId>(),
+            TypeId::of::<MarketMixing>(),
+        ],
+    )?;
+
+    let mut query = world.query_filtered::<(
+        &FarmId,
+        &HerdSize,
+        (
+            &AdjacentFarms,
+            Option<&AdjacencyWeights>,
+            Option<&FarmCoordinates>,
+        ),
+        &Susceptible,
+        &Infected,
+        &Recovered,
+        Option<&DiseaseParameters>,
+        (Option<&ContactRate>, Option<&MarketAttendance>),
+        (
+            Option<&FarmType>,
+            Option<&Biosecurity>,
+            Option<&ExogenousInfectionRate>,
+        ),
+        (
+            Option<&RegulatoryStatus>,
+            Option<&Culled>,
+            Option<&MovementRestriction>,
+            Option<&ZoneMembership>,
+            Option<&Vaccinated>,
+        ),
+    ), With<Cattle>>();
+    let farms = query
+        .iter(world)
+        .map(
+            |(
+                farm_id,
+                herd_size,
+                (adjacent_farms, adjacency_weights, coordinates),
+                susceptible,
+                infected,
+                recovered,
+                disease_parameters,
+                (contact_rate, market_attendance),
+                (farm_type, biosecurity, exogenous_infection_rate),
+                (regulatory_status, culled, movement_restriction, zone_membership, vaccinated),
+            )| FarmSnapshot {
+                farm_id: farm_id.0,
+                herd_size: herd_size.0,
+                adjacent_farms: adjacent_farms.0.iter().map(|x| x.0).collect(),
+                adjacency_weights: adjacency_weights.cloned(),
+                coordinates: coordinates.copied(),
+                susceptible: susceptible.0,
+                infected: infected.0,
+                recovered: recovered.0,
+                disease_parameters: disease_parameters.copied(),
+                contact_rate: contact_rate.copied(),
+                market_attendance: market_attendance.copied(),
+                farm_type: farm_type.copied(),
+                biosecurity: biosecurity.copied(),
+                exogenous_infection_rate: exogenous_infection_rate.copied(),
+                regulatory_status: regulatory_status.copied(),
+                culled: culled.copied(),
+                movement_restriction: movement_restriction.copied(),
+                zone_membership: zone_membership.copied(),
+                vaccinated: vaccinated.copied(),
+            },
+        )
+        .collect();
+
+    let markets = world
+        .query_filtered::<(&MarketId, &MarketMixing), With<Market>>()
+        .iter(world)
+        .map(|(market_id, mixing)| MarketSnapshot {
+            market_id: market_id.0,
+            mixing: *mixing,
+        })
+        .sorted_by_key(|market| market.market_id)
+        .collect();
+
+    let spatial_indices = SpatialIndices {
+        local_spread: world.get_resource::<SpatialIndex>().map(|x| x.cell_size()),
+        zoning: world.get_resource::<ZoningIndex>().map(|x| x.0.cell_size()),
+        vaccination: world
+            .get_resource::<VaccinationIndex>()
+            .map(|x| x.0.cell_size()),
+    };
+
+    let recorders = vec![
+        recorder_position::<CattleFarmsCSVRecorder>(world)?,
+        recorder_position::<BetweenHerdInfectionEventsRecorder>(world)?,
+        recorder_position::<ReproductionNumberRecorder>(world)?,
+        recorder_position::<AnimalMovementsRecorder>(world)?,
+        recorder_position::<ExogenousInfectionEventsRecorder>(world)?,
+        recorder_position::<StatusChangesRecorder>(world)?,
+        recorder_position::<HerdTestsRecorder>(world)?,
+        recorder_position::<CulledAnimalsRecorder>(world)?,
+        recorder_position::<ZoneMembershipRecorder>(world)?,
+        recorder_position::<DetectionsRecorder>(world)?,
+        recorder_position::<VaccinationsRecorder>(world)?,
+        recorder_position::<ControlDemandRecorder>(world)?,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    Ok(WorldSnapshot {
+        scenario_time: world
+            .get_resource::<ScenarioTime>()
+            .cloned()
+            .context("missing `ScenarioTime`")?,
+        master_seed: world.get_resource::<MasterSeed>().copied(),
+        random_streams: random_streams_state(world),
+        infection_events_batch_id: world.get_resource::<InfectionEventsBatchId>().copied(),
+        transmission_tree: world.get_resource::<TransmissionTree>().cloned(),
+        scheduled_actions: world.get_resource::<ScheduledActions>().cloned(),
+        national_standstill: world.get_resource::<NationalStandstill>().copied(),
+        contact_history: world.get_resource::<ContactHistory>().cloned(),
+        traced_farms: world.get_resource::<TracedFarms>().cloned(),
+        vaccination_queue: world.get_resource::<VaccinationQueue>().cloned(),
+        untreated_farms: world.get_resource::<UntreatedFarms>().cloned(),
+        farms,
+        markets,
+        spatial_indices,
+        recorders,
+    })
+}
+
+/// Fails if an entity with the component `M` has a component that is not
+/// among the `known` components of the snapshot.
+fn ensure_known_components<M: Component>(world: &World, known: &[TypeId]) -> Result<()> {
+    let components = world.components();
+    let marker = match components.get_id(TypeId::of::<M>()) {
+        Some(marker) => marker,
+        None => return Ok(()),
+    };
+    let known: HashSet<_> = known
+        .iter()
+        .filter_map(|type_id| components.get_id(*type_id))
+        .collect();
+    for archetype in world
+        .archetypes()
+        .iter()
+        .filter(|archetype| archetype.contains(marker) && !archetype.entities().is_empty())
+    {
+        if let Some(unknown) = archetype
+            .components()
+            .find(|component_id| !known.contains(component_id))
+        {
+            bail!(
+                "`{}` on entities with `{}` is not part of the snapshot",
+                components.get_info(unknown).unwrap().name(),
+                std::any::type_name::<M>()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Replace the farms and the simulation state in `world` with the snapshot.
+pub fn restore_snapshot(world: &mut World, snapshot: WorldSnapshot) -> Result<()> {
+    let WorldSnapshot {
+        scenario_time,
+        master_seed,
+        random_streams,
+        infection_events_batch_id,
+        transmission_tree,
+        scheduled_actions,
+        national_standstill,
+        contact_history,
+        traced_farms,
+        vaccination_queue,
+        untreated_farms,
+        farms,
+        markets,
+        spatial_indices,
+        recorders,
+    } = snapshot;
+
+    let mut present_farms = world
+        .query_filtered::<Entity, With<FarmId>>()
+        .iter(world)
+        .collect_vec();
+    present_farms.extend(world.query_filtered::<Entity, With<MarketId>>().iter(world));
+    for entity in present_farms {
+        world.despawn(entity);
+    }
+
+    for market in markets {
+        world.spawn().insert_bundle(MarketBundle::new(
+            FarmId::new_single_population(market.market_id),
+            market.mixing,
+        ));
+    }
+
+    let mut farm_coordinates = Vec::new();
+
+    let mut farm_id_to_entity_map = HashMap::with_capacity(farms.len());
+    for farm in farms {
+        let farm_id: FarmId = FarmId::new_single_population(farm.farm_id);
+        let adjacent_farms: AdjacentFarms = AdjacentFarms::new_single_population(
+            farm.adjacent_farms
+                .into_iter()
+                .map(FarmId::new_single_population)
+                .collect(),
+        );
+        let mut farm_entity = world.spawn();
+        farm_entity.insert_bundle((
+            Cattle,
+            farm_id,
+            HerdSize::new_single_population(farm.herd_size),
+            adjacent_farms,
+            Susceptible(farm.susceptible),
+            Infected(farm.infected),
+            Recovered(farm.recovered),
+        ));
+        if let Some(disease_parameters) = farm.disease_parameters {
+            farm_entity.insert(disease_parameters);
+        }
+        if let Some(contact_rate) = farm.contact_rate {
+            farm_entity.insert(contact_rate);
+        }
+        if let Some(adjacency_weights) = farm.adjacency_weights {
+            farm_entity.insert(adjacency_weights);
+        }
+        if let Some(coordinates) = farm.coordinates {
+            farm_entity.insert(coordinates);
+            farm_coordinates.push((farm_id, coordinates));
+        }
+        if let Some(market_attendance) = farm.market_attendance {
+            farm_entity.insert(market_attendance);
+        }
+        if let Some(farm_type) = farm.farm_type {
+            farm_entity.insert(farm_type);
+        }
+        if let Some(biosecurity) = farm.biosecurity {
+            farm_entity.insert(biosecurity);
+        }
+        if let Some(exogenous_infection_rate) = farm.exogenous_infection_rate {
+            farm_entity
+                .insert_bundle((exogenous_infection_rate, ExogenousIntroductions::default()));
+        }
+        if let Some(regulatory_status) = farm.regulatory_status {
+            farm_entity.insert(regulatory_status);
+        }
+        if let Some(culled) = farm.culled {
+            farm_entity.insert(culled);
+        }
+        if let Some(movement_restriction) = farm.movement_restriction {
+            farm_entity.insert(movement_restriction);
+        }
+        if let Some(zone_membership) = farm.zone_membership {
+            farm_entity.insert(zone_membership);
+        }
+        if let Some(vaccinated) = farm.vaccinated {
+            farm_entity.insert(vaccinated);
+        }
+        farm_id_to_entity_map.insert(farm_id, farm_entity.id());
+    }
+    world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+
+    let SpatialIndices {
+        local_spread,
+        zoning,
+        vaccination,
+    } = spatial_indices;
+    if let Some(cell_size) = local_spread {
+        world.insert_resource(SpatialIndex::new(cell_size, farm_coordinates.clone()));
+    }
+    if let Some(cell_size) = zoning {
+        world.insert_resource(ZoningIndex::from(SpatialIndex::new(
+            cell_size,
+            farm_coordinates.clone(),
+        )));
+    }
+    if let Some(cell_size) = vaccination {
+        world.insert_resource(VaccinationIndex::from(SpatialIndex::new(
+            cell_size,
+            farm_coordinates,
+        )));
+    }
+
+    world.insert_resource(scenario_time);
+    if let Some(master_seed) = master_seed {
+        world.insert_resource(master_seed);
+    }
+    restore_random_streams(world, &random_streams);
+    if let Some(infection_events_batch_id) = infection_events_batch_id {
+        world.insert_resource(infection_events_batch_id);
+    }
+    if let Some(transmission_tree) = transmission_tree {
+        world.insert_resource(transmission_tree);
+    }
+    if let Some(scheduled_actions) = scheduled_actions {
+        world.insert_resource(scheduled_actions);
+    }
+    if let Some(national_standstill) = national_standstill {
+        world.insert_resource(national_standstill);
+    }
+    if let Some(contact_history) = contact_history {
+        world.insert_resource(contact_history);
+    }
+    if let Some(traced_farms) = traced_farms {
+        world.insert_resource(traced_farms);
+    }
+    if let Some(vaccination_queue) = vaccination_queue {
+        world.insert_resource(vaccination_queue);
+    }
+    if let Some(untreated_farms) = untreated_farms {
+        world.insert_resource(untreated_farms);
+    }
+
+    restore_recorder::<CattleFarmsCSVRecorder>(world, &recorders)?;
+    restore_recorder::<BetweenHerdInfectionEventsRecorder>(world, &recorders)?;
+    restore_recorder::<ReproductionNumberRecorder>(world, &recorders)?;
+    restore_recorder::<AnimalMovementsRecorder>(world, &recorders)?;
+    restore_recorder::<ExogenousInfectionEventsRecorder>(world, &recorders)?;
+    restore_recorder::<StatusChangesRecorder>(world, &recorders)?;
+    restore_recorder::<HerdTestsRecorder>(world, &recorders)?;
+    restore_recorder::<CulledAnimalsRecorder>(world, &recorders)?;
+    restore_recorder::<ZoneMembershipRecorder>(world, &recorders)?;
+    restore_recorder::<DetectionsRecorder>(world, &recorders)?;
+    restore_recorder::<VaccinationsRecorder>(world, &recorders)?;
+    restore_recorder::<ControlDemandRecorder>(world, &recorders)?;
+
+    Ok(())
+}
+
+fn recorder_position<R: CsvRecorder>(world: &mut World) -> Result<Option<RecorderPosition>> {
+    let output_directory = output_directory(world);
+    if let Some(mut recorder) = world.get_resource_mut::<R>() {
+        let writer = recorder.writer_mut();
+        writer.flush()?;
+        let position = writer.get_ref().metadata()?.len();
+        Ok(Some(RecorderPosition {
+            path: output_directory.join(R::FILE_NAME),
+            position,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+fn output_directory(world: &World) -> OutputDirectory {
+    world
+        .get_resource::<OutputDirectory>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Re-open the output of the recorder in the [OutputDirectory], cut back to
+/// its position at the checkpoint.
+///
+/// If the output was written to another directory at the checkpoint (e.g. by
+/// the baseline of a counterfactual scenario), it is copied from there up to
+/// that position. Otherwise it must not have been truncated since, see
+/// [crate::csv_outputs::CsvOutputs].
+fn restore_recorder<R: CsvRecorder>(
+    world: &mut World,
+    recorders: &[RecorderPosition],
+) -> Result<()> {
+    let recorder_position = match recorders
+        .iter()
+        .find(|x| x.path.file_name() == Some(std::ffi::OsStr::new(R::FILE_NAME)))
+    {
+        Some(recorder_position) => recorder_position,
+        None => return Ok(()),
+    };
+
+    // flush and close the present recorder
+    if let Some(mut recorder) = world.remove_resource::<R>() {
+        recorder.writer_mut().flush()?;
+    }
+
+    let path = output_directory(world).join(R::FILE_NAME);
+    if path != recorder_position.path {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let source = File::open(&recorder_position.path)
+            .with_context(|| format!("failed to open {}", recorder_position.path.display()))?;
+        std::io::copy(
+            &mut source.take(recorder_position.position),
+            &mut File::create(&path)?,
+        )?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if file.metadata()?.len() < recorder_position.position {
+        bail!("{} is shorter than at the checkpoint", path.display());
+    }
+    file.set_len(recorder_position.position)?;
+    file.seek(SeekFrom::End(0))?;
+
+    world.insert_resource(R::from(csv_writer(file)));
+    Ok(())
+}
+
+/// Write a checkpoint at the given tick, see [checkpoint_at_tick].
+#[derive(Debug, Clone, derive_new::new)]
+pub struct CheckpointAt {
+    pub scenario_tick: Time,
+    pub path: PathBuf,
+}
+
+/// Writes a checkpoint if [CheckpointAt] is present and its tick is reached.
+///
+/// Add as an `exclusive_system` at the end of the main loop.
+pub fn checkpoint_at_tick(world: &mut World) {
+    let checkpoint_at = match world.get_resource::<CheckpointAt>() {
+        Some(checkpoint_at) => checkpoint_at.clone(),
+        None => return,
+    };
+    let current_time = world
+        .get_resource::<ScenarioTime>()
+        .expect("missing `ScenarioTime`")
+        .current_time();
+
+    if current_time == checkpoint_at.scenario_tick {
+        take_snapshot(world)
+            .and_then(|snapshot| snapshot.save(&checkpoint_at.path))
+            .expect("failed to write checkpoint");
+        info!(
+            "Wrote checkpoint at tick {} to {}",
+            current_time,
+            checkpoint_at.path.display()
+        );
+    }
+}
+
+/// Restores the checkpoint given by [ResumeFromCheckpoint], if present.
+///
+/// Add as an `exclusive_system` after the population (and its processes) has
+/// been set up. The csv-recorders that were set up before keep their outputs
+/// while [ResumeFromCheckpoint] is present, and these are cut back to the
+/// checkpoint here.
+pub fn resume_from_checkpoint(world: &mut World) {
+    if let Some(ResumeFromCheckpoint(path)) = world.remove_resource::<ResumeFromCheckpoint>() {
+        let snapshot = WorldSnapshot::load(&path).expect("failed to load checkpoint");
+        restore_snapshot(world, snapshot).expect("failed to restore checkpoint");
+        info!(
+            "Resumed from checkpoint {} at tick {}",
+            path.display(),
+            world.get_resource::<ScenarioTime>().unwrap().current_time()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        between_herd_spread_model::ContactRate,
+        markets::{infection_events_from_markets, update_markets},
+        parameters::{Probability, Rate},
+        regulator_actions::RegulatoryAction,
+        regulatory_status::FarmStatus,
+        scenario_rng::insert_random_streams,
+        sir_spread_model::update_disease_compartments,
+    };
+
+    fn example_world() -> World {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210624));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId(4));
+        let market_id: MarketId = FarmId::new_single_population(1);
+        world.spawn().insert_bundle(MarketBundle::new(
+            market_id,
+            MarketMixing::new(Rate::new(5.).unwrap()),
+        ));
+        let farms = (1..=5)
+            .map(|farm_id| {
+                let adjacent_farms: AdjacentFarms =
+                    AdjacentFarms::new_single_population(vec![FarmId::new_single_population(
+                        farm_id % 5 + 1,
+                    )]);
+                (
+                    Cattle,
+                    FarmId::<()>::new_single_population(farm_id),
+                    HerdSize::new_single_population(100),
+                    adjacent_farms,
+                    Susceptible(90),
+                    Infected(10),
+                    Recovered(0),
+                    DiseaseParameters::new(0.3, 0.1),
+                    FarmCoordinates::new(farm_id as f64, 0.),
+                    MarketAttendance::new(market_id, ContactRate::new(Rate::new(0.5).unwrap())),
+                    RegulatoryStatus::new(FarmStatus::Free, 1),
+                )
+            })
+            .collect_vec();
+        let farms = world.spawn_batch(farms).collect_vec();
+        world.entity_mut(farms[0]).insert(MovementRestriction::new(
+            Probability::new(0.5).unwrap(),
+            100,
+        ));
+
+        let spatial_index = SpatialIndex::new(
+            2.,
+            world
+                .query::<(&FarmId, &FarmCoordinates)>()
+                .iter(&world)
+                .map(|(farm_id, coordinates)| (*farm_id, *coordinates))
+                .collect_vec(),
+        );
+        world.insert_resource(spatial_index);
+        let mut scheduled_actions = ScheduledActions::default();
+        scheduled_actions.schedule(50, FarmId::new_single_population(2), RegulatoryAction::Cull);
+        world.insert_resource(scheduled_actions);
+        world
+    }
+
+    fn main_loop() -> SystemStage {
+        SystemStage::single_threaded()
+            .with_system(update_disease_compartments.system().label("disease"))
+            .with_system(
+                update_markets
+                    .system()
+                    .chain(infection_events_from_markets.system())
+                    .chain(crate::chain_tools::dispose.system())
+                    .after("disease"),
+            )
+    }
+
+    /// The snapshot without the recorders, as json.
+    fn state(world: &mut World) -> serde_json::Value {
+        let snapshot = take_snapshot(world).unwrap();
+        assert!(snapshot.recorders.is_empty());
+        serde_json::to_value(snapshot).unwrap()
+    }
+
+    #[test]
+    fn test_resumed_world_is_identical() {
+        let mut stage = main_loop();
+        // systems are initialised for one world only
+        let mut resumed_stage = main_loop();
+        let mut world = example_world();
+        for _ in 0..10 {
+            stage.run(&mut world);
+        }
+
+        let snapshot = take_snapshot(&mut world).unwrap();
+        assert_eq!(snapshot.markets.len(), 1);
+        let batch_id = world.get_resource::<InfectionEventsBatchId>().unwrap().0;
+        assert_eq!(snapshot.spatial_indices.local_spread, Some(2.));
+        // round-trip through json
+        let snapshot: WorldSnapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+        let mut resumed_world = World::new();
+        restore_snapshot(&mut resumed_world, snapshot).unwrap();
+        assert_eq!(
+            resumed_world
+                .get_resource::<InfectionEventsBatchId>()
+                .unwrap()
+                .0,
+            batch_id
+        );
+        assert_eq!(
+            resumed_world
+                .get_resource::<FarmIdEntityMap>()
+                .unwrap()
+                .0
+                .len(),
+            5
+        );
+        assert_eq!(
+            resumed_world
+                .get_resource::<SpatialIndex>()
+                .unwrap()
+                .within(FarmCoordinates::new(1., 0.), 1.5)
+                .count(),
+            2
+        );
+        assert!(resumed_world
+            .get_resource::<ScheduledActions>()
+            .unwrap()
+            .is_pending(FarmId::new_single_population(2), RegulatoryAction::Cull));
+        assert_eq!(state(&mut world), state(&mut resumed_world));
+
+        for _ in 0..10 {
+            stage.run(&mut world);
+            resumed_stage.run(&mut resumed_world);
+        }
+        assert_eq!(state(&mut world), state(&mut resumed_world));
+    }
+
+    #[test]
+    fn test_unknown_component_fails() {
+        struct NotInSnapshot;
+
+        let mut world = example_world();
+        let farm = world
+            .query_filtered::<Entity, With<FarmId>>()
+            .iter(&world)
+            .next()
+            .unwrap();
+        world.entity_mut(farm).insert(NotInSnapshot);
+        assert!(take_snapshot(&mut world).is_err());
+    }
+}
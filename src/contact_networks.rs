@@ -0,0 +1,544 @@
+//! Synthetic contact structures between farms.
+//!
+//! Instead of loading [AdjacentFarms] from a file, e.g.
+//! `assets/ring_adjacency.json`, these are generated from a [ContactNetwork]
+//! given as a resource, see [setup_contact_network].
+//!
+//! All networks are undirected, thus a farm is always adjacent to its
+//! adjacent farms. Farms may end up without any adjacent farms, e.g. in an
+//! Erdős–Rényi network.
+//!
+//! The networks are drawn from [ContactNetworkStream], and thus they are
+//! determined by the [crate::scenario_rng::MasterSeed].
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use thiserror::Error;
+
+use crate::{
+    local_spread_model::SpatialIndex,
+    parameters::Probability,
+    populations::{AdjacencyWeights, AdjacentFarms, Cattle, FarmCoordinates, FarmId, FarmType},
+    prelude::*,
+    scenario_rng::{ContactNetworkStream, ProcessRng},
+};
+
+#[derive(Error, Debug)]
+pub enum ContactNetworkError {
+    #[error("farm {farm_id} has no coordinates")]
+    MissingCoordinates { farm_id: usize },
+    #[error("farm {farm_id} is of type {farm_type}, but the block model has {blocks} blocks")]
+    UnknownFarmType {
+        farm_id: usize,
+        farm_type: usize,
+        blocks: usize,
+    },
+    #[error("the edge probabilities between blocks must be a symmetric matrix")]
+    InvalidBlockMatrix,
+    #[error("the radius of a random geometric network must be positive, got {radius}")]
+    InvalidRadius { radius: f64 },
+}
+
+/// Add as a resource to generate the [AdjacentFarms] of every farm.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub enum ContactNetwork {
+    /// Every farm is adjacent to the `neighbours` nearest farms on either side,
+    /// in the order of their farm id.
+    Ring { neighbours: usize },
+    /// Every pair of farms is adjacent with `edge_probability`.
+    ErdosRenyi { edge_probability: Probability },
+    /// A [ContactNetwork::Ring] where every edge is rewired to a random farm
+    /// with `rewiring_probability`.
+    WattsStrogatz {
+        neighbours: usize,
+        rewiring_probability: Probability,
+    },
+    /// Preferential attachment, where every farm is attached to
+    /// `edges_per_farm` of the preceding farms in proportion to their degree.
+    BarabasiAlbert { edges_per_farm: usize },
+    /// A pair of farms is adjacent with the probability given by their
+    /// [FarmType]s, i.e. `edge_probabilities[type_1][type_2]`.
+    StochasticBlock {
+        edge_probabilities: Vec<Vec<Probability>>,
+    },
+    /// Farms within `radius` km of each other are adjacent.
+    RandomGeometric { radius: f64 },
+}
+
+/// The attributes of a farm that the networks are generated from.
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct NetworkFarm {
+    pub farm_id: FarmId,
+    pub farm_type: Option<FarmType>,
+    pub coordinates: Option<FarmCoordinates>,
+}
+
+/// Undirected graph between the indices of the farms.
+type Graph = Vec<BTreeSet<usize>>;
+
+fn add_edge(graph: &mut Graph, from: usize, to: usize) {
+    if from != to {
+        graph[from].insert(to);
+        graph[to].insert(from);
+    }
+}
+
+fn remove_edge(graph: &mut Graph, from: usize, to: usize) {
+    graph[from].remove(&to);
+    graph[to].remove(&from);
+}
+
+impl ContactNetwork {
+    /// The adjacent farms of each of `farms` in the same order.
+    ///
+    /// The networks are sensitive to the order of `farms`, see
+    /// [setup_contact_network].
+    pub fn generate<R: Rng + ?Sized>(
+        &self,
+        farms: &[NetworkFarm],
+        rng: &mut R,
+    ) -> Result<Vec<AdjacentFarms>, ContactNetworkError> {
+        let total_farms = farms.len();
+        let graph = match self {
+            ContactNetwork::Ring { neighbours } => ring_lattice(total_farms, *neighbours),
+            ContactNetwork::ErdosRenyi { edge_probability } => {
+                erdos_renyi(total_farms, *edge_probability, rng)
+            }
+            ContactNetwork::WattsStrogatz {
+                neighbours,
+                rewiring_probability,
+            } => watts_strogatz(total_farms, *neighbours, *rewiring_probability, rng),
+            ContactNetwork::BarabasiAlbert { edges_per_farm } => {
+                barabasi_albert(total_farms, *edges_per_farm, rng)
+            }
+            ContactNetwork::StochasticBlock { edge_probabilities } => {
+                stochastic_block(farms, edge_probabilities, rng)?
+            }
+            ContactNetwork::RandomGeometric { radius } => random_geometric(farms, *radius)?,
+        };
+
+        Ok(graph
+            .into_iter()
+            .map(|adjacent| {
+                AdjacentFarms::new_single_population(
+                    adjacent.into_iter().map(|x| farms[x].farm_id).collect(),
+                )
+            })
+            .collect())
+    }
+}
+
+fn ring_lattice(total_farms: usize, neighbours: usize) -> Graph {
+    let mut graph = vec![BTreeSet::new(); total_farms];
+    for farm in 0..total_farms {
+        for offset in 1..=neighbours {
+            add_edge(&mut graph, farm, (farm + offset) % total_farms);
+        }
+    }
+    graph
+}
+
+/// Skips the pairs without an edge through geometric jumps, thus it is linear
+/// in the number of edges rather than in the number of pairs
+/// (Batagelj & Brandes, 2005).
+fn erdos_renyi<R: Rng + ?Sized>(
+    total_farms: usize,
+    edge_probability: Probability,
+    rng: &mut R,
+) -> Graph {
+    let mut graph = vec![BTreeSet::new(); total_farms];
+    let edge_probability = edge_probability.0;
+    if edge_probability <= 0. {
+        return graph;
+    }
+    if edge_probability >= 1. {
+        for farm in 0..total_farms {
+            for other in 0..farm {
+                add_edge(&mut graph, farm, other);
+            }
+        }
+        return graph;
+    }
+
+    let log_complement = (1. - edge_probability).ln();
+    let (mut farm, mut other) = (1, -1_i64);
+    while farm < total_farms {
+        let skip: f64 = rng.gen();
+        other += 1 + ((1. - skip).ln() / log_complement).floor() as i64;
+        while other >= farm as i64 && farm < total_farms {
+            other -= farm as i64;
+            farm += 1;
+        }
+        if farm < total_farms {
+            add_edge(&mut graph, farm, other as usize);
+        }
+    }
+    graph
+}
+
+fn watts_strogatz<R: Rng + ?Sized>(
+    total_farms: usize,
+    neighbours: usize,
+    rewiring_probability: Probability,
+    rng: &mut R,
+) -> Graph {
+    let mut graph = ring_lattice(total_farms, neighbours);
+    for farm in 0..total_farms {
+        for offset in 1..=neighbours {
+            let neighbour = (farm + offset) % total_farms;
+            if !graph[farm].contains(&neighbour) || !rng.gen_bool(rewiring_probability.0) {
+                continue;
+            }
+            // the farm is already adjacent to every other farm
+            if graph[farm].len() + 1 >= total_farms {
+                continue;
+            }
+            let new_neighbour = loop {
+                let candidate = rng.gen_range(0..total_farms);
+                if candidate != farm && !graph[farm].contains(&candidate) {
+                    break candidate;
+                }
+            };
+            remove_edge(&mut graph, farm, neighbour);
+            add_edge(&mut graph, farm, new_neighbour);
+        }
+    }
+    graph
+}
+
+fn barabasi_albert<R: Rng + ?Sized>(
+    total_farms: usize,
+    edges_per_farm: usize,
+    rng: &mut R,
+) -> Graph {
+    let mut graph = vec![BTreeSet::new(); total_farms];
+    // the first farms are all adjacent to each other
+    let initial_farms = total_farms.min(edges_per_farm + 1);
+    for farm in 0..initial_farms {
+        for other in 0..farm {
+            add_edge(&mut graph, farm, other);
+        }
+    }
+
+    // every farm appears once for each of its edges
+    let mut attachments = (0..initial_farms)
+        .flat_map(|farm| std::iter::repeat(farm).take(graph[farm].len()))
+        .collect_vec();
+    for farm in initial_farms..total_farms {
+        let mut targets = BTreeSet::new();
+        while targets.len() < edges_per_farm {
+            targets.insert(*attachments.choose(rng).unwrap());
+        }
+        for target in targets {
+            add_edge(&mut graph, farm, target);
+            attachments.push(target);
+            attachments.push(farm);
+        }
+    }
+    graph
+}
+
+fn stochastic_block<R: Rng + ?Sized>(
+    farms: &[NetworkFarm],
+    edge_probabilities: &[Vec<Probability>],
+    rng: &mut R,
+) -> Result<Graph, ContactNetworkError> {
+    let blocks = edge_probabilities.len();
+    let is_square = edge_probabilities.iter().all(|row| row.len() == blocks);
+    let is_symmetric = || {
+        (0..blocks).all(|block| {
+            (0..block).all(|other| {
+                (edge_probabilities[block][other].0 - edge_probabilities[other][block].0).abs()
+                    <= f64::EPSILON
+            })
+        })
+    };
+    if !is_square || !is_symmetric() {
+        return Err(ContactNetworkError::InvalidBlockMatrix);
+    }
+    let farm_types = farms
+        .iter()
+        .map(|farm| {
+            let farm_type = farm.farm_type.map_or(0, |x| x.0);
+            if farm_type < blocks {
+                Ok(farm_type)
+            } else {
+                Err(ContactNetworkError::UnknownFarmType {
+                    farm_id: farm.farm_id.0,
+                    farm_type,
+                    blocks,
+                })
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut graph = vec![BTreeSet::new(); farms.len()];
+    for farm in 0..farms.len() {
+        for other in 0..farm {
+            if rng.gen_bool(edge_probabilities[farm_types[farm]][farm_types[other]].0) {
+                add_edge(&mut graph, farm, other);
+            }
+        }
+    }
+    Ok(graph)
+}
+
+fn random_geometric(farms: &[NetworkFarm], radius: f64) -> Result<Graph, ContactNetworkError> {
+    if !(radius > 0. && radius.is_finite()) {
+        return Err(ContactNetworkError::InvalidRadius { radius });
+    }
+    let coordinates = farms
+        .iter()
+        .map(|farm| {
+            farm.coordinates
+                .ok_or(ContactNetworkError::MissingCoordinates {
+                    farm_id: farm.farm_id.0,
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let farm_index: BTreeMap<FarmId, usize> = farms
+        .iter()
+        .enumerate()
+        .map(|(index, farm)| (farm.farm_id, index))
+        .collect();
+    let spatial_index = SpatialIndex::new(
+        radius,
+        farms
+            .iter()
+            .zip(&coordinates)
+            .map(|(farm, coordinates)| (farm.farm_id, *coordinates)),
+    );
+
+    let mut graph = vec![BTreeSet::new(); farms.len()];
+    for (farm, coordinates) in coordinates.into_iter().enumerate() {
+        for (other, _) in spatial_index.within(coordinates, radius) {
+            add_edge(&mut graph, farm, farm_index[&other]);
+        }
+    }
+    Ok(graph)
+}
+
+/// Replaces the [AdjacentFarms] of every farm with a network drawn from
+/// [ContactNetwork].
+///
+/// Add this to a startup stage after the farms have been added, and before
+/// the (between-herd) contact processes are set up. Any [AdjacencyWeights] are
+/// removed, as they no longer match the adjacent farms.
+pub fn setup_contact_network(
+    mut commands: Commands,
+    contact_network: Res<ContactNetwork>,
+    mut rng: ResMut<ProcessRng<ContactNetworkStream>>,
+    query: Query<(Entity, &FarmId, Option<&FarmType>, Option<&FarmCoordinates>), With<Cattle>>,
+) {
+    // the networks depend on the order of the farms
+    let (entities, farms): (Vec<_>, Vec<_>) = query
+        .iter()
+        .map(|(entity, farm_id, farm_type, coordinates)| {
+            (
+                entity,
+                NetworkFarm::new(*farm_id, farm_type.copied(), coordinates.copied()),
+            )
+        })
+        .sorted_by_key(|(_, farm)| farm.farm_id)
+        .unzip();
+
+    let adjacent_farms = contact_network
+        .generate(&farms, &mut *rng)
+        .unwrap_or_else(|err| panic!("failed to generate {:?}: {}", *contact_network, err));
+    info!(
+        "Generated contact network with {} edges",
+        adjacent_farms.iter().map(|x| x.0.len()).sum::<usize>() / 2
+    );
+    for (entity, adjacent_farms) in entities.into_iter().zip(adjacent_farms) {
+        commands
+            .entity(entity)
+            .insert(adjacent_farms)
+            .remove::<AdjacencyWeights>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network_farms(total_farms: usize) -> Vec<NetworkFarm> {
+        (1..=total_farms)
+            .map(|farm_id| {
+                NetworkFarm::new(
+                    FarmId::new_single_population(farm_id),
+                    Some(FarmType(farm_id % 2)),
+                    Some(FarmCoordinates::new(
+                        (farm_id % 10) as f64,
+                        (farm_id / 10) as f64,
+                    )),
+                )
+            })
+            .collect()
+    }
+
+    fn degrees(adjacent_farms: &[AdjacentFarms]) -> Vec<usize> {
+        adjacent_farms.iter().map(|x| x.0.len()).collect()
+    }
+
+    fn total_edges(adjacent_farms: &[AdjacentFarms]) -> usize {
+        degrees(adjacent_farms).iter().sum::<usize>() / 2
+    }
+
+    #[test]
+    fn test_networks_are_undirected() {
+        let farms = network_farms(100);
+        let networks = vec![
+            ContactNetwork::Ring { neighbours: 2 },
+            ContactNetwork::ErdosRenyi {
+                edge_probability: Probability::new(0.05).unwrap(),
+            },
+            ContactNetwork::WattsStrogatz {
+                neighbours: 2,
+                rewiring_probability: Probability::new(0.2).unwrap(),
+            },
+            ContactNetwork::BarabasiAlbert { edges_per_farm: 2 },
+            ContactNetwork::StochasticBlock {
+                edge_probabilities: vec![
+                    vec![
+                        Probability::new(0.1).unwrap(),
+                        Probability::new(0.).unwrap(),
+                    ],
+                    vec![
+                        Probability::new(0.).unwrap(),
+                        Probability::new(0.1).unwrap(),
+                    ],
+                ],
+            },
+            ContactNetwork::RandomGeometric { radius: 1.5 },
+        ];
+        for network in networks {
+            let mut rng = ProcessRng::<ContactNetworkStream>::from_master_seed(
+                crate::scenario_rng::MasterSeed(20210709),
+            );
+            let adjacent_farms = network.generate(&farms, &mut rng).unwrap();
+            for (farm, adjacent) in farms.iter().zip(&adjacent_farms) {
+                for other in adjacent.0.iter() {
+                    assert_ne!(farm.farm_id, *other, "{:?}", network);
+                    assert!(
+                        adjacent_farms[other.0 - 1].0.contains(&farm.farm_id),
+                        "{:?}",
+                        network
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_network_structure() {
+        let farms = network_farms(1_000);
+        let mut rng = StdRng::seed_from_u64(20210709);
+
+        let ring = ContactNetwork::Ring { neighbours: 3 }
+            .generate(&farms, &mut rng)
+            .unwrap();
+        assert!(degrees(&ring).iter().all(|degree| *degree == 6));
+
+        let small_world = ContactNetwork::WattsStrogatz {
+            neighbours: 3,
+            rewiring_probability: Probability::new(0.3).unwrap(),
+        }
+        .generate(&farms, &mut rng)
+        .unwrap();
+        assert_eq!(total_edges(&small_world), total_edges(&ring));
+        assert_ne!(degrees(&small_world), degrees(&ring));
+
+        let random = ContactNetwork::ErdosRenyi {
+            edge_probability: Probability::new(0.01).unwrap(),
+        }
+        .generate(&farms, &mut rng)
+        .unwrap();
+        // expected no. of edges: 0.01 * 1000 * 999 / 2
+        assert!((4_600..5_400).contains(&total_edges(&random)));
+
+        let scale_free = ContactNetwork::BarabasiAlbert { edges_per_farm: 2 }
+            .generate(&farms, &mut rng)
+            .unwrap();
+        assert_eq!(total_edges(&scale_free), 3 + 2 * (1_000 - 3));
+        assert!(degrees(&scale_free).iter().all(|degree| *degree >= 2));
+        assert!(*degrees(&scale_free).iter().max().unwrap() > 20);
+
+        let blocks = ContactNetwork::StochasticBlock {
+            edge_probabilities: vec![
+                vec![
+                    Probability::new(0.01).unwrap(),
+                    Probability::new(0.).unwrap(),
+                ],
+                vec![
+                    Probability::new(0.).unwrap(),
+                    Probability::new(0.01).unwrap(),
+                ],
+            ],
+        }
+        .generate(&farms, &mut rng)
+        .unwrap();
+        for (farm, adjacent) in farms.iter().zip(&blocks) {
+            assert!(adjacent
+                .0
+                .iter()
+                .all(|other| other.0 % 2 == farm.farm_id.0 % 2));
+        }
+
+        let geometric = ContactNetwork::RandomGeometric { radius: 1. }
+            .generate(&farms, &mut rng)
+            .unwrap();
+        for (farm, adjacent) in farms.iter().zip(&geometric) {
+            let coordinates = farm.coordinates.unwrap();
+            let within_radius = farms
+                .iter()
+                .filter(|other| other.farm_id != farm.farm_id)
+                .filter(|other| coordinates.distance(&other.coordinates.unwrap()) <= 1.)
+                .count();
+            assert_eq!(adjacent.0.len(), within_radius);
+        }
+    }
+
+    #[test]
+    fn test_invalid_block_model() {
+        let farms = network_farms(10);
+        let mut rng = StdRng::seed_from_u64(20210709);
+        let asymmetric = ContactNetwork::StochasticBlock {
+            edge_probabilities: vec![
+                vec![
+                    Probability::new(0.1).unwrap(),
+                    Probability::new(0.2).unwrap(),
+                ],
+                vec![
+                    Probability::new(0.).unwrap(),
+                    Probability::new(0.1).unwrap(),
+                ],
+            ],
+        };
+        assert!(matches!(
+            asymmetric.generate(&farms, &mut rng),
+            Err(ContactNetworkError::InvalidBlockMatrix)
+        ));
+
+        let single_block = ContactNetwork::StochasticBlock {
+            edge_probabilities: vec![vec![Probability::new(0.1).unwrap()]],
+        };
+        assert!(matches!(
+            single_block.generate(&farms, &mut rng),
+            Err(ContactNetworkError::UnknownFarmType { farm_type: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_radius() {
+        let farms = network_farms(10);
+        let mut rng = StdRng::seed_from_u64(20210709);
+        for radius in [0., -1., f64::NAN] {
+            assert!(matches!(
+                ContactNetwork::RandomGeometric { radius }.generate(&farms, &mut rng),
+                Err(ContactNetworkError::InvalidRadius { .. })
+            ));
+        }
+    }
+}
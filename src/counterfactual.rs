@@ -0,0 +1,368 @@
+//! Counterfactual branching of a scenario.
+//!
+//! A baseline is run until a trigger (e.g. the first detection by
+//! [crate::regulator_active_surveillance::update_active_surveillance]), and
+//! then the [World] is forked into several [InterventionArm]s. Every arm
+//! starts from the identical state, i.e. the same history and the same
+//! state of the random streams, and is then run to completion.
+//!
+//! This gives a paired comparison of control strategies, with much lower
+//! variance than independent runs.
+//!
+//! The fork is facilitated through [WorldSnapshot], thus whatever is
+//! not part of the snapshot, has to be (re-)created by the world builder.
+//!
+//! Every run writes its outputs to its own [OutputDirectory] within the
+//! output directory of the scenario; the baseline up to the fork to `fork`,
+//! and each arm to `<no. of the arm>_<name of the arm>`. The outputs of an arm
+//! start with those of the baseline up to the fork.
+//!
+
+use std::path::PathBuf;
+
+use bevy::app::ManualEventReader;
+
+use crate::{
+    checkpoint::{restore_snapshot, take_snapshot, WorldSnapshot},
+    csv_outputs::OutputDirectory,
+    prelude::*,
+    regulator_active_surveillance::FarmDetected,
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::Infected,
+};
+
+/// A control strategy that is applied at the fork point.
+pub struct InterventionArm {
+    pub name: String,
+    intervention: Box<dyn Fn(&mut World, &mut SystemStage)>,
+}
+
+impl InterventionArm {
+    /// The intervention may change parameters (resources and components)
+    /// and add systems to the main loop of this arm.
+    pub fn new(
+        name: impl Into<String>,
+        intervention: impl Fn(&mut World, &mut SystemStage) + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            intervention: Box::new(intervention),
+        }
+    }
+
+    /// Continue the baseline without any changes.
+    pub fn baseline() -> Self {
+        Self::new("baseline", |_, _| {})
+    }
+}
+
+impl std::fmt::Debug for InterventionArm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterventionArm")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Final state of an arm.
+#[derive(Debug)]
+pub struct ArmOutcome {
+    pub name: String,
+    pub fork_tick: Time,
+    pub output_directory: OutputDirectory,
+    pub world: World,
+}
+
+/// Builders for the baseline and the arms of a counterfactual scenario.
+pub struct CounterfactualScenario {
+    /// Create a world with all parameters present, that writes its outputs
+    /// to the given directory. This is also used for the baseline, thus it
+    /// must seed the population and the infection.
+    build_world: Box<dyn Fn(OutputDirectory) -> World>,
+    /// Create the main loop; It must update the [ScenarioTime].
+    build_main_loop: Box<dyn Fn() -> SystemStage>,
+    /// Fail-safe for terminating the baseline and the arms.
+    max_timesteps: Time,
+    /// Directory of the outputs of all the runs.
+    output_directory: OutputDirectory,
+}
+
+impl std::fmt::Debug for CounterfactualScenario {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CounterfactualScenario")
+            .field("max_timesteps", &self.max_timesteps)
+            .field("output_directory", &self.output_directory)
+            .finish()
+    }
+}
+
+impl CounterfactualScenario {
+    /// The outputs are written to `outputs/counterfactual`, see
+    /// [CounterfactualScenario::with_output_directory].
+    pub fn new(
+        build_world: impl Fn(OutputDirectory) -> World + 'static,
+        build_main_loop: impl Fn() -> SystemStage + 'static,
+        max_timesteps: Time,
+    ) -> Self {
+        Self {
+            build_world: Box::new(build_world),
+            build_main_loop: Box::new(build_main_loop),
+            max_timesteps,
+            output_directory: OutputDirectory::new(
+                OutputDirectory::default().join("counterfactual"),
+            ),
+        }
+    }
+
+    pub fn with_output_directory(mut self, output_directory: impl Into<PathBuf>) -> Self {
+        self.output_directory = OutputDirectory::new(output_directory.into());
+        self
+    }
+
+    /// A world for the run `name`, that writes to its own output directory.
+    fn build_world(&self, name: &str) -> World {
+        let output_directory = OutputDirectory::new(self.output_directory.join(name));
+        let mut world = (self.build_world)(output_directory.clone());
+        world.insert_resource(output_directory);
+        world
+    }
+
+    /// Runs the baseline until `trigger` returns `true`, and returns the state
+    /// at that point. Returns `None` if the trigger never fired.
+    pub fn run_baseline_until(
+        &self,
+        mut trigger: impl FnMut(&mut World) -> bool,
+    ) -> Result<Option<WorldSnapshot>> {
+        let mut world = self.build_world("fork");
+        let mut main_loop = (self.build_main_loop)();
+
+        while current_time(&world) < self.max_timesteps {
+            main_loop.run(&mut world);
+            if trigger(&mut world) {
+                return take_snapshot(&mut world).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs each arm from the fork point until `is_finished` or the maximum
+    /// timesteps are reached.
+    pub fn run_arms(
+        &self,
+        fork: &WorldSnapshot,
+        arms: Vec<InterventionArm>,
+        is_finished: impl Fn(&mut World) -> bool,
+    ) -> Result<Vec<ArmOutcome>> {
+        arms.into_iter()
+            .enumerate()
+            .map(|(arm_no, arm)| {
+                let mut world = self.build_world(&format!("{}_{}", arm_no, arm.name));
+                // the outputs of the baseline are copied into those of the arm
+                restore_snapshot(&mut world, fork.clone())?;
+                let mut main_loop = (self.build_main_loop)();
+                (arm.intervention)(&mut world, &mut main_loop);
+
+                while current_time(&world) < self.max_timesteps && !is_finished(&mut world) {
+                    main_loop.run(&mut world);
+                }
+                info!(
+                    "Arm `{}` finished at tick {}",
+                    arm.name,
+                    current_time(&world)
+                );
+
+                Ok(ArmOutcome {
+                    name: arm.name,
+                    fork_tick: fork.scenario_time.current_time(),
+                    output_directory: world.get_resource::<OutputDirectory>().cloned().unwrap(),
+                    world,
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the baseline until `trigger`, and then all the arms from there.
+    /// Returns no outcomes if the trigger never fired.
+    pub fn run(
+        &self,
+        trigger: impl FnMut(&mut World) -> bool,
+        arms: Vec<InterventionArm>,
+        is_finished: impl Fn(&mut World) -> bool,
+    ) -> Result<Vec<ArmOutcome>> {
+        match self.run_baseline_until(trigger)? {
+            Some(fork) => self.run_arms(&fork, arms, is_finished),
+            None => {
+                warn!("The trigger for the counterfactual arms never fired.");
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+fn current_time(world: &World) -> Time {
+    world
+        .get_resource::<ScenarioTime>()
+        .expect("missing `ScenarioTime`")
+        .current_time()
+}
+
+/// Trigger on the first [FarmDetected]-event.
+///
+/// Note: This requires `Events<FarmDetected>` to be present.
+pub fn on_first_detection() -> impl FnMut(&mut World) -> bool {
+    let mut detections = ManualEventReader::<FarmDetected>::default();
+    move |world| {
+        let events = world
+            .get_resource::<Events<FarmDetected>>()
+            .expect("missing `Events<FarmDetected>`");
+        detections.iter(events).next().is_some()
+    }
+}
+
+/// Arms are finished when there are no more active infections.
+pub fn no_active_infections(world: &mut World) -> bool {
+    !world
+        .query::<&Infected>()
+        .iter(world)
+        .any(|infected| infected.0 > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cattle_farm_recorder::{record_cattle_farm_components, setup_cattle_farm_recorder},
+        parameters::{Probability, Rate},
+        populations::{AdjacentFarms, Cattle, FarmId, HerdSize},
+        regulator_active_surveillance::{
+            update_active_surveillance, DetectionRate, RemainingProportion,
+        },
+        scenario_rng::{insert_random_streams, MasterSeed},
+        scenario_time::scenario_intervals::update_scenario_tick,
+        sir_spread_model::{
+            update_disease_compartments, DiseaseParameters, Recovered, Susceptible,
+        },
+    };
+    use std::convert::TryFrom;
+
+    fn build_world(output_directory: OutputDirectory) -> World {
+        let mut world = World::new();
+        world.insert_resource(output_directory);
+        insert_random_streams(&mut world, MasterSeed(20210701));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(Events::<FarmDetected>::default());
+        world.insert_resource(DetectionRate::new(
+            Rate::try_from(Probability::new(0.001).unwrap()).unwrap(),
+        ));
+        world.insert_resource(RemainingProportion::new(Probability::new(0.1).unwrap()));
+        let _farms = world
+            .spawn_batch((1..=10).map(|farm_id| {
+                (
+                    Cattle,
+                    FarmId::<()>::new_single_population(farm_id),
+                    AdjacentFarms::<()>::new_single_population(Vec::new()),
+                    HerdSize::new_single_population(200),
+                    Susceptible(190),
+                    Infected(10),
+                    Recovered(0),
+                    DiseaseParameters::new(0.2, 0.05),
+                )
+            }))
+            .collect_vec();
+        SystemStage::single(setup_cattle_farm_recorder.system()).run(&mut world);
+        world
+    }
+
+    fn build_main_loop() -> SystemStage {
+        let mut main_loop = SystemStage::single_threaded();
+        main_loop
+            .add_system(update_scenario_tick.exclusive_system().at_start())
+            .add_system(update_disease_compartments.system().label("disease"))
+            .add_system(
+                update_active_surveillance
+                    .system()
+                    .label("surveillance")
+                    .after("disease"),
+            )
+            .add_system(record_cattle_farm_components.system().after("surveillance"))
+            .add_system(Events::<FarmDetected>::update_system.system());
+        main_loop
+    }
+
+    /// Compartments of each farm.
+    fn farm_states(world: &mut World) -> Vec<(usize, usize, usize, usize)> {
+        world
+            .query::<(&FarmId, &Susceptible, &Infected, &Recovered)>()
+            .iter(world)
+            .map(|(farm_id, s, i, r)| (farm_id.0, s.0, i.0, r.0))
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_identical_arms_are_paired() {
+        let output_directory = std::env::temp_dir().join("epi_bevy_test_counterfactual");
+        let scenario = CounterfactualScenario::new(build_world, build_main_loop, 500)
+            .with_output_directory(&output_directory);
+        let fork = scenario
+            .run_baseline_until(on_first_detection())
+            .unwrap()
+            .expect("no detection in the baseline");
+
+        // compare the arms at a fixed tick after the fork
+        let fork_tick = fork.scenario_time.current_time();
+        let mut outcomes = scenario
+            .run_arms(
+                &fork,
+                vec![
+                    InterventionArm::baseline(),
+                    InterventionArm::baseline(),
+                    InterventionArm::new("no surveillance", |world, _| {
+                        world.insert_resource(DetectionRate::new(Rate::new(0.).unwrap()));
+                    }),
+                ],
+                move |world| current_time(world) == fork_tick + 30,
+            )
+            .unwrap();
+
+        assert!(outcomes
+            .iter()
+            .all(|x| x.fork_tick == fork_tick && current_time(&x.world) == fork_tick + 30));
+        let states = outcomes
+            .iter_mut()
+            .map(|outcome| farm_states(&mut outcome.world))
+            .collect_vec();
+        // identical arms from identical states give identical outcomes
+        assert_eq!(states[0], states[1]);
+        assert_ne!(states[0], states[2], "the intervention had no effect");
+
+        // every arm has its own outputs, that start with those of the baseline
+        let baseline_output =
+            std::fs::read_to_string(output_directory.join("fork/cattle_farm_outputs.csv")).unwrap();
+        let outputs = outcomes
+            .iter_mut()
+            .map(|outcome| {
+                let mut csv_file = outcome
+                    .world
+                    .get_resource_mut::<crate::cattle_farm_recorder::CattleFarmsCSVRecorder>()
+                    .unwrap();
+                crate::checkpoint::CsvRecorder::writer_mut(&mut *csv_file)
+                    .flush()
+                    .unwrap();
+                std::fs::read_to_string(outcome.output_directory.join("cattle_farm_outputs.csv"))
+                    .unwrap()
+            })
+            .collect_vec();
+        assert_eq!(
+            outcomes[2].output_directory.0,
+            output_directory.join("2_no surveillance")
+        );
+        assert!(outputs
+            .iter()
+            .all(|output| output.starts_with(&baseline_output)
+                && output.len() > baseline_output.len()));
+        assert_eq!(outputs[0], outputs[1]);
+
+        std::fs::remove_dir_all(output_directory).unwrap();
+    }
+}
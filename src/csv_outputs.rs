@@ -0,0 +1,134 @@
+//! Output files of the csv-recorders.
+//!
+//! The recorders open their csv-file through [CsvOutputs], which starts a
+//! fresh file with the header of the recorder. If the scenario is resumed
+//! from a checkpoint (i.e. [ResumeFromCheckpoint] is present), an existing
+//! file is kept, as it is cut back to its length at the checkpoint when the
+//! checkpoint is restored, see `crate::checkpoint`.
+//!
+//! The outputs are written to the [OutputDirectory], e.g. a directory per arm
+//! of a counterfactual scenario.
+//!
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bevy::ecs::system::SystemParam;
+use csv::Writer;
+
+use crate::prelude::*;
+
+/// Directory that the csv-outputs are written to; `outputs` by default.
+#[derive(Debug, Clone, PartialEq, Eq, derive_new::new)]
+pub struct OutputDirectory(pub PathBuf);
+
+impl Default for OutputDirectory {
+    fn default() -> Self {
+        Self("outputs".into())
+    }
+}
+
+impl OutputDirectory {
+    /// Path of the output `file_name`.
+    pub fn join(&self, file_name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(file_name)
+    }
+}
+
+/// Resume the scenario from a checkpoint, see
+/// `crate::checkpoint::resume_from_checkpoint`.
+#[derive(Debug, Clone, derive_new::new)]
+pub struct ResumeFromCheckpoint(pub PathBuf);
+
+/// Opens the outputs of the csv-recorders, see [CsvOutputs::create].
+#[derive(SystemParam)]
+pub struct CsvOutputs<'a> {
+    output_directory: Option<Res<'a, OutputDirectory>>,
+    resume_from_checkpoint: Option<Res<'a, ResumeFromCheckpoint>>,
+}
+
+impl CsvOutputs<'_> {
+    /// Opens the output `file_name` for a recorder with the given header.
+    ///
+    /// Panics if the output cannot be opened.
+    pub fn create(&self, file_name: &str, header: &[&str]) -> Writer<File> {
+        let path = self
+            .output_directory
+            .as_deref()
+            .cloned()
+            .unwrap_or_default()
+            .join(file_name);
+        open_csv_output(&path, header, self.resume_from_checkpoint.is_some())
+            .unwrap_or_else(|err| panic!("failed to open {}: {:?}", path.display(), err))
+    }
+}
+
+/// Opens the csv-file at `path`, and writes the `header` if the file is
+/// empty.
+///
+/// An existing file is truncated, unless `keep_existing` is set, in which
+/// case the writer continues from the end of the file.
+pub fn open_csv_output(path: &Path, header: &[&str], keep_existing: bool) -> Result<Writer<File>> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if !keep_existing {
+        file.set_len(0)?;
+    }
+    let is_empty = file.seek(SeekFrom::End(0))? == 0;
+
+    let mut csv_writer = csv_writer(file);
+    if is_empty {
+        csv_writer.write_record(header)?;
+    }
+    Ok(csv_writer)
+}
+
+/// The csv-format shared by all the recorders.
+pub fn csv_writer(file: File) -> Writer<File> {
+    //TODO: determine an appropriate buffer capacity
+    let buffer_capacity_in_bytes = 100_000_000; // 100 mb.
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .buffer_capacity(buffer_capacity_in_bytes)
+        .flexible(false)
+        .delimiter(b';')
+        .from_writer(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumed_output_is_kept() {
+        let directory = std::env::temp_dir().join("epi_bevy_test_resumed_output_is_kept");
+        let path = directory.join("output.csv");
+
+        let mut csv_writer = open_csv_output(&path, &["a", "b"], false).unwrap();
+        csv_writer.write_record(&["1", "2"]).unwrap();
+        csv_writer.flush().unwrap();
+        drop(csv_writer);
+
+        let mut csv_writer = open_csv_output(&path, &["a", "b"], true).unwrap();
+        csv_writer.write_record(&["3", "4"]).unwrap();
+        csv_writer.flush().unwrap();
+        drop(csv_writer);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a;b\n1;2\n3;4\n");
+
+        let csv_writer = open_csv_output(&path, &["a", "b"], false).unwrap();
+        drop(csv_writer);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a;b\n");
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
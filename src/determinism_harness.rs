@@ -0,0 +1,382 @@
+//! Regression harness for the determinism of the simulation.
+//!
+//! A small reference scenario is run with a fixed [MasterSeed] on the ring
+//! population, i.e. `assets/population_info.json` and `assets/ring_adjacency.json`.
+//! The outputs of the csv-recorders are hashed and compared to the golden
+//! files in [GOLDEN_DIRECTORY], which are checked in.
+//!
+//! If a change in the code alters the results, [check_against_golden] reports
+//! the first tick and farm where the outputs diverge.
+//!
+//! Set the environment variable [REGENERATE_GOLDEN] to write new golden files,
+//! when a change in results is intended. Missing golden files are an error.
+//!
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    between_herd_spread_model::{
+        setup_between_herd_spread_model, update_between_herd_spread_model, ContactRate,
+    },
+    between_herd_spread_model_record::{
+        record_between_herd_infection_events, setup_between_herd_infection_events_recording,
+        BetweenHerdInfectionEventsRecorder,
+    },
+    cattle_farm_recorder::{
+        record_cattle_farm_components, setup_cattle_farm_recorder, CattleFarmsCSVRecorder,
+    },
+    cattle_population::load_ring_population,
+    checkpoint::CsvRecorder,
+    csv_outputs::OutputDirectory,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    parameters::Rate,
+    populations::FarmId,
+    prelude::*,
+    scenario_rng::{insert_random_streams, MasterSeed},
+    scenario_time::{
+        scenario_intervals::update_scenario_tick,
+        scenario_timer::{ScenarioTime, Time},
+    },
+    sir_spread_model::{
+        seed_infection_random, update_disease_compartments, DiseaseCompartments, DiseaseParameters,
+        Infected,
+    },
+};
+
+/// Directory of the checked-in golden files.
+pub const GOLDEN_DIRECTORY: &str = "assets/golden";
+
+/// Environment variable that, when set, regenerates the golden files.
+pub const REGENERATE_GOLDEN: &str = "EPI_BEVY_REGENERATE_GOLDEN";
+
+/// Parameters of the reference scenario.
+#[derive(Debug, Clone)]
+pub struct ReferenceScenario {
+    pub master_seed: MasterSeed,
+    pub max_timesteps: Time,
+    pub disease_parameters: DiseaseParameters,
+    pub contact_rate: ContactRate,
+}
+
+impl Default for ReferenceScenario {
+    fn default() -> Self {
+        Self {
+            master_seed: MasterSeed(20210426),
+            max_timesteps: 60,
+            disease_parameters: DiseaseParameters::new(0.3, 0.1),
+            contact_rate: ContactRate::new(Rate::new(0.095).unwrap()),
+        }
+    }
+}
+
+/// Outputs of the csv-recorders in the reference scenario, by their name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceOutputs(pub BTreeMap<String, String>);
+
+impl ReferenceOutputs {
+    /// Stable hash of each output, see [fnv1a_hash].
+    pub fn hashes(&self) -> BTreeMap<String, String> {
+        self.0
+            .iter()
+            .map(|(name, output)| {
+                (
+                    name.clone(),
+                    format!("{:016x}", fnv1a_hash(output.as_bytes())),
+                )
+            })
+            .collect()
+    }
+}
+
+/// 64-bit FNV-1a hash, which unlike [std::collections::hash_map::DefaultHasher]
+/// is guaranteed to be the same across platforms and toolchains.
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Runs the reference scenario until there are no active infections, or
+/// [ReferenceScenario::max_timesteps] is reached.
+///
+/// The csv-recorders write their outputs to `output_directory`, and these are
+/// then read back in.
+pub fn run_reference_scenario(
+    reference: &ReferenceScenario,
+    output_directory: impl Into<PathBuf>,
+) -> Result<ReferenceOutputs> {
+    let output_directory = OutputDirectory::new(output_directory.into());
+    let mut world = World::new();
+    insert_random_streams(&mut world, reference.master_seed);
+    world.insert_resource(ScenarioTime::new(1, None));
+    world.insert_resource(reference.contact_rate);
+    world.insert_resource(output_directory.clone());
+
+    let farm_id_to_entity_map: HashMap<FarmId, Entity> = load_ring_population()
+        .map(|bundle| {
+            let farm_id = bundle.farm_id;
+            let herd_size = bundle.herd_size;
+            let mut farm_entity = world.spawn();
+            farm_entity
+                .insert_bundle(bundle)
+                .insert_bundle(DiseaseCompartments::new(herd_size.0))
+                .insert(reference.disease_parameters);
+            (farm_id, farm_entity.id())
+        })
+        .collect();
+    world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+
+    let mut seed = SystemStage::single_threaded();
+    seed.add_system(setup_between_herd_spread_model.system())
+        .add_system(seed_infection_random.system())
+        .add_system(setup_cattle_farm_recorder.system())
+        .add_system(setup_between_herd_infection_events_recording.system());
+    seed.run(&mut world);
+
+    let mut main_loop = SystemStage::single_threaded();
+    main_loop
+        .add_system(update_scenario_tick.exclusive_system().at_start())
+        .add_system(
+            update_disease_compartments
+                .system()
+                .chain(
+                    update_between_herd_spread_model
+                        .system()
+                        .chain(record_between_herd_infection_events.system()),
+                )
+                .label("disease"),
+        )
+        .add_system(record_cattle_farm_components.system().after("disease"));
+
+    loop {
+        main_loop.run(&mut world);
+        let current_time = world.get_resource::<ScenarioTime>().unwrap().current_time();
+        let any_active_infection = world.query::<&Infected>().iter(&world).any(|x| x.0 > 0);
+        if !any_active_infection || current_time >= reference.max_timesteps {
+            break;
+        }
+    }
+
+    Ok(ReferenceOutputs(
+        vec![
+            read_output::<CattleFarmsCSVRecorder>(&mut world, &output_directory)?,
+            read_output::<BetweenHerdInfectionEventsRecorder>(&mut world, &output_directory)?,
+        ]
+        .into_iter()
+        .collect(),
+    ))
+}
+
+/// Flushes the recorder, and reads its output; The output is named after its
+/// csv-file, i.e. without the extension.
+fn read_output<R: CsvRecorder>(
+    world: &mut World,
+    output_directory: &OutputDirectory,
+) -> Result<(String, String)> {
+    world
+        .get_resource_mut::<R>()
+        .context("missing recorder")?
+        .writer_mut()
+        .flush()?;
+    let path = output_directory.join(R::FILE_NAME);
+    let output = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok((R::FILE_NAME.trim_end_matches(".csv").to_string(), output))
+}
+
+/// The first row where an output differs from its golden file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub output: String,
+    /// Line no. in the csv-file, starting from 1 with the header.
+    pub line: usize,
+    pub scenario_tick: Option<Time>,
+    /// Farm of the row; For infection events this is the origin farm.
+    pub farm_id: Option<usize>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` diverges at line {}", self.output, self.line)?;
+        if let Some(scenario_tick) = self.scenario_tick {
+            write!(f, ", tick {}", scenario_tick)?;
+        }
+        if let Some(farm_id) = self.farm_id {
+            write!(f, ", farm {}", farm_id)?;
+        }
+        write!(
+            f,
+            "\n  expected: {}\n  actual:   {}",
+            self.expected.as_deref().unwrap_or("<end of output>"),
+            self.actual.as_deref().unwrap_or("<end of output>")
+        )
+    }
+}
+
+/// First row that differs between `expected` and `actual` output.
+pub fn first_divergence(output: &str, expected: &str, actual: &str) -> Option<Divergence> {
+    let mut expected_rows = expected.lines();
+    let mut actual_rows = actual.lines();
+    let mut line = 0;
+    loop {
+        line += 1;
+        match (expected_rows.next(), actual_rows.next()) {
+            (None, None) => return None,
+            (expected_row, actual_row) if expected_row == actual_row => continue,
+            (expected_row, actual_row) => {
+                // the tick is the first column in either output, followed by
+                // the farm id, or the batch id, route and then the origin farm id.
+                let columns = actual_row
+                    .or(expected_row)
+                    .unwrap()
+                    .split(';')
+                    .collect_vec();
+                let farm_column = if output == "cattle_farm_outputs" {
+                    1
+                } else {
+                    3
+                };
+                return Some(Divergence {
+                    output: output.to_string(),
+                    line,
+                    scenario_tick: columns.get(0).and_then(|x| x.parse().ok()),
+                    farm_id: columns.get(farm_column).and_then(|x| x.parse().ok()),
+                    expected: expected_row.map(str::to_string),
+                    actual: actual_row.map(str::to_string),
+                });
+            }
+        }
+    }
+}
+
+/// Compares the outputs to the golden files in `golden_directory`.
+///
+/// The golden files are (re-)written if [REGENERATE_GOLDEN] is set, and
+/// missing golden files are an error otherwise.
+pub fn check_against_golden(
+    outputs: &ReferenceOutputs,
+    golden_directory: impl AsRef<Path>,
+) -> Result<Vec<Divergence>> {
+    let golden_directory = golden_directory.as_ref();
+    let hashes_path = golden_directory.join("hashes.json");
+    let regenerate = std::env::var_os(REGENERATE_GOLDEN).is_some();
+
+    if regenerate {
+        write_golden(outputs, golden_directory)?;
+        warn!("Wrote golden files to {}", golden_directory.display());
+        return Ok(Vec::new());
+    }
+    if !hashes_path.exists() {
+        bail!(
+            "missing golden hashes {}; set `{}` to write them",
+            hashes_path.display(),
+            REGENERATE_GOLDEN
+        );
+    }
+
+    let golden_hashes: BTreeMap<String, String> =
+        serde_json::from_reader(std::fs::File::open(&hashes_path)?)
+            .with_context(|| format!("invalid golden hashes {}", hashes_path.display()))?;
+    let hashes = outputs.hashes();
+
+    let mut divergences = Vec::new();
+    for (output, contents) in &outputs.0 {
+        if golden_hashes.get(output) == hashes.get(output) {
+            continue;
+        }
+        let golden_path = golden_output_path(golden_directory, output);
+        let golden = std::fs::read_to_string(&golden_path)
+            .with_context(|| format!("missing golden file {}", golden_path.display()))?;
+        // identical rows but different hashes means the golden file was edited
+        divergences.push(
+            first_divergence(output, &golden, contents).unwrap_or_else(|| Divergence {
+                output: output.clone(),
+                line: 0,
+                scenario_tick: None,
+                farm_id: None,
+                expected: golden_hashes.get(output).cloned(),
+                actual: hashes.get(output).cloned(),
+            }),
+        );
+    }
+    Ok(divergences)
+}
+
+fn golden_output_path(golden_directory: &Path, output: &str) -> PathBuf {
+    golden_directory.join(format!("{}.csv", output))
+}
+
+fn write_golden(outputs: &ReferenceOutputs, golden_directory: &Path) -> Result<()> {
+    std::fs::create_dir_all(golden_directory)?;
+    for (output, contents) in &outputs.0 {
+        std::fs::write(golden_output_path(golden_directory, output), contents)?;
+    }
+    let hashes = serde_json::to_string_pretty(&outputs.hashes())?;
+    std::fs::write(golden_directory.join("hashes.json"), hashes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_scenario_is_reproducible() {
+        let reference = ReferenceScenario {
+            max_timesteps: 30,
+            ..Default::default()
+        };
+        let output_directory = std::env::temp_dir().join("epi_bevy_test_reference_scenario");
+        let outputs = run_reference_scenario(&reference, output_directory.join("run")).unwrap();
+        let rerun_outputs =
+            run_reference_scenario(&reference, output_directory.join("rerun")).unwrap();
+        assert_eq!(outputs.hashes(), rerun_outputs.hashes());
+        std::fs::remove_dir_all(output_directory).unwrap();
+    }
+
+    #[test]
+    fn test_reference_scenario_matches_golden() {
+        let output_directory = std::env::temp_dir().join("epi_bevy_test_reference_golden");
+        let outputs =
+            run_reference_scenario(&ReferenceScenario::default(), &output_directory).unwrap();
+        std::fs::remove_dir_all(output_directory).unwrap();
+        let divergences = check_against_golden(&outputs, GOLDEN_DIRECTORY).unwrap();
+        assert!(
+            divergences.is_empty(),
+            "results changed; set `{}` if this is intended.\n{}",
+            REGENERATE_GOLDEN,
+            divergences.iter().join("\n")
+        );
+    }
+
+    #[test]
+    fn test_missing_golden_fails() {
+        let outputs = ReferenceOutputs(
+            vec![("output".to_string(), "a;b\n".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let golden_directory = std::env::temp_dir().join("epi_bevy_test_missing_golden");
+        if std::env::var_os(REGENERATE_GOLDEN).is_none() {
+            assert!(check_against_golden(&outputs, &golden_directory).is_err());
+        }
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let expected = "scenario_time;farm_id\n1;1\n1;2\n2;1\n";
+        let actual = "scenario_time;farm_id\n1;1\n1;3\n2;1\n";
+        let divergence = first_divergence("cattle_farm_outputs", expected, actual).unwrap();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.scenario_tick, Some(1));
+        assert_eq!(divergence.farm_id, Some(3));
+        assert!(first_divergence("cattle_farm_outputs", expected, expected).is_none());
+    }
+}
@@ -0,0 +1,384 @@
+//! Diagnostic tests with imperfect sensitivity and specificity.
+//!
+//! A [DiagnosticTest] describes the performance of a test on a single animal
+//! (or a single pooled sample). A [HerdTest] applies it to a farm according
+//! to a [HerdTestDesign]:
+//!
+//! * [HerdTestDesign::Sample] tests a number of animals, drawn from the
+//!   disease compartments without replacement,
+//! * [HerdTestDesign::Pooled] tests the sampled animals in pools, where a pool
+//!   with at least one infected animal is positive with the sensitivity,
+//! * [HerdTestDesign::BulkMilk] tests the herd as a whole, and only picks up
+//!   the infection above a minimum within-herd prevalence.
+//!
+//! The farm is positive if at least [HerdTest::cutoff] tests are positive,
+//! thus a herd test may be falsely positive. Recovered animals count as not
+//! infected.
+//!
+//! The [SurveillanceTesting]-programme tests farms at random and puts out
+//! the results as [HerdTested]-events, along with the cost of the tests. If
+//! present, it also decides the detections of
+//! [crate::regulator_active_surveillance] and the observed prevalence of
+//! [crate::regulator_passive_surveillance].
+//!
+
+use std::fs::File;
+
+use bevy::ecs::schedule::ShouldRun;
+use csv::Writer;
+use rand_distr::{Binomial, Hypergeometric};
+
+use crate::{
+    csv_outputs::CsvOutputs,
+    parameters::Probability,
+    populations::{Cattle, FarmId},
+    prelude::*,
+    regulator_capacity::{ControlActivity, ControlCapacity},
+    regulatory_status::{FarmStatus, RegulatoryStatus},
+    scenario_rng::{DiagnosticTestingStream, ProcessRng},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+/// Performance of a test on a single sample.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct DiagnosticTest {
+    /// Probability of a positive result on an infected sample.
+    pub sensitivity: Probability,
+    /// Probability of a negative result on a sample that isn't infected.
+    pub specificity: Probability,
+    /// Cost of a single test.
+    pub cost: f64,
+}
+
+impl DiagnosticTest {
+    /// Number of positive results among `infected` infected samples and
+    /// `not_infected` samples that aren't.
+    pub fn positives<R: Rng + ?Sized>(
+        &self,
+        infected: usize,
+        not_infected: usize,
+        rng: &mut R,
+    ) -> usize {
+        let true_positives = Binomial::new(infected as u64, self.sensitivity.0)
+            .unwrap()
+            .sample(rng);
+        let false_positives = Binomial::new(not_infected as u64, self.specificity.complement().0)
+            .unwrap()
+            .sample(rng);
+        (true_positives + false_positives) as usize
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy)]
+pub enum HerdTestDesign {
+    /// Test `sample_size` animals individually; all animals are tested in
+    /// smaller herds.
+    Sample { sample_size: usize },
+    /// Sample `sample_size` animals, and test them in pools of `pool_size`.
+    Pooled {
+        sample_size: usize,
+        pool_size: usize,
+    },
+    /// A single test of the whole herd, that is insensitive below
+    /// `minimum_prevalence`.
+    BulkMilk { minimum_prevalence: f64 },
+}
+
+/// Outcome of testing a single farm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HerdTestResult {
+    pub positive: bool,
+    pub tests_used: usize,
+    pub cost: f64,
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct HerdTest {
+    pub test: DiagnosticTest,
+    pub design: HerdTestDesign,
+    /// Least number of positive tests for the herd to be positive.
+    #[new(value = "1")]
+    pub cutoff: usize,
+}
+
+impl HerdTest {
+    #[must_use]
+    pub fn with_cutoff(mut self, cutoff: usize) -> Self {
+        self.cutoff = cutoff;
+        self
+    }
+
+    pub fn apply<R: Rng + ?Sized>(
+        &self,
+        susceptible: usize,
+        infected: usize,
+        recovered: usize,
+        rng: &mut R,
+    ) -> HerdTestResult {
+        let herd_size = susceptible + infected + recovered;
+        let (positives, tests_used) = match self.design {
+            HerdTestDesign::Sample { sample_size } => {
+                let sample_size = sample_size.min(herd_size);
+                let sampled_infected = sample_infected(herd_size, infected, sample_size, rng);
+                (
+                    self.test
+                        .positives(sampled_infected, sample_size - sampled_infected, rng),
+                    sample_size,
+                )
+            }
+            HerdTestDesign::Pooled {
+                sample_size,
+                pool_size,
+            } => {
+                let sample_size = sample_size.min(herd_size);
+                let sampled_infected = sample_infected(herd_size, infected, sample_size, rng);
+                let mut sample = vec![false; sample_size];
+                sample[..sampled_infected].fill(true);
+                sample.shuffle(rng);
+                let pools = sample.chunks(pool_size.max(1)).collect_vec();
+                let infected_pools = pools.iter().filter(|pool| pool.contains(&true)).count();
+                (
+                    self.test
+                        .positives(infected_pools, pools.len() - infected_pools, rng),
+                    pools.len(),
+                )
+            }
+            HerdTestDesign::BulkMilk { minimum_prevalence } => {
+                let is_detectable =
+                    herd_size > 0 && infected as f64 / herd_size as f64 >= minimum_prevalence;
+                let positives = if is_detectable {
+                    self.test.positives(1, 0, rng)
+                } else {
+                    self.test.positives(0, 1, rng)
+                };
+                (positives, 1)
+            }
+        };
+        HerdTestResult {
+            positive: tests_used > 0 && positives >= self.cutoff,
+            tests_used,
+            cost: tests_used as f64 * self.test.cost,
+        }
+    }
+}
+
+/// Number of infected animals in a sample drawn without replacement.
+fn sample_infected<R: Rng + ?Sized>(
+    herd_size: usize,
+    infected: usize,
+    sample_size: usize,
+    rng: &mut R,
+) -> usize {
+    if sample_size == 0 {
+        return 0;
+    }
+    Hypergeometric::new(herd_size as u64, infected as u64, sample_size as u64)
+        .unwrap()
+        .sample(rng) as usize
+}
+
+/// Add as a resource to test farms at random with the [HerdTest].
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct SurveillanceTesting {
+    pub herd_test: HerdTest,
+    /// Probability of a farm being tested per day.
+    pub testing_probability: Probability,
+}
+
+/// Event that is sent whenever a farm has been tested.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HerdTested {
+    pub farm_id: FarmId,
+    pub scenario_tick: Time,
+    pub result: HerdTestResult,
+    /// Whether the farm was actually infected.
+    pub is_infected: bool,
+}
+
+/// Run criteria for [update_surveillance_testing].
+pub fn run_if_surveillance_testing(
+    surveillance_testing: Option<Res<SurveillanceTesting>>,
+) -> ShouldRun {
+    if surveillance_testing.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Farms that are already confirmed or culled are not tested, and neither are
+/// the sampled farms beyond the [ControlCapacity] of the day.
+pub fn update_surveillance_testing(
+    surveillance_testing: Res<SurveillanceTesting>,
+    query: Query<
+        (
+            &FarmId,
+            &Susceptible,
+            &Infected,
+            &Recovered,
+            Option<&RegulatoryStatus>,
+        ),
+        With<Cattle>,
+    >,
+    mut rng: ResMut<ProcessRng<DiagnosticTestingStream>>,
+    scenario_time: Res<ScenarioTime>,
+    mut herd_tests: EventWriter<HerdTested>,
+    mut control_capacity: Option<ResMut<ControlCapacity>>,
+) {
+    let testing_probability = surveillance_testing.testing_probability.0;
+    for (farm_id, susceptible, infected, recovered, status) in
+        query.iter().sorted_by_key(|(farm_id, ..)| **farm_id)
+    {
+        let is_known = status.map_or(false, |status| {
+            matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+        });
+        if is_known || !rng.gen_bool(testing_probability) {
+            continue;
+        }
+        if !control_capacity.as_mut().map_or(true, |capacity| {
+            capacity.try_consume(
+                ControlActivity::Test,
+                *farm_id,
+                scenario_time.current_time(),
+            )
+        }) {
+            continue;
+        }
+        let result =
+            surveillance_testing
+                .herd_test
+                .apply(susceptible.0, infected.0, recovered.0, &mut *rng);
+        herd_tests.send(HerdTested {
+            farm_id: *farm_id,
+            scenario_tick: scenario_time.current_time(),
+            result,
+            is_infected: infected.0 > 0,
+        });
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct HerdTestsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for HerdTestsRecorder {
+    const FILE_NAME: &'static str = "herd_tests.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_herd_tests].
+pub fn setup_herd_tests_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "herd_tests.csv",
+        &[
+            "scenario_tick",
+            "farm_id",
+            "positive",
+            "is_infected",
+            "tests_used",
+            "cost",
+        ],
+    );
+    commands.insert_resource(HerdTestsRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_herd_tests_recording]
+pub fn record_herd_tests(
+    mut herd_tests: EventReader<HerdTested>,
+    mut csv_file: ResMut<HerdTestsRecorder>,
+) {
+    for herd_tested in herd_tests.iter() {
+        csv_file
+            .0
+            .serialize((
+                herd_tested.scenario_tick,
+                herd_tested.farm_id.0,
+                herd_tested.result.positive,
+                herd_tested.is_infected,
+                herd_tested.result.tests_used,
+                herd_tested.result.cost,
+            ))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario_rng::MasterSeed;
+
+    fn perfect_test() -> DiagnosticTest {
+        DiagnosticTest::new(
+            Probability::new(1.).unwrap(),
+            Probability::new(1.).unwrap(),
+            2.,
+        )
+    }
+
+    #[test]
+    fn test_herd_test_designs() {
+        let mut rng = ProcessRng::<DiagnosticTestingStream>::from_master_seed(MasterSeed(20210715));
+
+        let sample = HerdTest::new(perfect_test(), HerdTestDesign::Sample { sample_size: 30 });
+        // small herds are tested in full
+        assert_eq!(
+            sample.apply(9, 1, 0, &mut rng),
+            HerdTestResult {
+                positive: true,
+                tests_used: 10,
+                cost: 20.,
+            }
+        );
+        assert!(!sample.apply(1_000, 0, 10, &mut rng).positive);
+        assert!(!sample.with_cutoff(2).apply(9, 1, 0, &mut rng).positive);
+
+        let pooled = HerdTest::new(
+            perfect_test(),
+            HerdTestDesign::Pooled {
+                sample_size: 30,
+                pool_size: 4,
+            },
+        );
+        let result = pooled.apply(0, 100, 0, &mut rng);
+        assert!(result.positive);
+        assert_eq!(result.tests_used, 8);
+
+        let bulk_milk = HerdTest::new(
+            perfect_test(),
+            HerdTestDesign::BulkMilk {
+                minimum_prevalence: 0.05,
+            },
+        );
+        assert!(!bulk_milk.apply(99, 1, 0, &mut rng).positive);
+        assert!(bulk_milk.apply(90, 10, 0, &mut rng).positive);
+    }
+
+    #[test]
+    fn test_false_positives() {
+        let mut rng = ProcessRng::<DiagnosticTestingStream>::from_master_seed(MasterSeed(20210716));
+        let test = DiagnosticTest::new(
+            Probability::new(0.9).unwrap(),
+            Probability::new(0.95).unwrap(),
+            1.,
+        );
+        let herd_test = HerdTest::new(test, HerdTestDesign::Sample { sample_size: 20 });
+        let false_positives = (0..1_000)
+            .filter(|_| herd_test.apply(100, 0, 0, &mut rng).positive)
+            .count();
+        // 1 - 0.95^20 ≈ 0.64
+        assert!(
+            (550..=730).contains(&false_positives),
+            "{}",
+            false_positives
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Indirect contacts between farms, i.e. visits by vets, milk tankers, feed
+//! trucks and contractors.
+//!
+//! This is a between-herd route besides the shipments of animals, see
+//! [crate::between_herd_spread_model]. Every [VisitorKind] visits each farm
+//! a Poisson-distributed number of times per day, where the mean is the
+//! visit rate of the farm's [FarmType], reduced by its [Biosecurity]. The
+//! visits of a day are then shuffled into rounds of
+//! [VisitorKind::farms_per_round] farms, e.g. a truck that visits the farms
+//! A → B → C.
+//!
+//! Along a round the visitor is contaminated at an infected farm, and then
+//! infects a susceptible animal at the following farms, each with
+//! [VisitorKind::transmission_probability]. A farm infected on the day
+//! doesn't contaminate the visitors until the next day.
+//!
+//! The events are put out with [InfectionRoute::IndirectContact].
+//!
+
+use std::collections::{BTreeMap, HashMap};
+
+use rand_distr::Poisson;
+
+use crate::{
+    between_herd_spread_model::{InfectionEvents, InfectionEventsBatchId, InfectionRoute},
+    farm_id_to_entity_map::FarmIdEntityMap,
+    parameters::{Probability, Rate},
+    populations::{Cattle, FarmId, FarmType},
+    prelude::*,
+    regulator_biosecurity::Biosecurity,
+    scenario_rng::{IndirectContactStream, ProcessRng},
+    scenario_time::scenario_timer::ScenarioTime,
+    sir_spread_model::{Infected, Susceptible},
+};
+
+/// A kind of visitor, e.g. milk tankers.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct VisitorKind {
+    pub name: String,
+    /// Mean number of visits per farm per day.
+    pub visit_rate: Rate,
+    /// Visit rates of specific farm types, that take precedence over
+    /// `visit_rate`, e.g. milk tankers only visit dairy farms.
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub visit_rates_by_farm_type: HashMap<FarmType, Rate>,
+    /// Probability that a visit picks up the infection at an infected farm,
+    /// and likewise that it passes it on to a susceptible one.
+    pub transmission_probability: Probability,
+    /// Number of farms visited in a row, where `1` means no sequencing.
+    pub farms_per_round: usize,
+}
+
+impl VisitorKind {
+    pub fn new(
+        name: impl Into<String>,
+        visit_rate: Rate,
+        transmission_probability: Probability,
+        farms_per_round: usize,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            visit_rate,
+            visit_rates_by_farm_type: HashMap::new(),
+            transmission_probability,
+            farms_per_round,
+        }
+    }
+
+    /// Set the visit rate of farms of `farm_type`.
+    #[must_use]
+    pub fn with_farm_type_rate(mut self, farm_type: FarmType, visit_rate: Rate) -> Self {
+        self.visit_rates_by_farm_type.insert(farm_type, visit_rate);
+        self
+    }
+
+    pub fn visit_rate(&self, farm_type: Option<&FarmType>) -> Rate {
+        farm_type
+            .and_then(|farm_type| self.visit_rates_by_farm_type.get(farm_type))
+            .copied()
+            .unwrap_or(self.visit_rate)
+    }
+}
+
+/// Add as a resource to enable indirect contacts.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, derive_more::From)]
+pub struct IndirectContacts(pub Vec<VisitorKind>);
+
+/// Components necessary to schedule the visits.
+type VisitedFarms = (
+    &'static FarmId,
+    Option<&'static FarmType>,
+    Option<&'static Biosecurity>,
+    &'static Infected,
+);
+
+pub fn update_indirect_contacts(
+    indirect_contacts: Res<IndirectContacts>,
+    mut current_batch_id: ResMut<InfectionEventsBatchId>,
+    mut query: QuerySet<(
+        Query<'_, VisitedFarms, With<Cattle>>,
+        Query<'_, (&mut Susceptible, &mut Infected), With<Cattle>>,
+    )>,
+    mut rng: ResMut<ProcessRng<IndirectContactStream>>,
+    scenario_tick: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+) -> Option<InfectionEvents> {
+    // farms that are infectious at the start of the day
+    let farms = query
+        .q0()
+        .iter()
+        .map(|(farm_id, farm_type, biosecurity, infected)| {
+            (
+                *farm_id,
+                (farm_type.copied(), biosecurity.copied(), infected.0 > 0),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let mut new_infection_events: Vec<(FarmId, FarmId, usize)> = Vec::new();
+    for visitor_kind in indirect_contacts.0.iter() {
+        let mut visits = Vec::new();
+        for (farm_id, (farm_type, biosecurity, _)) in farms.iter() {
+            let visit_rate = (visitor_kind.visit_rate(farm_type.as_ref()).0)
+                * biosecurity.map_or(1., |x| (x.0).complement().0);
+            if visit_rate <= 0. {
+                continue;
+            }
+            let total_visits = Poisson::new(visit_rate).unwrap().sample(&mut *rng) as usize;
+            visits.extend(std::iter::repeat(*farm_id).take(total_visits));
+        }
+        visits.shuffle(&mut *rng);
+
+        let transmission_probability = visitor_kind.transmission_probability.0;
+        for round in visits.chunks(visitor_kind.farms_per_round.max(1)) {
+            let mut contaminated_by: Option<FarmId> = None;
+            for farm_id in round {
+                if let Some(origin) = contaminated_by.filter(|origin| origin != farm_id) {
+                    if rng.gen_bool(transmission_probability) {
+                        let (mut susceptible, mut infected) = query
+                            .q1_mut()
+                            .get_mut(farm_map.0[farm_id])
+                            .expect("failed to find visited farm");
+                        if susceptible.0 > 0 {
+                            susceptible.0 -= 1;
+                            infected.0 += 1;
+                            new_infection_events.push((origin, *farm_id, 1));
+                        }
+                    }
+                }
+                let (.., is_infectious) = farms[farm_id];
+                if is_infectious && rng.gen_bool(transmission_probability) {
+                    contaminated_by = Some(*farm_id);
+                }
+            }
+        }
+    }
+
+    if new_infection_events.is_empty() {
+        return None;
+    }
+    current_batch_id.0 += 1;
+    trace!(
+        "Total new indirect contact infections: {}",
+        new_infection_events.len()
+    );
+    Some(InfectionEvents {
+        scenario_tick: scenario_tick.current_time(),
+        batch_id: current_batch_id.0,
+        route: InfectionRoute::IndirectContact,
+        events_values: new_infection_events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        populations::HerdSize,
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::Recovered,
+    };
+
+    fn example_world() -> World {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210713));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId::default());
+        world.insert_resource(IndirectContacts(vec![VisitorKind::new(
+            "milk tanker",
+            Rate::new(0.).unwrap(),
+            Probability::new(1.).unwrap(),
+            4,
+        )
+        .with_farm_type_rate(FarmType(1), Rate::new(5.).unwrap())]));
+
+        let farm_id_to_entity_map: HashMap<FarmId, Entity> = [(1, 1), (2, 1), (3, 1), (4, 0)]
+            .iter()
+            .map(|&(farm_id, farm_type)| {
+                let farm_id: FarmId = FarmId::new_single_population(farm_id);
+                let infected = if farm_id.0 == 1 { 10 } else { 0 };
+                let entity = world
+                    .spawn()
+                    .insert_bundle((
+                        Cattle,
+                        farm_id,
+                        FarmType(farm_type),
+                        HerdSize::new_single_population(100),
+                        Susceptible(100 - infected),
+                        Infected(infected),
+                        Recovered(0),
+                    ))
+                    .id();
+                (farm_id, entity)
+            })
+            .collect();
+        world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+        world
+    }
+
+    fn infected_farms(world: &mut World) -> Vec<usize> {
+        world
+            .query::<(&FarmId, &Infected)>()
+            .iter(world)
+            .filter(|(_, infected)| infected.0 > 0)
+            .map(|(farm_id, _)| farm_id.0)
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_visits_by_farm_type_and_biosecurity() {
+        let mut world = example_world();
+        let farm_3 =
+            world.get_resource::<FarmIdEntityMap>().unwrap().0[&FarmId::new_single_population(3)];
+        world
+            .entity_mut(farm_3)
+            .insert(Biosecurity::new(Probability::new(1.).unwrap()));
+
+        let mut stage = SystemStage::single(
+            update_indirect_contacts
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        for _ in 0..5 {
+            stage.run(&mut world);
+        }
+        // farm 3 is never visited, and farm 4 is not visited by milk tankers
+        assert_eq!(infected_farms(&mut world), vec![1, 2]);
+    }
+}
@@ -41,11 +41,20 @@ pub mod parameters;
 pub mod tools;
 
 // generic simulation modules
+#[cfg(feature = "serialize")]
+pub mod checkpoint;
+#[cfg(feature = "serialize")]
+pub mod counterfactual;
+pub mod csv_outputs;
+#[cfg(feature = "serialize")]
+pub mod determinism_harness;
 pub mod farm_id_to_entity_map;
+pub mod scenario_rng;
 pub mod scenario_time;
 
 // (cattle) population model
 pub mod cattle_population;
+pub mod contact_networks;
 // within-herd spread model(s?)
 pub mod sir_spread_model;
 // TODO: Add a population that depends on the animal type and the disease
@@ -56,13 +65,32 @@ pub mod between_herd_spread_exogenous_model;
 pub mod between_herd_spread_model;
 pub mod between_herd_spread_model_record;
 pub mod cattle_farm_recorder;
+pub mod indirect_contacts;
+pub mod local_spread_model;
 pub mod population_model_record;
+pub mod reproduction_number;
+pub mod transmission_tree;
 
 // regulators
+pub mod diagnostic_tests;
+pub mod regulator_actions;
 pub mod regulator_active_surveillance;
+pub mod regulator_biosecurity;
+pub mod regulator_capacity;
+pub mod regulator_contact_tracing;
+pub mod regulator_control_outcomes;
+pub mod regulator_movement_restrictions;
 pub mod regulator_passive_surveillance;
+pub mod regulator_removals;
+pub mod regulator_restocking;
+pub mod regulator_vaccination;
+pub mod regulator_zones;
+pub mod regulatory_status;
 
 // animal movements
+pub mod animal_movements;
+pub mod markets;
+pub mod movement_register;
 pub mod repopulation_by_scaling;
 
 // deprecated
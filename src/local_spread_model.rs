@@ -0,0 +1,410 @@
+//! Local spread, i.e. airborne and neighbourhood spread between farms that
+//! are close to each other.
+//!
+//! This is a distinct route from the trade contacts of
+//! [crate::between_herd_spread_model], and it only involves farms with
+//! [FarmCoordinates].
+//!
+//! The daily hazard of infection of a farm without infected animals is
+//!
+//! `λ_j = Σ_i K(d_ij) I_i / N_i`
+//!
+//! over all infectious farms `i` within [LocalSpreadParameters::max_distance],
+//! where `K` is the [SpatialKernel]. The farm is infected with probability
+//! `1 - exp(-λ_j)`, and the infector is chosen in proportion to its
+//! contribution to `λ_j`.
+//!
+//! The neighbours are looked up through a grid, see [SpatialIndex], thus only
+//! nearby pairs of farms are evaluated.
+//!
+//! The startup system [setup_local_spread] is necessary for this to make sense.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::ensure;
+use rand::distributions::WeightedIndex;
+
+use crate::{
+    between_herd_spread_model::{InfectionEvents, InfectionEventsBatchId, InfectionRoute},
+    farm_id_to_entity_map::FarmIdEntityMap,
+    populations::{Cattle, FarmCoordinates, FarmId, HerdSize},
+    prelude::*,
+    scenario_rng::{LocalSpreadStream, ProcessRng},
+    scenario_time::scenario_timer::ScenarioTime,
+    sir_spread_model::{Infected, Susceptible},
+};
+
+/// Daily infection hazard from a fully infected farm as a function of the
+/// distance (in km).
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpatialKernel {
+    /// `beta * exp(-d / length_scale)`
+    Exponential { beta: f64, length_scale: f64 },
+    /// `beta / (1 + (d / d0)^alpha)`
+    PowerLaw { beta: f64, d0: f64, alpha: f64 },
+    /// `beta` within `radius`, and zero beyond.
+    Step { beta: f64, radius: f64 },
+}
+
+impl SpatialKernel {
+    pub fn hazard(&self, distance: f64) -> f64 {
+        match *self {
+            SpatialKernel::Exponential { beta, length_scale } => {
+                beta * (-distance / length_scale).exp()
+            }
+            SpatialKernel::PowerLaw { beta, d0, alpha } => {
+                beta / (1. + (distance / d0).powf(alpha))
+            }
+            SpatialKernel::Step { beta, radius } => {
+                if distance <= radius {
+                    beta
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+/// Add as a resource to enable local spread.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedLocalSpreadParameters")
+)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[readonly::make]
+pub struct LocalSpreadParameters {
+    pub kernel: SpatialKernel,
+    /// Farms further apart (in km) than this do not infect each other.
+    pub max_distance: f64,
+}
+
+impl LocalSpreadParameters {
+    /// The maximum distance must be positive, as it is also the cell size of
+    /// the [SpatialIndex].
+    pub fn new(kernel: SpatialKernel, max_distance: f64) -> Result<Self> {
+        ensure!(
+            max_distance > 0. && max_distance.is_finite(),
+            "maximum distance of local spread must be positive, got {}",
+            max_distance
+        );
+        Ok(Self {
+            kernel,
+            max_distance,
+        })
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+struct UncheckedLocalSpreadParameters {
+    kernel: SpatialKernel,
+    max_distance: f64,
+}
+
+#[cfg(feature = "serialize")]
+impl std::convert::TryFrom<UncheckedLocalSpreadParameters> for LocalSpreadParameters {
+    type Error = anyhow::Error;
+
+    fn try_from(parameters: UncheckedLocalSpreadParameters) -> Result<Self> {
+        Self::new(parameters.kernel, parameters.max_distance)
+    }
+}
+
+/// Grid of square cells with the farms that are located in them.
+#[derive(Debug, Clone)]
+pub struct SpatialIndex {
+    cell_size: f64,
+    cells: HashMap<(i64, i64), Vec<(FarmId, FarmCoordinates)>>,
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f64, farms: impl IntoIterator<Item = (FarmId, FarmCoordinates)>) -> Self {
+        assert!(cell_size > 0., "cell size must be positive");
+        let mut cells = HashMap::<_, Vec<_>>::new();
+        for (farm_id, coordinates) in farms {
+            cells
+                .entry(Self::cell(cell_size, &coordinates))
+                .or_default()
+                .push((farm_id, coordinates));
+        }
+        Self { cell_size, cells }
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    fn cell(cell_size: f64, coordinates: &FarmCoordinates) -> (i64, i64) {
+        (
+            (coordinates.x / cell_size).floor() as i64,
+            (coordinates.y / cell_size).floor() as i64,
+        )
+    }
+
+    /// Farms within `distance` of `coordinates` along with their distance.
+    pub fn within(
+        &self,
+        coordinates: FarmCoordinates,
+        distance: f64,
+    ) -> impl Iterator<Item = (FarmId, f64)> + '_ {
+        let (cell_x, cell_y) = Self::cell(self.cell_size, &coordinates);
+        let reach = (distance / self.cell_size).ceil() as i64;
+        (cell_x - reach..=cell_x + reach)
+            .cartesian_product(cell_y - reach..=cell_y + reach)
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .map(move |(farm_id, other)| (*farm_id, coordinates.distance(other)))
+            .filter(move |(_, other_distance)| *other_distance <= distance)
+    }
+}
+
+/// Builds the [SpatialIndex] of all farms with [FarmCoordinates].
+///
+/// Add this to a startup stage after the farms have been added.
+pub fn setup_local_spread(
+    mut commands: Commands,
+    parameters: Option<Res<LocalSpreadParameters>>,
+    batch_id: Option<Res<InfectionEventsBatchId>>,
+    query: Query<(&FarmId, &FarmCoordinates), With<Cattle>>,
+) {
+    let parameters = parameters.expect("Missing `LocalSpreadParameters` as a resource.");
+    let spatial_index = SpatialIndex::new(
+        parameters.max_distance,
+        query
+            .iter()
+            .map(|(farm_id, coordinates)| (*farm_id, *coordinates)),
+    );
+    commands.insert_resource(spatial_index);
+
+    // the batch ids are shared with the between-herd spread model
+    if batch_id.is_none() {
+        commands.insert_resource(InfectionEventsBatchId::default());
+    }
+}
+
+/// Components necessary to determine the local infection pressure.
+type LocalFarms = (
+    &'static FarmId,
+    &'static FarmCoordinates,
+    &'static HerdSize,
+    &'static Susceptible,
+    &'static Infected,
+);
+
+pub fn update_local_spread(
+    mut current_batch_id: ResMut<InfectionEventsBatchId>,
+    mut query: QuerySet<(
+        Query<'_, LocalFarms, With<Cattle>>,
+        Query<'_, (&mut Susceptible, &mut Infected), With<Cattle>>,
+    )>,
+    mut rng: ResMut<ProcessRng<LocalSpreadStream>>,
+    parameters: Res<LocalSpreadParameters>,
+    spatial_index: Res<SpatialIndex>,
+    scenario_tick: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+) -> Option<InfectionEvents> {
+    let infectious_farms = query
+        .q0()
+        .iter()
+        .filter(|(.., infected)| infected.0 > 0)
+        .map(|(farm_id, coordinates, herd_size, _, infected)| {
+            (
+                *farm_id,
+                *coordinates,
+                infected.0 as f64 / herd_size.0 as f64,
+            )
+        })
+        .sorted_by_key(|(farm_id, ..)| *farm_id)
+        .collect_vec();
+
+    // contributions to the hazard of each target, ordered by the farm ids of
+    // the targets and the origins, such that the draws do not depend on the
+    // iteration order of the farms.
+    let mut hazards = BTreeMap::<FarmId, Vec<(FarmId, f64)>>::new();
+    for (origin, coordinates, prevalence) in infectious_farms {
+        for (target, distance) in spatial_index.within(coordinates, parameters.max_distance) {
+            if target == origin {
+                continue;
+            }
+            let (.., susceptible, infected) = query
+                .q0()
+                .get(farm_map.0[&target])
+                .expect("farm in the spatial index is missing");
+            if infected.0 > 0 || susceptible.0 == 0 {
+                continue;
+            }
+            let hazard = parameters.kernel.hazard(distance) * prevalence;
+            if hazard > 0. {
+                hazards.entry(target).or_default().push((origin, hazard));
+            }
+        }
+    }
+
+    let mut new_infection_events: Vec<(FarmId, FarmId, usize)> = Vec::new();
+    for (target, contributions) in hazards {
+        let total_hazard: f64 = contributions.iter().map(|(_, hazard)| hazard).sum();
+        if !rng.gen_bool(1. - (-total_hazard).exp()) {
+            continue;
+        }
+        let infector = WeightedIndex::new(contributions.iter().map(|(_, hazard)| hazard))
+            .unwrap()
+            .sample(&mut *rng);
+        let (origin, _) = contributions[infector];
+
+        let (mut susceptible, mut infected) = query
+            .q1_mut()
+            .get_mut(farm_map.0[&target])
+            .expect("failed to find target farm to infect");
+        susceptible.0 -= 1;
+        infected.0 += 1;
+        new_infection_events.push((origin, target, 1));
+    }
+
+    if new_infection_events.is_empty() {
+        return None;
+    }
+    current_batch_id.0 += 1;
+    trace!(
+        "Total new local spread infections: {}",
+        new_infection_events.len()
+    );
+    Some(InfectionEvents {
+        scenario_tick: scenario_tick.current_time(),
+        batch_id: current_batch_id.0,
+        route: InfectionRoute::LocalSpread,
+        events_values: new_infection_events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::Recovered,
+    };
+
+    #[test]
+    fn test_kernels_decrease_with_distance() {
+        for kernel in [
+            SpatialKernel::Exponential {
+                beta: 0.1,
+                length_scale: 2.,
+            },
+            SpatialKernel::PowerLaw {
+                beta: 0.1,
+                d0: 1.,
+                alpha: 3.,
+            },
+            SpatialKernel::Step {
+                beta: 0.1,
+                radius: 3.,
+            },
+        ] {
+            assert!(kernel.hazard(0.) > 0., "{:?}", kernel);
+            assert!(kernel.hazard(1.) >= kernel.hazard(2.), "{:?}", kernel);
+            assert!(kernel.hazard(10.) < kernel.hazard(0.), "{:?}", kernel);
+        }
+    }
+
+    #[test]
+    fn test_invalid_max_distance() {
+        let kernel = SpatialKernel::Step {
+            beta: 1.,
+            radius: 1.,
+        };
+        assert!(LocalSpreadParameters::new(kernel, 0.).is_err());
+        assert!(LocalSpreadParameters::new(kernel, f64::NAN).is_err());
+        assert!(serde_json::from_str::<LocalSpreadParameters>(
+            r#"{"kernel": {"Step": {"beta": 1.0, "radius": 1.0}}, "max_distance": 0.0}"#
+        )
+        .is_err());
+        assert!(LocalSpreadParameters::new(kernel, 1.).is_ok());
+    }
+
+    #[test]
+    fn test_spatial_index_matches_all_pairs() {
+        let mut rng = StdRng::seed_from_u64(20210708);
+        let farms = (1..=200)
+            .map(|farm_id| {
+                (
+                    FarmId::new_single_population(farm_id),
+                    FarmCoordinates::new(rng.gen_range(-50.0..50.), rng.gen_range(-50.0..50.)),
+                )
+            })
+            .collect_vec();
+        let spatial_index = SpatialIndex::new(5., farms.clone());
+
+        for (_, coordinates) in farms.iter().take(20) {
+            let mut within = spatial_index
+                .within(*coordinates, 5.)
+                .map(|(farm_id, _)| farm_id.0)
+                .collect_vec();
+            within.sort_unstable();
+            let all_pairs = farms
+                .iter()
+                .filter(|(_, other)| coordinates.distance(other) <= 5.)
+                .map(|(farm_id, _)| farm_id.0)
+                .collect_vec();
+            assert_eq!(within, all_pairs);
+        }
+    }
+
+    #[test]
+    fn test_only_nearby_farms_are_infected() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210708));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(
+            LocalSpreadParameters::new(
+                SpatialKernel::Step {
+                    beta: 100.,
+                    radius: 2.,
+                },
+                2.,
+            )
+            .unwrap(),
+        );
+        let farm_id_to_entity_map: HashMap<FarmId, Entity> = [(1, 0.), (2, 1.5), (3, 10.)]
+            .iter()
+            .map(|&(farm_id, x)| {
+                let farm_id: FarmId = FarmId::new_single_population(farm_id);
+                let infected = if farm_id.0 == 1 { 50 } else { 0 };
+                let entity = world
+                    .spawn()
+                    .insert_bundle((
+                        Cattle,
+                        farm_id,
+                        FarmCoordinates::new(x, 0.),
+                        HerdSize::new_single_population(50),
+                        Susceptible(50 - infected),
+                        Infected(infected),
+                        Recovered(0),
+                    ))
+                    .id();
+                (farm_id, entity)
+            })
+            .collect();
+        world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+
+        let mut setup = SystemStage::single(setup_local_spread.system());
+        setup.run(&mut world);
+        let mut stage = SystemStage::single(
+            update_local_spread
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        stage.run(&mut world);
+
+        let mut infected = world
+            .query::<(&FarmId, &Infected)>()
+            .iter(&world)
+            .map(|(farm_id, infected)| (farm_id.0, infected.0))
+            .collect_vec();
+        infected.sort_unstable();
+        assert_eq!(infected, vec![(1, 50), (2, 1), (3, 0)]);
+    }
+}
@@ -14,7 +14,8 @@
 //! - [ ] Implement true passive surveillance, which is the true/observed
 //!       prevalence "watcher". Maybe set that to weekly or similar.
 //!
-//! - [ ] Add the 50% -> {0% infected, or 90% recovered} regulator
+//! - [x] Add the 50% -> {0% infected, or 90% recovered} regulator, see
+//!       [epi_bevy::regulator_control_outcomes] for other outcomes
 //! - [ ] Ensure that the simulation is "actually" deterministic, when everything
 //!       is set.
 //!
@@ -36,9 +37,10 @@ use epi_bevy::{
     parameters::{Probability, Rate},
     prelude::*,
     regulator_active_surveillance::{
-        update_active_surveillance, DetectionRate, RemainingProportion,
+        update_active_surveillance, DetectionRate, FarmDetected, RemainingProportion,
     },
     regulator_passive_surveillance::update_passive_surveillance,
+    scenario_rng::MasterSeed,
     scenario_time::scenario_intervals::run_every_month,
     sir_spread_model,
 };
@@ -98,20 +100,36 @@ enum Seed {
     Infection,
     /// Seed contacts stage
     Contacts,
+    /// Resume from a checkpoint stage
+    Checkpoint,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, StageLabel)]
 struct MainLoop;
 
+/// Phases of [Processes::Regulators], in order.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, SystemLabel)]
+enum Regulation {
+    /// Surveillance, inspections and tests
+    Detection,
+    /// Regulatory status on detection
+    Status,
+    /// Scheduled actions
+    Actions,
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, SystemLabel)]
 enum Processes {
     Disease,
+    WithinHerd,
+    Trade,
+    Estimation,
     Recording,
     Regulators,
+    Termination,
 }
 
 fn main() {
-
     //TODO: Add a CSV plugin
     // - [ ] Hide the CSV behind a mutex.
     // - [ ] Write all components of a specific entities (e.g. [CattleFarm])
@@ -133,7 +151,7 @@ fn main() {
     .add_plugins(MinimalPlugins)
     // TODO: Things that follow here
 
-    .insert_resource(StdRng::seed_from_u64(20210426))
+    .insert_resource(MasterSeed(20210426))
     .insert_resource(ScenarioTime::new(1, None))
     .insert_resource(ScenarioConfiguration {
         // max_timesteps: usize::MAX(),
@@ -162,8 +180,12 @@ fn main() {
     // procedure to amend the farms with components pertaining to those new systems
     // they can be regulators, and at least any other thing that should be
     // extended somehow...
+    .add_startup_system(epi_bevy::scenario_rng::setup_random_streams.exclusive_system())
     .add_startup_system(epi_bevy::cattle_farm_recorder::setup_cattle_farm_recorder.system())
     .add_startup_system(epi_bevy::between_herd_spread_model_record::setup_between_herd_infection_events_recording.system())
+    .add_startup_system(epi_bevy::animal_movements::setup_animal_movements_recording.system())
+    .add_startup_system(epi_bevy::transmission_tree::setup_transmission_tree.system())
+    .add_startup_system(epi_bevy::reproduction_number::setup_reproduction_number_estimator.system())
     //TODO: this stage doesn't need to be parallel.. but it is?
     // .add_startup_stage(Seed::Population, SystemStage::parallel())
     .add_startup_stage(Seed::Population, SystemStage::single_threaded())
@@ -179,10 +201,63 @@ fn main() {
         between_herd_spread_model::setup_between_herd_spread_model.system(),
     )
     // .add_startup_system_to_stage(Seed::Contacts, epi_bevy::deprecated_active_surveillance::setup_passive_surveillance.system())
+    // replays the register in place of sampling the trade from the adjacency
+    // .insert_resource(epi_bevy::movement_register::MovementRegisterPath::new("assets/movement_register.csv".into()))
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::movement_register::setup_movement_register.system())
+    // .insert_resource(epi_bevy::markets::MarketsPath::new("assets/markets.json".into()))
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::markets::setup_markets.system())
+    // replaces the adjacency from the population files
+    // .insert_resource(epi_bevy::contact_networks::ContactNetwork::WattsStrogatz { neighbours: 2, rewiring_probability: Probability::new(0.1).unwrap() })
+    // .add_startup_system_to_stage(Seed::Contacts, epi_bevy::contact_networks::setup_contact_network.system())
+    // requires farm coordinates in the population file
+    // .insert_resource(epi_bevy::local_spread_model::LocalSpreadParameters::new(epi_bevy::local_spread_model::SpatialKernel::Exponential { beta: 0.01, length_scale: 1. }, 5.).unwrap())
+    // .add_startup_system_to_stage(Seed::Contacts, epi_bevy::local_spread_model::setup_local_spread.system())
+    // .insert_resource(epi_bevy::indirect_contacts::IndirectContacts(vec![epi_bevy::indirect_contacts::VisitorKind::new("milk tanker", Rate::new(0.2).unwrap(), Probability::new(0.05).unwrap(), 8)]))
+    // .insert_resource(epi_bevy::regulator_biosecurity::BiosecurityOnDetection::new(Probability::new(0.5).unwrap()))
+    // .insert_resource(epi_bevy::between_herd_spread_exogenous_model::ExogenousInfectionRate::new(Rate::new(0.00001).unwrap()))
+    // .insert_resource(epi_bevy::between_herd_spread_exogenous_model::ExogenousSeasonality::new(0.5, 180).unwrap())
+    // .add_startup_system_to_stage(Seed::Contacts, epi_bevy::between_herd_spread_exogenous_model::setup_exogenous_infection_rate.system())
+    // .add_startup_system(epi_bevy::between_herd_spread_exogenous_model::setup_exogenous_infection_events_recording.system())
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::regulatory_status::setup_regulatory_status.system())
+    .add_startup_system(epi_bevy::regulatory_status::setup_status_changes_recording.system())
+    // .insert_resource(epi_bevy::diagnostic_tests::SurveillanceTesting::new(epi_bevy::diagnostic_tests::HerdTest::new(epi_bevy::diagnostic_tests::DiagnosticTest::new(Probability::new(0.9).unwrap(), Probability::new(0.99).unwrap(), 10.), epi_bevy::diagnostic_tests::HerdTestDesign::Sample { sample_size: 30 }), Probability::new(0.01).unwrap()))
+    .add_startup_system(epi_bevy::diagnostic_tests::setup_herd_tests_recording.system())
+    // .insert_resource(epi_bevy::regulator_removals::RemovalFate::Recovered)
+    // .insert_resource(epi_bevy::regulator_control_outcomes::ControlOutcomeModel::load("assets/control_outcomes.json").unwrap())
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::regulator_removals::setup_culled_animals.system())
+    .add_startup_system(epi_bevy::regulator_removals::setup_culled_animals_recording.system())
+    // .insert_resource(epi_bevy::regulator_restocking::RestockingPolicy::new(28, 90))
+    // .insert_resource(epi_bevy::regulator_actions::ScheduledActions::default())
+    // .insert_resource(epi_bevy::regulator_actions::ResponseDelays::new(epi_bevy::regulator_actions::ActionDelay::Fixed(7), epi_bevy::regulator_actions::ActionDelay::uniform(1, 5).unwrap(), epi_bevy::regulator_actions::RegulatoryAction::Cull))
+    // .insert_resource(epi_bevy::regulator_movement_restrictions::RestrictionPolicy::new(Probability::new(1.).unwrap(), 21).with_trade_contacts())
+    // .insert_resource(epi_bevy::regulator_movement_restrictions::NationalStandstill::new(3, Probability::new(0.9).unwrap()))
+    // requires farm coordinates in the population file
+    // .insert_resource(epi_bevy::regulator_zones::ZoningPolicy::default())
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::regulator_zones::setup_zoning.system())
+    .add_startup_system(epi_bevy::regulator_zones::setup_zone_membership_recording.system())
+    .add_startup_system(epi_bevy::regulator_active_surveillance::setup_detections_recording.system())
+    // .insert_resource(epi_bevy::regulator_contact_tracing::ContactHistory::default())
+    // .insert_resource(epi_bevy::regulator_contact_tracing::TracedFarms::default())
+    // .insert_resource(epi_bevy::regulator_contact_tracing::TracingPolicy::new(21, Probability::new(0.8).unwrap(), epi_bevy::regulator_actions::ActionDelay::uniform(1, 3).unwrap(), epi_bevy::diagnostic_tests::HerdTest::new(epi_bevy::diagnostic_tests::DiagnosticTest::new(Probability::new(0.9).unwrap(), Probability::new(0.99).unwrap(), 10.), epi_bevy::diagnostic_tests::HerdTestDesign::Sample { sample_size: 30 })))
+    // .insert_resource(epi_bevy::regulator_vaccination::VaccinationPolicy::new(epi_bevy::regulator_vaccination::Vaccine::new(Probability::new(0.9).unwrap(), Probability::new(0.95).unwrap(), 7), epi_bevy::regulator_vaccination::VaccinationStrategy::Protective, 10, 5).with_ring(epi_bevy::regulator_vaccination::VaccinationRing::TradeContacts))
+    .add_startup_system_to_stage(Seed::Contacts, epi_bevy::regulator_vaccination::setup_vaccination.system())
+    .add_startup_system(epi_bevy::regulator_vaccination::setup_vaccinations_recording.system())
+    // .insert_resource(epi_bevy::regulator_capacity::ControlCapacity::default().with_limit(epi_bevy::regulator_capacity::ControlActivity::Visit, 20).with_limit(epi_bevy::regulator_capacity::ControlActivity::Cull, 5).with_limit(epi_bevy::regulator_capacity::ControlActivity::Test, 50))
+    // .insert_resource(epi_bevy::regulator_active_surveillance::UntreatedFarms::default())
+    // .insert_resource(epi_bevy::regulator_capacity::PriorityPolicy::InfectionPressure)
+    .add_startup_system(epi_bevy::regulator_capacity::setup_control_demand_recording.system())
+    // .insert_resource(epi_bevy::checkpoint::ResumeFromCheckpoint::new("outputs/checkpoint.json".into()))
+    .add_startup_stage_after(Seed::Contacts, Seed::Checkpoint, SystemStage::single_threaded())
+    .add_startup_system_to_stage(Seed::Checkpoint, epi_bevy::checkpoint::resume_from_checkpoint.exclusive_system())
 
     // Main-loop
     // .add_stage(MainLoop, SystemStage::single_threaded())
     .add_stage(MainLoop, SystemStage::parallel())
+    .add_event::<FarmDetected>()
+    .add_event::<epi_bevy::regulatory_status::StatusChanged>()
+    .add_event::<epi_bevy::diagnostic_tests::HerdTested>()
+    // .insert_resource(epi_bevy::checkpoint::CheckpointAt::new(120, "outputs/checkpoint.json".into()))
+    .add_system_to_stage(MainLoop, epi_bevy::checkpoint::checkpoint_at_tick.exclusive_system().at_end())
 
     .add_system_set_to_stage(MainLoop,
         SystemSet::new()
@@ -193,19 +268,138 @@ fn main() {
             // .add_system_set(
                 SystemSet::new()
                 .label(Processes::Disease)
-                .with_system(epi_bevy::sir_spread_model::update_disease_compartments.system().chain(
-                // .with_system(
-                    between_herd_spread_model::update_between_herd_spread_model.system()
+                .with_system(epi_bevy::sir_spread_model::update_disease_compartments.system().label(Processes::WithinHerd))
+                // .with_system(epi_bevy::local_spread_model::update_local_spread.system()
+                //     .chain(epi_bevy::transmission_tree::record_transmission_tree.system())
+                //     .chain(epi_bevy::between_herd_spread_model_record::record_between_herd_infection_events.system()))
+                // .with_system(epi_bevy::indirect_contacts::update_indirect_contacts.system()
+                //     .chain(epi_bevy::transmission_tree::record_transmission_tree.system())
+                //     .chain(epi_bevy::between_herd_spread_model_record::record_between_herd_infection_events.system()))
+            )
+            // trade is sampled from the adjacency, unless a movement register was loaded
+            .add_system_set_to_stage(MainLoop,
+                SystemSet::new()
+                .label(Processes::Disease)
+                .label(Processes::Trade)
+                .after(Processes::WithinHerd)
+                .with_run_criteria(epi_bevy::movement_register::run_unless_movement_register.system())
+                .with_system(
+                    epi_bevy::animal_movements::update_animal_movements.system()
+                    .chain(epi_bevy::animal_movements::record_animal_movements.system())
+                    .chain(epi_bevy::regulator_contact_tracing::record_movement_contacts.system())
+                    .chain(epi_bevy::animal_movements::infection_events_from_movements.system())
+                    .chain(epi_bevy::transmission_tree::record_transmission_tree.system())
                     .chain(epi_bevy::between_herd_spread_model_record::record_between_herd_infection_events.system())
-                ))
+                )
+            )
+            .add_system_set_to_stage(MainLoop,
+                SystemSet::new()
+                .label(Processes::Disease)
+                .label(Processes::Trade)
+                .after(Processes::WithinHerd)
+                .with_run_criteria(epi_bevy::movement_register::run_if_movement_register.system())
+                .with_system(
+                    epi_bevy::movement_register::replay_registered_movements.system()
+                    .chain(epi_bevy::animal_movements::record_animal_movements.system())
+                    .chain(epi_bevy::regulator_contact_tracing::record_movement_contacts.system())
+                    .chain(epi_bevy::animal_movements::infection_events_from_movements.system())
+                    .chain(epi_bevy::transmission_tree::record_transmission_tree.system())
+                    .chain(epi_bevy::between_herd_spread_model_record::record_between_herd_infection_events.system())
+                )
+            )
+            // the markets trade after the direct movements
+            .add_system_set_to_stage(MainLoop,
+                SystemSet::new()
+                .label(Processes::Disease)
+                .after(Processes::Trade)
+                .with_system(
+                    epi_bevy::markets::update_markets.system()
+                    .chain(epi_bevy::regulator_contact_tracing::record_market_contacts.system())
+                    .chain(epi_bevy::markets::infection_events_from_markets.system())
+                    .chain(epi_bevy::transmission_tree::record_transmission_tree.system())
+                    .chain(epi_bevy::between_herd_spread_model_record::record_between_herd_infection_events.system())
+                )
+            )
+            .add_system_set_to_stage(MainLoop,
+                SystemSet::new()
+                .label(Processes::Estimation)
+                .after(Processes::Disease)
+                .before(Processes::Regulators)
+                .with_system(
+                    epi_bevy::reproduction_number::update_reproduction_number.system()
+                    .chain(epi_bevy::reproduction_number::record_reproduction_number.system())
+                )
+                // .with_system(
+                //     epi_bevy::between_herd_spread_exogenous_model::collect_exogenous_infection_events.system()
+                //     .chain(epi_bevy::between_herd_spread_exogenous_model::record_exogenous_infection_events.system())
+                //     .chain(epi_bevy::chain_tools::dispose.system())
+                // )
             )
             //TODO: Add a regulators system set! (and finish it)
+            // the regulators that aren't configured above are skipped, and the
+            // others run one after another, such that the capacity is shared
+            // in the same order every tick, and the events are read in the
+            // tick that they are sent
             .add_system_set_to_stage(MainLoop,SystemSet::new()
             .label(Processes::Regulators)
             .after(Processes::Disease)
-            .with_system(update_active_surveillance.system())
+            .with_system(update_active_surveillance.system().label(Regulation::Detection).label("surveillance"))
             .with_system(update_passive_surveillance.system()
-            .with_run_criteria(run_every_month.system()))
+            .with_run_criteria(run_every_month.system())
+            .label(Regulation::Detection).label("passive surveillance").after("surveillance"))
+            .with_system(epi_bevy::diagnostic_tests::update_surveillance_testing.system()
+            .with_run_criteria(epi_bevy::diagnostic_tests::run_if_surveillance_testing.system())
+            .label(Regulation::Detection).label("testing").after("passive surveillance"))
+            .with_system(epi_bevy::regulator_zones::update_zone_inspections.system()
+            .with_run_criteria(epi_bevy::regulator_zones::run_if_zoning.system())
+            .label(Regulation::Detection).label("inspections").after("testing"))
+            .with_system(epi_bevy::regulator_contact_tracing::test_traced_farms.system()
+            .with_run_criteria(epi_bevy::regulator_contact_tracing::run_if_tracing.system())
+            .label(Regulation::Detection).after("inspections"))
+            // positive herd tests make the farms suspected
+            .with_system(epi_bevy::regulatory_status::update_status_on_test_results.system()
+            .label(Regulation::Status).label("test results").after(Regulation::Detection))
+            .with_system(epi_bevy::regulatory_status::update_status_on_detection.system()
+            .with_run_criteria(epi_bevy::regulator_actions::run_unless_scheduled_actions.system())
+            .label(Regulation::Status).after("test results"))
+            // in place of `update_status_on_detection`
+            .with_system(epi_bevy::regulator_actions::schedule_confirmation_on_detection.system()
+            .with_run_criteria(epi_bevy::regulator_actions::run_if_scheduled_actions.system())
+            .label(Regulation::Status).after("test results"))
+            .with_system(epi_bevy::regulator_biosecurity::update_biosecurity_on_detection.system()
+            .with_run_criteria(epi_bevy::regulator_biosecurity::run_if_biosecurity_on_detection.system())
+            .label(Regulation::Status).after("test results"))
+            .with_system(epi_bevy::regulator_movement_restrictions::start_national_standstill.system()
+            .with_run_criteria(epi_bevy::regulator_movement_restrictions::run_if_national_standstill.system())
+            .label(Regulation::Status).after("test results"))
+            .with_system(epi_bevy::regulator_actions::execute_scheduled_actions.system()
+            .with_run_criteria(epi_bevy::regulator_actions::run_if_scheduled_actions.system())
+            .label(Regulation::Actions).after(Regulation::Status))
+            .with_system(epi_bevy::regulator_restocking::restock_culled_farms.system()
+            .with_run_criteria(epi_bevy::regulator_restocking::run_if_restocking.system())
+            .label("restocking").after(Regulation::Actions))
+            // the responses to the status changes
+            .with_system(epi_bevy::regulator_movement_restrictions::lift_movement_restrictions.system()
+            .label("lifting").after("restocking"))
+            .with_system(epi_bevy::regulator_movement_restrictions::update_movement_restrictions.system()
+            .with_run_criteria(epi_bevy::regulator_movement_restrictions::run_if_restriction_policy.system())
+            .label("restrictions").after("lifting"))
+            .with_system(epi_bevy::regulator_zones::update_zones.system()
+            .with_run_criteria(epi_bevy::regulator_zones::run_if_zoning.system())
+            .label("zones").after("restrictions"))
+            .with_system(epi_bevy::regulator_contact_tracing::trace_contacts.system()
+            .with_run_criteria(epi_bevy::regulator_contact_tracing::run_if_tracing.system())
+            .after("zones"))
+            .with_system(epi_bevy::regulator_vaccination::schedule_vaccination.system()
+            .with_run_criteria(epi_bevy::regulator_vaccination::run_if_vaccination.system())
+            .label("vaccination").after("zones"))
+            .with_system(
+                epi_bevy::regulator_vaccination::vaccinate_farms.system()
+                .chain(epi_bevy::regulator_vaccination::record_vaccinations.system())
+                .chain(epi_bevy::chain_tools::dispose.system())
+                .with_run_criteria(epi_bevy::regulator_vaccination::run_if_vaccination.system())
+                .after("vaccination")
+            )
         )
         .add_system_set_to_stage(MainLoop,
             SystemSet::new()
@@ -240,7 +434,31 @@ fn main() {
             // TODO: add application loop that displays the current estimates
             // .add_system(print_population_disease_states.system())
             .add_system_set_to_stage(MainLoop,
-            SystemSet::new().with_system(terminate_if_outbreak_is_over.system().after(Processes::Regulators))
+            SystemSet::new().with_system(terminate_if_outbreak_is_over.system().label(Processes::Termination).after(Processes::Regulators))
+        )
+        .add_system_set_to_stage(MainLoop,
+            SystemSet::new()
+            .after(Processes::Termination)
+            .with_system(epi_bevy::transmission_tree::export_transmission_tree_at_app_exit.system())
+            .with_system(epi_bevy::regulatory_status::record_status_changes.system())
+            .with_system(epi_bevy::diagnostic_tests::record_herd_tests.system())
+            .with_system(epi_bevy::regulator_active_surveillance::record_detections.system())
+            .with_system(
+                epi_bevy::regulator_removals::collect_culled_animals.system()
+                .chain(epi_bevy::regulator_removals::record_culled_animals.system())
+                .chain(epi_bevy::chain_tools::dispose.system())
+            )
+            .with_system(
+                epi_bevy::regulator_zones::collect_zoned_farms.system()
+                .chain(epi_bevy::regulator_zones::record_zone_membership.system())
+                .chain(epi_bevy::chain_tools::dispose.system())
+            )
+            .with_system(
+                epi_bevy::regulator_capacity::collect_control_demand.system()
+                .chain(epi_bevy::regulator_capacity::record_control_demand.system())
+                .chain(epi_bevy::chain_tools::dispose.system())
+                .with_run_criteria(epi_bevy::regulator_capacity::run_if_control_capacity.system())
+            )
         )
         .run();
 
@@ -256,24 +474,43 @@ fn seed_cattle_population(
 ) {
     let initial_disease_parameters =
         initial_disease_parameters.expect("no default/initial disease parameters are set.");
-    let cattle_population_bundle = epi_bevy::cattle_population::load_ring_population();
+    let cattle_population_bundle = epi_bevy::cattle_population::load_population(
+        "assets/population_info.json",
+        "assets/ring_adjacency.json",
+    )
+    .expect("failed to load the population");
     // FarmId and Entity id has to correspond, thus we add a resource
     // to contain this mapping.
     //TODO: maybe just collect, then find the length, and iterate further then
     let mut farm_id_to_entity_map: HashMap<FarmId, _> =
-        HashMap::with_capacity(cattle_population_bundle.clone().count());
+        HashMap::with_capacity(cattle_population_bundle.len());
     // info!("{:}", farm_id_to_entity_map.len());
 
-    for bundle in cattle_population_bundle {
+    for cattle_farm in cattle_population_bundle {
+        let epi_bevy::cattle_population::LoadedCattleFarm {
+            bundle,
+            adjacency_weights,
+            coordinates,
+            farm_type,
+        } = cattle_farm;
         let herd_size = bundle.herd_size;
         let farm_id = bundle.farm_id;
         // info!("inserted a herd of size {:?}", herd_size);
 
-        let farm_entity_id = commands
-            .spawn_bundle(bundle)
+        let mut farm_entity = commands.spawn_bundle(bundle);
+        farm_entity
             .insert_bundle(DiseaseCompartments::new(herd_size.0))
-            .insert(initial_disease_parameters.to_owned())
-            .id();
+            .insert(initial_disease_parameters.to_owned());
+        if let Some(adjacency_weights) = adjacency_weights {
+            farm_entity.insert(adjacency_weights);
+        }
+        if let Some(coordinates) = coordinates {
+            farm_entity.insert(coordinates);
+        }
+        if let Some(farm_type) = farm_type {
+            farm_entity.insert(farm_type);
+        }
+        let farm_entity_id = farm_entity.id();
 
         farm_id_to_entity_map.insert(farm_id, farm_entity_id);
     }
@@ -0,0 +1,538 @@
+//! Markets and collection centres as trade hubs between farms.
+//!
+//! A market is an entity of the [Market]-population, see [MarketBundle].
+//! Farms with a [MarketAttendance] send lots of [MovementBatchSize] animals to
+//! their market, according to the [ContactRate] of their attendance (see
+//! [ShipmentDistribution]). All the lots of a market day mix for that day,
+//! where every susceptible animal is infected with probability
+//! `1 - exp(-β I / N)`, with `β` being the [MarketMixing] of the market and
+//! `I / N` the prevalence among all the animals at the market. Afterwards,
+//! each lot is sold to another farm that attends the same market.
+//!
+//! Thus movements are routed farm → market → farm, and are put out as
+//! [MarketEvents]. Chain [update_markets] into [infection_events_from_markets]
+//! to get the [InfectionEvents] with [InfectionRoute::Market].
+//!
+//! Restricted farms may be kept from selling, see
+//! [crate::regulator_movement_restrictions].
+//!
+//! The markets and the farms that attend them are loaded by [setup_markets]
+//! from the file given in [MarketsPath], see [MarketRecord].
+//!
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{bail, Context};
+use rand::distributions::WeightedIndex;
+use rand_distr::Binomial;
+
+use crate::{
+    animal_movements::{sample_batch, AnimalMovement, MovementBatchSize, MovingFarm},
+    between_herd_spread_model::{
+        ContactRate, InfectionEvents, InfectionEventsBatchId, InfectionRoute, ShipmentDistribution,
+    },
+    farm_id_to_entity_map::FarmIdEntityMap,
+    parameters::Rate,
+    populations::{Cattle, FarmId, Market},
+    prelude::*,
+    regulator_movement_restrictions::MovementRestrictions,
+    scenario_rng::{MarketStream, ProcessRng},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+};
+
+/// Identifier of a market.
+pub type MarketId = FarmId<Market>;
+
+/// Daily transmission rate between the animals at a market.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
+pub struct MarketMixing(pub Rate);
+
+#[derive(Debug, Clone, Bundle, derive_new::new)]
+pub struct MarketBundle {
+    #[new(value = "Market")]
+    market: Market,
+    pub market_id: MarketId,
+    pub mixing: MarketMixing,
+}
+
+/// The market that a farm trades at, and its mean number of lots sent there
+/// per day.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct MarketAttendance {
+    pub market_id: MarketId,
+    pub sales_rate: ContactRate,
+}
+
+/// Path of the json-file with the [MarketRecord]s, see [setup_markets].
+#[derive(Debug, Clone, derive_new::new)]
+pub struct MarketsPath(pub PathBuf);
+
+/// A market and the farms that attend it.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct MarketRecord {
+    pub market_id: usize,
+    pub mixing: MarketMixing,
+    pub attendees: Vec<AttendeeRecord>,
+}
+
+/// A farm that attends a market, with its [MarketAttendance::sales_rate].
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy)]
+pub struct AttendeeRecord {
+    pub farm_id: usize,
+    pub sales_rate: ContactRate,
+}
+
+/// Loads the markets from `path`.
+#[cfg(feature = "serialize")]
+pub fn load_markets(path: impl AsRef<std::path::Path>) -> Result<Vec<MarketRecord>> {
+    let path = path.as_ref();
+    let markets_file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(markets_file))
+        .with_context(|| format!("invalid markets {}", path.display()))
+}
+
+/// The [MarketAttendance] of each farm entity.
+///
+/// Fails if a farm is not part of the population, or if it attends more than
+/// one market.
+pub fn market_attendances(
+    markets: &[MarketRecord],
+    farm_map: &FarmIdEntityMap,
+) -> Result<BTreeMap<FarmId, (Entity, MarketAttendance)>> {
+    let mut attendances = BTreeMap::new();
+    for market in markets {
+        let market_id = FarmId::new_single_population(market.market_id);
+        for attendee in &market.attendees {
+            let farm_id = FarmId::new_single_population(attendee.farm_id);
+            let entity = match farm_map.0.get(&farm_id) {
+                Some(entity) => *entity,
+                None => bail!(
+                    "farm {} at market {} is not part of the population",
+                    attendee.farm_id,
+                    market.market_id
+                ),
+            };
+            let attendance = MarketAttendance::new(market_id, attendee.sales_rate);
+            if attendances.insert(farm_id, (entity, attendance)).is_some() {
+                bail!("farm {} attends more than one market", attendee.farm_id);
+            }
+        }
+    }
+    Ok(attendances)
+}
+
+/// Spawns the markets from [MarketsPath], if present, and adds the
+/// [MarketAttendance] to the farms.
+///
+/// Add this to a startup stage after the farms and the [FarmIdEntityMap] have
+/// been added.
+#[cfg(feature = "serialize")]
+pub fn setup_markets(
+    mut commands: Commands,
+    markets_path: Option<Res<MarketsPath>>,
+    farm_map: Res<FarmIdEntityMap>,
+) {
+    let markets_path = match markets_path {
+        Some(markets_path) => markets_path,
+        None => return,
+    };
+    let markets = load_markets(&markets_path.0).expect("failed to load the markets");
+    let attendances = market_attendances(&markets, &farm_map)
+        .unwrap_or_else(|err| panic!("invalid markets {}: {}", markets_path.0.display(), err));
+    info!(
+        "Loaded {} markets with {} attending farms from {}",
+        markets.len(),
+        attendances.len(),
+        markets_path.0.display()
+    );
+
+    for market in markets {
+        commands.spawn_bundle(MarketBundle::new(
+            FarmId::new_single_population(market.market_id),
+            market.mixing,
+        ));
+    }
+    for (_, (entity, attendance)) in attendances {
+        commands.entity(entity).insert(attendance);
+    }
+}
+
+/// A lot of animals that was sold at a market.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarketSale {
+    pub market_id: MarketId,
+    /// The seller and the buyer, along with the animals as they were sold,
+    /// i.e. after mixing.
+    pub movement: AnimalMovement,
+    /// Animals of the lot that were infected at the market, by the farm of
+    /// the infecting animal.
+    pub infected_at_market: Vec<(FarmId, usize)>,
+}
+
+impl MarketSale {
+    /// Between-herd infections of the sale, as `(infector, buyer, new infected)`.
+    ///
+    /// Animals that were infected at the market by animals of the buyer are
+    /// not between-herd infections, and are left out.
+    pub fn infection_events(&self) -> Vec<(FarmId, FarmId, usize)> {
+        let AnimalMovement {
+            origin,
+            destination,
+            infected,
+            ..
+        } = self.movement;
+        let infected_at_market: usize = self.infected_at_market.iter().map(|(_, x)| x).sum();
+        let mut events_values = Vec::new();
+        if infected > infected_at_market {
+            events_values.push((origin, destination, infected - infected_at_market));
+        }
+        events_values.extend(
+            self.infected_at_market
+                .iter()
+                .filter(|(infector, _)| *infector != destination)
+                .map(|(infector, new_infected)| (*infector, destination, *new_infected)),
+        );
+        events_values
+    }
+}
+
+/// All market sales of a single tick.
+#[derive(Debug, Clone)]
+pub struct MarketEvents {
+    pub scenario_tick: Time,
+    pub sales: Vec<MarketSale>,
+}
+
+/// A lot of animals at a market, that was sent by `origin`.
+#[derive(Debug, Clone, Copy)]
+struct Lot {
+    origin: FarmId,
+    susceptible: usize,
+    infected: usize,
+    recovered: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_markets(
+    markets: Query<(&MarketId, &MarketMixing), With<Market>>,
+    attendees: Query<(Entity, &FarmId, &MarketAttendance), With<Cattle>>,
+    mut farms: Query<MovingFarm, With<Cattle>>,
+    mut rng: ResMut<ProcessRng<MarketStream>>,
+    batch_size: Option<Res<MovementBatchSize>>,
+    shipment_distribution: Option<Res<ShipmentDistribution>>,
+    scenario_time: Res<ScenarioTime>,
+    movement_restrictions: MovementRestrictions,
+) -> Option<MarketEvents> {
+    let batch_size = batch_size.map_or_else(MovementBatchSize::default, |x| *x);
+    let shipment_distribution = shipment_distribution.map_or_else(Default::default, |x| *x);
+
+    let mut attendees_by_market = BTreeMap::<MarketId, Vec<_>>::new();
+    attendees.for_each(|(entity, farm_id, attendance)| {
+        attendees_by_market
+            .entry(attendance.market_id)
+            .or_default()
+            .push((entity, *farm_id, attendance.sales_rate));
+    });
+    // the draws must not depend on the order of the entities, which differs
+    // in a world restored from a checkpoint
+    attendees_by_market
+        .values_mut()
+        .for_each(|attendees| attendees.sort_by_key(|(_, farm_id, _)| *farm_id));
+
+    let mut sales = Vec::new();
+    for (market_id, mixing) in markets.iter().sorted_by_key(|(market_id, _)| **market_id) {
+        let attendees = match attendees_by_market.get(market_id) {
+            // there is no one to sell to at a market with a single farm
+            Some(attendees) if attendees.len() > 1 => attendees,
+            _ => continue,
+        };
+
+        // the sellers send their lots to the market
+        let mut lots = Vec::new();
+        for (entity, farm_id, sales_rate) in attendees {
+            let (mut herd_size, mut sus, mut inf, mut rec) = farms
+                .get_mut(*entity)
+                .expect("failed to find farm attending a market");
+            for _ in 0..shipment_distribution.sample_shipments(*sales_rate, &mut *rng) {
+                if !movement_restrictions.permits_farm(*entity, &mut *rng) {
+                    // restricted farms are kept from selling
+                    continue;
+                }
+                let (susceptible, infected, recovered) =
+                    sample_batch(batch_size.0, sus.0, inf.0, rec.0, &mut *rng);
+                if susceptible + infected + recovered == 0 {
+                    // the farm has been emptied by earlier lots
+                    break;
+                }
+                sus.0 -= susceptible;
+                inf.0 -= infected;
+                rec.0 -= recovered;
+                herd_size.remove_animals(susceptible + infected + recovered);
+                lots.push(Lot {
+                    origin: *farm_id,
+                    susceptible,
+                    infected,
+                    recovered,
+                });
+            }
+        }
+        if lots.is_empty() {
+            continue;
+        }
+
+        // the animals at the market mix
+        let total_animals: usize = lots
+            .iter()
+            .map(|lot| lot.susceptible + lot.infected + lot.recovered)
+            .sum();
+        let total_infected: usize = lots.iter().map(|lot| lot.infected).sum();
+        let infection_probability =
+            1. - (-(mixing.0).0 * total_infected as f64 / total_animals as f64).exp();
+        // the infecting animal is from a lot in proportion to its infected
+        let infectors = WeightedIndex::new(lots.iter().map(|lot| lot.infected)).ok();
+        let origins = lots.iter().map(|lot| lot.origin).collect_vec();
+        let mut infected_at_market = vec![BTreeMap::<FarmId, usize>::new(); lots.len()];
+        if let Some(infectors) = infectors {
+            for (lot, infected_at_market) in lots.iter_mut().zip(infected_at_market.iter_mut()) {
+                let new_infected = Binomial::new(lot.susceptible as u64, infection_probability)
+                    .unwrap()
+                    .sample(&mut *rng) as usize;
+                for _ in 0..new_infected {
+                    let infector = origins[infectors.sample(&mut *rng)];
+                    *infected_at_market.entry(infector).or_default() += 1;
+                }
+                lot.susceptible -= new_infected;
+                lot.infected += new_infected;
+            }
+        }
+
+        // and are then sold to the other farms at the market
+        for (lot, infected_at_market) in lots.into_iter().zip(infected_at_market) {
+            let buyers = attendees
+                .iter()
+                .filter(|(_, farm_id, _)| *farm_id != lot.origin)
+                .collect_vec();
+            let (buyer_entity, buyer, _) = buyers
+                .choose(&mut *rng)
+                .expect("a market has other attendees than the seller");
+            let (buyer_entity, buyer) = (*buyer_entity, *buyer);
+            let (mut herd_size, mut sus, mut inf, mut rec) = farms
+                .get_mut(buyer_entity)
+                .expect("failed to find buyer of a market lot");
+            sus.0 += lot.susceptible;
+            inf.0 += lot.infected;
+            rec.0 += lot.recovered;
+            herd_size.add_animals(lot.susceptible + lot.infected + lot.recovered);
+
+            sales.push(MarketSale {
+                market_id: *market_id,
+                movement: AnimalMovement {
+                    origin: lot.origin,
+                    destination: buyer,
+                    susceptible: lot.susceptible,
+                    infected: lot.infected,
+                    recovered: lot.recovered,
+                },
+                infected_at_market: infected_at_market.into_iter().collect(),
+            });
+        }
+    }
+
+    if sales.is_empty() {
+        None
+    } else {
+        Some(MarketEvents {
+            scenario_tick: scenario_time.current_time(),
+            sales,
+        })
+    }
+}
+
+/// Sales of infected animals as a batch of between-herd infection events,
+/// from the seller (or the farm of the infecting animal, if infected at the
+/// market) to the buyer.
+pub fn infection_events_from_markets(
+    In(events): In<Option<MarketEvents>>,
+    mut current_batch_id: ResMut<InfectionEventsBatchId>,
+) -> Option<InfectionEvents> {
+    let MarketEvents {
+        scenario_tick,
+        sales,
+    } = events?;
+    let events_values = sales
+        .iter()
+        .flat_map(MarketSale::infection_events)
+        .collect_vec();
+    if events_values.is_empty() {
+        return None;
+    }
+
+    current_batch_id.0 += 1;
+    Some(InfectionEvents {
+        scenario_tick,
+        batch_id: current_batch_id.0,
+        route: InfectionRoute::Market,
+        events_values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        populations::HerdSize,
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::{Infected, Recovered, Susceptible},
+    };
+
+    #[test]
+    fn test_market_day() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210712));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId::default());
+        let market_id: MarketId = FarmId::new_single_population(1);
+        world.spawn().insert_bundle(MarketBundle::new(
+            market_id,
+            MarketMixing::new(Rate::new(50.).unwrap()),
+        ));
+        for (farm_id, infected) in [(1, 100), (2, 0), (3, 0)] {
+            let farm_id: FarmId = FarmId::new_single_population(farm_id);
+            world.spawn().insert_bundle((
+                Cattle,
+                farm_id,
+                HerdSize::new_single_population(100),
+                Susceptible(100 - infected),
+                Infected(infected),
+                Recovered(0),
+                MarketAttendance::new(market_id, ContactRate::new(Rate::new(2.).unwrap())),
+            ));
+        }
+
+        let mut stage = SystemStage::single(
+            update_markets
+                .system()
+                .chain(infection_events_from_markets.system())
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        stage.run(&mut world);
+
+        let herd_sizes = world
+            .query::<&HerdSize>()
+            .iter(&world)
+            .map(|x| x.0)
+            .sum::<usize>();
+        assert_eq!(herd_sizes, 300, "animals are only moved between farms");
+        let infected_elsewhere = world
+            .query::<(&FarmId, &Infected)>()
+            .iter(&world)
+            .filter(|(farm_id, _)| farm_id.0 != 1)
+            .map(|(_, infected)| infected.0)
+            .sum::<usize>();
+        assert!(infected_elsewhere > 0);
+        assert!(
+            world.get_resource::<InfectionEventsBatchId>().unwrap().0 > 0,
+            "no market infection events"
+        );
+    }
+
+    #[test]
+    fn test_single_attendee_does_not_sell() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210712));
+        world.insert_resource(ScenarioTime::new(1, None));
+        let market_id: MarketId = FarmId::new_single_population(1);
+        world.spawn().insert_bundle(MarketBundle::new(
+            market_id,
+            MarketMixing::new(Rate::new(50.).unwrap()),
+        ));
+        world.spawn().insert_bundle((
+            Cattle,
+            FarmId::<()>::new_single_population(1),
+            HerdSize::new_single_population(100),
+            Susceptible(90),
+            Infected(10),
+            Recovered(0),
+            MarketAttendance::new(market_id, ContactRate::new(Rate::new(2.).unwrap())),
+        ));
+
+        let mut stage = SystemStage::single(
+            update_markets
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        stage.run(&mut world);
+
+        let (herd_size, susceptible) = world
+            .query::<(&HerdSize, &Susceptible)>()
+            .iter(&world)
+            .map(|(herd_size, susceptible)| (herd_size.0, susceptible.0))
+            .next()
+            .unwrap();
+        assert_eq!((herd_size, susceptible), (100, 90));
+    }
+
+    #[test]
+    fn test_infections_by_the_buyer_are_dropped() {
+        let farm_id = |farm_id| FarmId::<()>::new_single_population(farm_id);
+        let sale = MarketSale {
+            market_id: FarmId::new_single_population(1),
+            movement: AnimalMovement {
+                origin: farm_id(1),
+                destination: farm_id(2),
+                susceptible: 0,
+                infected: 10,
+                recovered: 0,
+            },
+            infected_at_market: vec![(farm_id(2), 3), (farm_id(3), 2)],
+        };
+        assert_eq!(
+            sale.infection_events(),
+            vec![(farm_id(1), farm_id(2), 5), (farm_id(3), farm_id(2), 2)]
+        );
+    }
+
+    #[test]
+    fn test_market_attendances() {
+        let farm_map = FarmIdEntityMap::from(
+            (1..=3)
+                .map(|farm_id| {
+                    (
+                        FarmId::new_single_population(farm_id),
+                        Entity::new(farm_id as u32),
+                    )
+                })
+                .collect::<std::collections::HashMap<_, _>>(),
+        );
+        let sales_rate = ContactRate::new(Rate::new(0.5).unwrap());
+        let market = |market_id, farm_ids: &[usize]| MarketRecord {
+            market_id,
+            mixing: MarketMixing::new(Rate::new(1.).unwrap()),
+            attendees: farm_ids
+                .iter()
+                .map(|&farm_id| AttendeeRecord {
+                    farm_id,
+                    sales_rate,
+                })
+                .collect(),
+        };
+
+        let attendances =
+            market_attendances(&[market(1, &[1, 2]), market(2, &[3])], &farm_map).unwrap();
+        assert_eq!(attendances.len(), 3);
+        assert_eq!(
+            attendances[&FarmId::new_single_population(3)].1.market_id.0,
+            2
+        );
+
+        assert!(market_attendances(&[market(1, &[1, 4])], &farm_map).is_err());
+        assert!(market_attendances(&[market(1, &[1, 2]), market(2, &[2])], &farm_map).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! Temporal contact network from a register of recorded animal movements.
+//!
+//! The register is a csv-file with a header and the columns
+//!
+//! `date, origin farm_id, destination farm_id, number of animals`
+//!
+//! where the dates are `YYYY-MM-DD`. Dates are mapped to ticks through
+//! [ScenarioTime::tick_of_date], i.e. [ScenarioTime::first_day_date] is tick 1.
+//!
+//! Instead of sampling shipments from the static [crate::populations::AdjacentFarms],
+//! [replay_registered_movements] moves the registered number of animals on the
+//! matching tick, see [crate::animal_movements] for how the batch is composed.
+//! Registered batches to or from restricted farms may be stopped, as with the
+//! sampled shipments, see [crate::regulator_movement_restrictions].
+//! The output is the same [MovementEvents], and thus it can be chained into
+//! [crate::animal_movements::record_animal_movements] and
+//! [crate::animal_movements::infection_events_from_movements].
+//!
+//! The register is loaded by [setup_movement_register] from the path given in
+//! [MovementRegisterPath], if present. Use [run_if_movement_register] and
+//! [run_unless_movement_register] to replay the register in place of
+//! [crate::animal_movements::update_animal_movements].
+//!
+
+use std::{collections::BTreeMap, io::Read, path::PathBuf};
+
+use bevy::ecs::schedule::ShouldRun;
+use chrono::NaiveDate;
+use thiserror::Error;
+
+use crate::{
+    animal_movements::{transfer_batch, MovementEvents, MovingFarm},
+    farm_id_to_entity_map::FarmIdEntityMap,
+    populations::{Cattle, FarmId},
+    prelude::*,
+    regulator_movement_restrictions::MovementRestrictions,
+    scenario_rng::{MovementRegisterStream, ProcessRng},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+};
+
+#[derive(Error, Debug)]
+pub enum MovementRegisterError {
+    #[error("line {line}: farm id {farm_id} is not part of the population")]
+    UnknownFarmId { line: usize, farm_id: usize },
+    #[error("line {line}: `{value}` is not a valid {column}")]
+    InvalidValue {
+        line: usize,
+        column: &'static str,
+        value: String,
+    },
+    #[error("line {line}: expected 4 columns, found {columns}")]
+    MissingColumns { line: usize, columns: usize },
+    #[error("line {line}: {date} is before the first date of the scenario")]
+    BeforeScenarioStart { line: usize, date: NaiveDate },
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+}
+
+/// Path of the movement register, see [setup_movement_register].
+#[derive(Debug, Clone, derive_new::new)]
+pub struct MovementRegisterPath {
+    pub path: PathBuf,
+    /// Column separator of the register.
+    #[new(value = "b','")]
+    pub delimiter: u8,
+}
+
+/// A movement of the register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisteredMovement {
+    pub origin: FarmId,
+    pub destination: FarmId,
+    pub animals: usize,
+}
+
+/// The recorded movements grouped by tick.
+#[derive(Debug, Clone, Default)]
+pub struct MovementRegister(pub BTreeMap<Time, Vec<RegisteredMovement>>);
+
+impl MovementRegister {
+    /// Reads the register, and checks that every farm is present in
+    /// `farm_map`.
+    pub fn from_reader(
+        reader: impl Read,
+        delimiter: u8,
+        scenario_time: &ScenarioTime,
+        farm_map: &FarmIdEntityMap,
+    ) -> Result<Self, MovementRegisterError> {
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(delimiter)
+            .from_reader(reader);
+
+        let mut register = BTreeMap::<Time, Vec<RegisteredMovement>>::new();
+        for (index, record) in csv_reader.records().enumerate() {
+            // the header is line 1
+            let line = index + 2;
+            let record = record?;
+            if record.len() < 4 {
+                return Err(MovementRegisterError::MissingColumns {
+                    line,
+                    columns: record.len(),
+                });
+            }
+            let parse_number = |column: &'static str, value: &str| {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| MovementRegisterError::InvalidValue {
+                        line,
+                        column,
+                        value: value.to_string(),
+                    })
+            };
+            let parse_farm_id = |column: &'static str, value: &str| {
+                let farm_id: FarmId = FarmId::new_single_population(parse_number(column, value)?);
+                if farm_map.0.contains_key(&farm_id) {
+                    Ok(farm_id)
+                } else {
+                    Err(MovementRegisterError::UnknownFarmId {
+                        line,
+                        farm_id: farm_id.0,
+                    })
+                }
+            };
+
+            let date = NaiveDate::parse_from_str(&record[0], "%Y-%m-%d").map_err(|_| {
+                MovementRegisterError::InvalidValue {
+                    line,
+                    column: "date",
+                    value: record[0].to_string(),
+                }
+            })?;
+            let tick = scenario_time
+                .tick_of_date(date)
+                .ok_or(MovementRegisterError::BeforeScenarioStart { line, date })?;
+
+            register.entry(tick).or_default().push(RegisteredMovement {
+                origin: parse_farm_id("origin farm id", &record[1])?,
+                destination: parse_farm_id("destination farm id", &record[2])?,
+                animals: parse_number("number of animals", &record[3])?,
+            });
+        }
+        Ok(Self(register))
+    }
+
+    pub fn movements_at(&self, tick: Time) -> &[RegisteredMovement] {
+        self.0.get(&tick).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Total number of registered movements.
+    pub fn len(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Loads the [MovementRegister] from [MovementRegisterPath], if present.
+///
+/// Add this to a startup stage after the farms and the [FarmIdEntityMap] have
+/// been added.
+pub fn setup_movement_register(
+    mut commands: Commands,
+    register_path: Option<Res<MovementRegisterPath>>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+) {
+    let register_path = match register_path {
+        Some(register_path) => register_path,
+        None => return,
+    };
+    let register_file = std::fs::File::open(&register_path.path).unwrap_or_else(|err| {
+        panic!(
+            "failed to open movement register {}: {}",
+            register_path.path.display(),
+            err
+        )
+    });
+    let register = MovementRegister::from_reader(
+        std::io::BufReader::new(register_file),
+        register_path.delimiter,
+        &scenario_time,
+        &farm_map,
+    )
+    .unwrap_or_else(|err| {
+        panic!(
+            "invalid movement register {}: {}",
+            register_path.path.display(),
+            err
+        )
+    });
+    info!(
+        "Loaded {} movements from {}",
+        register.len(),
+        register_path.path.display()
+    );
+    commands.insert_resource(register);
+}
+
+/// Run criteria for the systems that replay the [MovementRegister].
+pub fn run_if_movement_register(register: Option<Res<MovementRegister>>) -> ShouldRun {
+    if register.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Run criteria for the systems that the [MovementRegister] replaces, i.e.
+/// [crate::animal_movements::update_animal_movements].
+pub fn run_unless_movement_register(register: Option<Res<MovementRegister>>) -> ShouldRun {
+    if register.is_some() {
+        ShouldRun::No
+    } else {
+        ShouldRun::Yes
+    }
+}
+
+/// Moves the registered batches of animals of the current tick, unless the
+/// [MovementRestrictions] stop them.
+pub fn replay_registered_movements(
+    register: Res<MovementRegister>,
+    mut farms: Query<MovingFarm, With<Cattle>>,
+    mut rng: ResMut<ProcessRng<MovementRegisterStream>>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+    movement_restrictions: MovementRestrictions,
+) -> Option<MovementEvents> {
+    let movements = register
+        .movements_at(scenario_time.current_time())
+        .iter()
+        .filter_map(|registered| {
+            // farms are validated when the register is loaded
+            let origin_entity = farm_map.0[&registered.origin];
+            let destination_entity = farm_map.0[&registered.destination];
+            if !movement_restrictions.permits(origin_entity, destination_entity, &mut *rng) {
+                return None;
+            }
+            transfer_batch(
+                &mut farms,
+                (registered.origin, origin_entity),
+                (registered.destination, destination_entity),
+                registered.animals,
+                &mut *rng,
+            )
+        })
+        .collect_vec();
+
+    if movements.is_empty() {
+        None
+    } else {
+        Some(MovementEvents {
+            scenario_tick: scenario_time.current_time(),
+            movements,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parameters::Probability,
+        populations::HerdSize,
+        regulator_movement_restrictions::MovementRestriction,
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::{Infected, Recovered, Susceptible},
+    };
+    use std::collections::HashMap;
+
+    fn example_world() -> World {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210707));
+        world.insert_resource(ScenarioTime::new(1, None));
+        let farm_id_to_entity_map: HashMap<FarmId, Entity> = (1..=3)
+            .map(|farm_id| {
+                let farm_id: FarmId = FarmId::new_single_population(farm_id);
+                let entity = world
+                    .spawn()
+                    .insert_bundle((
+                        Cattle,
+                        farm_id,
+                        HerdSize::new_single_population(50),
+                        Susceptible(50),
+                        Infected(0),
+                        Recovered(0),
+                    ))
+                    .id();
+                (farm_id, entity)
+            })
+            .collect();
+        world.insert_resource(FarmIdEntityMap::from(farm_id_to_entity_map));
+        world
+    }
+
+    #[test]
+    fn test_load_register() {
+        let world = example_world();
+        let scenario_time = world.get_resource::<ScenarioTime>().unwrap();
+        let farm_map = world.get_resource::<FarmIdEntityMap>().unwrap();
+
+        let register = "date,origin,destination,animals\n\
+                        2000-01-01,1,2,5\n\
+                        2000-01-03,2,3,10\n\
+                        2000-01-03,3,1,1\n";
+        let register =
+            MovementRegister::from_reader(register.as_bytes(), b',', scenario_time, farm_map)
+                .unwrap();
+        assert_eq!(register.len(), 3);
+        assert_eq!(register.movements_at(1).len(), 1);
+        assert_eq!(register.movements_at(2).len(), 0);
+        assert_eq!(register.movements_at(3).len(), 2);
+
+        let unknown_farm = "date,origin,destination,animals\n2000-01-01,1,4,5\n";
+        let error =
+            MovementRegister::from_reader(unknown_farm.as_bytes(), b',', scenario_time, farm_map)
+                .unwrap_err();
+        assert!(matches!(
+            error,
+            MovementRegisterError::UnknownFarmId {
+                line: 2,
+                farm_id: 4
+            }
+        ));
+    }
+
+    /// Registers a batch of 20 animals from farm 1 to farm 2 on tick 2.
+    fn insert_example_register(world: &mut World) {
+        let mut register = MovementRegister::default();
+        register.0.insert(
+            2,
+            vec![RegisteredMovement {
+                origin: FarmId::new_single_population(1),
+                destination: FarmId::new_single_population(2),
+                animals: 20,
+            }],
+        );
+        world.insert_resource(register);
+    }
+
+    fn herd_sizes(world: &mut World) -> Vec<(usize, usize)> {
+        let mut herd_sizes = world
+            .query::<(&FarmId, &HerdSize)>()
+            .iter(world)
+            .map(|(farm_id, herd_size)| (farm_id.0, herd_size.0))
+            .collect_vec();
+        herd_sizes.sort_unstable();
+        herd_sizes
+    }
+
+    #[test]
+    fn test_replay_register() {
+        let mut world = example_world();
+        insert_example_register(&mut world);
+
+        let mut stage = SystemStage::single(
+            replay_registered_movements
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        // tick 1 and then tick 2
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<ScenarioTime>()
+            .unwrap()
+            .update_time(1);
+        stage.run(&mut world);
+
+        assert_eq!(herd_sizes(&mut world), vec![(1, 30), (2, 70), (3, 50)]);
+    }
+
+    #[test]
+    fn test_restricted_register_movements() {
+        let mut world = example_world();
+        insert_example_register(&mut world);
+        let origin =
+            world.get_resource::<FarmIdEntityMap>().unwrap().0[&FarmId::new_single_population(1)];
+        // a complete ban on the origin
+        world
+            .entity_mut(origin)
+            .insert(MovementRestriction::new(Probability::new(1.).unwrap(), 10));
+        world
+            .get_resource_mut::<ScenarioTime>()
+            .unwrap()
+            .update_time(1);
+
+        let mut stage = SystemStage::single(
+            replay_registered_movements
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        stage.run(&mut world);
+
+        assert_eq!(herd_sizes(&mut world), vec![(1, 50), (2, 50), (3, 50)]);
+    }
+}
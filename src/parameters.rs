@@ -32,6 +32,7 @@ impl Rate {
     Debug,
     Clone,
     Copy,
+    PartialEq,
     derive_more::Display,
     derive_more::Into,
     // derive_more::MulSelf,
@@ -2,9 +2,10 @@
 //! Use `()` as the default [Population] if the multiple populations are not
 //! the target.
 //!
-use std::{hash::Hash, marker::PhantomData};
+use std::{convert::TryFrom, hash::Hash, marker::PhantomData, sync::Arc};
 
 use bevy::ecs::component::Component;
+use rand_distr::WeightedAliasIndex;
 
 // ! This is (supposed) to replace [crate::cattle_population].
 
@@ -19,6 +20,7 @@ pub enum EmbeddedPopulation {
     Cattle(Cattle),
     Pig(Pig),
     Sheep(Sheep),
+    Market(Market),
 }
 
 // this currently doesn't work. Nor does the `either` crate help with this.
@@ -56,6 +58,14 @@ pub struct Sheep;
 
 impl Population for Sheep {}
 
+/// Markets and collection centres, where animals from many herds mix for a
+/// day, see [crate::markets].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Market;
+
+impl Population for Market {}
+
 // #[readonly::make]
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Bundle)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
@@ -67,7 +77,7 @@ pub struct FarmBundle<P: Population = ()> {
 }
 
 // #[readonly::make]
-#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq, PartialOrd, Ord)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FarmId<P: Population = ()>(
     pub usize,
@@ -85,6 +95,95 @@ pub struct HerdSize<P: Population = ()>(pub usize, #[serde(skip_deserializing)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct AdjacentFarms<P: Population = ()>(pub Vec<FarmId<P>>, PhantomData<P>);
 
+/// Weights of the directed edges in [AdjacentFarms], e.g. trade volume or
+/// contact frequency, in the same order as the adjacent farms.
+///
+/// Neighbours are sampled in proportion to their weight through an alias
+/// table, see [AdjacentFarms::choose_neighbour].
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(try_from = "Vec<f64>", into = "Vec<f64>", bound = "")
+)]
+pub struct AdjacencyWeights<P: Population = ()> {
+    weights: Vec<f64>,
+    alias_table: Arc<WeightedAliasIndex<f64>>,
+    population: PhantomData<P>,
+}
+
+impl<P: Population> AdjacencyWeights<P> {
+    /// Weights must be non-negative, and not all zero.
+    pub fn new(weights: Vec<f64>) -> Result<Self> {
+        let alias_table = WeightedAliasIndex::new(weights.clone())?;
+        Ok(Self {
+            weights,
+            alias_table: Arc::new(alias_table),
+            population: PhantomData,
+        })
+    }
+
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+}
+
+// the alias table is shared, as it isn't [Clone]
+impl<P: Population> Clone for AdjacencyWeights<P> {
+    fn clone(&self) -> Self {
+        Self {
+            weights: self.weights.clone(),
+            alias_table: Arc::clone(&self.alias_table),
+            population: PhantomData,
+        }
+    }
+}
+
+impl<P: Population> std::fmt::Debug for AdjacencyWeights<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AdjacencyWeights")
+            .field(&self.weights)
+            .finish()
+    }
+}
+
+impl<P: Population> TryFrom<Vec<f64>> for AdjacencyWeights<P> {
+    type Error = anyhow::Error;
+
+    fn try_from(weights: Vec<f64>) -> Result<Self> {
+        Self::new(weights)
+    }
+}
+
+impl<P: Population> From<AdjacencyWeights<P>> for Vec<f64> {
+    fn from(adjacency_weights: AdjacencyWeights<P>) -> Self {
+        adjacency_weights.weights
+    }
+}
+
+/// Location of a farm in a projected coordinate system, in km.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, derive_new::new)]
+pub struct FarmCoordinates {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl FarmCoordinates {
+    /// Euclidean distance in km.
+    pub fn distance(&self, other: &Self) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+/// Type of farm, e.g. dairy or beef.
+///
+/// This determines the block of the farm in a
+/// [crate::contact_networks::ContactNetwork::StochasticBlock], where farms
+/// without a type are in block 0.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Into, derive_more::From)]
+pub struct FarmType(pub usize);
+
 /// Intended to be stored as a global available resource
 /// for each added population to the [crate::scenario_builder::Scenario].
 #[readonly::make]
@@ -104,6 +203,26 @@ impl<P: Population> AdjacentFarms<P> {
     pub fn new_single_population(value: Vec<FarmId<P>>) -> Self {
         Self(value, PhantomData)
     }
+
+    /// Samples a neighbour in proportion to `weights`, or uniformly if the
+    /// edges are unweighted.
+    pub fn choose_neighbour<R: Rng + ?Sized>(
+        &self,
+        weights: Option<&AdjacencyWeights<P>>,
+        rng: &mut R,
+    ) -> Option<&FarmId<P>> {
+        match weights {
+            Some(weights) => {
+                debug_assert_eq!(
+                    weights.weights.len(),
+                    self.0.len(),
+                    "there must be a weight for every adjacent farm"
+                );
+                self.0.get(weights.alias_table.sample(rng))
+            }
+            None => self.0.choose(rng),
+        }
+    }
 }
 
 impl HerdSize {
@@ -112,6 +231,21 @@ impl HerdSize {
     }
 }
 
+impl<P: Population> HerdSize<P> {
+    /// Animals that are moved onto the farm.
+    pub fn add_animals(&mut self, animals: usize) {
+        self.0 += animals;
+    }
+
+    /// Animals that are moved off the farm, or removed otherwise.
+    pub fn remove_animals(&mut self, animals: usize) {
+        self.0 = self
+            .0
+            .checked_sub(animals)
+            .expect("cannot remove more animals than there are in the herd");
+    }
+}
+
 impl<P: Population> FarmId<P> {
     pub fn new_single_population(value: usize) -> Self {
         Self(value, PhantomData)
@@ -156,4 +290,27 @@ mod tests {
 
         dbg!(world.get_resource::<TotalFarms>().unwrap());
     }
+
+    #[test]
+    fn test_weighted_neighbours() {
+        let mut rng = StdRng::seed_from_u64(20210708);
+        let adjacent_farms: AdjacentFarms = AdjacentFarms::new_single_population(vec![
+            FarmId::new_single_population(1),
+            FarmId::new_single_population(2),
+            FarmId::new_single_population(3),
+        ]);
+        let weights = AdjacencyWeights::new(vec![0., 1., 3.]).unwrap();
+
+        let mut counts = [0; 4];
+        for _ in 0..4_000 {
+            let neighbour = adjacent_farms
+                .choose_neighbour(Some(&weights), &mut rng)
+                .unwrap();
+            counts[neighbour.0] += 1;
+        }
+        assert_eq!(counts[1], 0, "edges with zero weight are never chosen");
+        assert!((2_800..3_200).contains(&counts[3]), "{:?}", counts);
+
+        assert!(AdjacencyWeights::<()>::new(vec![0., 0.]).is_err());
+    }
 }
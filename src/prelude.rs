@@ -11,6 +11,8 @@ pub use serde::{Deserialize, Serialize};
 pub use rand::prelude::*;
 pub use rand::SeedableRng;
 
+pub use crate::scenario_rng::ScenarioRng;
+
 pub use anyhow::Result;
 
 //TODO: add [bevy::ecs::system::SystemParam] as I believe this should
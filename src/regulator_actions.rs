@@ -0,0 +1,624 @@
+//! Delays between the detection of a farm and the actions of the regulators.
+//!
+//! Actions are put into the [ScheduledActions]-queue, keyed by the tick they
+//! are due at, and carried out by [execute_scheduled_actions]:
+//!
+//! * a detection schedules the confirmation of the farm, see
+//!   [schedule_confirmation_on_detection],
+//! * the confirmation schedules the [ResponseDelays::action_on_confirmation],
+//!   i.e. culling or treatment.
+//!
+//! The delays are given as an [ActionDelay] in [ResponseDelays]. With a
+//! [ControlCapacity] only so many actions of a kind are carried out per day,
+//! and the rest are carried over to the next day. The due actions are taken in
+//! the order of the [PriorityPolicy], i.e. oldest first by default.
+//!
+//! While the queue is present, [crate::regulator_active_surveillance] no
+//! longer removes the infection on detection.
+//!
+//! A farm that is culled without being confirmed, e.g. after suppressive
+//! vaccination, is [FarmStatus::Restricted] before it is culled.
+//!
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::ensure;
+use bevy::ecs::schedule::ShouldRun;
+use rand_distr::Poisson;
+
+use crate::{
+    between_herd_spread_model::ContactRate,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    populations::{Cattle, FarmId, FarmType, HerdSize},
+    prelude::*,
+    regulator_active_surveillance::{FarmDetected, RemainingProportion},
+    regulator_capacity::{ControlCapacity, FarmPriority, PriorityPolicy},
+    regulator_control_outcomes::{ControlOutcome, ControlOutcomeModel},
+    regulator_removals::{Culled, RemovalFate},
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_rng::{ActionExecutionStream, ProcessRng, RegulatorActionsStream},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegulatoryAction {
+    /// Confirm a detected farm, which schedules the follow-up action.
+    Confirm,
+    /// Remove all animals from the farm.
+    Cull,
+    /// Carry out a [crate::regulator_control_outcomes::ControlOutcome] on
+    /// the farm, where the removed animals meet their [RemovalFate].
+    Treat,
+}
+
+/// Number of days until an action is due.
+///
+/// Construct the random delays with [ActionDelay::uniform] and
+/// [ActionDelay::poisson].
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case", try_from = "UncheckedActionDelay")
+)]
+#[derive(Debug, Clone, Copy)]
+pub enum ActionDelay {
+    Fixed(Time),
+    /// Uniform between `min` and `max` (inclusive).
+    Uniform {
+        min: Time,
+        max: Time,
+    },
+    Poisson {
+        mean: f64,
+    },
+}
+
+impl ActionDelay {
+    /// The bounds are inclusive, and `min` must not exceed `max`.
+    pub fn uniform(min: Time, max: Time) -> Result<Self> {
+        ensure!(
+            min <= max,
+            "minimum delay {} exceeds the maximum delay {}",
+            min,
+            max
+        );
+        Ok(Self::Uniform { min, max })
+    }
+
+    /// The mean must be finite and non-negative.
+    pub fn poisson(mean: f64) -> Result<Self> {
+        ensure!(
+            mean.is_finite() && mean >= 0.,
+            "mean delay must be non-negative, got {}",
+            mean
+        );
+        Ok(Self::Poisson { mean })
+    }
+
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Time {
+        match *self {
+            ActionDelay::Fixed(delay) => delay,
+            ActionDelay::Uniform { min, max } => rng.gen_range(min..=max),
+            ActionDelay::Poisson { mean } if mean > 0. => {
+                Poisson::new(mean).unwrap().sample(rng) as Time
+            }
+            ActionDelay::Poisson { .. } => 0,
+        }
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UncheckedActionDelay {
+    Fixed(Time),
+    Uniform { min: Time, max: Time },
+    Poisson { mean: f64 },
+}
+
+#[cfg(feature = "serialize")]
+impl std::convert::TryFrom<UncheckedActionDelay> for ActionDelay {
+    type Error = anyhow::Error;
+
+    fn try_from(unchecked: UncheckedActionDelay) -> Result<Self> {
+        match unchecked {
+            UncheckedActionDelay::Fixed(delay) => Ok(Self::Fixed(delay)),
+            UncheckedActionDelay::Uniform { min, max } => Self::uniform(min, max),
+            UncheckedActionDelay::Poisson { mean } => Self::poisson(mean),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct ResponseDelays {
+    /// From detection to confirmation.
+    pub confirmation: ActionDelay,
+    /// From confirmation to the follow-up action.
+    pub action: ActionDelay,
+    /// Either [RegulatoryAction::Cull] or [RegulatoryAction::Treat].
+    pub action_on_confirmation: RegulatoryAction,
+}
+
+type ActionQueue = BTreeMap<Time, Vec<(FarmId, RegulatoryAction)>>;
+
+/// Queue of pending actions, by the tick they are due at.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(from = "ActionQueue", into = "ActionQueue")
+)]
+#[derive(Debug, Clone, Default)]
+pub struct ScheduledActions {
+    queue: ActionQueue,
+    /// The actions in the queue, to look them up without going through it.
+    pending: HashSet<(FarmId, RegulatoryAction)>,
+}
+
+// only the queue is stored, as the pending actions follow from it
+impl From<ActionQueue> for ScheduledActions {
+    fn from(queue: ActionQueue) -> Self {
+        let pending = queue.values().flatten().copied().collect();
+        Self { queue, pending }
+    }
+}
+
+impl From<ScheduledActions> for ActionQueue {
+    fn from(scheduled_actions: ScheduledActions) -> Self {
+        scheduled_actions.queue
+    }
+}
+
+impl ScheduledActions {
+    /// Returns `false` if the same action is already pending for the farm.
+    pub fn schedule(&mut self, due: Time, farm_id: FarmId, action: RegulatoryAction) -> bool {
+        if !self.pending.insert((farm_id, action)) {
+            return false;
+        }
+        self.queue.entry(due).or_default().push((farm_id, action));
+        true
+    }
+
+    pub fn is_pending(&self, farm_id: FarmId, action: RegulatoryAction) -> bool {
+        self.pending.contains(&(farm_id, action))
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Removes the actions that are due at `scenario_tick` (or overdue), in
+    /// the order of the `priority_policy`, and up to the remaining capacity of
+    /// each kind of action. The rest stay in the queue.
+    pub fn take_due(
+        &mut self,
+        scenario_tick: Time,
+        mut capacity: Option<&mut ControlCapacity>,
+        priority_policy: PriorityPolicy,
+        farm_priority: impl Fn(FarmId) -> FarmPriority,
+    ) -> Vec<(FarmId, RegulatoryAction)> {
+        let not_due = self.queue.split_off(&(scenario_tick + 1));
+        let mut pending = std::mem::replace(&mut self.queue, not_due)
+            .into_iter()
+            .flat_map(|(due, pending)| {
+                pending
+                    .into_iter()
+                    .map(move |(farm_id, action)| (due, farm_id, action))
+            })
+            .collect_vec();
+        priority_policy.prioritise(&mut pending, |(_, farm_id, _)| farm_priority(*farm_id));
+
+        let mut due_actions = Vec::new();
+        for (due, farm_id, action) in pending {
+            let has_capacity = capacity.as_mut().map_or(true, |capacity| {
+                capacity.try_consume(action.into(), farm_id, scenario_tick)
+            });
+            if has_capacity {
+                self.pending.remove(&(farm_id, action));
+                due_actions.push((farm_id, action));
+            } else {
+                self.queue.entry(due).or_default().push((farm_id, action));
+            }
+        }
+        due_actions
+    }
+}
+
+/// Run criteria for the systems that schedule and execute the actions.
+pub fn run_if_scheduled_actions(scheduled_actions: Option<Res<ScheduledActions>>) -> ShouldRun {
+    if scheduled_actions.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Run criteria for the systems that the [ScheduledActions] replace, i.e.
+/// [crate::regulatory_status::update_status_on_detection].
+pub fn run_unless_scheduled_actions(scheduled_actions: Option<Res<ScheduledActions>>) -> ShouldRun {
+    if scheduled_actions.is_some() {
+        ShouldRun::No
+    } else {
+        ShouldRun::Yes
+    }
+}
+
+/// Changes the status of farms that have one, and sends the [StatusChanged]
+/// if the event is present.
+fn change_status(
+    farm_id: FarmId,
+    status: Option<&mut RegulatoryStatus>,
+    to: FarmStatus,
+    scenario_tick: Time,
+    status_changes: &mut Option<ResMut<'_, Events<StatusChanged>>>,
+) {
+    let status_changed = status.and_then(|status| status.transition(farm_id, to, scenario_tick));
+    if let Some((status_changed, status_changes)) = status_changed.zip(status_changes.as_mut()) {
+        status_changes.send(status_changed);
+    }
+}
+
+/// Detected farms become suspected until confirmed. Use this in place of
+/// [crate::regulatory_status::update_status_on_detection].
+pub fn schedule_confirmation_on_detection(
+    mut detections: EventReader<FarmDetected>,
+    delays: Res<ResponseDelays>,
+    mut scheduled_actions: ResMut<ScheduledActions>,
+    mut query: Query<&mut RegulatoryStatus>,
+    farm_map: Res<FarmIdEntityMap>,
+    mut rng: ResMut<ProcessRng<RegulatorActionsStream>>,
+    mut status_changes: Option<ResMut<Events<StatusChanged>>>,
+) {
+    for detection in detections.iter() {
+        let mut status = query.get_mut(farm_map.0[&detection.farm_id]).ok();
+        let is_known = status.as_ref().map_or(false, |status| {
+            matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+        });
+        if is_known {
+            continue;
+        }
+        let due = detection.scenario_tick + delays.confirmation.sample(&mut *rng);
+        if scheduled_actions.schedule(due, detection.farm_id, RegulatoryAction::Confirm) {
+            change_status(
+                detection.farm_id,
+                status.as_deref_mut(),
+                FarmStatus::Suspected,
+                detection.scenario_tick,
+                &mut status_changes,
+            );
+        }
+    }
+}
+
+/// Components of a farm that the regulators act on.
+type ActedOnFarm = (
+    &'static mut HerdSize,
+    &'static mut Susceptible,
+    &'static mut Infected,
+    &'static mut Recovered,
+    Option<&'static mut RegulatoryStatus>,
+    Option<&'static mut Culled>,
+    Option<&'static FarmType>,
+    Option<&'static ContactRate>,
+);
+
+/// Actions that fall due while executing, i.e. with no delay, are carried out
+/// in the next tick.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_scheduled_actions(
+    mut scheduled_actions: ResMut<ScheduledActions>,
+    mut control_capacity: Option<ResMut<ControlCapacity>>,
+    priority_policy: Option<Res<PriorityPolicy>>,
+    delays: Res<ResponseDelays>,
+    remaining_proportion: Option<Res<RemainingProportion>>,
+    control_outcome_model: Option<Res<ControlOutcomeModel>>,
+    removal_fate: Option<Res<RemovalFate>>,
+    mut query: Query<ActedOnFarm, With<Cattle>>,
+    farm_map: Res<FarmIdEntityMap>,
+    mut rng: ResMut<ProcessRng<ActionExecutionStream>>,
+    scenario_time: Res<ScenarioTime>,
+    mut status_changes: Option<ResMut<Events<StatusChanged>>>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    let priority_policy = priority_policy.as_deref().copied().unwrap_or_default();
+    let due_actions = scheduled_actions.take_due(
+        scenario_tick,
+        control_capacity.as_deref_mut(),
+        priority_policy,
+        |farm_id| {
+            let farm = farm_map.0[&farm_id];
+            FarmPriority::new(
+                query.get_component::<HerdSize>(farm).unwrap().0,
+                query.get_component::<Infected>(farm).unwrap().0,
+                query.get_component::<ContactRate>(farm).ok(),
+            )
+        },
+    );
+    for (farm_id, action) in due_actions {
+        let (
+            mut herd_size,
+            mut susceptible,
+            mut infected,
+            mut recovered,
+            mut status,
+            culled,
+            farm_type,
+            _,
+        ) = query
+            .get_mut(farm_map.0[&farm_id])
+            .expect("failed to find farm of a scheduled action");
+        match action {
+            RegulatoryAction::Confirm => {
+                if status
+                    .as_ref()
+                    .map_or(false, |status| status.status == FarmStatus::Culled)
+                {
+                    continue;
+                }
+                change_status(
+                    farm_id,
+                    status.as_deref_mut(),
+                    FarmStatus::Confirmed,
+                    scenario_tick,
+                    &mut status_changes,
+                );
+                let due = scenario_tick + delays.action.sample(&mut *rng);
+                scheduled_actions.schedule(due, farm_id, delays.action_on_confirmation);
+            }
+            RegulatoryAction::Cull => {
+                let culled_animals = herd_size.0;
+                herd_size.remove_animals(culled_animals);
+                susceptible.0 = 0;
+                infected.0 = 0;
+                recovered.0 = 0;
+                if let Some(mut culled) = culled {
+                    culled.add(culled_animals, scenario_tick);
+                }
+                // a pre-emptive cull, e.g. of a vaccinated farm, restricts
+                // the farm first
+                let is_preemptive = status.as_ref().map_or(false, |status| {
+                    matches!(
+                        status.status,
+                        FarmStatus::Free | FarmStatus::Suspected | FarmStatus::Restocked
+                    )
+                });
+                if is_preemptive {
+                    change_status(
+                        farm_id,
+                        status.as_deref_mut(),
+                        FarmStatus::Restricted,
+                        scenario_tick,
+                        &mut status_changes,
+                    );
+                }
+                change_status(
+                    farm_id,
+                    status.as_deref_mut(),
+                    FarmStatus::Culled,
+                    scenario_tick,
+                    &mut status_changes,
+                );
+            }
+            RegulatoryAction::Treat => {
+                let control_outcome_model = ControlOutcomeModel::or_remaining_proportion(
+                    control_outcome_model.as_deref(),
+                    remaining_proportion.as_deref(),
+                );
+                let control_outcome =
+                    control_outcome_model.sample(herd_size.0, farm_type, &mut *rng);
+                let culled_animals = control_outcome.apply(
+                    removal_fate.as_deref().copied().unwrap_or_default(),
+                    &mut herd_size,
+                    &mut susceptible,
+                    &mut infected,
+                    &mut recovered,
+                    &mut *rng,
+                );
+                if let Some(mut culled) = culled {
+                    culled.add(culled_animals, scenario_tick);
+                }
+                if control_outcome == ControlOutcome::WholeHerdCull {
+                    change_status(
+                        farm_id,
+                        status.as_deref_mut(),
+                        FarmStatus::Culled,
+                        scenario_tick,
+                        &mut status_changes,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        regulator_active_surveillance::DetectionSource,
+        regulator_capacity::ControlActivity,
+        scenario_rng::{insert_random_streams, MasterSeed},
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_capacity_carries_over() {
+        let mut scheduled_actions = ScheduledActions::default();
+        for farm_id in 1..=3 {
+            let farm_id: FarmId = FarmId::new_single_population(farm_id);
+            assert!(scheduled_actions.schedule(farm_id.0 as Time, farm_id, RegulatoryAction::Cull));
+        }
+        let farm_id: FarmId = FarmId::new_single_population(1);
+        assert!(!scheduled_actions.schedule(5, farm_id, RegulatoryAction::Cull));
+        assert!(scheduled_actions.schedule(2, farm_id, RegulatoryAction::Treat));
+
+        let mut capacity = ControlCapacity::default().with_limit(ControlActivity::Cull, 1);
+        let mut take_due = |scenario_tick, capacity: Option<&mut ControlCapacity>| {
+            scheduled_actions
+                .take_due(
+                    scenario_tick,
+                    capacity,
+                    PriorityPolicy::Fifo,
+                    |_| unreachable!(),
+                )
+                .into_iter()
+                .map(|(farm_id, _)| farm_id.0)
+                .collect_vec()
+        };
+        assert_eq!(take_due(0, Some(&mut capacity)), Vec::<usize>::new());
+        assert_eq!(take_due(3, Some(&mut capacity)), vec![1, 1]);
+        // the capacity of the day is used up
+        assert_eq!(take_due(3, Some(&mut capacity)), Vec::<usize>::new());
+        // the farms that are left waiting are only counted once per day
+        assert_eq!(capacity.unmet(ControlActivity::Cull, 3), 2);
+        assert_eq!(take_due(4, Some(&mut capacity)), vec![2]);
+        assert_eq!(take_due(4, None), vec![3]);
+        assert!(scheduled_actions.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_delays_are_rejected() {
+        assert!(ActionDelay::uniform(3, 1).is_err());
+        assert!(ActionDelay::poisson(f64::NAN).is_err());
+        assert!(ActionDelay::poisson(f64::INFINITY).is_err());
+        assert!(ActionDelay::poisson(-1.).is_err());
+        assert!(
+            serde_json::from_str::<ActionDelay>(r#"{"uniform": {"min": 5, "max": 2}}"#).is_err()
+        );
+        assert!(
+            serde_json::from_str::<ActionDelay>(r#"{"uniform": {"min": 2, "max": 5}}"#).is_ok()
+        );
+        assert!(serde_json::from_str::<ActionDelay>(r#"{"fixed": 7}"#).is_ok());
+
+        let mut rng = StdRng::seed_from_u64(20210801);
+        let delay = ActionDelay::uniform(2, 2).unwrap();
+        assert_eq!(delay.sample(&mut rng), 2);
+        assert_eq!(ActionDelay::poisson(0.).unwrap().sample(&mut rng), 0);
+    }
+
+    #[test]
+    fn test_detection_to_culling() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210717));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(ScheduledActions::default());
+        world.insert_resource(ResponseDelays::new(
+            ActionDelay::Fixed(2),
+            ActionDelay::uniform(1, 3).unwrap(),
+            RegulatoryAction::Cull,
+        ));
+        world.insert_resource(Events::<FarmDetected>::default());
+        world.insert_resource(Events::<StatusChanged>::default());
+        let farm_id: FarmId = FarmId::new_single_population(1);
+        let farm = world
+            .spawn()
+            .insert_bundle((
+                Cattle,
+                farm_id,
+                HerdSize::new_single_population(100),
+                Susceptible(90),
+                Infected(10),
+                Recovered(0),
+                RegulatoryStatus::new(FarmStatus::Free, 1),
+            ))
+            .id();
+        world.insert_resource(FarmIdEntityMap::from(
+            vec![(farm_id, farm)].into_iter().collect::<HashMap<_, _>>(),
+        ));
+        world
+            .get_resource_mut::<Events<FarmDetected>>()
+            .unwrap()
+            .send(FarmDetected {
+                farm_id,
+                scenario_tick: 1,
+                source: DetectionSource::Surveillance,
+            });
+
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(
+                schedule_confirmation_on_detection
+                    .system()
+                    .label("schedule"),
+            )
+            .add_system(
+                execute_scheduled_actions
+                    .system()
+                    .label("execute")
+                    .after("schedule"),
+            )
+            .add_system(
+                (|mut scenario_time: ResMut<ScenarioTime>| scenario_time.update_time(1))
+                    .system()
+                    .after("execute"),
+            );
+        let mut statuses = Vec::new();
+        for _ in 0..8 {
+            stage.run(&mut world);
+            statuses.push(world.get::<RegulatoryStatus>(farm).unwrap().status);
+        }
+        // confirmed two days after the detection, and culled 1-3 days later
+        assert_eq!(statuses[0], FarmStatus::Suspected);
+        assert_eq!(statuses[2], FarmStatus::Confirmed);
+        assert_eq!(statuses[7], FarmStatus::Culled);
+        assert_eq!(world.get::<HerdSize>(farm).unwrap().0, 0);
+        assert!(world.get_resource::<ScheduledActions>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_preemptive_cull() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210723));
+        world.insert_resource(ScenarioTime::new(3, None));
+        world.insert_resource(ResponseDelays::new(
+            ActionDelay::Fixed(2),
+            ActionDelay::Fixed(1),
+            RegulatoryAction::Cull,
+        ));
+        world.insert_resource(Events::<StatusChanged>::default());
+        let farm_id: FarmId = FarmId::new_single_population(1);
+        let farm = world
+            .spawn()
+            .insert_bundle((
+                Cattle,
+                farm_id,
+                HerdSize::new_single_population(100),
+                Susceptible(100),
+                Infected(0),
+                Recovered(0),
+                RegulatoryStatus::new(FarmStatus::Free, 1),
+            ))
+            .id();
+        world.insert_resource(FarmIdEntityMap::from(
+            vec![(farm_id, farm)].into_iter().collect::<HashMap<_, _>>(),
+        ));
+        // e.g. after suppressive vaccination
+        let mut scheduled_actions = ScheduledActions::default();
+        scheduled_actions.schedule(3, farm_id, RegulatoryAction::Cull);
+        world.insert_resource(scheduled_actions);
+
+        SystemStage::single(execute_scheduled_actions.system()).run(&mut world);
+        assert_eq!(
+            world.get::<RegulatoryStatus>(farm).unwrap().status,
+            FarmStatus::Culled
+        );
+        let status_changes = world.get_resource::<Events<StatusChanged>>().unwrap();
+        assert_eq!(
+            status_changes
+                .get_reader()
+                .iter(status_changes)
+                .map(|status_changed| status_changed.to)
+                .collect_vec(),
+            vec![FarmStatus::Restricted, FarmStatus::Culled]
+        );
+        let scheduled_actions = world.get_resource::<ScheduledActions>().unwrap();
+        assert!(!scheduled_actions.is_pending(farm_id, RegulatoryAction::Cull));
+    }
+}
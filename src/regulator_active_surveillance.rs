@@ -1,32 +1,52 @@
 //! This module works in the following way:
 //!
 //! * Detection probability: 1\% x infection rate
-//! * If detected, then there is a 50% chance of eliminating
-//!   the infection completely, or 90%.
-//!
-//!
-//!
-//! This module works in the following way:
-//!
-//! * Detection probability: 1\% x infection rate
-//! * If detected, then there is a 50% chance of eliminating
+//! * If detected, then the outcome is drawn from the
+//!   [ControlOutcomeModel], which by default is a 50% chance of eliminating
 //!   the infection completely, or 90%.
+//! * With a [ControlCapacity], only as many detected farms are treated per
+//!   day as there is capacity for, see [crate::regulator_capacity]. The rest
+//!   wait in the [UntreatedFarms], and are treated on the following days,
+//!   in the order of the [PriorityPolicy].
+//! * With [SurveillanceTesting], the farms that are picked up by the detection
+//!   rate, and the farms that are sampled by the programme, are only detected
+//!   if the [crate::diagnostic_tests::HerdTest] is positive. Thus detections
+//!   are subject to the sensitivity, and may be falsely positive.
+//! * A [ControlOutcome::WholeHerdCull] confirms the farm, and sets its
+//!   [RegulatoryStatus] to culled.
 //!
 //!
 
 // Note that this implementation is to showcase the presence of the central
 // located parameters, and not once spread-out over the entities.
 
-use std::convert::TryFrom;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fs::File,
+};
+
+use csv::Writer;
 
 use crate::{
+    between_herd_spread_model::ContactRate,
+    csv_outputs::CsvOutputs,
+    diagnostic_tests::SurveillanceTesting,
     parameters::{Probability, Rate},
+    populations::{FarmId, FarmType, HerdSize},
     prelude::*,
-    sir_spread_model::Infected,
-    tools::FloatExt,
+    regulator_actions::ScheduledActions,
+    regulator_capacity::{ControlActivity, ControlCapacity, FarmPriority, PriorityPolicy},
+    regulator_control_outcomes::{ControlOutcome, ControlOutcomeModel},
+    regulator_removals::{Culled, RemovalFate},
+    regulator_zones::{ZoneMembership, ZoningPolicy},
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_rng::{ActiveSurveillanceStream, ProcessRng},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
 };
 use bevy::ecs::system::SystemParam;
-use rand::{prelude::StdRng, Rng};
+use rand::Rng;
 
 /// Rate of detection
 #[readonly::make]
@@ -50,43 +70,356 @@ pub struct ActiveSurveillance<'a> {
     /// not to wipe out the infection on a farm. Typically 10%.
     // #[system_param(ignore)]
     remaining_proportion: Option<Res<'a, RemainingProportion>>,
+    /// If present, the infection isn't removed on detection, but by the
+    /// actions that are scheduled, see [crate::regulator_actions].
+    scheduled_actions: Option<Res<'a, ScheduledActions>>,
+    /// What happens to the removed infected animals, by default they are
+    /// culled.
+    removal_fate: Option<Res<'a, RemovalFate>>,
+    /// Replaces the coin flip with [RemainingProportion], if present.
+    control_outcome_model: Option<Res<'a, ControlOutcomeModel>>,
+    /// Farms within a zone are detected at a higher rate, see
+    /// [crate::regulator_zones].
+    zoning_policy: Option<Res<'a, ZoningPolicy>>,
+    /// Detected farms are only treated while there is capacity left, see
+    /// [crate::regulator_capacity].
+    control_capacity: Option<ResMut<'a, ControlCapacity>>,
+    /// Detected farms that wait for the [ControlCapacity] to be treated.
+    untreated_farms: Option<ResMut<'a, UntreatedFarms>>,
+    /// Order in which the detected farms are treated, oldest first by
+    /// default.
+    priority_policy: Option<Res<'a, PriorityPolicy>>,
+    /// Detections are confirmed by a herd test, see
+    /// [crate::diagnostic_tests].
+    surveillance_testing: Option<Res<'a, SurveillanceTesting>>,
+    /// Sent if a farm is culled, see [crate::regulatory_status].
+    status_changes: Option<ResMut<'a, Events<StatusChanged>>>,
+}
+
+/// The regulator that detected a farm.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DetectionSource {
+    /// See [update_active_surveillance].
+    Surveillance,
+    /// Clinical inspections within a zone, see [crate::regulator_zones].
+    Inspection,
+    /// Testing of traced contacts, see [crate::regulator_contact_tracing].
+    Tracing,
+}
+
+impl DetectionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectionSource::Surveillance => "surveillance",
+            DetectionSource::Inspection => "inspection",
+            DetectionSource::Tracing => "tracing",
+        }
+    }
+}
+
+impl std::fmt::Display for DetectionSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Event that is sent whenever an infected farm is detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FarmDetected {
+    pub farm_id: FarmId,
+    pub scenario_tick: Time,
+    pub source: DetectionSource,
+}
+
+/// Detected farms that are waiting to be treated, as there was no
+/// [ControlCapacity] left on the day that they were detected.
+///
+/// Add this as a resource along with a [ControlCapacity].
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(from = "Vec<FarmId>", into = "Vec<FarmId>")
+)]
+#[derive(Debug, Clone, Default)]
+pub struct UntreatedFarms {
+    /// Oldest first.
+    pending: Vec<FarmId>,
+    /// The farms in the queue, to look them up without going through it.
+    waiting: HashSet<FarmId>,
+}
+
+// only the queue is stored, as the waiting farms follow from it
+impl From<Vec<FarmId>> for UntreatedFarms {
+    fn from(pending: Vec<FarmId>) -> Self {
+        let waiting = pending.iter().copied().collect();
+        Self { pending, waiting }
+    }
+}
+
+impl From<UntreatedFarms> for Vec<FarmId> {
+    fn from(untreated_farms: UntreatedFarms) -> Self {
+        untreated_farms.pending
+    }
 }
 
-//TODO: return which farms with the infection where detected
-// and their infected status
+impl UntreatedFarms {
+    /// Queues the farm, unless it is already waiting.
+    pub fn enqueue(&mut self, farm_id: FarmId) -> bool {
+        if !self.waiting.insert(farm_id) {
+            return false;
+        }
+        self.pending.push(farm_id);
+        true
+    }
+
+    pub fn contains(&self, farm_id: FarmId) -> bool {
+        self.waiting.contains(&farm_id)
+    }
+
+    /// Empties the queue, and returns the farms oldest first.
+    pub fn take(&mut self) -> Vec<FarmId> {
+        self.waiting.clear();
+        std::mem::take(&mut self.pending)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
 
+/// Components of a detected farm that the infection is removed from.
+type SurveilledFarm = (
+    Entity,
+    &'static FarmId,
+    &'static mut HerdSize,
+    &'static mut Susceptible,
+    &'static mut Infected,
+    &'static mut Recovered,
+    Option<&'static mut Culled>,
+    Option<&'static FarmType>,
+    Option<&'static ZoneMembership>,
+    Option<&'static mut RegulatoryStatus>,
+    Option<&'static ContactRate>,
+);
+
+/// Detected farms are treated after all farms have been surveilled, with the
+/// farms that have waited the longest first, unless there is a
+/// [PriorityPolicy].
 pub fn update_active_surveillance(
     active_surveillance: ActiveSurveillance,
-    mut query: Query<&mut Infected>,
-    mut rng: ResMut<StdRng>,
+    mut query: Query<SurveilledFarm>,
+    mut rng: ResMut<ProcessRng<ActiveSurveillanceStream>>,
+    scenario_time: Res<ScenarioTime>,
+    mut detections: EventWriter<FarmDetected>,
 ) {
     let detection_rate = active_surveillance.detection_rate.as_ref().unwrap().0;
-    let remaining_proportion = active_surveillance.remaining_proportion.as_ref().unwrap().0;
+    let control_outcome_model = ControlOutcomeModel::or_remaining_proportion(
+        active_surveillance.control_outcome_model.as_deref(),
+        active_surveillance.remaining_proportion.as_deref(),
+    );
+    let removal_fate = active_surveillance
+        .removal_fate
+        .as_deref()
+        .copied()
+        .unwrap_or_default();
+    let scenario_tick = scenario_time.current_time();
+    let zoning_policy = active_surveillance.zoning_policy.as_deref();
+    let is_scheduled = active_surveillance.scheduled_actions.is_some();
+    let mut control_capacity = active_surveillance.control_capacity;
+    let mut untreated_farms = active_surveillance.untreated_farms;
+    let priority_policy = active_surveillance
+        .priority_policy
+        .as_deref()
+        .copied()
+        .unwrap_or_default();
+    let surveillance_testing = active_surveillance.surveillance_testing.as_deref();
+    let mut status_changes = active_surveillance.status_changes;
 
     // dbg!(detection_rate, remaining_proportion);
 
-    query.for_each_mut(|mut infected| {
-        if infected.0 > 0 {
-            //infected farm
-            if rng.gen_bool(
-                Probability::try_from(Rate::new((infected.0 as f64) * detection_rate.0).unwrap())
+    // farms to treat, by their id
+    let mut treatable = HashMap::new();
+    let mut detected = Vec::new();
+    query.for_each_mut(
+        |(
+            farm,
+            farm_id,
+            herd_size,
+            susceptible,
+            infected,
+            recovered,
+            _,
+            _,
+            zone_membership,
+            status,
+            contact_rate,
+        )| {
+            let is_suspected = infected.0 > 0 && {
+                //infected farm
+                let detection_rate_multiplier = zoning_policy.map_or(1., |zoning_policy| {
+                    zoning_policy.detection_rate_multiplier(zone_membership, scenario_tick)
+                });
+                rng.gen_bool(
+                    Probability::try_from(
+                        Rate::new(
+                            (infected.0 as f64) * detection_rate.0 * detection_rate_multiplier,
+                        )
+                        .unwrap(),
+                    )
                     .unwrap()
                     .0,
-            ) {
-                // the infection was detected
-                if rng.gen_bool(0.5) {
-                    //remove all infected, and make dead
-                    //FIXME: removed animals from farm, where did the
-                    // animals go? they ain't recovered!
-                    infected.0 = 0;
-                } else {
-                    // failed to remove the entire infection.
-                    infected.0 = ((infected.0 as f64) * remaining_proportion.0)
-                        .round_stoch(&mut *rng) as usize;
+                )
+            };
+            let is_known = status.as_ref().map_or(false, |status| {
+                matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+            });
+            let is_detected = match surveillance_testing {
+                None => is_suspected,
+                Some(surveillance_testing) => {
+                    let is_sampled = is_suspected
+                        || (!is_known && rng.gen_bool(surveillance_testing.testing_probability.0));
+                    is_sampled
+                        && control_capacity.as_mut().map_or(true, |capacity| {
+                            capacity.try_consume(ControlActivity::Test, *farm_id, scenario_tick)
+                        })
+                        && surveillance_testing
+                            .herd_test
+                            .apply(susceptible.0, infected.0, recovered.0, &mut *rng)
+                            .positive
+                }
+            };
+            if is_detected {
+                // the infection was detected (or a false positive)
+                detections.send(FarmDetected {
+                    farm_id: *farm_id,
+                    scenario_tick,
+                    source: DetectionSource::Surveillance,
+                });
+                if !is_scheduled {
+                    detected.push(*farm_id);
+                }
+            }
+            let is_waiting = untreated_farms
+                .as_ref()
+                .map_or(false, |untreated_farms| untreated_farms.contains(*farm_id));
+            let is_culled = status
+                .as_ref()
+                .map_or(false, |status| status.status == FarmStatus::Culled);
+            if (is_detected && !is_scheduled) || (is_waiting && !is_culled) {
+                let farm_priority = FarmPriority::new(herd_size.0, infected.0, contact_rate);
+                treatable.insert(*farm_id, (farm, farm_priority));
+            }
+        },
+    );
+
+    // the farms that have waited are treated before the newly detected ones,
+    // and the waiting farms that were culled in the meantime are dropped
+    let mut pending = untreated_farms
+        .as_mut()
+        .map(|untreated_farms| untreated_farms.take())
+        .unwrap_or_default()
+        .into_iter()
+        .chain(detected)
+        .unique()
+        .filter_map(|farm_id| {
+            treatable
+                .get(&farm_id)
+                .map(|(farm, farm_priority)| (farm_id, *farm, *farm_priority))
+        })
+        .collect_vec();
+    priority_policy.prioritise(&mut pending, |(_, _, farm_priority)| *farm_priority);
+
+    for (farm_id, farm, _) in pending {
+        let is_treated = control_capacity.as_mut().map_or(true, |capacity| {
+            capacity.try_consume(ControlActivity::Treat, farm_id, scenario_tick)
+        });
+        if !is_treated {
+            untreated_farms
+                .as_mut()
+                .expect("a `ControlCapacity` requires the `UntreatedFarms` as a resource")
+                .enqueue(farm_id);
+            continue;
+        }
+        let (
+            _,
+            _,
+            mut herd_size,
+            mut susceptible,
+            mut infected,
+            mut recovered,
+            culled,
+            farm_type,
+            _,
+            status,
+            _,
+        ) = query.get_mut(farm).unwrap();
+        let control_outcome = control_outcome_model.sample(herd_size.0, farm_type, &mut *rng);
+        let culled_animals = control_outcome.apply(
+            removal_fate,
+            &mut herd_size,
+            &mut susceptible,
+            &mut infected,
+            &mut recovered,
+            &mut *rng,
+        );
+        if let Some(mut culled) = culled {
+            culled.add(culled_animals, scenario_tick);
+        }
+        if let (ControlOutcome::WholeHerdCull, Some(mut status)) = (control_outcome, status) {
+            for to in [FarmStatus::Confirmed, FarmStatus::Culled] {
+                let status_changed = status.transition(farm_id, to, scenario_tick);
+                if let Some((status_changed, status_changes)) =
+                    status_changed.zip(status_changes.as_mut())
+                {
+                    status_changes.send(status_changed);
                 }
             }
         }
-    })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct DetectionsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for DetectionsRecorder {
+    const FILE_NAME: &'static str = "detections.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_detections].
+pub fn setup_detections_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create("detections.csv", &["scenario_tick", "farm_id", "source"]);
+    commands.insert_resource(DetectionsRecorder::from(csv_writer));
+}
+
+/// Records every detection along with its [DetectionSource], e.g. to compare
+/// tracing against surveillance.
+///
+/// The saved fields must correspond to [setup_detections_recording]
+pub fn record_detections(
+    mut detections: EventReader<FarmDetected>,
+    mut csv_file: ResMut<DetectionsRecorder>,
+) {
+    for detection in detections.iter() {
+        csv_file
+            .0
+            .serialize((
+                detection.scenario_tick,
+                detection.farm_id.0,
+                detection.source.as_str(),
+            ))
+            .unwrap();
+    }
 }
 
 mod tests;
@@ -1,5 +1,5 @@
 use super::*;
-use rand::SeedableRng;
+use crate::scenario_rng::{insert_random_streams, MasterSeed};
 
 #[test]
 fn test_active_surveillance() {
@@ -7,28 +7,212 @@ fn test_active_surveillance() {
     let mut mini_world = World::new();
 
     let farm_ids: Vec<Entity> = mini_world
-        .spawn_batch(vec![
-            (Infected::new(0),),
-            (Infected::new(1),),
-            (Infected::new(10),),
-            (Infected::new(100),),
-            (Infected::new(1000),),
-            (Infected::new(10000),),
-            (Infected::new(100000),),
-        ])
+        .spawn_batch(
+            vec![0, 1, 10, 100, 1000, 10000, 100000]
+                .into_iter()
+                .enumerate()
+                .map(|(farm_id, infected)| {
+                    let farm_id: FarmId = FarmId::new_single_population(farm_id);
+                    (
+                        farm_id,
+                        HerdSize::new_single_population(infected),
+                        Susceptible(0),
+                        Infected::new(infected),
+                        Recovered(0),
+                    )
+                }),
+        )
         .collect();
 
-    mini_world.insert_resource(StdRng::seed_from_u64(20210507 - 10));
+    insert_random_streams(&mut mini_world, MasterSeed(20210507 - 10));
     mini_world.insert_resource(DetectionRate(
         Rate::try_from(Probability::new(0.01).unwrap()).unwrap(),
     ));
     mini_world.insert_resource(RemainingProportion(Probability::new(0.01).unwrap()));
+    mini_world.insert_resource(ScenarioTime::new(1, None));
+    mini_world.insert_resource(Events::<FarmDetected>::default());
 
     let mut stage = SystemStage::single(update_active_surveillance.system());
     stage.run(&mut mini_world);
 
+    let detections = mini_world.get_resource::<Events<FarmDetected>>().unwrap();
+    let detected = detections
+        .get_reader()
+        .iter(detections)
+        .inspect(|detection| {
+            assert_eq!(detection.scenario_tick, 1);
+            assert_eq!(detection.source, DetectionSource::Surveillance);
+        })
+        .map(|detection| detection.farm_id)
+        .collect_vec();
+    // the uninfected farm is never detected, and the largest outbreaks always
+    let farm = FarmId::new_single_population;
+    assert!(!detected.contains(&farm(0)));
+    for farm_id in 4..=6 {
+        assert!(detected.contains(&farm(farm_id)));
+    }
+
     dbg!(farm_ids
         .into_iter()
         .map(|x| mini_world.get::<Infected>(x))
         .collect_vec());
 }
+
+#[test]
+fn test_detections_are_tested() {
+    let mut world = World::new();
+    for (farm_id, infected) in [(1, 0), (2, 0), (3, 50)] {
+        world.spawn().insert_bundle((
+            FarmId::<()>::new_single_population(farm_id),
+            HerdSize::new_single_population(100),
+            Susceptible(100 - infected),
+            Infected::new(infected),
+            Recovered(0),
+        ));
+    }
+    insert_random_streams(&mut world, MasterSeed(20210720));
+    world.insert_resource(DetectionRate(
+        Rate::try_from(Probability::new(0.5).unwrap()).unwrap(),
+    ));
+    world.insert_resource(RemainingProportion(Probability::new(1.).unwrap()));
+    world.insert_resource(ScenarioTime::new(1, None));
+    world.insert_resource(Events::<FarmDetected>::default());
+
+    let detected_farms = |world: &mut World, sensitivity, specificity| {
+        let test = crate::diagnostic_tests::DiagnosticTest::new(
+            Probability::new(sensitivity).unwrap(),
+            Probability::new(specificity).unwrap(),
+            1.,
+        );
+        world.insert_resource(SurveillanceTesting::new(
+            crate::diagnostic_tests::HerdTest::new(
+                test,
+                crate::diagnostic_tests::HerdTestDesign::Sample { sample_size: 10 },
+            ),
+            Probability::new(1.).unwrap(),
+        ));
+        world.insert_resource(Events::<FarmDetected>::default());
+        SystemStage::single(update_active_surveillance.system()).run(world);
+        let detections = world.get_resource::<Events<FarmDetected>>().unwrap();
+        detections
+            .get_reader()
+            .iter(detections)
+            .map(|detection| detection.farm_id.0)
+            .sorted()
+            .collect_vec()
+    };
+
+    // the infected farm is missed by an insensitive test
+    assert_eq!(detected_farms(&mut world, 0., 1.), Vec::<usize>::new());
+    // every sampled farm is falsely positive
+    assert_eq!(detected_farms(&mut world, 1., 0.), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_whole_herd_cull_is_culled() {
+    let mut world = World::new();
+    let farm = world
+        .spawn()
+        .insert_bundle((
+            FarmId::<()>::new_single_population(1),
+            HerdSize::new_single_population(100),
+            Susceptible(50),
+            Infected::new(50),
+            Recovered(0),
+            RegulatoryStatus::new(FarmStatus::Free, 0),
+        ))
+        .id();
+    insert_random_streams(&mut world, MasterSeed(20210721));
+    world.insert_resource(DetectionRate(
+        Rate::try_from(Probability::new(0.5).unwrap()).unwrap(),
+    ));
+    world.insert_resource(ControlOutcomeModel::from(vec![
+        crate::regulator_control_outcomes::ControlOutcomeRule::new(vec![(
+            ControlOutcome::WholeHerdCull,
+            1.,
+        )])
+        .unwrap(),
+    ]));
+    world.insert_resource(ScenarioTime::new(1, None));
+    world.insert_resource(Events::<FarmDetected>::default());
+    world.insert_resource(Events::<StatusChanged>::default());
+
+    SystemStage::single(update_active_surveillance.system()).run(&mut world);
+
+    assert_eq!(world.get::<HerdSize>(farm).unwrap().0, 0);
+    assert_eq!(
+        world.get::<RegulatoryStatus>(farm).unwrap().status,
+        FarmStatus::Culled
+    );
+    let status_changes = world.get_resource::<Events<StatusChanged>>().unwrap();
+    assert_eq!(
+        status_changes
+            .get_reader()
+            .iter(status_changes)
+            .map(|status_changed| status_changed.to)
+            .collect_vec(),
+        vec![FarmStatus::Confirmed, FarmStatus::Culled]
+    );
+}
+
+#[test]
+fn test_untreated_farms_wait() {
+    let mut world = World::new();
+    let farms = [10, 30, 20]
+        .iter()
+        .enumerate()
+        .map(|(farm_id, herd_size)| {
+            world
+                .spawn()
+                .insert_bundle((
+                    FarmId::<()>::new_single_population(farm_id),
+                    HerdSize::new_single_population(*herd_size),
+                    Susceptible(0),
+                    Infected::new(*herd_size),
+                    Recovered(0),
+                ))
+                .id()
+        })
+        .collect_vec();
+    insert_random_streams(&mut world, MasterSeed(20210721));
+    // every infected farm is detected
+    world.insert_resource(DetectionRate(Rate::new(10.).unwrap()));
+    world.insert_resource(ControlOutcomeModel::from(vec![
+        crate::regulator_control_outcomes::ControlOutcomeRule::new(vec![(
+            ControlOutcome::WholeHerdCull,
+            1.,
+        )])
+        .unwrap(),
+    ]));
+    world.insert_resource(ControlCapacity::default().with_limit(ControlActivity::Treat, 1));
+    world.insert_resource(UntreatedFarms::default());
+    world.insert_resource(PriorityPolicy::LargestHerd);
+    world.insert_resource(ScenarioTime::new(1, None));
+    world.insert_resource(Events::<FarmDetected>::default());
+
+    let mut stage = SystemStage::single(update_active_surveillance.system());
+    let mut culled = Vec::new();
+    for _ in 0..3 {
+        stage.run(&mut world);
+        culled.push(
+            farms
+                .iter()
+                .map(|farm| world.get::<HerdSize>(*farm).unwrap().0 == 0)
+                .collect_vec(),
+        );
+        world
+            .get_resource_mut::<ScenarioTime>()
+            .unwrap()
+            .update_time(1);
+    }
+    // one farm a day, largest first
+    assert_eq!(
+        culled,
+        vec![
+            vec![false, true, false],
+            vec![false, true, true],
+            vec![true, true, true]
+        ]
+    );
+    assert!(world.get_resource::<UntreatedFarms>().unwrap().is_empty());
+}
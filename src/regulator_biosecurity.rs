@@ -0,0 +1,58 @@
+//! Biosecurity measures on farms, that reduce the visits of indirect
+//! contacts, see [crate::indirect_contacts].
+//!
+//! A farm with [Biosecurity] receives the given proportion fewer visits. The
+//! regulator [update_biosecurity_on_detection] enforces [BiosecurityOnDetection]
+//! on every farm that is detected by the surveillance.
+//!
+
+use bevy::ecs::schedule::ShouldRun;
+
+use crate::{
+    farm_id_to_entity_map::FarmIdEntityMap, parameters::Probability, prelude::*,
+    regulator_active_surveillance::FarmDetected,
+};
+
+/// Proportion by which the visits to a farm are reduced.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
+pub struct Biosecurity(pub Probability);
+
+/// Biosecurity that is enforced on detected farms.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
+pub struct BiosecurityOnDetection(pub Probability);
+
+/// Run criteria for [update_biosecurity_on_detection].
+pub fn run_if_biosecurity_on_detection(
+    biosecurity_on_detection: Option<Res<BiosecurityOnDetection>>,
+) -> ShouldRun {
+    if biosecurity_on_detection.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+pub fn update_biosecurity_on_detection(
+    mut commands: Commands,
+    biosecurity_on_detection: Res<BiosecurityOnDetection>,
+    mut detections: EventReader<FarmDetected>,
+    farm_map: Res<FarmIdEntityMap>,
+    query: Query<&Biosecurity>,
+) {
+    for detection in detections.iter() {
+        let farm_entity = farm_map.0[&detection.farm_id];
+        // measures already in place are only ever tightened
+        let is_stricter = query.get(farm_entity).map_or(true, |biosecurity| {
+            (biosecurity.0).0 < (biosecurity_on_detection.0).0
+        });
+        if is_stricter {
+            commands
+                .entity(farm_entity)
+                .insert(Biosecurity::new(biosecurity_on_detection.0));
+        }
+    }
+}
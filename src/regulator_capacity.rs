@@ -0,0 +1,420 @@
+//! Daily capacity of the regulators to carry out control operations.
+//!
+//! A [ControlCapacity] limits the number of farms that are visited, culled,
+//! treated, tested or vaccinated per day, see [ControlActivity]. Every
+//! regulator consumes capacity from it when present, and defers the rest:
+//!
+//! * [crate::regulator_actions::execute_scheduled_actions] carries the
+//!   pending actions over to the next day,
+//! * [crate::regulator_active_surveillance::update_active_surveillance] keeps
+//!   the detected farms that aren't treated in the
+//!   [crate::regulator_active_surveillance::UntreatedFarms], for the next day,
+//! * [crate::regulator_zones::update_zone_inspections] inspects the remaining
+//!   farms once there is capacity again,
+//! * [crate::regulator_contact_tracing::test_traced_farms] tests the
+//!   remaining traced farms the next day,
+//! * [crate::regulator_vaccination::vaccinate_farms] keeps the remaining
+//!   farms in the queue,
+//! * [crate::diagnostic_tests::update_surveillance_testing] skips the
+//!   remaining farms.
+//!
+//! All but the last give the capacity to the pending farms in the order of
+//! the [PriorityPolicy], oldest first by default.
+//!
+//! Every farm that an operation could not be carried out on counts as unmet
+//! demand, once per day, which is put out by [collect_control_demand], and
+//! recorded by [record_control_demand].
+//!
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::File,
+};
+
+use bevy::ecs::schedule::ShouldRun;
+use csv::Writer;
+
+use crate::{
+    between_herd_spread_model::ContactRate,
+    csv_outputs::CsvOutputs,
+    populations::FarmId,
+    prelude::*,
+    regulator_actions::RegulatoryAction,
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ControlActivity {
+    /// Visits to a farm, i.e. confirmations and clinical inspections.
+    Visit,
+    Cull,
+    Treat,
+    /// Herd tests, of traced or randomly sampled farms.
+    Test,
+    Vaccinate,
+}
+
+impl ControlActivity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlActivity::Visit => "visit",
+            ControlActivity::Cull => "cull",
+            ControlActivity::Treat => "treat",
+            ControlActivity::Test => "test",
+            ControlActivity::Vaccinate => "vaccinate",
+        }
+    }
+}
+
+impl std::fmt::Display for ControlActivity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<RegulatoryAction> for ControlActivity {
+    fn from(action: RegulatoryAction) -> Self {
+        match action {
+            RegulatoryAction::Confirm => ControlActivity::Visit,
+            RegulatoryAction::Cull => ControlActivity::Cull,
+            RegulatoryAction::Treat => ControlActivity::Treat,
+        }
+    }
+}
+
+/// Most farms per day for each [ControlActivity]. Activities that aren't
+/// listed are unlimited.
+///
+/// The capacity that is used up, and the farms that were left waiting, are
+/// kept for the current day only. Thus only the limits are (de)serialised,
+/// e.g. as `{"cull": 5, "visit": 20}`.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(
+        from = "HashMap<ControlActivity, usize>",
+        into = "HashMap<ControlActivity, usize>"
+    )
+)]
+#[derive(Debug, Clone, Default)]
+pub struct ControlCapacity {
+    daily_limits: HashMap<ControlActivity, usize>,
+    scenario_tick: Time,
+    used: HashMap<ControlActivity, usize>,
+    unmet: HashMap<ControlActivity, HashSet<FarmId>>,
+}
+
+impl From<HashMap<ControlActivity, usize>> for ControlCapacity {
+    fn from(daily_limits: HashMap<ControlActivity, usize>) -> Self {
+        Self {
+            daily_limits,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ControlCapacity> for HashMap<ControlActivity, usize> {
+    fn from(control_capacity: ControlCapacity) -> Self {
+        control_capacity.daily_limits
+    }
+}
+
+impl ControlCapacity {
+    pub fn with_limit(mut self, activity: ControlActivity, daily_limit: usize) -> Self {
+        self.daily_limits.insert(activity, daily_limit);
+        self
+    }
+
+    pub fn daily_limit(&self, activity: ControlActivity) -> Option<usize> {
+        self.daily_limits.get(&activity).copied()
+    }
+
+    /// Forgets the usage of the previous day.
+    fn start_day(&mut self, scenario_tick: Time) {
+        if self.scenario_tick != scenario_tick {
+            self.scenario_tick = scenario_tick;
+            self.used.clear();
+            self.unmet.clear();
+        }
+    }
+
+    /// Capacity left at `scenario_tick`, or `None` if unlimited.
+    pub fn remaining(&self, activity: ControlActivity, scenario_tick: Time) -> Option<usize> {
+        let used = if self.scenario_tick == scenario_tick {
+            self.used.get(&activity).copied().unwrap_or_default()
+        } else {
+            0
+        };
+        self.daily_limit(activity)
+            .map(|daily_limit| daily_limit.saturating_sub(used))
+    }
+
+    /// Uses up the capacity for the farm, or counts it as unmet demand if
+    /// there is none left. A farm that is tried again on the same day is
+    /// only counted once.
+    pub fn try_consume(
+        &mut self,
+        activity: ControlActivity,
+        farm_id: FarmId,
+        scenario_tick: Time,
+    ) -> bool {
+        self.start_day(scenario_tick);
+        if self.remaining(activity, scenario_tick) == Some(0) {
+            self.unmet.entry(activity).or_default().insert(farm_id);
+            return false;
+        }
+        *self.used.entry(activity).or_default() += 1;
+        true
+    }
+
+    /// Counts farms that are left waiting, without trying them one by one.
+    pub fn add_unmet(
+        &mut self,
+        activity: ControlActivity,
+        scenario_tick: Time,
+        farms: impl IntoIterator<Item = FarmId>,
+    ) {
+        self.start_day(scenario_tick);
+        self.unmet.entry(activity).or_default().extend(farms);
+    }
+
+    pub fn used(&self, activity: ControlActivity, scenario_tick: Time) -> usize {
+        if self.scenario_tick != scenario_tick {
+            return 0;
+        }
+        self.used.get(&activity).copied().unwrap_or_default()
+    }
+
+    pub fn unmet(&self, activity: ControlActivity, scenario_tick: Time) -> usize {
+        if self.scenario_tick != scenario_tick {
+            return 0;
+        }
+        self.unmet.get(&activity).map_or(0, HashSet::len)
+    }
+}
+
+/// Order in which pending farms are given the available capacity.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityPolicy {
+    /// Oldest first.
+    Fifo,
+    LargestHerd,
+    /// Highest [FarmPriority::infection_pressure] first.
+    InfectionPressure,
+}
+
+impl Default for PriorityPolicy {
+    fn default() -> Self {
+        PriorityPolicy::Fifo
+    }
+}
+
+impl PriorityPolicy {
+    /// Orders the `pending` farms, that are given oldest first, by their
+    /// priority. Farms of equal priority remain oldest first.
+    pub fn prioritise<T>(&self, pending: &mut [T], farm_priority: impl Fn(&T) -> FarmPriority) {
+        match self {
+            PriorityPolicy::Fifo => {}
+            PriorityPolicy::LargestHerd => {
+                pending.sort_by_key(|x| Reverse(farm_priority(x).herd_size));
+            }
+            PriorityPolicy::InfectionPressure => {
+                pending.sort_by(|a, b| {
+                    farm_priority(b)
+                        .infection_pressure
+                        .partial_cmp(&farm_priority(a).infection_pressure)
+                        .unwrap()
+                });
+            }
+        }
+    }
+}
+
+/// What the [PriorityPolicy] knows about a pending farm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FarmPriority {
+    pub herd_size: usize,
+    /// The expected number of infected animals that are shipped off the farm
+    /// per day, i.e. the prevalence times the [ContactRate], or just the
+    /// prevalence for farms without one.
+    pub infection_pressure: f64,
+}
+
+impl FarmPriority {
+    pub fn new(herd_size: usize, infected: usize, contact_rate: Option<&ContactRate>) -> Self {
+        let prevalence = if herd_size == 0 {
+            0.
+        } else {
+            infected as f64 / herd_size as f64
+        };
+        Self {
+            herd_size,
+            infection_pressure: prevalence
+                * contact_rate.map_or(1., |contact_rate| contact_rate.0 .0),
+        }
+    }
+}
+
+/// Run criteria for [collect_control_demand].
+pub fn run_if_control_capacity(control_capacity: Option<Res<ControlCapacity>>) -> ShouldRun {
+    if control_capacity.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// The usage of the [ControlCapacity] in a tick, for the limited activities.
+#[derive(Debug, Clone)]
+pub struct ControlDemand {
+    pub scenario_tick: Time,
+    /// Activity, farms it was carried out on, and farms that were left waiting.
+    pub activities: Vec<(ControlActivity, usize, usize)>,
+}
+
+/// Add this after the regulators.
+pub fn collect_control_demand(
+    control_capacity: Res<ControlCapacity>,
+    scenario_time: Res<ScenarioTime>,
+) -> Option<ControlDemand> {
+    let scenario_tick = scenario_time.current_time();
+    let activities = control_capacity
+        .daily_limits
+        .keys()
+        .copied()
+        .sorted()
+        .map(|activity| {
+            (
+                activity,
+                control_capacity.used(activity, scenario_tick),
+                control_capacity.unmet(activity, scenario_tick),
+            )
+        })
+        .filter(|(_, used, unmet)| *used > 0 || *unmet > 0)
+        .collect_vec();
+    if activities.is_empty() {
+        None
+    } else {
+        Some(ControlDemand {
+            scenario_tick,
+            activities,
+        })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct ControlDemandRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for ControlDemandRecorder {
+    const FILE_NAME: &'static str = "control_demand.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_control_demand].
+pub fn setup_control_demand_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "control_demand.csv",
+        &["scenario_tick", "activity", "used", "unmet"],
+    );
+    commands.insert_resource(ControlDemandRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_control_demand_recording]
+pub fn record_control_demand(
+    In(events): In<Option<ControlDemand>>,
+    mut csv_file: ResMut<ControlDemandRecorder>,
+) -> Option<ControlDemand> {
+    if let Some(ControlDemand {
+        scenario_tick,
+        activities,
+    }) = &events
+    {
+        for (activity, used, unmet) in activities {
+            csv_file
+                .0
+                .serialize((scenario_tick, activity.as_str(), used, unmet))
+                .unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::Rate;
+
+    #[test]
+    fn test_daily_limits() {
+        let mut capacity = ControlCapacity::default().with_limit(ControlActivity::Cull, 2);
+        assert_eq!(capacity.remaining(ControlActivity::Cull, 1), Some(2));
+        assert_eq!(capacity.remaining(ControlActivity::Test, 1), None);
+
+        let farm_id = FarmId::new_single_population;
+        let consumed = (0..4)
+            .map(|x| capacity.try_consume(ControlActivity::Cull, farm_id(x), 1))
+            .collect_vec();
+        assert_eq!(consumed, vec![true, true, false, false]);
+        assert!(capacity.try_consume(ControlActivity::Test, farm_id(0), 1));
+        // farm 3 is already waiting
+        capacity.add_unmet(ControlActivity::Cull, 1, (3..6).map(farm_id));
+        assert!(!capacity.try_consume(ControlActivity::Cull, farm_id(2), 1));
+        assert_eq!(capacity.used(ControlActivity::Cull, 1), 2);
+        assert_eq!(capacity.unmet(ControlActivity::Cull, 1), 4);
+
+        // a new day
+        assert_eq!(capacity.remaining(ControlActivity::Cull, 2), Some(2));
+        assert!(capacity.try_consume(ControlActivity::Cull, farm_id(2), 2));
+        assert_eq!(capacity.used(ControlActivity::Cull, 2), 1);
+        assert_eq!(capacity.unmet(ControlActivity::Cull, 2), 0);
+    }
+
+    #[test]
+    fn test_limits_only_are_serialised() {
+        let mut capacity: ControlCapacity =
+            serde_json::from_str(r#"{"cull": 5, "visit": 20}"#).unwrap();
+        assert_eq!(capacity.daily_limit(ControlActivity::Cull), Some(5));
+        assert_eq!(capacity.daily_limit(ControlActivity::Visit), Some(20));
+        assert_eq!(capacity.daily_limit(ControlActivity::Test), None);
+
+        capacity.try_consume(ControlActivity::Cull, FarmId::new_single_population(1), 1);
+        let capacity: ControlCapacity =
+            serde_json::from_str(&serde_json::to_string(&capacity).unwrap()).unwrap();
+        assert_eq!(capacity.daily_limit(ControlActivity::Cull), Some(5));
+        assert_eq!(capacity.remaining(ControlActivity::Cull, 1), Some(5));
+    }
+
+    #[test]
+    fn test_priority_policies() {
+        let contact_rate = ContactRate::new(Rate::new(4.).unwrap());
+        let farms = vec![
+            (1, FarmPriority::new(100, 10, None)),
+            (2, FarmPriority::new(500, 5, None)),
+            (3, FarmPriority::new(50, 5, Some(&contact_rate))),
+            (4, FarmPriority::new(500, 50, None)),
+        ];
+        let prioritised = |policy: PriorityPolicy| {
+            let mut pending = farms.clone();
+            policy.prioritise(&mut pending, |(_, farm_priority)| *farm_priority);
+            pending
+                .into_iter()
+                .map(|(farm_id, _)| farm_id)
+                .collect_vec()
+        };
+        assert_eq!(prioritised(PriorityPolicy::Fifo), vec![1, 2, 3, 4]);
+        assert_eq!(prioritised(PriorityPolicy::LargestHerd), vec![2, 4, 1, 3]);
+        assert_eq!(
+            prioritised(PriorityPolicy::InfectionPressure),
+            vec![3, 1, 4, 2]
+        );
+    }
+}
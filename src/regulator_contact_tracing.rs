@@ -0,0 +1,454 @@
+//! Tracing of the recent contacts of confirmed farms.
+//!
+//! The [ContactHistory] holds the contacts that actually took place, i.e. the
+//! [crate::populations::AdjacentFarms] that shipments were sent to by
+//! [crate::between_herd_spread_model], and the movements that are chained
+//! through [record_movement_contacts] and [record_market_contacts]. While it
+//! is present, the between-herd model samples the shipments of every farm,
+//! not just the infected ones, such that uninfected farms can be traced.
+//!
+//! Whenever a farm is [FarmStatus::Confirmed], [trace_contacts] goes back
+//! (to the farms that it received animals from) and forward (to the farms it
+//! sent animals to) through its contacts within the [TracingPolicy::window].
+//! Each contact is found with [TracingPolicy::completeness], and is then
+//! flagged for testing after the [TracingPolicy::delay]. The traced farms are
+//! tested by [test_traced_farms], where a positive herd test is a
+//! [FarmDetected] with [DetectionSource::Tracing], see
+//! [crate::regulator_active_surveillance::record_detections].
+//!
+//! With a [ControlCapacity], the traced farms that aren't tested on the day
+//! they are due, are tested the next day. The due farms are tested in the
+//! order of the [PriorityPolicy].
+//!
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use bevy::ecs::schedule::ShouldRun;
+
+use crate::{
+    animal_movements::MovementEvents,
+    between_herd_spread_model::ContactRate,
+    diagnostic_tests::{HerdTest, HerdTested},
+    farm_id_to_entity_map::FarmIdEntityMap,
+    markets::MarketEvents,
+    parameters::Probability,
+    populations::{FarmId, HerdSize},
+    prelude::*,
+    regulator_actions::ActionDelay,
+    regulator_active_surveillance::{DetectionSource, FarmDetected},
+    regulator_capacity::{ControlActivity, ControlCapacity, FarmPriority, PriorityPolicy},
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_rng::{ContactTracingStream, ProcessRng, TracedTestingStream},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+/// Contacts between farms, from origin to destination, by the tick they took
+/// place in.
+///
+/// Add as a resource to keep track of the contacts.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Default)]
+pub struct ContactHistory {
+    contacts: BTreeMap<Time, Vec<(FarmId, FarmId)>>,
+}
+
+impl ContactHistory {
+    pub fn add(&mut self, scenario_tick: Time, origin: FarmId, destination: FarmId) {
+        self.contacts
+            .entry(scenario_tick)
+            .or_default()
+            .push((origin, destination));
+    }
+
+    /// Drops the contacts before `scenario_tick`, that can no longer be traced.
+    pub fn forget_before(&mut self, scenario_tick: Time) {
+        self.contacts = self.contacts.split_off(&scenario_tick);
+    }
+
+    /// The farms that `farm_id` received animals from, and the farms it sent
+    /// animals to, from tick `since` onwards.
+    pub fn contacts_of(
+        &self,
+        farm_id: FarmId,
+        since: Time,
+    ) -> (BTreeSet<FarmId>, BTreeSet<FarmId>) {
+        let mut backward = BTreeSet::new();
+        let mut forward = BTreeSet::new();
+        for (origin, destination) in self.contacts.range(since..).flat_map(|(_, x)| x) {
+            if *destination == farm_id && *origin != farm_id {
+                backward.insert(*origin);
+            }
+            if *origin == farm_id && *destination != farm_id {
+                forward.insert(*destination);
+            }
+        }
+        (backward, forward)
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+}
+
+/// Add as a resource to trace the contacts of confirmed farms.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct TracingPolicy {
+    /// Days before the confirmation that the contacts are traced for.
+    pub window: Time,
+    /// Probability that a contact is found.
+    pub completeness: Probability,
+    /// Days until a traced farm is tested.
+    pub delay: ActionDelay,
+    pub herd_test: HerdTest,
+    #[new(value = "true")]
+    pub backward: bool,
+    #[new(value = "true")]
+    pub forward: bool,
+}
+
+impl TracingPolicy {
+    /// Only trace the farms that animals came from (`backward`), or went to
+    /// (`forward`).
+    #[must_use]
+    pub fn with_directions(mut self, backward: bool, forward: bool) -> Self {
+        self.backward = backward;
+        self.forward = forward;
+        self
+    }
+}
+
+type TracedQueue = BTreeMap<Time, Vec<FarmId>>;
+
+/// Traced farms, by the tick they are due to be tested.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(from = "TracedQueue", into = "TracedQueue")
+)]
+#[derive(Debug, Clone, Default)]
+pub struct TracedFarms {
+    queue: TracedQueue,
+    /// The farms in the queue, to look them up without going through it.
+    pending: HashSet<FarmId>,
+}
+
+// only the queue is stored, as the pending farms follow from it
+impl From<TracedQueue> for TracedFarms {
+    fn from(queue: TracedQueue) -> Self {
+        let pending = queue.values().flatten().copied().collect();
+        Self { queue, pending }
+    }
+}
+
+impl From<TracedFarms> for TracedQueue {
+    fn from(traced_farms: TracedFarms) -> Self {
+        traced_farms.queue
+    }
+}
+
+impl TracedFarms {
+    /// Flags the farm for testing, unless it is already awaiting a test.
+    pub fn flag(&mut self, farm_id: FarmId, due: Time) -> bool {
+        if !self.pending.insert(farm_id) {
+            return false;
+        }
+        self.queue.entry(due).or_default().push(farm_id);
+        true
+    }
+
+    pub fn is_pending(&self, farm_id: FarmId) -> bool {
+        self.pending.contains(&farm_id)
+    }
+
+    /// Farms that are due for testing at `scenario_tick`.
+    pub fn take_due(&mut self, scenario_tick: Time) -> Vec<FarmId> {
+        let not_due = self.queue.split_off(&(scenario_tick + 1));
+        let due_farms = std::mem::replace(&mut self.queue, not_due)
+            .into_iter()
+            .flat_map(|(_, farms)| farms)
+            .collect_vec();
+        for farm_id in &due_farms {
+            self.pending.remove(farm_id);
+        }
+        due_farms
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Run criteria for [trace_contacts] and [test_traced_farms].
+pub fn run_if_tracing(tracing_policy: Option<Res<TracingPolicy>>) -> ShouldRun {
+    if tracing_policy.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Add a [ContactHistory] and [TracedFarms] as resources along with this.
+pub fn trace_contacts(
+    policy: Res<TracingPolicy>,
+    mut status_changes: EventReader<StatusChanged>,
+    mut contact_history: ResMut<ContactHistory>,
+    mut traced_farms: ResMut<TracedFarms>,
+    mut rng: ResMut<ProcessRng<ContactTracingStream>>,
+    scenario_time: Res<ScenarioTime>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    let since = scenario_tick.saturating_sub(policy.window);
+    for status_changed in status_changes
+        .iter()
+        .filter(|status_changed| status_changed.to == FarmStatus::Confirmed)
+    {
+        let (backward, forward) = contact_history.contacts_of(status_changed.farm_id, since);
+        let backward = backward.into_iter().filter(|_| policy.backward);
+        let forward = forward.into_iter().filter(|_| policy.forward);
+        for contact in backward.chain(forward) {
+            if !rng.gen_bool(policy.completeness.0) {
+                // the contact was missed
+                continue;
+            }
+            let delay = policy.delay.sample(&mut *rng);
+            traced_farms.flag(contact, scenario_tick + delay);
+        }
+    }
+    contact_history.forget_before(since);
+}
+
+/// Tests the traced farms that are due, except those already confirmed or
+/// culled.
+#[allow(clippy::too_many_arguments)]
+pub fn test_traced_farms(
+    policy: Res<TracingPolicy>,
+    mut traced_farms: ResMut<TracedFarms>,
+    query: Query<(
+        &Susceptible,
+        &Infected,
+        &Recovered,
+        Option<&RegulatoryStatus>,
+    )>,
+    farm_map: Res<FarmIdEntityMap>,
+    mut rng: ResMut<ProcessRng<TracedTestingStream>>,
+    scenario_time: Res<ScenarioTime>,
+    mut detections: EventWriter<FarmDetected>,
+    mut herd_tests: Option<ResMut<Events<HerdTested>>>,
+    mut control_capacity: Option<ResMut<ControlCapacity>>,
+    priority_policy: Option<Res<PriorityPolicy>>,
+    priorities: Query<(&HerdSize, &Infected, Option<&ContactRate>)>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    let mut due_farms = traced_farms.take_due(scenario_tick);
+    if let Some(priority_policy) = priority_policy {
+        priority_policy.prioritise(&mut due_farms, |farm_id| {
+            let (herd_size, infected, contact_rate) = priorities
+                .get(farm_map.0[farm_id])
+                .expect("failed to find traced farm");
+            FarmPriority::new(herd_size.0, infected.0, contact_rate)
+        });
+    }
+    for farm_id in due_farms {
+        let (susceptible, infected, recovered, status) = query
+            .get(farm_map.0[&farm_id])
+            .expect("failed to find traced farm");
+        if status.map_or(false, |status| {
+            matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+        }) {
+            continue;
+        }
+        if !control_capacity.as_mut().map_or(true, |capacity| {
+            capacity.try_consume(ControlActivity::Test, farm_id, scenario_tick)
+        }) {
+            traced_farms.flag(farm_id, scenario_tick + 1);
+            continue;
+        }
+        let result = policy
+            .herd_test
+            .apply(susceptible.0, infected.0, recovered.0, &mut *rng);
+        if let Some(herd_tests) = herd_tests.as_mut() {
+            herd_tests.send(HerdTested {
+                farm_id,
+                scenario_tick,
+                result,
+                is_infected: infected.0 > 0,
+            });
+        }
+        if result.positive {
+            detections.send(FarmDetected {
+                farm_id,
+                scenario_tick,
+                source: DetectionSource::Tracing,
+            });
+        }
+    }
+}
+
+/// Chain this after [crate::animal_movements::update_animal_movements], or
+/// [crate::movement_register::replay_registered_movements]. This does nothing
+/// without a [ContactHistory].
+pub fn record_movement_contacts(
+    In(events): In<Option<MovementEvents>>,
+    contact_history: Option<ResMut<ContactHistory>>,
+) -> Option<MovementEvents> {
+    if let (
+        Some(MovementEvents {
+            scenario_tick,
+            movements,
+        }),
+        Some(mut contact_history),
+    ) = (&events, contact_history)
+    {
+        for movement in movements {
+            contact_history.add(*scenario_tick, movement.origin, movement.destination);
+        }
+    }
+    events
+}
+
+/// Chain this after [crate::markets::update_markets]. This does nothing
+/// without a [ContactHistory].
+pub fn record_market_contacts(
+    In(events): In<Option<MarketEvents>>,
+    contact_history: Option<ResMut<ContactHistory>>,
+) -> Option<MarketEvents> {
+    if let (
+        Some(MarketEvents {
+            scenario_tick,
+            sales,
+        }),
+        Some(mut contact_history),
+    ) = (&events, contact_history)
+    {
+        for sale in sales {
+            contact_history.add(
+                *scenario_tick,
+                sale.movement.origin,
+                sale.movement.destination,
+            );
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic_tests::{DiagnosticTest, HerdTestDesign};
+
+    #[test]
+    fn test_contacts_within_window() {
+        let farm = FarmId::new_single_population;
+        let mut contact_history = ContactHistory::default();
+        contact_history.add(1, farm(1), farm(2));
+        contact_history.add(5, farm(3), farm(2));
+        contact_history.add(6, farm(2), farm(4));
+        contact_history.add(6, farm(2), farm(4));
+        contact_history.add(7, farm(4), farm(5));
+
+        assert_eq!(
+            contact_history.contacts_of(farm(2), 0),
+            (
+                [farm(1), farm(3)].iter().copied().collect(),
+                [farm(4)].iter().copied().collect()
+            )
+        );
+        contact_history.forget_before(5);
+        assert_eq!(contact_history.len(), 4);
+        assert_eq!(
+            contact_history.contacts_of(farm(2), 0).0,
+            [farm(3)].iter().copied().collect()
+        );
+    }
+
+    #[test]
+    fn test_traced_farms_are_tested() {
+        let mut world = World::new();
+        crate::scenario_rng::insert_random_streams(
+            &mut world,
+            crate::scenario_rng::MasterSeed(20210720),
+        );
+        world.insert_resource(ScenarioTime::new(10, None));
+        world.insert_resource(Events::<StatusChanged>::default());
+        world.insert_resource(Events::<FarmDetected>::default());
+        let perfect_test = HerdTest::new(
+            DiagnosticTest::new(
+                Probability::new(1.).unwrap(),
+                Probability::new(1.).unwrap(),
+                1.,
+            ),
+            HerdTestDesign::Sample { sample_size: 100 },
+        );
+        world.insert_resource(TracingPolicy::new(
+            7,
+            Probability::new(1.).unwrap(),
+            ActionDelay::Fixed(2),
+            perfect_test,
+        ));
+        world.insert_resource(TracedFarms::default());
+
+        let farm = FarmId::new_single_population;
+        let mut farm_map = std::collections::HashMap::new();
+        // farm 2 infected farm 3 through a shipment, farm 1 is outside the window
+        for (farm_id, infected) in [(1, 0), (2, 10), (3, 10), (4, 0)] {
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    Susceptible(100 - infected),
+                    Infected(infected),
+                    Recovered(0),
+                ))
+                .id();
+            farm_map.insert(farm(farm_id), entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map));
+        let mut contact_history = ContactHistory::default();
+        contact_history.add(1, farm(1), farm(2));
+        contact_history.add(8, farm(2), farm(3));
+        contact_history.add(9, farm(4), farm(2));
+        world.insert_resource(contact_history);
+
+        world
+            .get_resource_mut::<Events<StatusChanged>>()
+            .unwrap()
+            .send(StatusChanged {
+                farm_id: farm(2),
+                from: FarmStatus::Suspected,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 10,
+            });
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(trace_contacts.system().label("trace"))
+            .add_system(test_traced_farms.system().after("trace"));
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<TracedFarms>().unwrap().len(), 2);
+
+        world.insert_resource(ScenarioTime::new(12, None));
+        stage.run(&mut world);
+        assert!(world.get_resource::<TracedFarms>().unwrap().is_empty());
+        let detections = world.get_resource::<Events<FarmDetected>>().unwrap();
+        let detections = bevy::app::ManualEventReader::<FarmDetected>::default()
+            .iter(detections)
+            .copied()
+            .collect_vec();
+        assert_eq!(
+            detections,
+            vec![FarmDetected {
+                farm_id: farm(3),
+                scenario_tick: 12,
+                source: DetectionSource::Tracing,
+            }]
+        );
+    }
+}
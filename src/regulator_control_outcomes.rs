@@ -0,0 +1,385 @@
+//! What the regulators do on a detected farm.
+//!
+//! The [ControlOutcomeModel] is a list of [ControlOutcomeRule]s, where the
+//! first rule that applies to a farm (by its herd size and [FarmType]) picks
+//! one of its [ControlOutcome]s at random, in proportion to their weights.
+//! Farms that no rule applies to are left as they are. The weights of a rule
+//! must be non-negative, and not all zero.
+//!
+//! Without a model, the regulators fall back to
+//! [ControlOutcomeModel::from_remaining_proportion], i.e. a fair coin flip
+//! between removing every infected animal, and leaving the
+//! [RemainingProportion] of them.
+//!
+//! The model can be read from a json-file, e.g.
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     {
+//!       "max_herd_size": 50,
+//!       "outcomes": [["whole_herd_cull", 1.0]]
+//!     },
+//!     {
+//!       "outcomes": [
+//!         [{ "test_and_remove": { "efficacy": 0.9 } }, 0.8],
+//!         ["no_action", 0.2]
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+
+use std::borrow::Cow;
+
+use anyhow::ensure;
+use rand::distributions::WeightedIndex;
+use rand_distr::Binomial;
+
+use crate::{
+    parameters::Probability,
+    populations::{FarmType, HerdSize},
+    prelude::*,
+    regulator_active_surveillance::RemainingProportion,
+    regulator_removals::RemovalFate,
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlOutcome {
+    NoAction,
+    /// Every animal on the farm is culled.
+    WholeHerdCull,
+    /// Each infected animal is found with `efficacy`, and removed according
+    /// to the [RemovalFate].
+    TestAndRemove {
+        efficacy: Probability,
+    },
+    /// Each infected animal is cured with `efficacy`, and recovers.
+    Treatment {
+        efficacy: Probability,
+    },
+}
+
+impl ControlOutcome {
+    /// Carries out the outcome on a farm, and returns the number of culled
+    /// animals.
+    pub fn apply<R: Rng + ?Sized>(
+        &self,
+        removal_fate: RemovalFate,
+        herd_size: &mut HerdSize,
+        susceptible: &mut Susceptible,
+        infected: &mut Infected,
+        recovered: &mut Recovered,
+        rng: &mut R,
+    ) -> usize {
+        match *self {
+            ControlOutcome::NoAction => 0,
+            ControlOutcome::WholeHerdCull => {
+                let culled = herd_size.0;
+                herd_size.remove_animals(culled);
+                susceptible.0 = 0;
+                infected.0 = 0;
+                recovered.0 = 0;
+                culled
+            }
+            ControlOutcome::TestAndRemove { efficacy } => {
+                let removed = Binomial::new(infected.0 as u64, efficacy.0)
+                    .unwrap()
+                    .sample(rng) as usize;
+                removal_fate.remove_infected(removed, herd_size, susceptible, infected, recovered)
+            }
+            ControlOutcome::Treatment { efficacy } => {
+                let cured = Binomial::new(infected.0 as u64, efficacy.0)
+                    .unwrap()
+                    .sample(rng) as usize;
+                RemovalFate::Recovered.remove_infected(
+                    cured,
+                    herd_size,
+                    susceptible,
+                    infected,
+                    recovered,
+                )
+            }
+        }
+    }
+}
+
+/// Outcomes for the farms within a range of herd sizes, and of a farm type.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UncheckedControlOutcomeRule")
+)]
+#[derive(Debug, Clone, PartialEq)]
+#[readonly::make]
+pub struct ControlOutcomeRule {
+    /// Applies to every farm type if not given.
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub farm_type: Option<FarmType>,
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub min_herd_size: Option<usize>,
+    /// Exclusive.
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub max_herd_size: Option<usize>,
+    /// Outcomes along with their relative weights.
+    pub outcomes: Vec<(ControlOutcome, f64)>,
+}
+
+impl ControlOutcomeRule {
+    /// Rule that applies to every farm. The weights must be non-negative,
+    /// and not all zero.
+    pub fn new(outcomes: Vec<(ControlOutcome, f64)>) -> Result<Self> {
+        ensure!(
+            outcomes
+                .iter()
+                .all(|(_, weight)| weight.is_finite() && *weight >= 0.),
+            "weights of the control outcomes must be non-negative, got {:?}",
+            outcomes
+        );
+        ensure!(
+            outcomes.iter().any(|(_, weight)| *weight > 0.),
+            "weights of the control outcomes must not all be zero, got {:?}",
+            outcomes
+        );
+        Ok(Self {
+            farm_type: None,
+            min_herd_size: None,
+            max_herd_size: None,
+            outcomes,
+        })
+    }
+
+    #[must_use]
+    pub fn with_farm_type(mut self, farm_type: FarmType) -> Self {
+        self.farm_type = Some(farm_type);
+        self
+    }
+
+    #[must_use]
+    pub fn with_herd_sizes(mut self, min: Option<usize>, max: Option<usize>) -> Self {
+        self.min_herd_size = min;
+        self.max_herd_size = max;
+        self
+    }
+
+    pub fn applies_to(&self, herd_size: usize, farm_type: Option<&FarmType>) -> bool {
+        self.farm_type
+            .map_or(true, |rule_type| Some(&rule_type) == farm_type)
+            && self.min_herd_size.map_or(true, |min| herd_size >= min)
+            && self.max_herd_size.map_or(true, |max| herd_size < max)
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(Deserialize)]
+struct UncheckedControlOutcomeRule {
+    #[serde(default)]
+    farm_type: Option<FarmType>,
+    #[serde(default)]
+    min_herd_size: Option<usize>,
+    #[serde(default)]
+    max_herd_size: Option<usize>,
+    outcomes: Vec<(ControlOutcome, f64)>,
+}
+
+#[cfg(feature = "serialize")]
+impl std::convert::TryFrom<UncheckedControlOutcomeRule> for ControlOutcomeRule {
+    type Error = anyhow::Error;
+
+    fn try_from(unchecked: UncheckedControlOutcomeRule) -> Result<Self> {
+        let rule = Self::new(unchecked.outcomes)?
+            .with_herd_sizes(unchecked.min_herd_size, unchecked.max_herd_size);
+        Ok(match unchecked.farm_type {
+            Some(farm_type) => rule.with_farm_type(farm_type),
+            None => rule,
+        })
+    }
+}
+
+/// Add as a resource to replace the fixed control of detected farms.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, derive_more::From)]
+pub struct ControlOutcomeModel {
+    pub rules: Vec<ControlOutcomeRule>,
+}
+
+impl ControlOutcomeModel {
+    /// Removes every infected animal half of the time, and otherwise all but
+    /// the `remaining_proportion` of them.
+    pub fn from_remaining_proportion(remaining_proportion: Probability) -> Self {
+        Self {
+            rules: vec![ControlOutcomeRule::new(vec![
+                (
+                    ControlOutcome::TestAndRemove {
+                        efficacy: Probability::new(1.).unwrap(),
+                    },
+                    0.5,
+                ),
+                (
+                    ControlOutcome::TestAndRemove {
+                        efficacy: remaining_proportion.complement(),
+                    },
+                    0.5,
+                ),
+            ])
+            .unwrap()],
+        }
+    }
+
+    /// The given model, or the one from the [RemainingProportion].
+    pub fn or_remaining_proportion<'a>(
+        control_outcome_model: Option<&'a ControlOutcomeModel>,
+        remaining_proportion: Option<&RemainingProportion>,
+    ) -> Cow<'a, ControlOutcomeModel> {
+        match control_outcome_model {
+            Some(control_outcome_model) => Cow::Borrowed(control_outcome_model),
+            None => Cow::Owned(Self::from_remaining_proportion(
+                remaining_proportion
+                    .expect("either a `ControlOutcomeModel` or `RemainingProportion` is required")
+                    .0,
+            )),
+        }
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        use anyhow::Context;
+
+        let path = path.as_ref();
+        let reader = std::io::BufReader::new(
+            std::fs::File::open(path)
+                .with_context(|| format!("failed to open control outcomes {}", path.display()))?,
+        );
+        serde_json::from_reader(reader)
+            .with_context(|| format!("invalid control outcomes in {}", path.display()))
+    }
+
+    /// Outcome for a detected farm, from the first rule that applies.
+    pub fn sample<R: Rng + ?Sized>(
+        &self,
+        herd_size: usize,
+        farm_type: Option<&FarmType>,
+        rng: &mut R,
+    ) -> ControlOutcome {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies_to(herd_size, farm_type))
+            .map(|rule| {
+                let outcomes = WeightedIndex::new(rule.outcomes.iter().map(|(_, weight)| weight))
+                    .expect("weights of the control outcomes are checked in the rule");
+                rule.outcomes[outcomes.sample(rng)].0
+            })
+            .unwrap_or(ControlOutcome::NoAction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario_rng::{ActiveSurveillanceStream, MasterSeed, ProcessRng};
+
+    #[test]
+    fn test_rules_by_herd_size_and_farm_type() {
+        let mut rng =
+            ProcessRng::<ActiveSurveillanceStream>::from_master_seed(MasterSeed(20210718));
+        let treatment = ControlOutcome::Treatment {
+            efficacy: Probability::new(0.5).unwrap(),
+        };
+        let model = ControlOutcomeModel::from(vec![
+            ControlOutcomeRule::new(vec![(ControlOutcome::WholeHerdCull, 1.)])
+                .unwrap()
+                .with_herd_sizes(None, Some(50)),
+            ControlOutcomeRule::new(vec![(treatment, 1.), (ControlOutcome::NoAction, 0.)])
+                .unwrap()
+                .with_farm_type(FarmType(1)),
+        ]);
+        assert_eq!(
+            model.sample(10, None, &mut rng),
+            ControlOutcome::WholeHerdCull
+        );
+        assert_eq!(model.sample(100, Some(&FarmType(1)), &mut rng), treatment);
+        assert_eq!(
+            model.sample(100, Some(&FarmType(2)), &mut rng),
+            ControlOutcome::NoAction
+        );
+
+        let mut herd_size = HerdSize::new_single_population(100);
+        let (mut susceptible, mut infected, mut recovered) =
+            (Susceptible(50), Infected(40), Recovered(10));
+        let culled = treatment.apply(
+            RemovalFate::Culled,
+            &mut herd_size,
+            &mut susceptible,
+            &mut infected,
+            &mut recovered,
+            &mut rng,
+        );
+        assert_eq!(culled, 0);
+        assert_eq!(herd_size.0, 100);
+        assert_eq!(infected.0 + recovered.0, 50);
+        assert!(infected.0 < 40);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_from_json() {
+        let model: ControlOutcomeModel = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {
+                        "max_herd_size": 50,
+                        "outcomes": [["whole_herd_cull", 1.0]]
+                    },
+                    {
+                        "farm_type": 1,
+                        "outcomes": [
+                            [{ "test_and_remove": { "efficacy": 0.9 } }, 0.8],
+                            ["no_action", 0.2]
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            model,
+            ControlOutcomeModel::from(vec![
+                ControlOutcomeRule::new(vec![(ControlOutcome::WholeHerdCull, 1.)])
+                    .unwrap()
+                    .with_herd_sizes(None, Some(50)),
+                ControlOutcomeRule::new(vec![
+                    (
+                        ControlOutcome::TestAndRemove {
+                            efficacy: Probability::new(0.9).unwrap()
+                        },
+                        0.8
+                    ),
+                    (ControlOutcome::NoAction, 0.2)
+                ])
+                .unwrap()
+                .with_farm_type(FarmType(1)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_invalid_weights() {
+        assert!(ControlOutcomeRule::new(vec![(ControlOutcome::WholeHerdCull, -1.)]).is_err());
+        assert!(ControlOutcomeRule::new(vec![(ControlOutcome::WholeHerdCull, f64::NAN)]).is_err());
+        assert!(ControlOutcomeRule::new(vec![(ControlOutcome::WholeHerdCull, 0.)]).is_err());
+        assert!(ControlOutcomeRule::new(vec![]).is_err());
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_invalid_weights_from_json() {
+        assert!(serde_json::from_str::<ControlOutcomeModel>(
+            r#"{ "rules": [{ "outcomes": [["whole_herd_cull", 0.0], ["no_action", 0.0]] }] }"#,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! Movement restrictions on suspected and confirmed farms, and a national
+//! standstill.
+//!
+//! A farm with a [MovementRestriction] has its shipments, both outgoing and
+//! incoming, vetoed with the given reduction, until the restriction lifts.
+//! The restrictions are imposed by [update_movement_restrictions] whenever a
+//! farm becomes [FarmStatus::Suspected] or [FarmStatus::Confirmed], and
+//! optionally on its trade contacts, which are then
+//! [FarmStatus::Restricted]. After [RestrictionPolicy::duration] days they
+//! are lifted by [lift_movement_restrictions].
+//!
+//! The [NationalStandstill] vetoes shipments between all farms for a number
+//! of days after the first detection, see [start_national_standstill].
+//!
+//! The vetoes are applied by the movement processes through the
+//! [MovementRestrictions]-parameter, i.e. in
+//! [crate::between_herd_spread_model], [crate::animal_movements] and
+//! [crate::markets].
+//!
+
+use std::collections::HashMap;
+
+use bevy::{
+    app::ManualEventReader,
+    ecs::{schedule::ShouldRun, system::SystemParam},
+};
+
+use crate::{
+    farm_id_to_entity_map::FarmIdEntityMap,
+    parameters::Probability,
+    populations::{AdjacentFarms, FarmId},
+    prelude::*,
+    regulator_active_surveillance::FarmDetected,
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+};
+
+/// Shipments to and from the farm are vetoed with probability `reduction`,
+/// until `until`.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, derive_new::new)]
+pub struct MovementRestriction {
+    pub reduction: Probability,
+    pub until: Time,
+}
+
+impl MovementRestriction {
+    pub fn is_active(&self, scenario_tick: Time) -> bool {
+        scenario_tick < self.until
+    }
+
+    /// Combines this with a restriction that may already be in place, as
+    /// restrictions are only ever tightened while they are active.
+    ///
+    /// A farm only has a single restriction, thus the larger reduction and
+    /// the later end are taken independently of each other. A strict, short
+    /// restriction combined with a lenient, long one is then strict until
+    /// the later end, i.e. this errs on the side of restricting more.
+    #[must_use]
+    pub fn tightened(self, existing: Option<&Self>, scenario_tick: Time) -> Self {
+        match existing.filter(|existing| existing.is_active(scenario_tick)) {
+            Some(existing) => Self {
+                reduction: if (existing.reduction).0 > (self.reduction).0 {
+                    existing.reduction
+                } else {
+                    self.reduction
+                },
+                until: existing.until.max(self.until),
+            },
+            None => self,
+        }
+    }
+}
+
+/// Add as a resource to restrict suspected and confirmed farms.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct RestrictionPolicy {
+    /// 1 for a complete ban on movements.
+    pub reduction: Probability,
+    /// Days until the restrictions are lifted.
+    pub duration: Time,
+    /// Whether the [AdjacentFarms] of the farm are restricted as well.
+    #[new(value = "false")]
+    pub restrict_trade_contacts: bool,
+}
+
+impl RestrictionPolicy {
+    #[must_use]
+    pub fn with_trade_contacts(mut self) -> Self {
+        self.restrict_trade_contacts = true;
+        self
+    }
+}
+
+/// Add as a resource to stop movements nationally for `days` after the
+/// first detection.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_new::new)]
+pub struct NationalStandstill {
+    pub days: Time,
+    pub reduction: Probability,
+    #[new(default)]
+    started: Option<Time>,
+}
+
+impl NationalStandstill {
+    pub fn started(&self) -> Option<Time> {
+        self.started
+    }
+
+    pub fn is_active(&self, scenario_tick: Time) -> bool {
+        self.started
+            .map_or(false, |started| scenario_tick < started + self.days)
+    }
+}
+
+/// Vetoes of shipments, for the movement processes.
+#[derive(SystemParam)]
+pub struct MovementRestrictions<'a> {
+    restrictions: Query<'a, &'static MovementRestriction>,
+    national_standstill: Option<Res<'a, NationalStandstill>>,
+    scenario_time: Res<'a, ScenarioTime>,
+}
+
+impl MovementRestrictions<'_> {
+    /// Probability that a shipment from (or to) the farms is let through.
+    fn pass_probability(&self, farms: &[Entity]) -> f64 {
+        let scenario_tick = self.scenario_time.current_time();
+        let national = self
+            .national_standstill
+            .as_ref()
+            .filter(|standstill| standstill.is_active(scenario_tick))
+            .map_or(1., |standstill| standstill.reduction.complement().0);
+        farms
+            .iter()
+            .filter_map(|farm| self.restrictions.get(*farm).ok())
+            .filter(|restriction| restriction.is_active(scenario_tick))
+            .fold(national, |pass, restriction| {
+                pass * restriction.reduction.complement().0
+            })
+    }
+
+    /// Whether a shipment from `origin` to `destination` goes ahead.
+    ///
+    /// This only draws from `rng` if any restriction applies.
+    pub fn permits<R: Rng + ?Sized>(
+        &self,
+        origin: Entity,
+        destination: Entity,
+        rng: &mut R,
+    ) -> bool {
+        let pass_probability = self.pass_probability(&[origin, destination]);
+        pass_probability >= 1. || rng.gen_bool(pass_probability)
+    }
+
+    /// Whether a farm may trade at all, e.g. at a market.
+    pub fn permits_farm<R: Rng + ?Sized>(&self, farm: Entity, rng: &mut R) -> bool {
+        let pass_probability = self.pass_probability(&[farm]);
+        pass_probability >= 1. || rng.gen_bool(pass_probability)
+    }
+}
+
+/// Run criteria for [start_national_standstill].
+pub fn run_if_national_standstill(
+    national_standstill: Option<Res<NationalStandstill>>,
+) -> ShouldRun {
+    if national_standstill.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+pub fn start_national_standstill(
+    mut detections: EventReader<FarmDetected>,
+    mut national_standstill: ResMut<NationalStandstill>,
+) {
+    if let Some(detection) = detections.iter().next() {
+        if national_standstill.started.is_none() {
+            info!(
+                "National standstill of {} days from tick {}",
+                national_standstill.days, detection.scenario_tick
+            );
+            national_standstill.started = Some(detection.scenario_tick);
+        }
+    }
+}
+
+/// Run criteria for [update_movement_restrictions].
+pub fn run_if_restriction_policy(restriction_policy: Option<Res<RestrictionPolicy>>) -> ShouldRun {
+    if restriction_policy.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Restricts the farms that become suspected or confirmed, where restrictions
+/// that are already in place are tightened, see
+/// [MovementRestriction::tightened].
+pub fn update_movement_restrictions(
+    mut commands: Commands,
+    policy: Res<RestrictionPolicy>,
+    mut reader: Local<ManualEventReader<StatusChanged>>,
+    mut status_changes: ResMut<Events<StatusChanged>>,
+    mut query: Query<(&AdjacentFarms, Option<&mut RegulatoryStatus>)>,
+    existing_restrictions: Query<&MovementRestriction>,
+    farm_map: Res<FarmIdEntityMap>,
+) {
+    let restricted_farms = reader
+        .iter(&status_changes)
+        .filter(|status_changed| {
+            matches!(
+                status_changed.to,
+                FarmStatus::Suspected | FarmStatus::Confirmed
+            )
+        })
+        .map(|status_changed| (status_changed.farm_id, status_changed.scenario_tick))
+        .collect_vec();
+
+    // the inserted restrictions are only visible in the next update
+    let mut imposed = HashMap::new();
+    let mut restrict = |entity: Entity, restriction: MovementRestriction, scenario_tick| {
+        let existing = imposed
+            .get(&entity)
+            .or_else(|| existing_restrictions.get(entity).ok());
+        let restriction = restriction.tightened(existing, scenario_tick);
+        imposed.insert(entity, restriction);
+        commands.entity(entity).insert(restriction);
+    };
+
+    for (farm_id, scenario_tick) in restricted_farms {
+        let restriction =
+            MovementRestriction::new(policy.reduction, scenario_tick + policy.duration);
+        let farm_entity = farm_map.0[&farm_id];
+        restrict(farm_entity, restriction, scenario_tick);
+        if !policy.restrict_trade_contacts {
+            continue;
+        }
+
+        let (adjacent_farms, _) = query
+            .get_mut(farm_entity)
+            .expect("failed to find restricted farm");
+        let contacts = adjacent_farms.0.clone();
+        for contact in contacts.into_iter().filter(|contact| *contact != farm_id) {
+            let contact_entity = farm_map.0[&contact];
+            restrict(contact_entity, restriction, scenario_tick);
+            if let Ok((_, Some(mut status))) = query.get_mut(contact_entity) {
+                // suspected and confirmed farms keep their status
+                if !matches!(status.status, FarmStatus::Free | FarmStatus::Restocked) {
+                    continue;
+                }
+                if let Some(status_changed) =
+                    status.transition(contact, FarmStatus::Restricted, scenario_tick)
+                {
+                    status_changes.send(status_changed);
+                }
+            }
+        }
+    }
+}
+
+/// Removes the expired restrictions, and restricted farms are free again.
+pub fn lift_movement_restrictions(
+    mut commands: Commands,
+    query: Query<(Entity, &FarmId, &MovementRestriction)>,
+    mut statuses: Query<&mut RegulatoryStatus>,
+    scenario_time: Res<ScenarioTime>,
+    mut status_changes: Option<ResMut<Events<StatusChanged>>>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    for (entity, farm_id, restriction) in query.iter() {
+        if restriction.is_active(scenario_tick) {
+            continue;
+        }
+        commands.entity(entity).remove::<MovementRestriction>();
+        if let Ok(mut status) = statuses.get_mut(entity) {
+            if status.status != FarmStatus::Restricted {
+                continue;
+            }
+            let status_changed = status.transition(*farm_id, FarmStatus::Free, scenario_tick);
+            if let Some((status_changed, status_changes)) =
+                status_changed.zip(status_changes.as_mut())
+            {
+                status_changes.send(status_changed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        between_herd_spread_model::{
+            update_between_herd_spread_model, ContactRate, InfectionEventsBatchId,
+        },
+        parameters::Rate,
+        populations::{Cattle, HerdSize},
+        regulator_active_surveillance::DetectionSource,
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::{Infected, Recovered, Susceptible},
+    };
+    use std::collections::HashMap;
+
+    /// Farm 1 is fully infected, and trades heavily with farm 2.
+    fn example_world() -> (World, Entity, Entity) {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210719));
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(InfectionEventsBatchId::default());
+        world.insert_resource(Events::<StatusChanged>::default());
+        let mut farm_map = HashMap::new();
+        let mut entities = Vec::new();
+        for (farm_id, contact, infected) in [(1, 2, 100), (2, 1, 0)] {
+            let farm_id: FarmId = FarmId::new_single_population(farm_id);
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    Cattle,
+                    farm_id,
+                    HerdSize::new_single_population(100),
+                    AdjacentFarms::<()>::new_single_population(vec![
+                        FarmId::new_single_population(contact),
+                    ]),
+                    ContactRate::new(Rate::new(10.).unwrap()),
+                    Susceptible(100 - infected),
+                    Infected(infected),
+                    Recovered(0),
+                    RegulatoryStatus::new(FarmStatus::Free, 1),
+                ))
+                .id();
+            farm_map.insert(farm_id, entity);
+            entities.push(entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map));
+        (world, entities[0], entities[1])
+    }
+
+    #[test]
+    fn test_restricted_contacts_and_lifting() {
+        let (mut world, farm_1, farm_2) = example_world();
+        world.insert_resource(
+            RestrictionPolicy::new(Probability::new(1.).unwrap(), 3).with_trade_contacts(),
+        );
+        world
+            .get_resource_mut::<Events<StatusChanged>>()
+            .unwrap()
+            .send(StatusChanged {
+                farm_id: FarmId::new_single_population(1),
+                from: FarmStatus::Free,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 1,
+            });
+
+        let mut restrict = SystemStage::single(update_movement_restrictions.system());
+        restrict.run(&mut world);
+        assert!(world.get::<MovementRestriction>(farm_1).is_some());
+        assert_eq!(
+            world.get::<RegulatoryStatus>(farm_2).unwrap().status,
+            FarmStatus::Restricted
+        );
+
+        // a complete ban keeps the infection on farm 1
+        let mut spread = SystemStage::single(
+            update_between_herd_spread_model
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        spread.run(&mut world);
+        assert_eq!(world.get::<Infected>(farm_2).unwrap().0, 0);
+
+        world.insert_resource(ScenarioTime::new(4, None));
+        let mut lift = SystemStage::single(lift_movement_restrictions.system());
+        lift.run(&mut world);
+        assert!(world.get::<MovementRestriction>(farm_2).is_none());
+        assert_eq!(
+            world.get::<RegulatoryStatus>(farm_2).unwrap().status,
+            FarmStatus::Free
+        );
+
+        spread.run(&mut world);
+        assert!(world.get::<Infected>(farm_2).unwrap().0 > 0);
+    }
+
+    #[test]
+    fn test_existing_restrictions_are_tightened() {
+        let (mut world, farm_1, farm_2) = example_world();
+        world.insert_resource(
+            RestrictionPolicy::new(Probability::new(0.5).unwrap(), 3).with_trade_contacts(),
+        );
+        let existing = MovementRestriction::new(Probability::new(0.2).unwrap(), 30);
+        world.entity_mut(farm_2).insert(existing);
+        {
+            let mut status_changes = world.get_resource_mut::<Events<StatusChanged>>().unwrap();
+            // both farms are restricted twice in the same update
+            for farm_id in [1, 2] {
+                status_changes.send(StatusChanged {
+                    farm_id: FarmId::new_single_population(farm_id),
+                    from: FarmStatus::Free,
+                    to: FarmStatus::Suspected,
+                    scenario_tick: 1,
+                });
+            }
+        }
+
+        SystemStage::single(update_movement_restrictions.system()).run(&mut world);
+        let reduction = Probability::new(0.5).unwrap();
+        assert_eq!(
+            world.get::<MovementRestriction>(farm_1),
+            Some(&MovementRestriction::new(reduction, 4))
+        );
+        assert_eq!(
+            world.get::<MovementRestriction>(farm_2),
+            Some(&MovementRestriction::new(reduction, 30))
+        );
+    }
+
+    #[test]
+    fn test_national_standstill() {
+        let (mut world, _, farm_2) = example_world();
+        world.insert_resource(Events::<FarmDetected>::default());
+        world.insert_resource(NationalStandstill::new(5, Probability::new(1.).unwrap()));
+        world
+            .get_resource_mut::<Events<FarmDetected>>()
+            .unwrap()
+            .send(FarmDetected {
+                farm_id: FarmId::new_single_population(1),
+                scenario_tick: 1,
+                source: DetectionSource::Surveillance,
+            });
+
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(start_national_standstill.system().label("standstill"))
+            .add_system(
+                update_between_herd_spread_model
+                    .system()
+                    .chain(crate::chain_tools::dispose.system())
+                    .after("standstill"),
+            );
+        stage.run(&mut world);
+        assert_eq!(
+            world
+                .get_resource::<NationalStandstill>()
+                .unwrap()
+                .started(),
+            Some(1)
+        );
+        assert_eq!(world.get::<Infected>(farm_2).unwrap().0, 0);
+
+        world.insert_resource(ScenarioTime::new(6, None));
+        stage.run(&mut world);
+        assert!(world.get::<Infected>(farm_2).unwrap().0 > 0);
+    }
+}
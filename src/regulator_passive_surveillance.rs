@@ -5,17 +5,22 @@
 //!
 //! Could use [DetectionRate] and report an observed preference
 //!
+//! With [SurveillanceTesting], the observed prevalence is that of the farms
+//! that test positive, thus it includes false positives.
+//!
 
 use std::convert::TryFrom;
 
-use rand::{prelude::StdRng, Rng};
+use rand::Rng;
 
 use crate::{
+    diagnostic_tests::SurveillanceTesting,
     parameters::{Probability, Rate},
     prelude::*,
     regulator_active_surveillance::DetectionRate,
+    scenario_rng::{PassiveSurveillanceStream, ProcessRng},
     scenario_time::scenario_timer::ScenarioTime,
-    sir_spread_model::Infected,
+    sir_spread_model::{Infected, Recovered, Susceptible},
 };
 
 pub struct TotalFarms(pub usize);
@@ -23,11 +28,12 @@ pub struct TotalFarms(pub usize);
 // Reports the population prevalence of the disease.
 pub fn update_passive_surveillance(
     mut commands: Commands,
-    query: Query<&Infected>,
+    query: Query<(&Susceptible, &Infected, &Recovered)>,
     total_farms: Option<Res<TotalFarms>>,
     scenario_time: Res<ScenarioTime>,
-    mut rng: ResMut<StdRng>,
+    mut rng: ResMut<ProcessRng<PassiveSurveillanceStream>>,
     detection_rate: Res<DetectionRate>,
+    surveillance_testing: Option<Res<SurveillanceTesting>>,
 ) {
     // if the number of total farms isn't available then write it down.
     let total_farms = if let Some(total_farms) = total_farms {
@@ -43,21 +49,36 @@ pub fn update_passive_surveillance(
 
     let observed_prevalence = query
         .iter()
-        .filter(|x| {
-            // no false positives
-
-            (x.0 > 0)
+        .filter(|(susceptible, infected, recovered)| {
+            let is_suspected = (infected.0 > 0)
                 && rng.gen_bool(
-                    Probability::try_from(Rate::new((x.0 as f64) * detection_rate.0 .0).unwrap())
-                        .unwrap()
-                        .0,
-                )
+                    Probability::try_from(
+                        Rate::new((infected.0 as f64) * detection_rate.0 .0).unwrap(),
+                    )
+                    .unwrap()
+                    .0,
+                );
+            match surveillance_testing.as_deref() {
+                // no false positives
+                None => is_suspected,
+                Some(surveillance_testing) => {
+                    (is_suspected || rng.gen_bool(surveillance_testing.testing_probability.0))
+                        && surveillance_testing
+                            .herd_test
+                            .apply(susceptible.0, infected.0, recovered.0, &mut *rng)
+                            .positive
+                }
+            }
         })
         .count() as f64
         / total_farms as f64;
     // dbg!(total_farms);
 
-    let true_prevalence = query.iter().filter(|x| x.0 > 0).count() as f64 / total_farms as f64;
+    let true_prevalence = query
+        .iter()
+        .filter(|(_, infected, _)| infected.0 > 0)
+        .count() as f64
+        / total_farms as f64;
 
     info!(
         "\n{:>5} => True prevalence: {:.4}\tObserved_prevalence: {:.4}",
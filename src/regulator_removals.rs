@@ -0,0 +1,222 @@
+//! Fate of the animals that the regulators remove from the infected
+//! compartment.
+//!
+//! Removed animals no longer vanish from the farm. Depending on the
+//! [RemovalFate] they are either culled, i.e. taken off the [HerdSize], or
+//! treated, and then put back as susceptible or recovered. Thus the herd size
+//! always equals the sum of the disease compartments.
+//!
+//! Farms with a [Culled]-counter keep track of their culled animals, which
+//! are put out as [CulledAnimals] by [collect_culled_animals], and recorded
+//! by [record_culled_animals] for the estimation of costs.
+//!
+
+use std::fs::File;
+
+use csv::Writer;
+
+use crate::{
+    csv_outputs::CsvOutputs,
+    populations::{Cattle, FarmId, HerdSize},
+    prelude::*,
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::{Infected, Recovered, Susceptible},
+};
+
+/// What happens to the infected animals that are removed by a regulator.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemovalFate {
+    /// Killed and taken off the farm.
+    Culled,
+    /// Treated, and susceptible again.
+    Susceptible,
+    /// Treated, and immune.
+    Recovered,
+}
+
+impl Default for RemovalFate {
+    fn default() -> Self {
+        Self::Culled
+    }
+}
+
+impl RemovalFate {
+    /// Removes `animals` of the infected animals, and returns the number of
+    /// culled animals.
+    pub fn remove_infected(
+        &self,
+        animals: usize,
+        herd_size: &mut HerdSize,
+        susceptible: &mut Susceptible,
+        infected: &mut Infected,
+        recovered: &mut Recovered,
+    ) -> usize {
+        let animals = animals.min(infected.0);
+        infected.0 -= animals;
+        match self {
+            RemovalFate::Culled => {
+                herd_size.remove_animals(animals);
+                return animals;
+            }
+            RemovalFate::Susceptible => susceptible.0 += animals,
+            RemovalFate::Recovered => recovered.0 += animals,
+        }
+        0
+    }
+}
+
+/// Animals culled on a farm, in total and in the latest tick that there was
+/// a cull.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Culled {
+    pub total: usize,
+    pub scenario_tick: Time,
+    pub latest: usize,
+}
+
+impl Culled {
+    pub fn add(&mut self, animals: usize, scenario_tick: Time) {
+        if animals == 0 {
+            return;
+        }
+        if self.scenario_tick == scenario_tick {
+            self.latest += animals;
+        } else {
+            self.scenario_tick = scenario_tick;
+            self.latest = animals;
+        }
+        self.total += animals;
+    }
+}
+
+/// Adds the [Culled]-counter to every farm.
+pub fn setup_culled_animals(mut commands: Commands, query: Query<Entity, With<Cattle>>) {
+    query.for_each(|entity| {
+        commands.entity(entity).insert(Culled::default());
+    });
+}
+
+/// Culls of a single tick.
+#[derive(Debug, Clone)]
+pub struct CulledAnimals {
+    pub scenario_tick: Time,
+    /// Farms and their number of culled animals.
+    pub culled: Vec<(FarmId, usize)>,
+}
+
+/// Add this after the regulators.
+pub fn collect_culled_animals(
+    query: Query<(&FarmId, &Culled), Changed<Culled>>,
+    scenario_time: Res<ScenarioTime>,
+) -> Option<CulledAnimals> {
+    let scenario_tick = scenario_time.current_time();
+    let culled = query
+        .iter()
+        .filter(|(_, culled)| culled.scenario_tick == scenario_tick && culled.latest > 0)
+        .map(|(farm_id, culled)| (*farm_id, culled.latest))
+        .sorted_by_key(|(farm_id, _)| *farm_id)
+        .collect_vec();
+    if culled.is_empty() {
+        None
+    } else {
+        Some(CulledAnimals {
+            scenario_tick,
+            culled,
+        })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct CulledAnimalsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for CulledAnimalsRecorder {
+    const FILE_NAME: &'static str = "culled_animals.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_culled_animals].
+pub fn setup_culled_animals_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "culled_animals.csv",
+        &["scenario_tick", "farm_id", "culled"],
+    );
+    commands.insert_resource(CulledAnimalsRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_culled_animals_recording]
+pub fn record_culled_animals(
+    In(events): In<Option<CulledAnimals>>,
+    mut csv_file: ResMut<CulledAnimalsRecorder>,
+) -> Option<CulledAnimals> {
+    if let Some(CulledAnimals {
+        scenario_tick,
+        culled,
+    }) = &events
+    {
+        for (farm_id, culled) in culled {
+            csv_file
+                .0
+                .serialize((scenario_tick, farm_id.0, culled))
+                .unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_herd_totals_are_kept() {
+        // (fate, culled, (herd size, susceptible, infected, recovered))
+        for (fate, expected_culled, expected) in [
+            (RemovalFate::Culled, 20, (80, 50, 0, 30)),
+            (RemovalFate::Susceptible, 0, (100, 70, 0, 30)),
+            (RemovalFate::Recovered, 0, (100, 50, 0, 50)),
+        ] {
+            let mut herd_size = HerdSize::new_single_population(100);
+            let (mut susceptible, mut infected, mut recovered) =
+                (Susceptible(50), Infected(20), Recovered(30));
+            // only the infected animals can be removed
+            let culled = fate.remove_infected(
+                40,
+                &mut herd_size,
+                &mut susceptible,
+                &mut infected,
+                &mut recovered,
+            );
+            assert_eq!(culled, expected_culled);
+            assert_eq!(
+                (herd_size.0, susceptible.0, infected.0, recovered.0),
+                expected
+            );
+            assert_eq!(herd_size.0, susceptible.0 + infected.0 + recovered.0);
+        }
+    }
+
+    #[test]
+    fn test_culled_per_tick() {
+        let mut culled = Culled::default();
+        culled.add(10, 3);
+        culled.add(5, 3);
+        assert_eq!((culled.total, culled.latest), (15, 15));
+        culled.add(0, 4);
+        culled.add(7, 5);
+        assert_eq!(
+            culled,
+            Culled {
+                total: 22,
+                scenario_tick: 5,
+                latest: 7,
+            }
+        );
+    }
+}
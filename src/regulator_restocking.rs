@@ -0,0 +1,157 @@
+//! Repopulation of culled farms.
+//!
+//! With a [RestockingPolicy], a [FarmStatus::Culled] farm stays empty for
+//! [RestockingPolicy::empty_period] days, and is then restocked with as many
+//! animals as were taken off it in its last cull, see [Culled::latest]. The
+//! new animals are all susceptible, and any earlier vaccination of the farm
+//! is void. After [RestockingPolicy::probation_period] days, a farm that is
+//! still [FarmStatus::Restocked] is free again.
+//!
+//! Both steps are carried out by [restock_culled_farms].
+//!
+
+use bevy::ecs::schedule::ShouldRun;
+
+use crate::{
+    populations::{Cattle, FarmId, HerdSize},
+    prelude::*,
+    regulator_removals::Culled,
+    regulator_vaccination::Vaccinated,
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::Susceptible,
+};
+
+/// Add as a resource to restock the culled farms.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_new::new)]
+pub struct RestockingPolicy {
+    /// Days that a culled farm is kept empty.
+    pub empty_period: Time,
+    /// Days until a restocked farm is free.
+    pub probation_period: Time,
+}
+
+/// Run criteria for [restock_culled_farms].
+pub fn run_if_restocking(restocking_policy: Option<Res<RestockingPolicy>>) -> ShouldRun {
+    if restocking_policy.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Components of a farm that is restocked.
+type RestockedFarm = (
+    Entity,
+    &'static FarmId,
+    &'static mut RegulatoryStatus,
+    &'static Culled,
+    &'static mut HerdSize,
+    &'static mut Susceptible,
+    Option<&'static Vaccinated>,
+);
+
+/// Restocks the culled farms after the empty period, and frees the restocked
+/// farms after the probation period.
+pub fn restock_culled_farms(
+    mut commands: Commands,
+    policy: Res<RestockingPolicy>,
+    mut query: Query<RestockedFarm, With<Cattle>>,
+    scenario_time: Res<ScenarioTime>,
+    mut status_changes: Option<ResMut<Events<StatusChanged>>>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    for (entity, farm_id, mut status, culled, mut herd_size, mut susceptible, vaccinated) in
+        query.iter_mut()
+    {
+        let (to, period) = match status.status {
+            FarmStatus::Culled => (FarmStatus::Restocked, policy.empty_period),
+            FarmStatus::Restocked => (FarmStatus::Free, policy.probation_period),
+            _ => continue,
+        };
+        if status.duration(scenario_tick) < period {
+            continue;
+        }
+        let status_changed = match status.transition(*farm_id, to, scenario_tick) {
+            Some(status_changed) => status_changed,
+            None => continue,
+        };
+        if to == FarmStatus::Restocked {
+            herd_size.add_animals(culled.latest);
+            susceptible.0 += culled.latest;
+            if vaccinated.is_some() {
+                commands.entity(entity).remove::<Vaccinated>();
+            }
+        }
+        if let Some(status_changes) = status_changes.as_mut() {
+            status_changes.send(status_changed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parameters::Probability, regulator_vaccination::Vaccine};
+    use bevy::app::ManualEventReader;
+
+    #[test]
+    fn test_restock_after_empty_period() {
+        let mut world = World::new();
+        world.insert_resource(RestockingPolicy::new(10, 20));
+        world.insert_resource(Events::<StatusChanged>::default());
+        world.insert_resource(ScenarioTime::new(14, None));
+        let farm_id: FarmId = FarmId::new_single_population(1);
+        let mut culled = Culled::default();
+        culled.add(50, 5);
+        let farm = world
+            .spawn()
+            .insert_bundle((
+                Cattle,
+                farm_id,
+                RegulatoryStatus::new(FarmStatus::Culled, 5),
+                culled,
+                HerdSize::new_single_population(0),
+                Susceptible(0),
+                Vaccinated {
+                    vaccine: Vaccine::new(
+                        Probability::new(0.9).unwrap(),
+                        Probability::new(1.).unwrap(),
+                        7,
+                    ),
+                    scenario_tick: 2,
+                },
+            ))
+            .id();
+
+        let mut stage = SystemStage::single(restock_culled_farms.system());
+        let mut run_at = |world: &mut World, scenario_tick| {
+            *world.get_resource_mut::<ScenarioTime>().unwrap() =
+                ScenarioTime::new(scenario_tick, None);
+            stage.run(world);
+            world.get::<RegulatoryStatus>(farm).unwrap().status
+        };
+        assert_eq!(run_at(&mut world, 14), FarmStatus::Culled);
+        assert_eq!(world.get::<HerdSize>(farm).unwrap().0, 0);
+
+        assert_eq!(run_at(&mut world, 15), FarmStatus::Restocked);
+        assert_eq!(world.get::<HerdSize>(farm).unwrap().0, 50);
+        assert_eq!(world.get::<Susceptible>(farm).unwrap().0, 50);
+        assert!(world.get::<Vaccinated>(farm).is_none());
+
+        assert_eq!(run_at(&mut world, 34), FarmStatus::Restocked);
+        assert_eq!(run_at(&mut world, 35), FarmStatus::Free);
+        assert_eq!(world.get::<HerdSize>(farm).unwrap().0, 50);
+
+        let status_changes = world.get_resource::<Events<StatusChanged>>().unwrap();
+        let status_changes = ManualEventReader::<StatusChanged>::default()
+            .iter(status_changes)
+            .map(|status_changed| (status_changed.to, status_changed.scenario_tick))
+            .collect_vec();
+        assert_eq!(
+            status_changes,
+            vec![(FarmStatus::Restocked, 15), (FarmStatus::Free, 35)]
+        );
+    }
+}
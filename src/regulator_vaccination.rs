@@ -0,0 +1,601 @@
+//! Vaccination campaigns.
+//!
+//! A [Vaccinated] farm has a [Vaccine::coverage] of its herd vaccinated, and
+//! once the immunity has set in, after [Vaccine::onset_delay] days, the
+//! vaccinated animals are protected with [Vaccine::efficacy]. That is, the
+//! susceptibility of the herd is reduced by `coverage * efficacy`, which is
+//! honoured by the infection step of
+//! [crate::sir_spread_model::update_disease_compartments].
+//!
+//! Farms are queued for vaccination by [schedule_vaccination], either
+//!
+//! * all farms, on each of the [VaccinationPolicy::preventive_dates], or
+//! * the farms in a [VaccinationRing] around every confirmed farm, i.e. within
+//!   a radius, or its trade contacts.
+//!
+//! The queue is worked through by [vaccinate_farms], with as many farms a day
+//! as the vaccination teams can visit. With the
+//! [VaccinationStrategy::Suppressive]-strategy, the vaccinated farms are culled
+//! afterwards, see [crate::regulator_actions]. A [ControlCapacity] limits the
+//! vaccinations per day further, and a [PriorityPolicy] changes the order of
+//! the queue from oldest first.
+//!
+//! The vaccinations are recorded by [record_vaccinations].
+//!
+
+use std::{
+    collections::{BTreeSet, HashSet, VecDeque},
+    fs::File,
+};
+
+use bevy::ecs::schedule::ShouldRun;
+use csv::Writer;
+
+use crate::{
+    between_herd_spread_model::ContactRate,
+    csv_outputs::CsvOutputs,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    local_spread_model::SpatialIndex,
+    parameters::Probability,
+    populations::{AdjacentFarms, Cattle, FarmCoordinates, FarmId, HerdSize},
+    prelude::*,
+    regulator_actions::{RegulatoryAction, ScheduledActions},
+    regulator_capacity::{ControlActivity, ControlCapacity, FarmPriority, PriorityPolicy},
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::Infected,
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, derive_new::new)]
+pub struct Vaccine {
+    /// Protection of a vaccinated animal against infection.
+    pub efficacy: Probability,
+    /// Proportion of a herd that is vaccinated.
+    pub coverage: Probability,
+    /// Days until the vaccinated animals are protected.
+    pub onset_delay: Time,
+}
+
+/// A farm that has been vaccinated.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vaccinated {
+    pub vaccine: Vaccine,
+    pub scenario_tick: Time,
+}
+
+impl Vaccinated {
+    pub fn is_immune(&self, scenario_tick: Time) -> bool {
+        scenario_tick >= self.scenario_tick + self.vaccine.onset_delay
+    }
+
+    /// Factor on the infection rate of the herd's susceptible animals.
+    pub fn susceptibility(&self, scenario_tick: Time) -> f64 {
+        if self.is_immune(scenario_tick) {
+            1. - (self.vaccine.coverage).0 * (self.vaccine.efficacy).0
+        } else {
+            1.
+        }
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VaccinationStrategy {
+    /// Vaccinate-to-live.
+    Protective,
+    /// Vaccinate-to-kill, i.e. the vaccinated farms are culled after
+    /// `cull_after` days.
+    ///
+    /// This requires the [ScheduledActions] of [crate::regulator_actions].
+    Suppressive { cull_after: Time },
+}
+
+/// The farms that are vaccinated around a confirmed farm.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VaccinationRing {
+    /// Farms within the radius in km, see [FarmCoordinates].
+    Radius(f64),
+    /// The [AdjacentFarms] of the confirmed farm.
+    TradeContacts,
+}
+
+/// Add as a resource to enable vaccination.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, derive_new::new)]
+pub struct VaccinationPolicy {
+    pub vaccine: Vaccine,
+    pub strategy: VaccinationStrategy,
+    /// Number of vaccination teams.
+    pub teams: usize,
+    /// Farms that a team vaccinates in a day.
+    pub farms_per_team: usize,
+    #[new(default)]
+    pub ring: Option<VaccinationRing>,
+    /// Ticks at which every farm is vaccinated, where farms that were
+    /// vaccinated before are vaccinated again.
+    #[new(default)]
+    pub preventive_dates: Vec<Time>,
+}
+
+impl VaccinationPolicy {
+    /// The latest of the preventive dates up until `scenario_tick`.
+    pub fn latest_preventive_date(&self, scenario_tick: Time) -> Option<Time> {
+        self.preventive_dates
+            .iter()
+            .copied()
+            .filter(|date| *date <= scenario_tick)
+            .max()
+    }
+
+    #[must_use]
+    pub fn with_ring(mut self, ring: VaccinationRing) -> Self {
+        self.ring = Some(ring);
+        self
+    }
+
+    #[must_use]
+    pub fn with_preventive_dates(mut self, preventive_dates: Vec<Time>) -> Self {
+        self.preventive_dates = preventive_dates;
+        self
+    }
+
+    /// Farms that can be vaccinated in a day.
+    pub fn daily_capacity(&self) -> usize {
+        self.teams * self.farms_per_team
+    }
+}
+
+/// Farms awaiting vaccination, in the order they were queued.
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(from = "VecDeque<FarmId>", into = "VecDeque<FarmId>")
+)]
+#[derive(Debug, Clone, Default)]
+pub struct VaccinationQueue {
+    pending: VecDeque<FarmId>,
+    /// The farms in the queue, to look them up without going through it.
+    queued: HashSet<FarmId>,
+}
+
+// only the queue is stored, as the queued farms follow from it
+impl From<VecDeque<FarmId>> for VaccinationQueue {
+    fn from(pending: VecDeque<FarmId>) -> Self {
+        let queued = pending.iter().copied().collect();
+        Self { pending, queued }
+    }
+}
+
+impl From<VaccinationQueue> for VecDeque<FarmId> {
+    fn from(vaccination_queue: VaccinationQueue) -> Self {
+        vaccination_queue.pending
+    }
+}
+
+impl VaccinationQueue {
+    /// Queues the farm, unless it is already queued.
+    pub fn enqueue(&mut self, farm_id: FarmId) -> bool {
+        if !self.queued.insert(farm_id) {
+            return false;
+        }
+        self.pending.push_back(farm_id);
+        true
+    }
+
+    pub fn dequeue(&mut self) -> Option<FarmId> {
+        let farm_id = self.pending.pop_front()?;
+        self.queued.remove(&farm_id);
+        Some(farm_id)
+    }
+
+    /// Removes the given farms from wherever they are in the queue.
+    pub fn remove_all(&mut self, farms: &HashSet<FarmId>) {
+        self.pending.retain(|farm_id| !farms.contains(farm_id));
+        self.queued.retain(|farm_id| !farms.contains(farm_id));
+    }
+
+    /// Queued farms, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = FarmId> + '_ {
+        self.pending.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Farms by their location, for the [VaccinationRing::Radius].
+#[derive(Debug, Clone, derive_more::From)]
+pub struct VaccinationIndex(pub SpatialIndex);
+
+/// Run criteria for [schedule_vaccination] and [vaccinate_farms].
+pub fn run_if_vaccination(vaccination_policy: Option<Res<VaccinationPolicy>>) -> ShouldRun {
+    if vaccination_policy.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Add this to a startup stage after the farms have been added. This does
+/// nothing without a [VaccinationPolicy].
+pub fn setup_vaccination(
+    mut commands: Commands,
+    policy: Option<Res<VaccinationPolicy>>,
+    query: Query<(&FarmId, &FarmCoordinates), With<Cattle>>,
+) {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return,
+    };
+    if let Some(VaccinationRing::Radius(radius)) = policy.ring {
+        let spatial_index = SpatialIndex::new(
+            radius,
+            query
+                .iter()
+                .map(|(farm_id, coordinates)| (*farm_id, *coordinates)),
+        );
+        commands.insert_resource(VaccinationIndex::from(spatial_index));
+    }
+    commands.insert_resource(VaccinationQueue::default());
+}
+
+/// Queues the farms for preventive and ring vaccination.
+pub fn schedule_vaccination(
+    policy: Res<VaccinationPolicy>,
+    mut status_changes: EventReader<StatusChanged>,
+    mut queue: ResMut<VaccinationQueue>,
+    vaccination_index: Option<Res<VaccinationIndex>>,
+    farms: Query<(&FarmId, Option<&AdjacentFarms>, Option<&FarmCoordinates>), With<Cattle>>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    if policy.preventive_dates.contains(&scenario_tick) {
+        info!(
+            "Preventive vaccination of all farms at tick {}",
+            scenario_tick
+        );
+        for farm_id in farms.iter().map(|(farm_id, ..)| *farm_id).sorted() {
+            queue.enqueue(farm_id);
+        }
+    }
+
+    let ring = match policy.ring {
+        Some(ring) => ring,
+        None => return,
+    };
+    for status_changed in status_changes
+        .iter()
+        .filter(|status_changed| status_changed.to == FarmStatus::Confirmed)
+    {
+        let (_, adjacent_farms, coordinates) = farms
+            .get(farm_map.0[&status_changed.farm_id])
+            .expect("failed to find confirmed farm");
+        let ring_farms: BTreeSet<FarmId> = match ring {
+            VaccinationRing::Radius(radius) => match coordinates {
+                Some(coordinates) => vaccination_index
+                    .as_ref()
+                    .expect("ring vaccination requires the `VaccinationIndex`")
+                    .0
+                    .within(*coordinates, radius)
+                    .map(|(farm_id, _)| farm_id)
+                    .collect(),
+                // farms without a location have no ring
+                None => BTreeSet::new(),
+            },
+            VaccinationRing::TradeContacts => adjacent_farms
+                .map(|adjacent_farms| adjacent_farms.0.iter().copied().collect())
+                .unwrap_or_default(),
+        };
+        for farm_id in ring_farms
+            .into_iter()
+            .filter(|farm_id| *farm_id != status_changed.farm_id)
+        {
+            queue.enqueue(farm_id);
+        }
+    }
+}
+
+/// The farms vaccinated in a tick.
+#[derive(Debug, Clone)]
+pub struct FarmsVaccinated {
+    pub scenario_tick: Time,
+    pub farms: Vec<FarmId>,
+}
+
+/// Vaccinates as many of the queued farms as the teams can visit in a day, in
+/// the order of the [PriorityPolicy].
+///
+/// Farms that are confirmed or culled are skipped, as are the farms that are
+/// already vaccinated, unless they were vaccinated before the latest of the
+/// [VaccinationPolicy::preventive_dates]. Those are vaccinated again, and the
+/// immunity sets in anew.
+#[allow(clippy::too_many_arguments)]
+pub fn vaccinate_farms(
+    mut commands: Commands,
+    policy: Res<VaccinationPolicy>,
+    mut queue: ResMut<VaccinationQueue>,
+    query: Query<(Option<&Vaccinated>, Option<&RegulatoryStatus>)>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+    mut scheduled_actions: Option<ResMut<ScheduledActions>>,
+    mut control_capacity: Option<ResMut<ControlCapacity>>,
+    priority_policy: Option<Res<PriorityPolicy>>,
+    priorities: Query<(&HerdSize, &Infected, Option<&ContactRate>)>,
+) -> Option<FarmsVaccinated> {
+    let scenario_tick = scenario_time.current_time();
+    let latest_preventive_date = policy.latest_preventive_date(scenario_tick);
+    let mut pending = queue.iter().collect_vec();
+    if let Some(priority_policy) = priority_policy {
+        priority_policy.prioritise(&mut pending, |farm_id| {
+            let (herd_size, infected, contact_rate) = priorities
+                .get(farm_map.0[farm_id])
+                .expect("failed to find farm to vaccinate");
+            FarmPriority::new(herd_size.0, infected.0, contact_rate)
+        });
+    }
+    let mut visited = HashSet::new();
+    let mut farms = Vec::new();
+    for farm_id in pending {
+        if farms.len() == policy.daily_capacity() {
+            break;
+        }
+        if let Some(capacity) = control_capacity.as_mut() {
+            if capacity.remaining(ControlActivity::Vaccinate, scenario_tick) == Some(0) {
+                capacity.add_unmet(
+                    ControlActivity::Vaccinate,
+                    scenario_tick,
+                    queue.iter().filter(|farm_id| !visited.contains(farm_id)),
+                );
+                break;
+            }
+        }
+        visited.insert(farm_id);
+        let farm_entity = farm_map.0[&farm_id];
+        let (vaccinated, status) = query
+            .get(farm_entity)
+            .expect("failed to find farm to vaccinate");
+        let is_vaccinated = vaccinated.map_or(false, |vaccinated| {
+            latest_preventive_date.map_or(true, |date| vaccinated.scenario_tick >= date)
+        });
+        if is_vaccinated
+            || status.map_or(false, |status| {
+                matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+            })
+        {
+            continue;
+        }
+
+        if let Some(capacity) = control_capacity.as_mut() {
+            capacity.try_consume(ControlActivity::Vaccinate, farm_id, scenario_tick);
+        }
+        commands.entity(farm_entity).insert(Vaccinated {
+            vaccine: policy.vaccine,
+            scenario_tick,
+        });
+        if let VaccinationStrategy::Suppressive { cull_after } = policy.strategy {
+            scheduled_actions
+                .as_mut()
+                .expect("suppressive vaccination requires `ScheduledActions`")
+                .schedule(scenario_tick + cull_after, farm_id, RegulatoryAction::Cull);
+        }
+        farms.push(farm_id);
+    }
+    queue.remove_all(&visited);
+
+    if farms.is_empty() {
+        None
+    } else {
+        Some(FarmsVaccinated {
+            scenario_tick,
+            farms,
+        })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct VaccinationsRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for VaccinationsRecorder {
+    const FILE_NAME: &'static str = "vaccinations.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_vaccinations].
+pub fn setup_vaccinations_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create("vaccinations.csv", &["scenario_tick", "farm_id"]);
+    commands.insert_resource(VaccinationsRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_vaccinations_recording]
+pub fn record_vaccinations(
+    In(events): In<Option<FarmsVaccinated>>,
+    mut csv_file: ResMut<VaccinationsRecorder>,
+) -> Option<FarmsVaccinated> {
+    if let Some(FarmsVaccinated {
+        scenario_tick,
+        farms,
+    }) = &events
+    {
+        for farm_id in farms {
+            csv_file.0.serialize((scenario_tick, farm_id.0)).unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scenario_rng::{insert_random_streams, MasterSeed},
+        sir_spread_model::{
+            update_disease_compartments, DiseaseParameters, Recovered, Susceptible,
+        },
+    };
+    use std::collections::HashMap;
+
+    fn vaccine() -> Vaccine {
+        Vaccine::new(
+            Probability::new(1.).unwrap(),
+            Probability::new(1.).unwrap(),
+            7,
+        )
+    }
+
+    #[test]
+    fn test_efficacy_in_within_herd_spread() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210721));
+        world.insert_resource(ScenarioTime::new(1, None));
+        let vaccinated = world
+            .spawn()
+            .insert_bundle((
+                HerdSize::new_single_population(100),
+                Susceptible(50),
+                Infected(50),
+                Recovered(0),
+                DiseaseParameters::new(0.5, 0.),
+                Vaccinated {
+                    vaccine: vaccine(),
+                    scenario_tick: 1,
+                },
+            ))
+            .id();
+        let mut stage = SystemStage::single(update_disease_compartments.system());
+
+        // the immunity has yet to set in
+        stage.run(&mut world);
+        assert!(world.get::<Infected>(vaccinated).unwrap().0 > 50);
+
+        world.insert_resource(ScenarioTime::new(8, None));
+        let infected = world.get::<Infected>(vaccinated).unwrap().0;
+        stage.run(&mut world);
+        assert_eq!(world.get::<Infected>(vaccinated).unwrap().0, infected);
+    }
+
+    #[test]
+    fn test_ring_vaccination_with_teams() {
+        let mut world = World::new();
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(Events::<StatusChanged>::default());
+        world.insert_resource(
+            VaccinationPolicy::new(vaccine(), VaccinationStrategy::Protective, 1, 2)
+                .with_ring(VaccinationRing::TradeContacts),
+        );
+        world.insert_resource(VaccinationQueue::default());
+
+        let farm = FarmId::new_single_population;
+        let mut farm_map = HashMap::new();
+        for farm_id in 1..=4 {
+            let contacts = if farm_id == 1 { vec![2, 3, 4] } else { vec![1] };
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    Cattle,
+                    farm(farm_id),
+                    AdjacentFarms::new_single_population(contacts.into_iter().map(farm).collect()),
+                ))
+                .id();
+            farm_map.insert(farm(farm_id), entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map.clone()));
+        world
+            .get_resource_mut::<Events<StatusChanged>>()
+            .unwrap()
+            .send(StatusChanged {
+                farm_id: farm(1),
+                from: FarmStatus::Suspected,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 1,
+            });
+
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(schedule_vaccination.system().label("schedule"))
+            .add_system(
+                vaccinate_farms
+                    .system()
+                    .chain(crate::chain_tools::dispose.system())
+                    .after("schedule"),
+            );
+        stage.run(&mut world);
+        // a single team vaccinates two farms a day
+        let is_vaccinated =
+            |world: &World, farm_id| world.get::<Vaccinated>(farm_map[&farm(farm_id)]).is_some();
+        assert!(!is_vaccinated(&world, 1));
+        assert!(is_vaccinated(&world, 2) && is_vaccinated(&world, 3));
+        assert!(!is_vaccinated(&world, 4));
+        assert_eq!(world.get_resource::<VaccinationQueue>().unwrap().len(), 1);
+
+        stage.run(&mut world);
+        assert!(is_vaccinated(&world, 4));
+        assert!(world.get_resource::<VaccinationQueue>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_repeated_preventive_vaccination() {
+        let mut world = World::new();
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(Events::<StatusChanged>::default());
+        world.insert_resource(
+            VaccinationPolicy::new(vaccine(), VaccinationStrategy::Protective, 1, 10)
+                .with_preventive_dates(vec![1, 30]),
+        );
+        world.insert_resource(VaccinationQueue::default());
+        let farm = FarmId::new_single_population;
+        let mut farm_map = HashMap::new();
+        for farm_id in 1..=2 {
+            let entity = world.spawn().insert_bundle((Cattle, farm(farm_id))).id();
+            farm_map.insert(farm(farm_id), entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map.clone()));
+
+        let mut stage = SystemStage::single_threaded();
+        stage
+            .add_system(schedule_vaccination.system().label("schedule"))
+            .add_system(
+                vaccinate_farms
+                    .system()
+                    .chain(crate::chain_tools::dispose.system())
+                    .after("schedule"),
+            );
+        let mut vaccinated_at = |world: &mut World, scenario_tick| {
+            world.insert_resource(ScenarioTime::new(scenario_tick, None));
+            stage.run(world);
+            (1..=2)
+                .map(|farm_id| {
+                    world
+                        .get::<Vaccinated>(farm_map[&farm(farm_id)])
+                        .unwrap()
+                        .scenario_tick
+                })
+                .collect_vec()
+        };
+        assert_eq!(vaccinated_at(&mut world, 1), vec![1, 1]);
+        assert_eq!(vaccinated_at(&mut world, 10), vec![1, 1]);
+        assert_eq!(vaccinated_at(&mut world, 30), vec![30, 30]);
+
+        // e.g. a ring around a confirmed farm does not vaccinate again
+        world
+            .get_resource_mut::<VaccinationQueue>()
+            .unwrap()
+            .enqueue(farm(1));
+        assert_eq!(vaccinated_at(&mut world, 31), vec![30, 30]);
+        assert!(world.get_resource::<VaccinationQueue>().unwrap().is_empty());
+    }
+}
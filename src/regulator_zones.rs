@@ -0,0 +1,567 @@
+//! Protection and surveillance zones around confirmed farms.
+//!
+//! Whenever a farm with [FarmCoordinates] is [FarmStatus::Confirmed], every
+//! farm within the [ZoningPolicy::protection] radius (e.g. 3 km) joins the
+//! protection zone, and every farm within the [ZoningPolicy::surveillance]
+//! radius (e.g. 10 km) joins the surveillance zone, each for the
+//! [ZoneRules::minimum_duration] of the zone. A farm that is already zoned has
+//! its [ZoneMembership] extended, thus overlapping zones merge. Once the
+//! protection zone has ended, its farms remain in the surveillance zone, until
+//! that has ended as well.
+//!
+//! The [ZoneRules] of the zone that a farm is in, are
+//!
+//! * a [MovementRestriction], see [crate::regulator_movement_restrictions],
+//!   and the farm is [FarmStatus::Restricted],
+//! * clinical inspection visits, see [update_zone_inspections],
+//! * an increased detection rate in [crate::regulator_active_surveillance].
+//!
+//! The zones are maintained by [update_zones], which needs the [ZoningIndex]
+//! from [setup_zoning]. The farms that are zoned are recorded every tick by
+//! [record_zone_membership].
+//!
+
+use std::{collections::BTreeMap, fs::File};
+
+use bevy::{app::ManualEventReader, ecs::schedule::ShouldRun};
+use csv::Writer;
+
+use crate::{
+    between_herd_spread_model::ContactRate,
+    csv_outputs::CsvOutputs,
+    farm_id_to_entity_map::FarmIdEntityMap,
+    local_spread_model::SpatialIndex,
+    parameters::Probability,
+    populations::{Cattle, FarmCoordinates, FarmId, HerdSize},
+    prelude::*,
+    regulator_active_surveillance::{DetectionSource, FarmDetected},
+    regulator_capacity::{ControlActivity, ControlCapacity, FarmPriority, PriorityPolicy},
+    regulator_movement_restrictions::MovementRestriction,
+    regulatory_status::{FarmStatus, RegulatoryStatus, StatusChanged},
+    scenario_rng::{ProcessRng, ZoneInspectionsStream},
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    sir_spread_model::Infected,
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ZoneKind {
+    Protection,
+    Surveillance,
+}
+
+impl ZoneKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoneKind::Protection => "protection",
+            ZoneKind::Surveillance => "surveillance",
+        }
+    }
+}
+
+impl std::fmt::Display for ZoneKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Extent of a zone, and the rules that apply within it.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, derive_new::new)]
+pub struct ZoneRules {
+    /// Radius in km around the confirmed farm.
+    pub radius: f64,
+    /// Days that a farm stays in the zone, after the latest confirmed farm
+    /// around it.
+    pub minimum_duration: Time,
+    /// Reduction of the shipments to and from the farms in the zone, where 1
+    /// is a movement ban.
+    pub movement_reduction: Probability,
+    /// Days between clinical inspections, or none if the farms aren't
+    /// inspected.
+    #[new(default)]
+    pub inspection_interval: Option<Time>,
+    /// Factor on the [crate::regulator_active_surveillance::DetectionRate].
+    #[new(value = "1.")]
+    pub detection_rate_multiplier: f64,
+}
+
+impl ZoneRules {
+    #[must_use]
+    pub fn with_inspections(mut self, inspection_interval: Time) -> Self {
+        self.inspection_interval = Some(inspection_interval);
+        self
+    }
+
+    #[must_use]
+    pub fn with_detection_rate_multiplier(mut self, detection_rate_multiplier: f64) -> Self {
+        self.detection_rate_multiplier = detection_rate_multiplier;
+        self
+    }
+}
+
+/// Add as a resource to enable zoning.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, derive_new::new)]
+pub struct ZoningPolicy {
+    pub protection: ZoneRules,
+    pub surveillance: ZoneRules,
+    /// Probability that an inspection finds an infected farm.
+    pub inspection_sensitivity: Probability,
+}
+
+impl Default for ZoningPolicy {
+    /// A movement ban and weekly inspections within 3 km for 15 days, and
+    /// reduced movements and a doubled detection rate within 10 km for 30
+    /// days.
+    fn default() -> Self {
+        Self {
+            protection: ZoneRules::new(3., 15, Probability::new(1.).unwrap()).with_inspections(7),
+            surveillance: ZoneRules::new(10., 30, Probability::new(0.5).unwrap())
+                .with_detection_rate_multiplier(2.),
+            inspection_sensitivity: Probability::new(0.9).unwrap(),
+        }
+    }
+}
+
+impl ZoningPolicy {
+    pub fn rules(&self, zone: ZoneKind) -> &ZoneRules {
+        match zone {
+            ZoneKind::Protection => &self.protection,
+            ZoneKind::Surveillance => &self.surveillance,
+        }
+    }
+
+    /// Factor on the detection rate of a farm, that is 1 outside the zones.
+    pub fn detection_rate_multiplier(
+        &self,
+        zone_membership: Option<&ZoneMembership>,
+        scenario_tick: Time,
+    ) -> f64 {
+        zone_membership
+            .and_then(|zone_membership| zone_membership.zone(scenario_tick))
+            .map_or(1., |zone| self.rules(zone).detection_rate_multiplier)
+    }
+}
+
+/// The zones that a farm is in, and until when.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZoneMembership {
+    pub protection_until: Option<Time>,
+    pub surveillance_until: Option<Time>,
+    pub last_inspection: Option<Time>,
+}
+
+impl ZoneMembership {
+    /// The strictest zone that the farm is in.
+    pub fn zone(&self, scenario_tick: Time) -> Option<ZoneKind> {
+        [ZoneKind::Protection, ZoneKind::Surveillance]
+            .iter()
+            .copied()
+            .find(|zone| {
+                self.until(*zone)
+                    .map_or(false, |until| scenario_tick < until)
+            })
+    }
+
+    pub fn until(&self, zone: ZoneKind) -> Option<Time> {
+        match zone {
+            ZoneKind::Protection => self.protection_until,
+            ZoneKind::Surveillance => self.surveillance_until,
+        }
+    }
+
+    /// Keeps the farm in the zone until at least `until`.
+    pub fn extend(&mut self, zone: ZoneKind, until: Time) {
+        let zone_until = match zone {
+            ZoneKind::Protection => &mut self.protection_until,
+            ZoneKind::Surveillance => &mut self.surveillance_until,
+        };
+        *zone_until = Some(zone_until.map_or(until, |zone_until| zone_until.max(until)));
+    }
+
+    /// Whether the farm is due for a clinical inspection.
+    pub fn is_due_for_inspection(&self, inspection_interval: Time, scenario_tick: Time) -> bool {
+        self.last_inspection.map_or(true, |last_inspection| {
+            scenario_tick >= last_inspection + inspection_interval
+        })
+    }
+}
+
+/// Farms by their location, to look up the farms within a zone.
+#[derive(Debug, Clone, derive_more::From)]
+pub struct ZoningIndex(pub SpatialIndex);
+
+/// Run criteria for [update_zones] and [update_zone_inspections].
+pub fn run_if_zoning(zoning_policy: Option<Res<ZoningPolicy>>) -> ShouldRun {
+    if zoning_policy.is_some() {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// Add this to a startup stage after the farms have been added. This does
+/// nothing without a [ZoningPolicy].
+pub fn setup_zoning(
+    mut commands: Commands,
+    policy: Option<Res<ZoningPolicy>>,
+    query: Query<(&FarmId, &FarmCoordinates), With<Cattle>>,
+) {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return,
+    };
+    let spatial_index = SpatialIndex::new(
+        policy.surveillance.radius.max(policy.protection.radius),
+        query
+            .iter()
+            .map(|(farm_id, coordinates)| (*farm_id, *coordinates)),
+    );
+    commands.insert_resource(ZoningIndex::from(spatial_index));
+}
+
+/// Draws zones around the newly confirmed farms, and applies the rules of the
+/// zones to the farms within them.
+#[allow(clippy::too_many_arguments)]
+pub fn update_zones(
+    mut commands: Commands,
+    policy: Res<ZoningPolicy>,
+    zoning_index: Res<ZoningIndex>,
+    mut reader: Local<ManualEventReader<StatusChanged>>,
+    mut status_changes: ResMut<Events<StatusChanged>>,
+    farms: Query<(Option<&FarmCoordinates>, Option<&MovementRestriction>)>,
+    mut memberships: Query<(Entity, &FarmId, &mut ZoneMembership)>,
+    mut statuses: Query<&mut RegulatoryStatus>,
+    scenario_time: Res<ScenarioTime>,
+    farm_map: Res<FarmIdEntityMap>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    let confirmed_farms = reader
+        .iter(&status_changes)
+        .filter(|status_changed| status_changed.to == FarmStatus::Confirmed)
+        .map(|status_changed| status_changed.farm_id)
+        .collect_vec();
+
+    let mut new_members = BTreeMap::<FarmId, ZoneMembership>::new();
+    for confirmed_farm in confirmed_farms {
+        let coordinates = match farms.get(farm_map.0[&confirmed_farm]) {
+            Ok((Some(coordinates), _)) => *coordinates,
+            // farms without a location cannot be zoned
+            _ => continue,
+        };
+        for (farm_id, distance) in zoning_index
+            .0
+            .within(coordinates, policy.surveillance.radius)
+        {
+            let extend = |zone_membership: &mut ZoneMembership| {
+                if distance <= policy.protection.radius {
+                    zone_membership.extend(
+                        ZoneKind::Protection,
+                        scenario_tick + policy.protection.minimum_duration,
+                    );
+                }
+                zone_membership.extend(
+                    ZoneKind::Surveillance,
+                    scenario_tick + policy.surveillance.minimum_duration,
+                );
+            };
+            match memberships.get_mut(farm_map.0[&farm_id]) {
+                Ok((.., mut zone_membership)) => extend(&mut *zone_membership),
+                Err(_) => extend(new_members.entry(farm_id).or_default()),
+            }
+        }
+    }
+
+    let zoned_farms =
+        memberships
+            .iter_mut()
+            .map(|(entity, farm_id, zone_membership)| (*farm_id, (entity, *zone_membership)))
+            .chain(new_members.iter().map(|(farm_id, zone_membership)| {
+                (*farm_id, (farm_map.0[farm_id], *zone_membership))
+            }))
+            .collect::<BTreeMap<_, _>>();
+    for (farm_id, (entity, zone_membership)) in zoned_farms {
+        let zone = match zone_membership.zone(scenario_tick) {
+            Some(zone) => zone,
+            None => {
+                // the restriction and the status are lifted along with the
+                // other movement restrictions
+                commands.entity(entity).remove::<ZoneMembership>();
+                continue;
+            }
+        };
+        if new_members.contains_key(&farm_id) {
+            commands.entity(entity).insert(zone_membership);
+        }
+
+        let (_, existing_restriction) = farms.get(entity).expect("failed to find zoned farm");
+        let restriction = MovementRestriction::new(
+            policy.rules(zone).movement_reduction,
+            zone_membership.until(zone).unwrap(),
+        )
+        .tightened(existing_restriction, scenario_tick);
+        if Some(&restriction) != existing_restriction {
+            commands.entity(entity).insert(restriction);
+        }
+
+        if let Ok(mut status) = statuses.get_mut(entity) {
+            // suspected and confirmed farms keep their status
+            if matches!(status.status, FarmStatus::Free | FarmStatus::Restocked) {
+                if let Some(status_changed) =
+                    status.transition(farm_id, FarmStatus::Restricted, scenario_tick)
+                {
+                    status_changes.send(status_changed);
+                }
+            }
+        }
+    }
+}
+
+/// Clinical inspections of the zoned farms, that detect infected farms with
+/// [ZoningPolicy::inspection_sensitivity].
+///
+/// Farms that are due, but beyond the [ControlCapacity] of the day, are
+/// inspected once there is capacity again. The due farms are inspected in the
+/// order of the [PriorityPolicy].
+#[allow(clippy::too_many_arguments)]
+pub fn update_zone_inspections(
+    policy: Res<ZoningPolicy>,
+    mut query: Query<(
+        Entity,
+        &FarmId,
+        &mut ZoneMembership,
+        &Infected,
+        Option<&RegulatoryStatus>,
+    )>,
+    mut rng: ResMut<ProcessRng<ZoneInspectionsStream>>,
+    scenario_time: Res<ScenarioTime>,
+    mut detections: EventWriter<FarmDetected>,
+    mut control_capacity: Option<ResMut<ControlCapacity>>,
+    priority_policy: Option<Res<PriorityPolicy>>,
+    priorities: Query<(&HerdSize, &Infected, Option<&ContactRate>)>,
+) {
+    let scenario_tick = scenario_time.current_time();
+    let mut due_farms = query
+        .iter_mut()
+        .filter(|(_, _, zone_membership, _, status)| {
+            // already known to be infected
+            if status.map_or(false, |status| {
+                matches!(status.status, FarmStatus::Confirmed | FarmStatus::Culled)
+            }) {
+                return false;
+            }
+            zone_membership
+                .zone(scenario_tick)
+                .and_then(|zone| policy.rules(zone).inspection_interval)
+                .map_or(false, |inspection_interval| {
+                    zone_membership.is_due_for_inspection(inspection_interval, scenario_tick)
+                })
+        })
+        .map(|(farm, farm_id, ..)| (farm, *farm_id))
+        .collect_vec();
+    if let Some(priority_policy) = priority_policy {
+        priority_policy.prioritise(&mut due_farms, |(farm, _)| {
+            let (herd_size, infected, contact_rate) =
+                priorities.get(*farm).expect("failed to find zoned farm");
+            FarmPriority::new(herd_size.0, infected.0, contact_rate)
+        });
+    }
+    for (farm, farm_id) in due_farms {
+        if !control_capacity.as_mut().map_or(true, |capacity| {
+            capacity.try_consume(ControlActivity::Visit, farm_id, scenario_tick)
+        }) {
+            continue;
+        }
+        let (_, _, mut zone_membership, infected, _) = query.get_mut(farm).unwrap();
+        zone_membership.last_inspection = Some(scenario_tick);
+        if infected.0 > 0 && rng.gen_bool(policy.inspection_sensitivity.0) {
+            detections.send(FarmDetected {
+                farm_id,
+                scenario_tick,
+                source: DetectionSource::Inspection,
+            });
+        }
+    }
+}
+
+/// The farms that are zoned in a tick.
+#[derive(Debug, Clone)]
+pub struct ZonedFarms {
+    pub scenario_tick: Time,
+    pub farms: Vec<(FarmId, ZoneKind)>,
+}
+
+/// Add this after [update_zones].
+pub fn collect_zoned_farms(
+    query: Query<(&FarmId, &ZoneMembership)>,
+    scenario_time: Res<ScenarioTime>,
+) -> Option<ZonedFarms> {
+    let scenario_tick = scenario_time.current_time();
+    let farms = query
+        .iter()
+        .filter_map(|(farm_id, zone_membership)| {
+            Some((*farm_id, zone_membership.zone(scenario_tick)?))
+        })
+        .sorted_by_key(|(farm_id, _)| *farm_id)
+        .collect_vec();
+    if farms.is_empty() {
+        None
+    } else {
+        Some(ZonedFarms {
+            scenario_tick,
+            farms,
+        })
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct ZoneMembershipRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for ZoneMembershipRecorder {
+    const FILE_NAME: &'static str = "zone_membership.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_zone_membership].
+pub fn setup_zone_membership_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer =
+        csv_outputs.create("zone_membership.csv", &["scenario_tick", "farm_id", "zone"]);
+    commands.insert_resource(ZoneMembershipRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_zone_membership_recording]
+pub fn record_zone_membership(
+    In(events): In<Option<ZonedFarms>>,
+    mut csv_file: ResMut<ZoneMembershipRecorder>,
+) -> Option<ZonedFarms> {
+    if let Some(ZonedFarms {
+        scenario_tick,
+        farms,
+    }) = &events
+    {
+        for (farm_id, zone) in farms {
+            csv_file
+                .0
+                .serialize((scenario_tick, farm_id.0, zone.as_str()))
+                .unwrap();
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_overlapping_zones_merge() {
+        let mut zone_membership = ZoneMembership::default();
+        assert_eq!(zone_membership.zone(1), None);
+        zone_membership.extend(ZoneKind::Protection, 16);
+        zone_membership.extend(ZoneKind::Surveillance, 31);
+        // a later confirmation nearby only lengthens the surveillance zone
+        zone_membership.extend(ZoneKind::Surveillance, 40);
+        zone_membership.extend(ZoneKind::Protection, 10);
+        assert_eq!(zone_membership.zone(15), Some(ZoneKind::Protection));
+        assert_eq!(zone_membership.zone(16), Some(ZoneKind::Surveillance));
+        assert_eq!(zone_membership.zone(39), Some(ZoneKind::Surveillance));
+        assert_eq!(zone_membership.zone(40), None);
+
+        assert!(zone_membership.is_due_for_inspection(7, 1));
+        zone_membership.last_inspection = Some(1);
+        assert!(!zone_membership.is_due_for_inspection(7, 7));
+        assert!(zone_membership.is_due_for_inspection(7, 8));
+    }
+
+    #[test]
+    fn test_zones_around_confirmed_farms() {
+        let mut world = World::new();
+        world.insert_resource(ScenarioTime::new(1, None));
+        world.insert_resource(Events::<StatusChanged>::default());
+        world.insert_resource(ZoningPolicy::default());
+        let mut farm_map = HashMap::new();
+        let mut entities = Vec::new();
+        // at 0 km, 2 km, 5 km and 20 km from the first farm
+        for (farm_id, x) in [(1, 0.), (2, 2.), (3, 5.), (4, 20.)] {
+            let farm_id = FarmId::new_single_population(farm_id);
+            let entity = world
+                .spawn()
+                .insert_bundle((
+                    Cattle,
+                    farm_id,
+                    HerdSize::new_single_population(100),
+                    FarmCoordinates { x, y: 0. },
+                    RegulatoryStatus::new(FarmStatus::Free, 1),
+                ))
+                .id();
+            farm_map.insert(farm_id, entity);
+            entities.push(entity);
+        }
+        world.insert_resource(FarmIdEntityMap::from(farm_map));
+
+        let mut setup = SystemStage::single(setup_zoning.system());
+        setup.run(&mut world);
+        world
+            .get_resource_mut::<Events<StatusChanged>>()
+            .unwrap()
+            .send(StatusChanged {
+                farm_id: FarmId::new_single_population(1),
+                from: FarmStatus::Free,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 1,
+            });
+        let mut stage = SystemStage::single(update_zones.system());
+        stage.run(&mut world);
+
+        let zone = |world: &World, entity: Entity, scenario_tick: Time| {
+            world
+                .get::<ZoneMembership>(entity)
+                .and_then(|zone_membership| zone_membership.zone(scenario_tick))
+        };
+        assert_eq!(zone(&world, entities[1], 1), Some(ZoneKind::Protection));
+        assert_eq!(zone(&world, entities[2], 1), Some(ZoneKind::Surveillance));
+        assert_eq!(zone(&world, entities[3], 1), None);
+        assert_eq!(
+            world.get::<MovementRestriction>(entities[1]),
+            Some(&MovementRestriction::new(Probability::new(1.).unwrap(), 16))
+        );
+        assert_eq!(
+            world.get::<RegulatoryStatus>(entities[2]).unwrap().status,
+            FarmStatus::Restricted
+        );
+
+        // a confirmed farm in the surveillance zone extends it
+        world.insert_resource(ScenarioTime::new(10, None));
+        world
+            .get_resource_mut::<Events<StatusChanged>>()
+            .unwrap()
+            .send(StatusChanged {
+                farm_id: FarmId::new_single_population(3),
+                from: FarmStatus::Restricted,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 10,
+            });
+        stage.run(&mut world);
+        assert_eq!(zone(&world, entities[0], 31), Some(ZoneKind::Surveillance));
+        assert_eq!(zone(&world, entities[3], 10), None);
+        assert_eq!(
+            world
+                .get::<ZoneMembership>(entities[2])
+                .unwrap()
+                .protection_until,
+            Some(25)
+        );
+
+        world.insert_resource(ScenarioTime::new(40, None));
+        stage.run(&mut world);
+        assert!(world.get::<ZoneMembership>(entities[0]).is_none());
+    }
+}
@@ -0,0 +1,319 @@
+//! Official status of a farm, that the control measures are conditional on.
+//!
+//! The regulators only know what has been observed, thus the status may
+//! differ from the true infection state, e.g. a suspected farm may not be
+//! infected at all.
+//!
+//! The farms start out [FarmStatus::Free], and move through
+//!
+//! * free → suspected → confirmed → culled → restocked → free, where culled
+//!   farms are restocked by [crate::regulator_restocking],
+//! * suspected → free, if the suspicion is cleared,
+//! * restricted, e.g. as a traced contact or within a zone, until the
+//!   restrictions are lifted, or the farm is confirmed,
+//!
+//! see [FarmStatus::can_transition_to] for all allowed transitions. Every
+//! change is put out as a [StatusChanged]-event, which is recorded by
+//! [record_status_changes].
+//!
+
+use std::fs::File;
+
+use csv::Writer;
+
+use crate::{
+    csv_outputs::CsvOutputs,
+    diagnostic_tests::HerdTested,
+    populations::{Cattle, FarmId},
+    prelude::*,
+    regulator_active_surveillance::FarmDetected,
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+};
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FarmStatus {
+    /// Officially free of the disease.
+    Free,
+    /// Under investigation, e.g. after a positive screening test or clinical
+    /// signs.
+    Suspected,
+    /// The infection has been confirmed.
+    Confirmed,
+    /// Under movement restrictions, e.g. as a contact or within a zone.
+    Restricted,
+    /// Depopulated.
+    Culled,
+    /// Repopulated after culling.
+    Restocked,
+}
+
+impl FarmStatus {
+    pub fn can_transition_to(&self, to: FarmStatus) -> bool {
+        use FarmStatus::*;
+        matches!(
+            (*self, to),
+            (Free, Suspected)
+                | (Free, Confirmed)
+                | (Free, Restricted)
+                | (Suspected, Free)
+                | (Suspected, Confirmed)
+                | (Suspected, Restricted)
+                | (Confirmed, Restricted)
+                | (Confirmed, Culled)
+                | (Restricted, Free)
+                | (Restricted, Suspected)
+                | (Restricted, Confirmed)
+                | (Restricted, Culled)
+                | (Culled, Restocked)
+                | (Restocked, Free)
+                | (Restocked, Suspected)
+                | (Restocked, Confirmed)
+                | (Restocked, Restricted)
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FarmStatus::Free => "free",
+            FarmStatus::Suspected => "suspected",
+            FarmStatus::Confirmed => "confirmed",
+            FarmStatus::Restricted => "restricted",
+            FarmStatus::Culled => "culled",
+            FarmStatus::Restocked => "restocked",
+        }
+    }
+}
+
+impl std::fmt::Display for FarmStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The official status of a farm, and the tick it was entered.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_new::new)]
+pub struct RegulatoryStatus {
+    pub status: FarmStatus,
+    pub since: Time,
+}
+
+impl RegulatoryStatus {
+    /// Ticks spent in the current status.
+    pub fn duration(&self, scenario_tick: Time) -> Time {
+        scenario_tick.saturating_sub(self.since)
+    }
+
+    /// Changes the status if the transition is allowed, and returns the
+    /// corresponding event.
+    pub fn transition(
+        &mut self,
+        farm_id: FarmId,
+        to: FarmStatus,
+        scenario_tick: Time,
+    ) -> Option<StatusChanged> {
+        if !self.status.can_transition_to(to) {
+            return None;
+        }
+        let from = self.status;
+        self.status = to;
+        self.since = scenario_tick;
+        Some(StatusChanged {
+            farm_id,
+            from,
+            to,
+            scenario_tick,
+        })
+    }
+}
+
+/// Event that is sent whenever the [RegulatoryStatus] of a farm changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusChanged {
+    pub farm_id: FarmId,
+    pub from: FarmStatus,
+    pub to: FarmStatus,
+    pub scenario_tick: Time,
+}
+
+/// Every farm starts out [FarmStatus::Free].
+pub fn setup_regulatory_status(
+    mut commands: Commands,
+    scenario_time: Res<ScenarioTime>,
+    query: Query<Entity, With<Cattle>>,
+) {
+    query.for_each(|entity| {
+        commands.entity(entity).insert(RegulatoryStatus::new(
+            FarmStatus::Free,
+            scenario_time.current_time(),
+        ));
+    });
+}
+
+/// Detected farms are confirmed, unless they are already confirmed (or culled).
+pub fn update_status_on_detection(
+    mut detections: EventReader<FarmDetected>,
+    mut query: Query<(&FarmId, &mut RegulatoryStatus)>,
+    farm_map: Res<crate::farm_id_to_entity_map::FarmIdEntityMap>,
+    mut status_changes: EventWriter<StatusChanged>,
+) {
+    for detection in detections.iter() {
+        let (farm_id, mut status) = query
+            .get_mut(farm_map.0[&detection.farm_id])
+            .expect("detected farm has no regulatory status");
+        if let Some(status_changed) =
+            status.transition(*farm_id, FarmStatus::Confirmed, detection.scenario_tick)
+        {
+            status_changes.send(status_changed);
+        }
+    }
+}
+
+/// Positive farms become suspected, and suspected farms that test negative
+/// are cleared.
+pub fn update_status_on_test_results(
+    mut herd_tests: EventReader<HerdTested>,
+    mut query: Query<(&FarmId, &mut RegulatoryStatus)>,
+    farm_map: Res<crate::farm_id_to_entity_map::FarmIdEntityMap>,
+    mut status_changes: EventWriter<StatusChanged>,
+) {
+    for herd_tested in herd_tests.iter() {
+        let (farm_id, mut status) = query
+            .get_mut(farm_map.0[&herd_tested.farm_id])
+            .expect("tested farm has no regulatory status");
+        let to = if herd_tested.result.positive {
+            FarmStatus::Suspected
+        } else if status.status == FarmStatus::Suspected {
+            FarmStatus::Free
+        } else {
+            continue;
+        };
+        if let Some(status_changed) = status.transition(*farm_id, to, herd_tested.scenario_tick) {
+            status_changes.send(status_changed);
+        }
+    }
+}
+
+#[derive(derive_more::From)]
+pub struct StatusChangesRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for StatusChangesRecorder {
+    const FILE_NAME: &'static str = "status_changes.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// This is coupled with system [record_status_changes].
+pub fn setup_status_changes_recording(mut commands: Commands, csv_outputs: CsvOutputs) {
+    let csv_writer = csv_outputs.create(
+        "status_changes.csv",
+        &["scenario_tick", "farm_id", "from", "to"],
+    );
+    commands.insert_resource(StatusChangesRecorder::from(csv_writer));
+}
+
+/// The saved fields must correspond to [setup_status_changes_recording]
+pub fn record_status_changes(
+    mut status_changes: EventReader<StatusChanged>,
+    mut csv_file: ResMut<StatusChangesRecorder>,
+) {
+    for status_changed in status_changes.iter() {
+        csv_file
+            .0
+            .serialize((
+                status_changed.scenario_tick,
+                status_changed.farm_id.0,
+                status_changed.from.as_str(),
+                status_changed.to.as_str(),
+            ))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        farm_id_to_entity_map::FarmIdEntityMap, regulator_active_surveillance::DetectionSource,
+    };
+    use bevy::app::ManualEventReader;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_transitions() {
+        let farm_id = FarmId::new_single_population(1);
+        let mut status = RegulatoryStatus::new(FarmStatus::Free, 1);
+        assert!(status.transition(farm_id, FarmStatus::Culled, 2).is_none());
+        assert_eq!(status.status, FarmStatus::Free);
+
+        for (tick, to) in [
+            (3, FarmStatus::Suspected),
+            (5, FarmStatus::Confirmed),
+            (6, FarmStatus::Culled),
+            (40, FarmStatus::Restocked),
+            (60, FarmStatus::Free),
+        ] {
+            let from = status.status;
+            assert_eq!(
+                status.transition(farm_id, to, tick),
+                Some(StatusChanged {
+                    farm_id,
+                    from,
+                    to,
+                    scenario_tick: tick,
+                })
+            );
+        }
+        assert_eq!(status.since, 60);
+        assert_eq!(status.duration(70), 10);
+        assert!(status.transition(farm_id, FarmStatus::Free, 71).is_none());
+    }
+
+    #[test]
+    fn test_confirm_on_detection() {
+        let mut world = World::new();
+        world.insert_resource(Events::<FarmDetected>::default());
+        world.insert_resource(Events::<StatusChanged>::default());
+        let farm_id: FarmId = FarmId::new_single_population(1);
+        let farm = world
+            .spawn()
+            .insert_bundle((farm_id, RegulatoryStatus::new(FarmStatus::Free, 1)))
+            .id();
+        let farm_map: HashMap<FarmId, Entity> = vec![(farm_id, farm)].into_iter().collect();
+        world.insert_resource(FarmIdEntityMap::from(farm_map));
+
+        let mut detections = world.get_resource_mut::<Events<FarmDetected>>().unwrap();
+        for scenario_tick in [4, 5] {
+            detections.send(FarmDetected {
+                farm_id,
+                scenario_tick,
+                source: DetectionSource::Surveillance,
+            });
+        }
+        let mut stage = SystemStage::single(update_status_on_detection.system());
+        stage.run(&mut world);
+
+        let status = world.get::<RegulatoryStatus>(farm).unwrap();
+        assert_eq!(*status, RegulatoryStatus::new(FarmStatus::Confirmed, 4));
+        let status_changes = world.get_resource::<Events<StatusChanged>>().unwrap();
+        let status_changes = ManualEventReader::<StatusChanged>::default()
+            .iter(status_changes)
+            .copied()
+            .collect_vec();
+        assert_eq!(
+            status_changes,
+            vec![StatusChanged {
+                farm_id,
+                from: FarmStatus::Free,
+                to: FarmStatus::Confirmed,
+                scenario_tick: 4,
+            }]
+        );
+    }
+}
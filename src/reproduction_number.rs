@@ -0,0 +1,331 @@
+//! Online estimator of the farm-level (effective) reproduction number `Rt`.
+//!
+//! Secondary farm infections are counted per infected farm by the
+//! [TransmissionTree], as the infection events come in, and the estimate is
+//! the mean number of secondary infections among farms that were infected
+//! within the last [ReproductionNumberWindow] ticks. Thus every update only
+//! looks at the farms within the window.
+//!
+//! Note: Farms infected recently have had less time to infect others, thus
+//! the estimate is biased downwards in the early part of the window.
+//!
+//! The estimate is available as the resource [ReproductionNumber], and can
+//! be used as a run-criteria through [run_if_reproduction_number_above],
+//! e.g. to trigger an intensified control policy while `Rt > 1`.
+//!
+
+use std::collections::BTreeMap;
+
+use bevy::ecs::{schedule::ShouldRun, system::System};
+use csv::Writer;
+use std::fs::File;
+
+use crate::{
+    csv_outputs::CsvOutputs,
+    prelude::*,
+    scenario_time::scenario_timer::{ScenarioTime, Time},
+    transmission_tree::TransmissionTree,
+};
+
+/// Number of ticks that the rolling mean of `Rt` is taken over.
+#[readonly::make]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, derive_more::Into, derive_more::Display, derive_new::new)]
+pub struct ReproductionNumberWindow(pub Time);
+
+impl Default for ReproductionNumberWindow {
+    fn default() -> Self {
+        Self(28)
+    }
+}
+
+/// Current estimate of the farm-level reproduction number.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ReproductionNumber {
+    /// Time of the estimate.
+    pub scenario_tick: Time,
+    /// Rolling mean of secondary infections for farms infected within
+    /// the window, if there are any.
+    pub rolling_mean: Option<f64>,
+    /// Distribution of secondary infections within the window as
+    /// `no. of secondary infections ~> no. of farms`.
+    pub distribution: BTreeMap<usize, usize>,
+    /// Cumulative reproduction number of the index farm(s), i.e. the size of
+    /// the first generation per index farm.
+    pub index_reproduction_number: Option<f64>,
+}
+
+impl ReproductionNumber {
+    /// Number of infected farms that entered the rolling mean.
+    pub fn total_infectors(&self) -> usize {
+        self.distribution.values().sum()
+    }
+
+    /// Returns `true` if the rolling mean of `Rt` exceeds `threshold`.
+    pub fn exceeds(&self, threshold: f64) -> bool {
+        self.rolling_mean.map_or(false, |rt| rt > threshold)
+    }
+}
+
+/// This is coupled with the systems [update_reproduction_number] and
+/// [record_reproduction_number].
+pub fn setup_reproduction_number_estimator(mut commands: Commands, csv_outputs: CsvOutputs) {
+    commands.insert_resource(ReproductionNumber::default());
+
+    let csv_writer = csv_outputs.create(
+        "reproduction_number.csv",
+        &[
+            "scenario_tick",
+            "reproduction_number",
+            "index_reproduction_number",
+            "infectors",
+            "secondary_infections_distribution",
+        ],
+    );
+    commands.insert_resource(ReproductionNumberRecorder::from(csv_writer));
+}
+
+/// Estimate `Rt` from the current [TransmissionTree].
+///
+/// The estimate is stored in [ReproductionNumber] and passed on, see
+/// [record_reproduction_number].
+pub fn update_reproduction_number(
+    transmission_tree: Res<TransmissionTree>,
+    window: Option<Res<ReproductionNumberWindow>>,
+    scenario_time: Res<ScenarioTime>,
+    mut estimate: ResMut<ReproductionNumber>,
+) -> ReproductionNumber {
+    let window = window.map_or_else(ReproductionNumberWindow::default, |x| *x);
+    let current_time = scenario_time.current_time();
+
+    // farms with `infection_time + window > current_time`
+    let distribution = transmission_tree
+        .infected_within((current_time + 1).saturating_sub(window.0)..)
+        .fold(BTreeMap::new(), |mut distribution, (_, secondary)| {
+            *distribution.entry(secondary).or_insert(0) += 1;
+            distribution
+        });
+
+    let (secondary_infections, infectors) = distribution
+        .iter()
+        .fold((0, 0), |(secondary, infectors), (&count, &farms)| {
+            (secondary + count * farms, infectors + farms)
+        });
+    let rolling_mean = (infectors > 0).then(|| secondary_infections as f64 / infectors as f64);
+
+    let index_farms = transmission_tree.index_farms();
+    let index_reproduction_number = (!index_farms.is_empty()).then(|| {
+        index_farms
+            .iter()
+            .map(|x| transmission_tree.secondary_infections(x))
+            .sum::<usize>() as f64
+            / index_farms.len() as f64
+    });
+
+    *estimate = ReproductionNumber {
+        scenario_tick: current_time,
+        rolling_mean,
+        distribution,
+        index_reproduction_number,
+    };
+    estimate.clone()
+}
+
+#[derive(derive_more::From)]
+pub struct ReproductionNumberRecorder(Writer<File>);
+
+#[cfg(feature = "serialize")]
+impl crate::checkpoint::CsvRecorder for ReproductionNumberRecorder {
+    const FILE_NAME: &'static str = "reproduction_number.csv";
+
+    fn writer_mut(&mut self) -> &mut Writer<File> {
+        &mut self.0
+    }
+}
+
+/// The saved fields must correspond to [setup_reproduction_number_estimator]
+pub fn record_reproduction_number(
+    In(estimate): In<ReproductionNumber>,
+    mut csv_file: ResMut<ReproductionNumberRecorder>,
+) {
+    let ReproductionNumber {
+        scenario_tick,
+        rolling_mean,
+        distribution,
+        index_reproduction_number,
+    } = estimate;
+    let infectors: usize = distribution.values().sum();
+    // e.g. `0:4|1:2|3:1`
+    let distribution = distribution
+        .into_iter()
+        .map(|(secondary, farms)| format!("{}:{}", secondary, farms))
+        .join("|");
+
+    csv_file
+        .0
+        .serialize((
+            scenario_tick,
+            rolling_mean,
+            index_reproduction_number,
+            infectors,
+            distribution,
+        ))
+        .unwrap();
+}
+
+/// Attach as a run-criteria to systems that should only run while the
+/// estimate of `Rt` exceeds `threshold`, e.g. 1 for a growing epidemic.
+///
+/// The systems don't run while there is no estimate, i.e. before the first
+/// update, or when no farm was infected within the window.
+///
+/// This is already a system, thus it is attached as
+/// `.with_run_criteria(run_if_reproduction_number_above(1.))`.
+pub fn run_if_reproduction_number_above(threshold: f64) -> impl System<In = (), Out = ShouldRun> {
+    (move |estimate: Option<Res<ReproductionNumber>>| {
+        if estimate.map_or(false, |estimate| estimate.exceeds(threshold)) {
+            ShouldRun::Yes
+        } else {
+            ShouldRun::No
+        }
+    })
+    .system()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        between_herd_spread_model::{InfectionEvents, InfectionRoute},
+        populations::FarmId,
+    };
+
+    #[test]
+    fn test_rolling_reproduction_number() {
+        let farm = FarmId::new_single_population;
+        let mut world = World::new();
+
+        let mut transmission_tree = TransmissionTree::new(0);
+        // 1 ~> {2, 3} at tick 2, and then 2 ~> 4 at tick 10
+        transmission_tree.add_events(&InfectionEvents {
+            scenario_tick: 2,
+            batch_id: 1,
+            route: InfectionRoute::Trade,
+            events_values: vec![(farm(1), farm(2), 1), (farm(1), farm(3), 1)],
+        });
+        transmission_tree.add_events(&InfectionEvents {
+            scenario_tick: 10,
+            batch_id: 2,
+            route: InfectionRoute::Trade,
+            events_values: vec![(farm(2), farm(4), 1)],
+        });
+        world.insert_resource(transmission_tree);
+        world.insert_resource(ReproductionNumberWindow::new(5));
+        world.insert_resource(ReproductionNumber::default());
+        world.insert_resource(ScenarioTime::new(10, None));
+
+        let mut stage = SystemStage::single(
+            update_reproduction_number
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        stage.run(&mut world);
+
+        let estimate = world.get_resource::<ReproductionNumber>().unwrap();
+        // only farm 4 is infected within the window
+        assert_eq!(estimate.rolling_mean, Some(0.));
+        assert_eq!(estimate.index_reproduction_number, Some(2.));
+        assert_eq!(estimate.total_infectors(), 1);
+        assert!(!estimate.exceeds(1.));
+    }
+
+    #[test]
+    fn test_estimate_is_updated_every_tick() {
+        let farm = FarmId::new_single_population;
+        let mut world = World::new();
+        world.insert_resource(TransmissionTree::new(0));
+        world.insert_resource(ReproductionNumberWindow::new(3));
+        world.insert_resource(ReproductionNumber::default());
+        world.insert_resource(ScenarioTime::new(1, None));
+        let mut stage = SystemStage::single(
+            update_reproduction_number
+                .system()
+                .chain(crate::chain_tools::dispose.system()),
+        );
+        let mut run = |world: &mut World, scenario_tick, events_values| {
+            world.insert_resource(ScenarioTime::new(scenario_tick, None));
+            world
+                .get_resource_mut::<TransmissionTree>()
+                .unwrap()
+                .add_events(&InfectionEvents {
+                    scenario_tick,
+                    batch_id: scenario_tick as usize,
+                    route: InfectionRoute::Trade,
+                    events_values,
+                });
+            stage.run(world);
+            world.get_resource::<ReproductionNumber>().unwrap().clone()
+        };
+
+        let estimate = run(&mut world, 1, vec![(farm(1), farm(2), 1)]);
+        assert_eq!(estimate.rolling_mean, Some(0.5));
+
+        // the re-infection of farm 1 is not an offspring of farm 3
+        let estimate = run(
+            &mut world,
+            2,
+            vec![
+                (farm(2), farm(3), 1),
+                (farm(2), farm(4), 1),
+                (farm(3), farm(1), 1),
+            ],
+        );
+        assert_eq!(estimate.rolling_mean, Some(0.75));
+        assert_eq!(
+            estimate.distribution,
+            maplit::btreemap! { 0 => 2, 1 => 1, 2 => 1 }
+        );
+        assert_eq!(estimate.index_reproduction_number, Some(1.));
+
+        // everyone has left the window
+        let estimate = run(&mut world, 5, vec![]);
+        assert_eq!(estimate.rolling_mean, None);
+        assert_eq!(estimate.total_infectors(), 0);
+    }
+
+    #[test]
+    fn test_run_if_reproduction_number_above() {
+        #[derive(Default)]
+        struct Runs(usize);
+        fn count_runs(mut runs: ResMut<Runs>) {
+            runs.0 += 1;
+        }
+
+        let mut world = World::new();
+        world.insert_resource(Runs::default());
+        let mut stage = SystemStage::single(
+            count_runs
+                .system()
+                .with_run_criteria(run_if_reproduction_number_above(1.)),
+        );
+        // no estimate yet
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<Runs>().unwrap().0, 0);
+
+        let mut runs = |world: &mut World, rolling_mean| {
+            world.insert_resource(ReproductionNumber {
+                rolling_mean,
+                ..Default::default()
+            });
+            stage.run(world);
+            world.get_resource::<Runs>().unwrap().0
+        };
+        assert_eq!(runs(&mut world, Some(0.8)), 0);
+        assert_eq!(runs(&mut world, Some(1.)), 0);
+        assert_eq!(runs(&mut world, Some(1.5)), 1);
+        assert_eq!(runs(&mut world, Some(1.2)), 2);
+        assert_eq!(runs(&mut world, Some(0.9)), 2);
+        assert_eq!(runs(&mut world, None), 2);
+    }
+}
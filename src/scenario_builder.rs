@@ -11,10 +11,10 @@
 use crate::{
     populations::{EmbeddedPopulation, Population},
     prelude::*,
+    scenario_rng::{insert_random_streams, MasterSeed},
     scenario_time::scenario_timer::ScenarioTime,
 };
 use bevy::ecs::{component::Component, schedule::ReportExecutionOrderAmbiguities};
-use rand::{prelude::StdRng, SeedableRng};
 use std::collections::HashMap;
 
 /// Build up a scenario; meaning include all the populations that need to be
@@ -46,7 +46,7 @@ impl ScenarioBuilder {
         world.insert_resource(ReportExecutionOrderAmbiguities);
 
         // world.insert_resource(StdRng::seed_from_u64(20210426));
-        world.insert_resource(StdRng::seed_from_u64(self.seed));
+        insert_random_streams(&mut world, MasterSeed(self.seed));
         world.insert_resource(ScenarioTime::new(1, None));
 
         Scenario { world }
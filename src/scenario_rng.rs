@@ -0,0 +1,220 @@
+//! The random number generators of the simulation processes.
+//!
+//! [ScenarioRng] is the same generator as [rand::rngs::StdRng] (ChaCha with
+//! 12 rounds), but unlike [rand::rngs::StdRng] its state is accessible, and can be
+//! serialised, see [crate::checkpoint].
+//!
+//! Every process draws from its own stream [ProcessRng], and these are all
+//! derived from the [MasterSeed]. Thus adding, removing or reordering a
+//! process doesn't change the random draws of the other processes, and the
+//! results are reproducible regardless of the order the systems are scheduled
+//! in, e.g. in a parallel stage.
+//!
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use bevy::prelude::*;
+use rand::{RngCore, SeedableRng};
+
+/// Random number generator that the process streams are made of.
+pub type ScenarioRng = rand_chacha::ChaCha12Rng;
+
+/// Seed from which all random number streams are derived.
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Into, derive_more::From)]
+pub struct MasterSeed(pub u64);
+
+/// Marker of a random stream.
+pub trait RandomStream: Send + Sync + 'static {
+    /// Stream no. unique for every process, and less than `2^32`.
+    const STREAM: u64;
+}
+
+/// Random number generator of the process `S`.
+pub struct ProcessRng<S: RandomStream> {
+    rng: ScenarioRng,
+    stream: PhantomData<S>,
+}
+
+impl<S: RandomStream> ProcessRng<S> {
+    pub fn from_master_seed(master_seed: MasterSeed) -> Self {
+        let mut rng = ScenarioRng::seed_from_u64(master_seed.0);
+        rng.set_stream(S::STREAM);
+        Self::from_rng_state(rng)
+    }
+
+    /// Continue the stream from a previous state, see [random_streams_state].
+    pub fn from_rng_state(rng: ScenarioRng) -> Self {
+        Self {
+            rng,
+            stream: PhantomData,
+        }
+    }
+}
+
+impl<S: RandomStream> std::fmt::Debug for ProcessRng<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessRng")
+            .field("stream", &S::STREAM)
+            .field("word_pos", &self.rng.get_word_pos())
+            .finish()
+    }
+}
+
+impl<S: RandomStream> RngCore for ProcessRng<S> {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+macro_rules! random_streams {
+    ($($(#[$meta:meta])* $stream:ident = $stream_no:expr),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, Copy)]
+            pub struct $stream;
+
+            impl RandomStream for $stream {
+                const STREAM: u64 = $stream_no;
+            }
+        )*
+
+        /// Insert the [MasterSeed] and the streams of all processes derived
+        /// from it.
+        pub fn insert_random_streams(world: &mut World, master_seed: MasterSeed) {
+            world.insert_resource(master_seed);
+            $(
+                world.insert_resource(ProcessRng::<$stream>::from_master_seed(master_seed));
+            )*
+        }
+
+        /// Generator states of all the process streams, keyed by stream no.
+        pub fn random_streams_state(world: &World) -> BTreeMap<u64, ScenarioRng> {
+            let mut states = BTreeMap::new();
+            $(
+                if let Some(process_rng) = world.get_resource::<ProcessRng<$stream>>() {
+                    states.insert($stream::STREAM, process_rng.rng.clone());
+                }
+            )*
+            states
+        }
+
+        /// Continue the process streams from the given states, see [random_streams_state].
+        pub fn restore_random_streams(world: &mut World, states: &BTreeMap<u64, ScenarioRng>) {
+            $(
+                if let Some(rng) = states.get(&$stream::STREAM) {
+                    world.insert_resource(ProcessRng::<$stream>::from_rng_state(rng.clone()));
+                }
+            )*
+        }
+    };
+}
+
+random_streams! {
+    /// [crate::sir_spread_model::seed_infection_random]
+    SeedInfectionStream = 1,
+    /// [crate::sir_spread_model::update_disease_compartments]
+    WithinHerdSpreadStream = 2,
+    /// [crate::between_herd_spread_model::update_between_herd_spread_model]
+    BetweenHerdSpreadStream = 3,
+    /// [crate::regulator_active_surveillance::update_active_surveillance]
+    ActiveSurveillanceStream = 4,
+    /// [crate::regulator_passive_surveillance::update_passive_surveillance]
+    PassiveSurveillanceStream = 5,
+    /// [crate::animal_movements::update_animal_movements]
+    AnimalMovementsStream = 6,
+    /// [crate::movement_register::replay_registered_movements]
+    MovementRegisterStream = 7,
+    /// [crate::local_spread_model::update_local_spread]
+    LocalSpreadStream = 8,
+    /// [crate::contact_networks::setup_contact_network]
+    ContactNetworkStream = 9,
+    /// [crate::markets::update_markets]
+    MarketStream = 10,
+    /// [crate::indirect_contacts::update_indirect_contacts]
+    IndirectContactStream = 11,
+    /// [crate::diagnostic_tests::update_surveillance_testing]
+    DiagnosticTestingStream = 12,
+    /// [crate::regulator_actions::schedule_confirmation_on_detection]
+    RegulatorActionsStream = 13,
+    /// [crate::regulator_zones::update_zone_inspections]
+    ZoneInspectionsStream = 14,
+    /// [crate::regulator_contact_tracing::trace_contacts]
+    ContactTracingStream = 15,
+    /// [crate::regulator_contact_tracing::test_traced_farms]
+    TracedTestingStream = 16,
+    /// [crate::regulator_actions::execute_scheduled_actions]
+    ActionExecutionStream = 17,
+}
+
+/// Derives all the process streams from the [MasterSeed]-resource.
+///
+/// Add as an `exclusive_system` to the startup stage.
+pub fn setup_random_streams(world: &mut World) {
+    let master_seed = *world
+        .get_resource::<MasterSeed>()
+        .expect("missing `MasterSeed` as a resource");
+    insert_random_streams(world, master_seed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_streams_are_independent_of_order() {
+        let master_seed = MasterSeed(20210701);
+        let mut within_herd = ProcessRng::<WithinHerdSpreadStream>::from_master_seed(master_seed);
+        let mut between_herd = ProcessRng::<BetweenHerdSpreadStream>::from_master_seed(master_seed);
+        let draws: Vec<u64> = (0..10).map(|_| within_herd.gen()).collect();
+        let _: Vec<u64> = (0..10).map(|_| between_herd.gen()).collect();
+
+        // drawing from another stream first doesn't change this stream
+        let mut between_herd = ProcessRng::<BetweenHerdSpreadStream>::from_master_seed(master_seed);
+        let _: Vec<u64> = (0..25).map(|_| between_herd.gen()).collect();
+        let mut within_herd = ProcessRng::<WithinHerdSpreadStream>::from_master_seed(master_seed);
+        let redraws: Vec<u64> = (0..10).map(|_| within_herd.gen()).collect();
+        assert_eq!(draws, redraws);
+
+        let mut between_herd = ProcessRng::<BetweenHerdSpreadStream>::from_master_seed(master_seed);
+        let between_herd_draws: Vec<u64> = (0..10).map(|_| between_herd.gen()).collect();
+        assert_ne!(draws, between_herd_draws);
+    }
+
+    #[test]
+    fn test_restore_streams() {
+        let mut world = World::new();
+        insert_random_streams(&mut world, MasterSeed(20210701));
+        let _: f64 = world
+            .get_resource_mut::<ProcessRng<ActiveSurveillanceStream>>()
+            .unwrap()
+            .gen();
+
+        let states = random_streams_state(&world);
+        let mut restored_world = World::new();
+        restore_random_streams(&mut restored_world, &states);
+
+        let next: u64 = world
+            .get_resource_mut::<ProcessRng<ActiveSurveillanceStream>>()
+            .unwrap()
+            .gen();
+        let restored_next: u64 = restored_world
+            .get_resource_mut::<ProcessRng<ActiveSurveillanceStream>>()
+            .unwrap()
+            .gen();
+        assert_eq!(next, restored_next);
+    }
+}
@@ -174,6 +174,15 @@ impl ScenarioTime {
         chrono::NaiveDate::from_ymd(2000, 1, 1)
     }
 
+    /// Returns the tick of `date`, where [ScenarioTime::first_day_date] is tick 1.
+    ///
+    /// Returns `None` if `date` is before the first date.
+    #[must_use]
+    pub fn tick_of_date(&self, date: chrono::NaiveDate) -> Option<Time> {
+        let days = (date - self.first_day_date()).num_days();
+        (days >= 0).then(|| days as Time + 1)
+    }
+
     /// Returns true if it is the first day of the week, optionally provide which week to consider.
     #[must_use]
     pub fn first_day_of_week(&self, week_no: Option<Time>) -> bool {
@@ -4,10 +4,19 @@ use serde::{Deserialize, Serialize};
 use bevy::prelude::*;
 use rand::prelude::*;
 
-use crate::populations::HerdSize;
+use crate::{
+    between_herd_spread_exogenous_model::{
+        ExogenousInfectionRate, ExogenousIntroductions, ExogenousSeasonality,
+    },
+    populations::HerdSize,
+    regulator_vaccination::Vaccinated,
+    scenario_rng::{ProcessRng, SeedInfectionStream, WithinHerdSpreadStream},
+    scenario_time::scenario_timer::ScenarioTime,
+};
 
 #[readonly::make]
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct DiseaseParameters {
     /// Infection rate
     infection_rate: f64,
@@ -90,6 +99,12 @@ impl DiseaseCompartments {
 // TODO: Add a [DiseaseParameter] that is part of the [ScenarioConfiguration]
 
 /// Update disease dynamics
+///
+/// Farms with an [ExogenousInfectionRate] are also infected from the
+/// reservoir, see [crate::between_herd_spread_exogenous_model].
+///
+/// The susceptible animals of [Vaccinated] farms are infected at a reduced
+/// rate, see [crate::regulator_vaccination].
 pub fn update_disease_compartments(
     // scenario_configuration: Res<ScenarioConfiguration>,
     mut query: Query<(
@@ -98,13 +113,35 @@ pub fn update_disease_compartments(
         &mut Infected,
         &mut Recovered,
         &DiseaseParameters,
+        Option<(&ExogenousInfectionRate, &mut ExogenousIntroductions)>,
+        Option<&Vaccinated>,
     )>,
-    mut rng: ResMut<StdRng>,
+    mut rng: ResMut<ProcessRng<WithinHerdSpreadStream>>,
+    exogenous_seasonality: Option<Res<ExogenousSeasonality>>,
+    scenario_time: Option<Res<ScenarioTime>>,
 ) {
-    for (herd_size, mut susceptible, mut infected, mut recovered, disease_parameters) in
-        query.iter_mut()
+    let seasonal_multiplier = exogenous_seasonality.map_or(1., |seasonality| {
+        let scenario_time = scenario_time
+            .as_ref()
+            .expect("`ExogenousSeasonality` requires `ScenarioTime`");
+        seasonality.multiplier(scenario_time.day_in_the_year())
+    });
+
+    for (
+        herd_size,
+        mut susceptible,
+        mut infected,
+        mut recovered,
+        disease_parameters,
+        exogenous,
+        vaccinated,
+    ) in query.iter_mut()
     {
         // dbg!("any");
+        if herd_size.0 == 0 {
+            // culled farms remain empty
+            continue;
+        }
         let DiseaseParameters {
             infection_rate,
             recovery_rate,
@@ -114,7 +151,13 @@ pub fn update_disease_compartments(
         // maybe no-one ever recovers..
         // and maybe no-one ever get infected, so we need to do something about this..
 
-        let delta_infected = infection_rate * (susceptible.0 * infected.0) as f64;
+        let susceptibility = vaccinated.map_or(1., |vaccinated| {
+            let scenario_time = scenario_time
+                .as_ref()
+                .expect("`Vaccinated` requires `ScenarioTime`");
+            vaccinated.susceptibility(scenario_time.current_time())
+        });
+        let delta_infected = infection_rate * susceptibility * (susceptible.0 * infected.0) as f64;
         let delta_infected = delta_infected / herd_size.0 as f64;
         // let delta_infected = delta_infected.round() as usize;
         let delta_infected = if rng.gen_bool(delta_infected.fract()) {
@@ -128,6 +171,24 @@ pub fn update_disease_compartments(
             delta_infected <= susceptible.0,
             "cannot infect more animals than there are present."
         );
+
+        // infections from the reservoir of the animals that remain susceptible
+        let delta_infected = if let Some((exogenous_rate, mut introductions)) = exogenous {
+            let delta_exogenous = (exogenous_rate.0).0
+                * seasonal_multiplier
+                * susceptibility
+                * susceptible.0.saturating_sub(delta_infected) as f64;
+            let delta_exogenous = if rng.gen_bool(delta_exogenous.fract()) {
+                delta_exogenous.ceil()
+            } else {
+                delta_exogenous.floor()
+            } as usize;
+            let delta_exogenous = delta_exogenous.min(susceptible.0.saturating_sub(delta_infected));
+            introductions.0 = delta_exogenous;
+            delta_infected + delta_exogenous
+        } else {
+            delta_infected
+        };
         let delta_recovered = recovery_rate * infected.0 as f64;
         // let delta_recovered = delta_recovered.round() as usize;
         let delta_recovered = if rng.gen_bool(delta_recovered.fract()) {
@@ -155,7 +216,7 @@ pub fn update_disease_compartments(
 
 /// Place one infected individual into the mix.
 pub fn seed_infection_random(
-    mut rng: ResMut<StdRng>,
+    mut rng: ResMut<ProcessRng<SeedInfectionStream>>,
     mut query: Query<(&mut Susceptible, &mut Infected)>,
 ) {
     let mut empty_query = true;
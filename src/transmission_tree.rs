@@ -0,0 +1,606 @@
+//! Transmission tree(s) assembled from the between-herd [InfectionEvents].
+//!
+//! Each run (replicate) gets one [TransmissionTree], which is really a forest,
+//! as there may be more than one index farm. The first infection of a farm
+//! determines its parent (infector), and any subsequent infection events
+//! towards an already infected farm are kept as re-infections, but are not
+//! part of the tree itself.
+//!
+//! The tree can be exported as GraphML, DOT or as a JSON edge list, together
+//! with summary metrics:
+//!
+//! * Generation number per farm, where index farms are generation 0
+//! * Offspring distribution, i.e. no. of farms infected by each infected farm
+//! * Farm-level reproduction number `R` over time, grouped by the tick the
+//!   infector was infected.
+//!
+//! All three formats share the same attributes. The nodes are farms with
+//! `generation`, `infection_tick` and `offspring`, and the edges are
+//! [TransmissionEdge]s with `tick`, `batch_id`, `new_infections`, `route` and
+//! `reinfection`, see [TransmissionEdge::ATTRIBUTES].
+//!
+//! Use [record_transmission_tree] chained after a between-herd spread model,
+//! and before [crate::between_herd_spread_model_record::record_between_herd_infection_events].
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use bevy::app::AppExit;
+
+use crate::{
+    between_herd_spread_model::{InfectionEvents, InfectionRoute},
+    csv_outputs::OutputDirectory,
+    populations::FarmId,
+    prelude::*,
+    scenario_time::scenario_timer::Time,
+};
+
+/// A single between-herd infection event `origin ~> target`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct TransmissionEdge {
+    pub scenario_tick: Time,
+    pub batch_id: usize,
+    #[cfg_attr(feature = "serialize", serde(with = "farm_id_number"))]
+    pub origin: FarmId,
+    #[cfg_attr(feature = "serialize", serde(with = "farm_id_number"))]
+    pub target: FarmId,
+    pub new_infections: usize,
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub route: InfectionRoute,
+    /// `true` if the target farm was already infected when this event occurred,
+    /// thus this edge is not part of the transmission tree.
+    pub reinfection: bool,
+}
+
+impl TransmissionEdge {
+    /// Name and GraphML type of the exported edge attributes, in the order of
+    /// [TransmissionEdge::attribute_values].
+    pub const ATTRIBUTES: [(&'static str, &'static str); 5] = [
+        ("tick", "long"),
+        ("batch_id", "int"),
+        ("new_infections", "int"),
+        ("route", "string"),
+        ("reinfection", "boolean"),
+    ];
+
+    pub fn attribute_values(&self) -> [String; 5] {
+        [
+            self.scenario_tick.to_string(),
+            self.batch_id.to_string(),
+            self.new_infections.to_string(),
+            self.route.to_string(),
+            self.reinfection.to_string(),
+        ]
+    }
+
+    /// Pairs of attribute name and value.
+    fn attributes(&self) -> impl Iterator<Item = (&'static str, String)> {
+        Self::ATTRIBUTES
+            .iter()
+            .map(|(name, _)| *name)
+            .zip(self.attribute_values())
+    }
+}
+
+/// Farm ids are exported as their number.
+#[cfg(feature = "serialize")]
+mod farm_id_number {
+    use super::FarmId;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(farm_id: &FarmId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(farm_id.0 as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FarmId, D::Error> {
+        usize::deserialize(deserializer).map(FarmId::new_single_population)
+    }
+}
+
+/// Who infected whom.
+///
+/// Note: Index farms are inferred as farms that infect others without having
+/// been infected through a recorded event. Their infection time is then the
+/// tick they were first seen as an infectious farm.
+///
+/// The tree is (de)serialised as its replicate no. and its edges, as
+/// everything else can be reconstructed from these.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(Serialize, Deserialize),
+    serde(from = "TransmissionTreeRecord", into = "TransmissionTreeRecord")
+)]
+pub struct TransmissionTree {
+    /// Replicate (iteration) no. of the scenario that this tree belongs to.
+    pub replicate: usize,
+    /// All recorded events, in the order they occurred.
+    edges: Vec<TransmissionEdge>,
+    /// Infector of each (non-index) farm.
+    parent: HashMap<FarmId, FarmId>,
+    /// Time of first infection, or first appearance for index farms.
+    infection_time: HashMap<FarmId, Time>,
+    /// Farms that started a chain of transmission, in order of appearance.
+    index_farms: Vec<FarmId>,
+    /// Farms that are known to have been infected, with the number of farms
+    /// they have infected so far.
+    secondary_infections: HashMap<FarmId, usize>,
+    /// Infected farms by their [TransmissionTree::infection_time].
+    infected_at: BTreeMap<Time, Vec<FarmId>>,
+}
+
+impl TransmissionTree {
+    pub fn new(replicate: usize) -> Self {
+        Self {
+            replicate,
+            ..Default::default()
+        }
+    }
+
+    /// Rebuild a tree from previously recorded edges.
+    pub fn from_edges(replicate: usize, edges: impl IntoIterator<Item = TransmissionEdge>) -> Self {
+        let mut tree = Self::new(replicate);
+        for edge in edges {
+            tree.add_event(
+                edge.scenario_tick,
+                edge.batch_id,
+                edge.origin,
+                edge.target,
+                edge.new_infections,
+                edge.route,
+            );
+        }
+        tree
+    }
+
+    /// Add a batch of between-herd infection events to the tree.
+    pub fn add_events(&mut self, events: &InfectionEvents) {
+        for &(origin, target, new_infections) in &events.events_values {
+            self.add_event(
+                events.scenario_tick,
+                events.batch_id,
+                origin,
+                target,
+                new_infections,
+                events.route,
+            );
+        }
+    }
+
+    fn add_event(
+        &mut self,
+        scenario_tick: Time,
+        batch_id: usize,
+        origin: FarmId,
+        target: FarmId,
+        new_infections: usize,
+        route: InfectionRoute,
+    ) {
+        if !self.secondary_infections.contains_key(&origin) {
+            // an infectious farm that we haven't seen infected
+            self.index_farms.push(origin);
+            self.add_infected_farm(origin, scenario_tick);
+        }
+
+        let reinfection = self.secondary_infections.contains_key(&target);
+        if !reinfection {
+            self.parent.insert(target, origin);
+            self.add_infected_farm(target, scenario_tick);
+            *self.secondary_infections.get_mut(&origin).unwrap() += 1;
+        }
+
+        self.edges.push(TransmissionEdge {
+            scenario_tick,
+            batch_id,
+            origin,
+            target,
+            new_infections,
+            route,
+            reinfection,
+        });
+    }
+
+    fn add_infected_farm(&mut self, farm_id: FarmId, scenario_tick: Time) {
+        self.secondary_infections.insert(farm_id, 0);
+        self.infection_time.insert(farm_id, scenario_tick);
+        self.infected_at
+            .entry(scenario_tick)
+            .or_default()
+            .push(farm_id);
+    }
+
+    /// All recorded events, including re-infections.
+    pub fn edges(&self) -> &[TransmissionEdge] {
+        &self.edges
+    }
+
+    /// Farms without a recorded infector.
+    pub fn index_farms(&self) -> &[FarmId] {
+        &self.index_farms
+    }
+
+    /// Returns the infector of `farm_id` if it is not an index farm.
+    pub fn infected_by(&self, farm_id: &FarmId) -> Option<FarmId> {
+        self.parent.get(farm_id).copied()
+    }
+
+    /// Number of farms that `farm_id` has infected so far.
+    pub fn secondary_infections(&self, farm_id: &FarmId) -> usize {
+        self.secondary_infections
+            .get(farm_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Farms that were infected (or first seen) within `ticks`, with their
+    /// secondary infections so far.
+    pub fn infected_within(
+        &self,
+        ticks: impl std::ops::RangeBounds<Time>,
+    ) -> impl Iterator<Item = (FarmId, usize)> + '_ {
+        self.infected_at
+            .range(ticks)
+            .flat_map(|(_, farms)| farms)
+            .map(move |farm_id| (*farm_id, self.secondary_infections[farm_id]))
+    }
+
+    /// Returns the tick the farm was infected, if it was.
+    pub fn infection_time(&self, farm_id: &FarmId) -> Option<Time> {
+        self.infection_time.get(farm_id).copied()
+    }
+
+    /// Generation number of every infected farm, with index farms as
+    /// generation 0.
+    pub fn generations(&self) -> BTreeMap<FarmId, usize> {
+        let mut generations: BTreeMap<FarmId, usize> = self
+            .index_farms
+            .iter()
+            .map(|&farm_id| (farm_id, 0))
+            .collect();
+        // parents are always infected before their offspring, and thus
+        // appear earlier in the list of edges.
+        for edge in self.edges.iter().filter(|edge| !edge.reinfection) {
+            let generation = generations[&edge.origin] + 1;
+            generations.insert(edge.target, generation);
+        }
+        generations
+    }
+
+    /// Number of farms infected by each infected farm (secondary infections).
+    pub fn offspring(&self) -> BTreeMap<FarmId, usize> {
+        self.secondary_infections
+            .iter()
+            .map(|(farm_id, secondary)| (*farm_id, *secondary))
+            .collect()
+    }
+
+    /// Offspring distribution as `no. of secondary infections ~> no. of farms`.
+    pub fn offspring_distribution(&self) -> BTreeMap<usize, usize> {
+        let mut distribution = BTreeMap::new();
+        for count in self.offspring().values() {
+            *distribution.entry(*count).or_insert(0) += 1;
+        }
+        distribution
+    }
+
+    /// Farm-level reproduction number over time: Mean number of secondary
+    /// infections caused by farms that were infected at a given tick.
+    ///
+    /// Note: The most recent ticks are underestimated, as their farms may
+    /// still infect others.
+    pub fn reproduction_number_over_time(&self) -> BTreeMap<Time, f64> {
+        let mut cohorts: BTreeMap<Time, (usize, usize)> = BTreeMap::new();
+        for (farm_id, count) in self.offspring() {
+            let infection_time = self.infection_time[&farm_id];
+            let cohort = cohorts.entry(infection_time).or_insert((0, 0));
+            cohort.0 += count;
+            cohort.1 += 1;
+        }
+        cohorts
+            .into_iter()
+            .map(|(tick, (secondary, infectors))| (tick, secondary as f64 / infectors as f64))
+            .collect()
+    }
+
+    /// Export the tree in the [DOT](https://graphviz.org/doc/info/lang.html) format.
+    pub fn to_dot(&self) -> String {
+        let generations = self.generations();
+        let offspring = self.offspring();
+        let mut dot = String::new();
+        writeln!(dot, "digraph transmission_tree_{} {{", self.replicate).unwrap();
+        for (farm_id, generation) in &generations {
+            writeln!(
+                dot,
+                "  {} [generation={}, infection_tick={}, offspring={}];",
+                farm_id.0, generation, self.infection_time[farm_id], offspring[farm_id]
+            )
+            .unwrap();
+        }
+        for edge in &self.edges {
+            let attributes = edge
+                .attributes()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .join(", ");
+            writeln!(
+                dot,
+                "  {} -> {} [{}{}];",
+                edge.origin.0,
+                edge.target.0,
+                attributes,
+                if edge.reinfection {
+                    ", style=dashed"
+                } else {
+                    ""
+                }
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Export the tree in the [GraphML](http://graphml.graphdrawing.org/) format.
+    pub fn to_graphml(&self) -> String {
+        let generations = self.generations();
+        let offspring = self.offspring();
+        let mut graphml = String::new();
+        graphml.push_str(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#,
+            "\n",
+            r#"  <key id="generation" for="node" attr.name="generation" attr.type="int"/>"#,
+            "\n",
+            r#"  <key id="infection_tick" for="node" attr.name="infection_tick" attr.type="long"/>"#,
+            "\n",
+            r#"  <key id="offspring" for="node" attr.name="offspring" attr.type="int"/>"#,
+            "\n",
+        ));
+        for (name, attr_type) in &TransmissionEdge::ATTRIBUTES {
+            writeln!(
+                graphml,
+                r#"  <key id="{0}" for="edge" attr.name="{0}" attr.type="{1}"/>"#,
+                name, attr_type
+            )
+            .unwrap();
+        }
+        writeln!(
+            graphml,
+            r#"  <graph id="transmission_tree_{}" edgedefault="directed">"#,
+            self.replicate
+        )
+        .unwrap();
+        for (farm_id, generation) in &generations {
+            writeln!(
+                graphml,
+                concat!(
+                    r#"    <node id="{0}">"#,
+                    r#"<data key="generation">{1}</data>"#,
+                    r#"<data key="infection_tick">{2}</data>"#,
+                    r#"<data key="offspring">{3}</data>"#,
+                    r#"</node>"#
+                ),
+                farm_id.0, generation, self.infection_time[farm_id], offspring[farm_id]
+            )
+            .unwrap();
+        }
+        for (edge_no, edge) in self.edges.iter().enumerate() {
+            let data = edge
+                .attributes()
+                .map(|(name, value)| format!(r#"<data key="{}">{}</data>"#, name, value))
+                .join("");
+            writeln!(
+                graphml,
+                r#"    <edge id="e{}" source="{}" target="{}">{}</edge>"#,
+                edge_no, edge.origin.0, edge.target.0, data
+            )
+            .unwrap();
+        }
+        graphml.push_str("  </graph>\n</graphml>\n");
+        graphml
+    }
+
+    /// Export the tree as a JSON edge list along with the summary metrics.
+    #[cfg(feature = "serialize")]
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct TransmissionTreeExport<'a> {
+            replicate: usize,
+            index_farms: Vec<usize>,
+            edges: &'a [TransmissionEdge],
+            generation: BTreeMap<usize, usize>,
+            offspring_distribution: BTreeMap<usize, usize>,
+            reproduction_number: BTreeMap<Time, f64>,
+        }
+
+        serde_json::to_string_pretty(&TransmissionTreeExport {
+            replicate: self.replicate,
+            index_farms: self.index_farms.iter().map(|farm_id| farm_id.0).collect(),
+            edges: &self.edges,
+            generation: self
+                .generations()
+                .into_iter()
+                .map(|(farm_id, generation)| (farm_id.0, generation))
+                .collect(),
+            offspring_distribution: self.offspring_distribution(),
+            reproduction_number: self.reproduction_number_over_time(),
+        })
+    }
+
+    /// Writes `transmission_tree_<replicate>.{graphml,dot,json}` to `directory`.
+    pub fn export(&self, directory: impl AsRef<std::path::Path>) -> Result<()> {
+        let directory = directory.as_ref();
+        std::fs::create_dir_all(directory)?;
+        let file_stem = format!("transmission_tree_{}", self.replicate);
+
+        std::fs::write(
+            directory.join(&file_stem).with_extension("graphml"),
+            self.to_graphml(),
+        )?;
+        std::fs::write(
+            directory.join(&file_stem).with_extension("dot"),
+            self.to_dot(),
+        )?;
+        #[cfg(feature = "serialize")]
+        std::fs::write(
+            directory.join(&file_stem).with_extension("json"),
+            self.to_json()?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Serialised form of [TransmissionTree].
+#[cfg(feature = "serialize")]
+#[derive(Serialize, Deserialize)]
+struct TransmissionTreeRecord {
+    replicate: usize,
+    edges: Vec<TransmissionEdge>,
+}
+
+#[cfg(feature = "serialize")]
+impl From<TransmissionTreeRecord> for TransmissionTree {
+    fn from(record: TransmissionTreeRecord) -> Self {
+        Self::from_edges(record.replicate, record.edges)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl From<TransmissionTree> for TransmissionTreeRecord {
+    fn from(tree: TransmissionTree) -> Self {
+        Self {
+            replicate: tree.replicate,
+            edges: tree.edges,
+        }
+    }
+}
+
+/// This is coupled with the systems [record_transmission_tree] and
+/// [export_transmission_tree_at_app_exit].
+pub fn setup_transmission_tree(mut commands: Commands) {
+    commands.insert_resource(TransmissionTree::new(0));
+}
+
+/// Adds the infection events to the [TransmissionTree], and passes them on
+/// to the next system in the chain.
+pub fn record_transmission_tree(
+    In(events): In<Option<InfectionEvents>>,
+    mut transmission_tree: ResMut<TransmissionTree>,
+) -> Option<InfectionEvents> {
+    if let Some(events) = events.as_ref() {
+        transmission_tree.add_events(events);
+    }
+    events
+}
+
+/// Writes the transmission tree to the [OutputDirectory] once the scenario
+/// is terminated.
+pub fn export_transmission_tree_at_app_exit(
+    transmission_tree: Res<TransmissionTree>,
+    output_directory: Option<Res<OutputDirectory>>,
+    mut event_reader: EventReader<AppExit>,
+) {
+    if event_reader.iter().next().is_some() {
+        let output_directory = output_directory.map_or_else(Default::default, |x| x.clone());
+        transmission_tree
+            .export(&output_directory.0)
+            .expect("failed to export the transmission tree");
+        info!(
+            "Exported transmission tree with {} infected farms",
+            transmission_tree.secondary_infections.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn farm(farm_id: usize) -> FarmId {
+        FarmId::new_single_population(farm_id)
+    }
+
+    /// `1 ~> 2 ~> {3, 4}` and `3 ~> 2` as a re-infection.
+    fn example_tree() -> TransmissionTree {
+        let mut tree = TransmissionTree::new(1);
+        tree.add_events(&InfectionEvents {
+            scenario_tick: 3,
+            batch_id: 1,
+            route: InfectionRoute::Trade,
+            events_values: vec![(farm(1), farm(2), 1)],
+        });
+        tree.add_events(&InfectionEvents {
+            scenario_tick: 5,
+            batch_id: 2,
+            route: InfectionRoute::Trade,
+            events_values: vec![(farm(2), farm(3), 1), (farm(2), farm(4), 1)],
+        });
+        tree.add_events(&InfectionEvents {
+            scenario_tick: 9,
+            batch_id: 3,
+            route: InfectionRoute::LocalSpread,
+            events_values: vec![(farm(3), farm(2), 1)],
+        });
+        tree
+    }
+
+    #[test]
+    fn test_generations_and_offspring() {
+        let tree = example_tree();
+
+        assert_eq!(tree.index_farms(), &[farm(1)]);
+        assert_eq!(tree.infected_by(&farm(3)), Some(farm(2)));
+        assert_eq!(tree.infected_by(&farm(1)), None);
+
+        let generations = tree.generations();
+        assert_eq!(generations[&farm(1)], 0);
+        assert_eq!(generations[&farm(2)], 1);
+        assert_eq!(generations[&farm(3)], 2);
+        assert_eq!(generations[&farm(4)], 2);
+
+        // re-infection of farm 2 doesn't count as offspring of farm 3
+        let offspring = tree.offspring();
+        assert_eq!(offspring[&farm(1)], 1);
+        assert_eq!(offspring[&farm(2)], 2);
+        assert_eq!(offspring[&farm(3)], 0);
+
+        assert_eq!(
+            tree.offspring_distribution(),
+            maplit::btreemap! { 0 => 2, 1 => 1, 2 => 1 }
+        );
+
+        let reproduction_number = tree.reproduction_number_over_time();
+        // farm 1 and 2 are both infected at tick 3
+        assert_eq!(reproduction_number[&3], 1.5);
+        assert_eq!(reproduction_number[&5], 0.0);
+    }
+
+    #[test]
+    fn test_exports() {
+        let tree = example_tree();
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph transmission_tree_1"));
+        assert_eq!(dot.matches("->").count(), 4);
+
+        let graphml = tree.to_graphml();
+        assert_eq!(graphml.matches("<node ").count(), 4);
+        assert_eq!(graphml.matches("<edge ").count(), 4);
+
+        #[cfg(feature = "serialize")]
+        {
+            let json: serde_json::Value = serde_json::from_str(&tree.to_json().unwrap()).unwrap();
+            assert_eq!(json["edges"].as_array().unwrap().len(), 4);
+            assert_eq!(json["edges"][3]["route"], "local_spread");
+            assert_eq!(json["generation"]["4"], 2);
+            assert_eq!(json["edges"][3]["origin"], 3);
+
+            let record = serde_json::to_string(&tree).unwrap();
+            let restored: TransmissionTree = serde_json::from_str(&record).unwrap();
+            assert_eq!(restored.edges(), tree.edges());
+            assert_eq!(restored.generations(), tree.generations());
+        }
+    }
+}